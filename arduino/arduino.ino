@@ -11,6 +11,9 @@ void setup() {
 
   lcd.setCursor(0, 1);
   lcd.print("Searching...");
+
+  // Tells the host the board is done booting after the reset caused by opening the port.
+  Serial.println("READY");
 }
 
 void loop() {
@@ -0,0 +1,2111 @@
+//! PDP Explorer API client and classification of proofset roots into display statuses.
+
+#[cfg(any(test, feature = "test-util"))]
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+#[cfg(any(test, feature = "test-util"))]
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use futures_util::TryStreamExt as _;
+use log::{debug, info, log, warn, Level};
+use metrics::{counter, histogram};
+use parking_lot::Mutex;
+use rand::Rng as _;
+use reqwest::Client;
+use serde::de::{DeserializeSeed, Error as _, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::Deserialize;
+use tokio_util::io::{StreamReader, SyncIoBridge};
+use tracing::Instrument as _;
+
+use crate::cli::{ClassifyBy, Cli, RootSelection, RootsOrderBy, SortOrder, ZeroEpoch};
+use crate::error::PdpError;
+use crate::status::{DisplayStatus, RootStatus};
+
+pub(crate) const API_PREFLIGHT_TIMEOUT_SECS: u64 = 5;
+pub(crate) const API_MAX_PAGES: u64 = 1_000;
+// Error code the API sends for an unknown proofset.
+pub(crate) const PROOFSET_NOT_FOUND_CODE: &str = "proofset_not_found";
+// Characters of a non-JSON error body kept in logs and errors, e.g. of an HTML error page.
+pub(crate) const API_ERROR_TEXT_MAX_LEN: usize = 200;
+
+// These structs must contain all fields from the API response for proper deserialization,
+// even if we don't use all fields in our logic.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ProofSetRoots {
+    pub data: Vec<ProofSetRoot>,
+    pub metadata: Metadata,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ProofSetRoot {
+    #[serde(rename = "rootId")]
+    pub root_id: u64,
+    pub cid: String,
+    pub size: u64,
+    pub removed: bool,
+    #[serde(rename = "totalPeriodsFaulted")]
+    pub total_periods_faulted: u64,
+    #[serde(rename = "totalProofsSubmitted")]
+    pub total_proofs_submitted: u64,
+    #[serde(rename = "lastProvenEpoch")]
+    pub last_proven_epoch: Option<u64>,
+    #[serde(rename = "lastProvenAt")]
+    pub last_proven_at: Option<String>,
+    #[serde(rename = "lastFaultedEpoch")]
+    pub last_faulted_epoch: Option<u64>,
+    #[serde(rename = "lastFaultedAt")]
+    pub last_faulted_at: Option<String>,
+    #[serde(rename = "createdAt")]
+    pub created_at: String,
+}
+
+impl ProofSetRoot {
+    /// Last proven epoch, or `None` if the root hasn't been proven yet.
+    pub fn proven_epoch(&self, zero_epoch: ZeroEpoch) -> Option<u64> {
+        epoch_if_set(self.last_proven_epoch, zero_epoch)
+    }
+
+    /// Last faulted epoch, or `None` if the root hasn't faulted yet.
+    pub fn faulted_epoch(&self, zero_epoch: ZeroEpoch) -> Option<u64> {
+        epoch_if_set(self.last_faulted_epoch, zero_epoch)
+    }
+
+    /// When the root was added, or `None` if `createdAt` can't be parsed.
+    pub fn created_at(&self) -> Option<DateTime<Utc>> {
+        DateTime::parse_from_rfc3339(&self.created_at)
+            .inspect_err(|e| {
+                debug!(
+                    "Ignoring unparseable createdAt {:?}: {}",
+                    self.created_at, e
+                )
+            })
+            .ok()
+            .map(|created_at| created_at.with_timezone(&Utc))
+    }
+
+    /// When the root was last proven, or `None` if it hasn't been. Without a parseable
+    /// `lastProvenAt`, it's derived from the last proven epoch.
+    pub fn proven_at(&self, clock: ChainClock, zero_epoch: ZeroEpoch) -> Option<DateTime<Utc>> {
+        parse_timestamp("lastProvenAt", self.last_proven_at.as_deref()).or_else(|| {
+            self.proven_epoch(zero_epoch)
+                .map(|epoch| clock.epoch_to_datetime(epoch))
+        })
+    }
+
+    /// When the root was last proven, as compared by `options.by`: the epoch, or the
+    /// `lastProvenAt` timestamp in milliseconds. `None` if the root hasn't been proven yet.
+    pub fn proven_mark(&self, options: ClassifyOptions) -> Option<i64> {
+        match options.by {
+            ClassifyBy::Epoch => self
+                .proven_epoch(options.zero_epoch)
+                .map(|epoch| epoch as i64),
+            ClassifyBy::Timestamp => {
+                parse_timestamp("lastProvenAt", self.last_proven_at.as_deref())
+                    .map(|proven_at| proven_at.timestamp_millis())
+            }
+        }
+    }
+
+    /// When the root last faulted, as compared by `options.by`, see
+    /// [`ProofSetRoot::proven_mark`]. `None` if the root hasn't faulted yet.
+    pub fn faulted_mark(&self, options: ClassifyOptions) -> Option<i64> {
+        match options.by {
+            ClassifyBy::Epoch => self
+                .faulted_epoch(options.zero_epoch)
+                .map(|epoch| epoch as i64),
+            ClassifyBy::Timestamp => {
+                parse_timestamp("lastFaultedAt", self.last_faulted_at.as_deref())
+                    .map(|faulted_at| faulted_at.timestamp_millis())
+            }
+        }
+    }
+
+    /// Share of the root's proving periods that faulted, 0 if it has none yet.
+    pub fn fault_ratio(&self) -> f64 {
+        let periods = self.total_proofs_submitted + self.total_periods_faulted;
+        if periods == 0 {
+            return 0.0;
+        }
+        self.total_periods_faulted as f64 / periods as f64
+    }
+
+    /// Whether the root faulted after it was last proven, or faulted without ever being proven.
+    pub fn is_faulty(&self, options: ClassifyOptions) -> bool {
+        match (self.proven_mark(options), self.faulted_mark(options)) {
+            (Some(proven), Some(faulted)) => proven < faulted,
+            (None, Some(_)) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the root was proven again after it last faulted.
+    pub fn is_recovered(&self, options: ClassifyOptions) -> bool {
+        matches!(
+            (self.proven_mark(options), self.faulted_mark(options)),
+            (Some(proven), Some(faulted)) if proven > faulted
+        )
+    }
+}
+
+/// Parses the RFC 3339 timestamp of the `field` of a root, `None` if it's unset or can't be parsed.
+fn parse_timestamp(field: &str, value: Option<&str>) -> Option<DateTime<Utc>> {
+    let value = value?;
+    DateTime::parse_from_rfc3339(value)
+        .inspect_err(|e| debug!("Ignoring unparseable {} {:?}: {}", field, value, e))
+        .ok()
+        .map(|parsed| parsed.with_timezone(&Utc))
+}
+
+/// Start time of chain epochs, see `--chain-genesis-unix` and `--chain-epoch-secs`.
+#[derive(Debug, Clone, Copy)]
+pub struct ChainClock {
+    pub genesis_unix: i64,
+    pub epoch_secs: i64,
+}
+
+impl ChainClock {
+    pub(crate) fn from_cli(cli: &Cli) -> Self {
+        Self {
+            genesis_unix: cli.chain_genesis_unix,
+            epoch_secs: cli.chain_epoch_secs,
+        }
+    }
+
+    pub fn epoch_to_datetime(&self, epoch: u64) -> DateTime<Utc> {
+        epoch_to_datetime(epoch, self.genesis_unix, self.epoch_secs)
+    }
+}
+
+/// Start time of `epoch` on a chain with the given genesis and epoch duration. Epochs too far in
+/// the future to represent saturate at the latest representable time.
+pub fn epoch_to_datetime(epoch: u64, genesis_unix: i64, epoch_secs: i64) -> DateTime<Utc> {
+    i64::try_from(epoch)
+        .ok()
+        .and_then(|epoch| epoch.checked_mul(epoch_secs))
+        .and_then(|offset| offset.checked_add(genesis_unix))
+        .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0))
+        .unwrap_or(DateTime::<Utc>::MAX_UTC)
+}
+
+/// How roots are classified, see `--classify-by` and `--zero-epoch`.
+#[derive(Debug, Clone, Copy)]
+pub struct ClassifyOptions {
+    pub by: ClassifyBy,
+    pub zero_epoch: ZeroEpoch,
+}
+
+impl ClassifyOptions {
+    pub(crate) fn from_cli(cli: &Cli) -> Self {
+        Self {
+            by: cli.classify_by,
+            zero_epoch: cli.zero_epoch,
+        }
+    }
+}
+
+/// `epoch` unless it's unset, which an epoch of `0` is with [`ZeroEpoch::Unset`].
+pub(crate) fn epoch_if_set(epoch: Option<u64>, zero_epoch: ZeroEpoch) -> Option<u64> {
+    epoch.filter(|&epoch| !(zero_epoch == ZeroEpoch::Unset && epoch == 0))
+}
+
+/// Proofset-level details. Only the fields used are deserialized, and all of them are optional as
+/// not every API version reports them.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ProofSet {
+    #[serde(rename = "totalRoots")]
+    pub total_roots: Option<u64>,
+    #[serde(rename = "totalFaultedPeriods")]
+    pub total_faulted_periods: Option<u64>,
+    #[serde(rename = "nextChallengeEpoch")]
+    pub next_challenge_epoch: Option<u64>,
+    #[serde(rename = "isActive")]
+    pub is_active: Option<bool>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Metadata {
+    pub total: u64,
+    pub offset: u64,
+    pub limit: u64,
+}
+
+/// Source of the roots of a proofset.
+pub trait ProofStatusProvider {
+    fn fetch_roots(
+        &self,
+        proofset_id: &str,
+    ) -> impl std::future::Future<Output = Result<ProofSetRoots, PdpError>> + Send;
+
+    /// Fetches the roots of a proofset that may match the first of `cids` present, tried in
+    /// order. Sources that can't filter roots return all of them.
+    fn fetch_roots_matching(
+        &self,
+        proofset_id: &str,
+        _cids: &[String],
+    ) -> impl std::future::Future<Output = Result<ProofSetRoots, PdpError>> + Send {
+        self.fetch_roots(proofset_id)
+    }
+
+    /// Fetches the proofset itself. An unknown proofset fails with
+    /// [`PdpError::ProofsetNotFound`].
+    fn fetch_proofset(
+        &self,
+        proofset_id: &str,
+    ) -> impl std::future::Future<Output = Result<ProofSet, PdpError>> + Send;
+
+    /// Takes a request of `proofset_id` off the rate limit without waiting, returning `false` if
+    /// it's reached, so the file can wait for the next poll cycle rather than hold up the others.
+    fn try_reserve(&self, _proofset_id: &str) -> bool {
+        true
+    }
+
+    /// API requests made so far, retries and further pages included. Sources that make no
+    /// requests report none.
+    fn requests_made(&self) -> u64 {
+        0
+    }
+}
+
+/// Fetches roots from the PDP explorer API, failing over between its base URLs.
+pub struct HttpProofStatusProvider {
+    client: Client,
+    api_base_urls: Vec<String>,
+    /// Index of the base URL that last served a response, tried first.
+    preferred: AtomicUsize,
+    query: RootsQuery,
+    retry: RetryPolicy,
+    limiter: RateLimiter,
+    /// Query parameter roots are filtered by CID with, see `--api-cid-filter-param`.
+    cid_filter_param: Option<String>,
+    /// Set once the API returned roots not matching the CID filter, so it's no longer sent.
+    cid_filter_ignored: AtomicBool,
+}
+
+impl HttpProofStatusProvider {
+    /// Uses `client` with the API URL, roots query and retry policy configured in `cli`.
+    pub fn new(client: Client, cli: &Cli) -> Self {
+        Self {
+            client,
+            api_base_urls: cli.api_url.clone(),
+            preferred: AtomicUsize::new(0),
+            query: RootsQuery::from_cli(cli),
+            retry: RetryPolicy::from_cli(cli),
+            limiter: RateLimiter::new(cli.api_rate_limit),
+            cid_filter_param: cli.api_cid_filter_param.clone(),
+            cid_filter_ignored: AtomicBool::new(false),
+        }
+    }
+
+    /// Runs `request` against the preferred base URL, then the others in order, moving on after
+    /// connection failures and server errors. Other errors, such as a 404, are authoritative and
+    /// returned right away.
+    async fn with_failover<'a, T, F: std::future::Future<Output = Result<T, PdpError>>>(
+        &'a self,
+        request: impl Fn(&'a str) -> F,
+    ) -> Result<T, PdpError> {
+        let preferred = self.preferred.load(Ordering::Relaxed);
+        let others = (0..self.api_base_urls.len()).filter(|&index| index != preferred);
+        let mut order = std::iter::once(preferred).chain(others).peekable();
+        while let Some(index) = order.next() {
+            let api_base_url = &self.api_base_urls[index];
+            match request(api_base_url).await {
+                Ok(response) => {
+                    debug!("Response served by {}", api_base_url);
+                    self.preferred.store(index, Ordering::Relaxed);
+                    return Ok(response);
+                }
+                Err(e @ PdpError::ApiUnreachable(_)) if order.peek().is_some() => {
+                    warn!("API at {} failed, failing over: {:#}", api_base_url, e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        unreachable!("there is always at least one API base URL")
+    }
+
+    /// Fetches all roots of a proofset, keeping only the ones with one of `cids` while they're
+    /// read, or all of them if `cids` is empty.
+    async fn fetch_roots_keeping(
+        &self,
+        proofset_id: &str,
+        cids: &[String],
+    ) -> Result<ProofSetRoots, PdpError> {
+        let query = RootsQuery {
+            keep_cids: cids.to_vec(),
+            ..self.query.clone()
+        };
+        self.with_failover(|api_base_url| {
+            check_proof_status(
+                &self.client,
+                api_base_url,
+                proofset_id,
+                &query,
+                self.retry,
+                &self.limiter,
+            )
+        })
+        .await
+    }
+}
+
+impl ProofStatusProvider for HttpProofStatusProvider {
+    fn try_reserve(&self, _proofset_id: &str) -> bool {
+        self.limiter.try_acquire()
+    }
+
+    fn requests_made(&self) -> u64 {
+        self.limiter.acquired()
+    }
+
+    async fn fetch_roots(&self, proofset_id: &str) -> Result<ProofSetRoots, PdpError> {
+        self.fetch_roots_keeping(proofset_id, &[]).await
+    }
+
+    async fn fetch_roots_matching(
+        &self,
+        proofset_id: &str,
+        cids: &[String],
+    ) -> Result<ProofSetRoots, PdpError> {
+        let param = match &self.cid_filter_param {
+            Some(param) if !self.cid_filter_ignored.load(Ordering::Relaxed) => param,
+            _ => return self.fetch_roots_keeping(proofset_id, cids).await,
+        };
+        let mut filtered = None;
+        for cid in cids {
+            let query = RootsQuery {
+                cid_filter: Some((param.clone(), cid.clone())),
+                ..self.query.clone()
+            };
+            let roots = self
+                .with_failover(|api_base_url| {
+                    check_proof_status(
+                        &self.client,
+                        api_base_url,
+                        proofset_id,
+                        &query,
+                        self.retry,
+                        &self.limiter,
+                    )
+                })
+                .await?;
+            if !roots.data.iter().all(|root| root.cid == *cid) {
+                warn!(
+                    "API ignores the {} query parameter, fetching all roots and filtering them locally",
+                    param
+                );
+                self.cid_filter_ignored.store(true, Ordering::Relaxed);
+                return self.fetch_roots_keeping(proofset_id, cids).await;
+            }
+            debug!(
+                "API filtered the roots of proofset {} by {}={}, {} matching",
+                proofset_id,
+                param,
+                cid,
+                roots.data.len()
+            );
+            let found = !roots.data.is_empty();
+            filtered = Some(roots);
+            if found {
+                break;
+            }
+        }
+        match filtered {
+            Some(roots) => Ok(roots),
+            None => self.fetch_roots_keeping(proofset_id, cids).await,
+        }
+    }
+
+    async fn fetch_proofset(&self, proofset_id: &str) -> Result<ProofSet, PdpError> {
+        self.with_failover(|api_base_url| async move {
+            self.limiter.acquire().await;
+            fetch_proofset(&self.client, api_base_url, proofset_id)
+                .await
+                .map_err(|e| PdpError::from_api(e, proofset_id))
+        })
+        .await
+    }
+}
+
+/// Serves the roots and proofsets set on it, failing for unknown proofsets, or failing every request
+/// as unreachable while [`FakeProvider::set_unreachable`]. Clones share the responses, so a test can
+/// change them while another task polls. Only built for tests, or with the `test-util` feature.
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Default, Clone)]
+pub struct FakeProvider {
+    roots: Arc<Mutex<HashMap<String, ProofSetRoots>>>,
+    proofsets: Arc<Mutex<HashMap<String, ProofSet>>>,
+    unreachable: Arc<AtomicBool>,
+    /// Proofsets whose requests are over the rate limit.
+    throttled: Arc<Mutex<HashSet<String>>>,
+    requests: Arc<AtomicUsize>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl FakeProvider {
+    pub fn set_roots(&self, proofset_id: &str, roots: ProofSetRoots) {
+        self.roots.lock().insert(proofset_id.to_string(), roots);
+    }
+
+    pub fn set_proofset(&self, proofset_id: &str, proofset: ProofSet) {
+        self.proofsets
+            .lock()
+            .insert(proofset_id.to_string(), proofset);
+    }
+
+    pub fn set_unreachable(&self, unreachable: bool) {
+        self.unreachable.store(unreachable, Ordering::Relaxed);
+    }
+
+    pub fn set_throttled(&self, proofset_id: &str, throttled: bool) {
+        let mut throttled_proofsets = self.throttled.lock();
+        if throttled {
+            throttled_proofsets.insert(proofset_id.to_string());
+        } else {
+            throttled_proofsets.remove(proofset_id);
+        }
+    }
+
+    /// Requests served so far, failed ones included.
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    fn serve<T: Clone>(
+        &self,
+        responses: &Mutex<HashMap<String, T>>,
+        proofset_id: &str,
+    ) -> Result<T, PdpError> {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if self.unreachable.load(Ordering::Relaxed) {
+            return Err(PdpError::ApiUnreachable(anyhow::anyhow!(
+                "connection refused"
+            )));
+        }
+        responses
+            .lock()
+            .get(proofset_id)
+            .cloned()
+            .ok_or_else(|| PdpError::ProofsetNotFound(proofset_id.to_string()))
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl ProofStatusProvider for FakeProvider {
+    fn try_reserve(&self, proofset_id: &str) -> bool {
+        !self.throttled.lock().contains(proofset_id)
+    }
+
+    fn requests_made(&self) -> u64 {
+        self.requests() as u64
+    }
+
+    async fn fetch_roots(&self, proofset_id: &str) -> Result<ProofSetRoots, PdpError> {
+        self.serve(&self.roots, proofset_id)
+    }
+
+    async fn fetch_proofset(&self, proofset_id: &str) -> Result<ProofSet, PdpError> {
+        self.serve(&self.proofsets, proofset_id)
+    }
+}
+
+/// Checks that `url` is a well-formed http(s) URL, so a typo fails startup rather than every poll.
+pub(crate) fn validate_api_base_url(url: &str) -> anyhow::Result<reqwest::Url> {
+    let parsed = reqwest::Url::parse(url).context(format!("Malformed API base URL: {}", url))?;
+    anyhow::ensure!(
+        matches!(parsed.scheme(), "http" | "https"),
+        "API base URL must use http or https, got: {}",
+        parsed.scheme()
+    );
+    anyhow::ensure!(parsed.has_host(), "API base URL has no host: {}", url);
+    Ok(parsed)
+}
+
+/// Makes a cheap request to the API and logs whether it is reachable, returning whether it is. An
+/// unreachable API isn't fatal since it may come up later.
+pub(crate) async fn preflight_api(client: &Client, url: &reqwest::Url) -> bool {
+    let response = client
+        .head(url.clone())
+        .timeout(Duration::from_secs(API_PREFLIGHT_TIMEOUT_SECS))
+        .send()
+        .await;
+    match response {
+        Ok(response) if !response.status().is_server_error() => {
+            info!("API reachable at {} (status: {})", url, response.status());
+            true
+        }
+        Ok(response) => {
+            warn!(
+                "API at {} responded with status: {}",
+                url,
+                response.status()
+            );
+            false
+        }
+        Err(e) => {
+            warn!("API not reachable at {}: {}", url, e);
+            false
+        }
+    }
+}
+
+/// Decides the display status of `target_cid` from the proofset roots, or `None` if no root matches.
+/// Removed roots are ignored unless all matching roots are removed, which makes the file removed.
+/// A faulty root makes the file faulty even if other roots are proven, and matching roots that are
+/// neither proven nor faulted yet leave it stored. A root that faulted without ever being proven is
+/// faulty too. A proven file is degraded if any proven root faulted in more than
+/// `degraded_fault_ratio` of its periods, or else recovered if any proven root faulted before it
+/// was last proven.
+///
+/// With [`RootSelection::Latest`] only the most recently created matching root counts.
+///
+/// With [`ClassifyBy::Epoch`] proofs and faults are ordered by `lastProvenEpoch` and
+/// `lastFaultedEpoch`, with [`ClassifyBy::Timestamp`] by `lastProvenAt` and `lastFaultedAt`. They
+/// disagree when an API version sets one pair and not the other, e.g. a root with a proven epoch
+/// but a null `lastProvenAt` is proven by epoch and only stored by timestamp.
+///
+/// With [`ZeroEpoch::Unset`] an epoch of `0` counts as unset, with [`ZeroEpoch::Genesis`] as the
+/// genesis epoch.
+///
+/// Only set-wise checks are used, so the result doesn't depend on the order the API lists roots in.
+pub fn classify_roots(
+    roots: &[ProofSetRoot],
+    target_cid: &str,
+    degraded_fault_ratio: f64,
+    selection: RootSelection,
+    options: ClassifyOptions,
+) -> Option<DisplayStatus> {
+    let matching_roots = select_roots(roots, target_cid, selection);
+    if matching_roots.is_empty() {
+        return None;
+    }
+    if matching_roots.iter().all(|root| root.removed) {
+        return Some(DisplayStatus::Removed);
+    }
+
+    // Find all live matching roots that have been proven or faulted
+    let relevant_roots: Vec<_> = matching_roots
+        .into_iter()
+        .filter(|root| !root.removed)
+        .filter(|root| {
+            let (proven, faulted) = (root.proven_mark(options), root.faulted_mark(options));
+            debug!(
+                "Found matching root: proven={:?}, faulted={:?} (by {:?})",
+                proven, faulted, options.by
+            );
+            proven.is_some() || faulted.is_some()
+        })
+        .collect();
+    debug!("Found {} relevant roots", relevant_roots.len());
+
+    // If any root is faulty, the status is faulty. Matching roots without epochs set are "stored".
+    let proven_roots: Vec<_> = relevant_roots
+        .iter()
+        .filter(|root| root.proven_mark(options).is_some())
+        .collect();
+    let status = if relevant_roots.iter().any(|root| root.is_faulty(options)) {
+        DisplayStatus::StoredFaulty
+    } else if proven_roots
+        .iter()
+        .any(|root| root.total_periods_faulted > 0 && root.fault_ratio() > degraded_fault_ratio)
+    {
+        DisplayStatus::StoredDegraded
+    } else if proven_roots.iter().any(|root| root.is_recovered(options)) {
+        DisplayStatus::StoredRecovered
+    } else if !proven_roots.is_empty() {
+        DisplayStatus::StoredProven
+    } else {
+        DisplayStatus::Stored
+    };
+    Some(status)
+}
+
+/// Roots matching `target_cid` that decide its status. The latest root is the one with the most
+/// recent `createdAt`, ties and unparseable timestamps going to the highest root id.
+pub fn select_roots<'a>(
+    roots: &'a [ProofSetRoot],
+    target_cid: &str,
+    selection: RootSelection,
+) -> Vec<&'a ProofSetRoot> {
+    let matching_roots = roots.iter().filter(|root| root.cid == target_cid);
+    match selection {
+        RootSelection::Aggregate => matching_roots.collect(),
+        RootSelection::Latest => matching_roots
+            .max_by_key(|root| (root.created_at(), root.root_id))
+            .into_iter()
+            .collect(),
+    }
+}
+
+/// Most recent time any selected live root matching `root_cid` was proven.
+pub fn last_proven_at(
+    roots: &[ProofSetRoot],
+    root_cid: &str,
+    clock: ChainClock,
+    selection: RootSelection,
+    zero_epoch: ZeroEpoch,
+) -> Option<DateTime<Utc>> {
+    select_roots(roots, root_cid, selection)
+        .into_iter()
+        .filter(|root| !root.removed)
+        .filter_map(|root| root.proven_at(clock, zero_epoch))
+        .max()
+}
+
+/// Best status of a file stored in several proofsets: proven if any copy is proven, preferring a
+/// copy that never faulted, then one that recovered over a degraded one, faulty if all copies that
+/// were proven faulted since, then stored, and removed only if removed everywhere.
+pub fn best_status(statuses: impl IntoIterator<Item = DisplayStatus>) -> Option<DisplayStatus> {
+    statuses.into_iter().max_by_key(|status| match status {
+        DisplayStatus::StoredProven => 6,
+        DisplayStatus::StoredRecovered => 5,
+        DisplayStatus::StoredDegraded => 4,
+        DisplayStatus::StoredFaulty => 3,
+        DisplayStatus::Stored => 2,
+        DisplayStatus::Removed => 1,
+        _ => 0,
+    })
+}
+
+/// Per-root proven/faulted state of the roots matching `root_cid`, ordered by root id.
+pub fn root_statuses(
+    roots: &[ProofSetRoot],
+    root_cid: &str,
+    options: ClassifyOptions,
+) -> Vec<RootStatus> {
+    let mut statuses: Vec<_> = roots
+        .iter()
+        .filter(|root| root.cid == root_cid)
+        .map(|root| {
+            let faulted = root.is_faulty(options);
+            RootStatus {
+                root_id: root.root_id,
+                proven: root.proven_mark(options).is_some() && !faulted,
+                faulted,
+                epoch: root
+                    .proven_epoch(options.zero_epoch)
+                    .max(root.faulted_epoch(options.zero_epoch)),
+                proofs_submitted: root.total_proofs_submitted,
+            }
+        })
+        .collect();
+    statuses.sort_by_key(|status| status.root_id);
+    statuses
+}
+
+/// How failed API requests are retried.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RetryPolicy {
+    pub(crate) max_retries: u32,
+    pub(crate) base_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub(crate) fn from_cli(cli: &Cli) -> Self {
+        Self {
+            max_retries: cli.api_max_retries,
+            base_delay: Duration::from_millis(cli.api_retry_base_delay_ms),
+        }
+    }
+
+    /// Exponential backoff before the given retry (starting at 1), with jitter so that retries of
+    /// several clients don't line up.
+    pub(crate) fn delay(&self, retry: u32) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)));
+        backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+/// Page size, ordering and filter of the roots requested from the API, and which of the roots
+/// returned are kept.
+#[derive(Debug, Clone)]
+pub(crate) struct RootsQuery {
+    pub(crate) page_size: u64,
+    pub(crate) order_by: RootsOrderBy,
+    pub(crate) order: Option<SortOrder>,
+    /// Query parameter and CID the API is asked to filter the roots by.
+    pub(crate) cid_filter: Option<(String, String)>,
+    /// CIDs of the roots kept while reading the response, all roots if empty.
+    pub(crate) keep_cids: Vec<String>,
+    /// Whether to stop reading at the first root with the first of `keep_cids`, see
+    /// `--api-stop-at-first-root`.
+    pub(crate) stop_at_first_root: bool,
+}
+
+impl RootsQuery {
+    pub(crate) fn from_cli(cli: &Cli) -> Self {
+        Self {
+            page_size: cli.api_page_size,
+            order_by: cli.api_order_by,
+            order: cli.api_order,
+            cid_filter: None,
+            keep_cids: Vec::new(),
+            stop_at_first_root: cli.api_stop_at_first_root,
+        }
+    }
+}
+
+/// Token bucket bounding the rate of API requests, see `--api-rate-limit`. Bursts of up to a
+/// second's worth of requests (at least one) go through right away. Every request passes through
+/// it, so it also counts them.
+pub(crate) struct RateLimiter {
+    /// Requests per second, `None` for no limit.
+    rate: Option<f64>,
+    bucket: Mutex<Bucket>,
+    /// Requests let through so far.
+    acquired: AtomicU64,
+}
+
+struct Bucket {
+    /// Available tokens, negative while requests wait for theirs, so they get their turn in order.
+    tokens: f64,
+    refilled_at: Instant,
+    /// Tokens taken by `try_acquire` for the requests that follow it.
+    reserved: u32,
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: f64) {
+        self.tokens =
+            (self.tokens + self.refilled_at.elapsed().as_secs_f64() * rate).min(rate.max(1.0));
+        self.refilled_at = Instant::now();
+    }
+}
+
+impl RateLimiter {
+    pub(crate) fn new(rate: Option<f64>) -> Self {
+        Self {
+            rate,
+            bucket: Mutex::new(Bucket {
+                tokens: rate.unwrap_or_default().max(1.0),
+                refilled_at: Instant::now(),
+                reserved: 0,
+            }),
+            acquired: AtomicU64::new(0),
+        }
+    }
+
+    /// Requests let through by `acquire` so far.
+    pub(crate) fn acquired(&self) -> u64 {
+        self.acquired.load(Ordering::Relaxed)
+    }
+
+    /// Waits until a request may be made. The token is taken right away and the wait sleeps off
+    /// outside the lock, so other requests don't queue behind the sleep.
+    pub(crate) async fn acquire(&self) {
+        self.acquired.fetch_add(1, Ordering::Relaxed);
+        let Some(rate) = self.rate else {
+            return;
+        };
+        let wait = {
+            let mut bucket = self.bucket.lock();
+            if bucket.reserved > 0 {
+                bucket.reserved -= 1;
+                return;
+            }
+            bucket.refill(rate);
+            bucket.tokens -= 1.0;
+            Duration::from_secs_f64((-bucket.tokens).max(0.0) / rate)
+        };
+        if !wait.is_zero() {
+            debug!("Rate limiting API request for {:?}", wait);
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Takes a token for the next `acquire` if one is available right now, returning whether it
+    /// was.
+    pub(crate) fn try_acquire(&self) -> bool {
+        let Some(rate) = self.rate else {
+            return true;
+        };
+        let mut bucket = self.bucket.lock();
+        bucket.refill(rate);
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        bucket.reserved += 1;
+        true
+    }
+}
+
+/// Body of a failed API request: the message and code of a JSON body such as
+/// `{"error": "...", "code": "..."}`, or else the body's text, e.g. of an HTML error page.
+#[derive(Debug, Default)]
+pub(crate) struct ApiError {
+    pub(crate) message: String,
+    pub(crate) code: Option<String>,
+}
+
+impl ApiError {
+    pub(crate) fn parse(body: &str) -> Self {
+        #[derive(Deserialize)]
+        struct ErrorBody {
+            error: Option<String>,
+            message: Option<String>,
+            code: Option<serde_json::Value>,
+        }
+        if let Ok(parsed) = serde_json::from_str::<ErrorBody>(body) {
+            if parsed.error.is_some() || parsed.code.is_some() {
+                return Self {
+                    message: parsed.error.or(parsed.message).unwrap_or_default(),
+                    code: parsed.code.map(|code| match code {
+                        serde_json::Value::String(code) => code,
+                        code => code.to_string(),
+                    }),
+                };
+            }
+        }
+        let text = body.split_whitespace().collect::<Vec<_>>().join(" ");
+        Self {
+            message: text.chars().take(API_ERROR_TEXT_MAX_LEN).collect(),
+            code: None,
+        }
+    }
+}
+
+/// Non-success HTTP status returned by the API, along with the error it sent.
+#[derive(Debug)]
+pub(crate) struct ApiStatusError(reqwest::StatusCode, ApiError);
+
+impl ApiStatusError {
+    /// Reads the error body of a failed response and logs it.
+    async fn from_response(response: reqwest::Response) -> Self {
+        let status = response.status();
+        let error = match response.text().await {
+            Ok(body) => ApiError::parse(&body),
+            Err(e) => ApiError {
+                message: format!("unreadable body: {}", e),
+                code: None,
+            },
+        };
+        // Expected while a proofset is still being created, and reported by the poller.
+        let level = if status == reqwest::StatusCode::NOT_FOUND {
+            Level::Debug
+        } else {
+            Level::Error
+        };
+        log!(
+            level,
+            "Error response: status={} code={} message={:?}",
+            status,
+            error.code.as_deref().unwrap_or("-"),
+            error.message
+        );
+        Self(status, error)
+    }
+
+    fn has_code(&self, code: &str) -> bool {
+        self.1.code.as_deref() == Some(code)
+    }
+}
+
+impl std::fmt::Display for ApiStatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "API request failed with status: {}", self.0)?;
+        if let Some(code) = &self.1.code {
+            write!(f, " ({})", code)?;
+        }
+        if !self.1.message.is_empty() {
+            write!(f, ": {}", self.1.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ApiStatusError {}
+
+/// Whether a failed API request may succeed if retried: connection problems and server errors
+/// may be transient, client errors, malformed responses and unknown proofsets won't fix themselves.
+pub(crate) fn is_retryable(error: &anyhow::Error) -> bool {
+    if let Some(error @ ApiStatusError(status, _)) = error.downcast_ref() {
+        return status.is_server_error() && !error.has_code(PROOFSET_NOT_FOUND_CODE);
+    }
+    match error.downcast_ref::<reqwest::Error>() {
+        Some(e) => e.is_connect() || e.is_timeout() || e.is_request(),
+        None => false,
+    }
+}
+
+impl PdpError {
+    /// Classifies a failed API request for `proofset_id`.
+    pub(crate) fn from_api(error: anyhow::Error, proofset_id: &str) -> Self {
+        match error.downcast_ref() {
+            Some(ApiStatusError(reqwest::StatusCode::NOT_FOUND, _)) => {
+                return PdpError::ProofsetNotFound(proofset_id.to_string())
+            }
+            Some(error) if ApiStatusError::has_code(error, PROOFSET_NOT_FOUND_CODE) => {
+                return PdpError::ProofsetNotFound(proofset_id.to_string())
+            }
+            Some(ApiStatusError(
+                status @ (reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN),
+                _,
+            )) => return PdpError::ApiUnauthorized(*status),
+            _ => {}
+        }
+        if is_retryable(&error) {
+            PdpError::ApiUnreachable(error)
+        } else {
+            PdpError::Api(error)
+        }
+    }
+}
+
+/// Fetches all roots of a proofset, following pagination until `metadata.total` roots are read.
+pub(crate) async fn check_proof_status(
+    client: &Client,
+    api_base_url: &str,
+    proofset_id: &str,
+    query: &RootsQuery,
+    retry: RetryPolicy,
+    limiter: &RateLimiter,
+) -> Result<ProofSetRoots, PdpError> {
+    let started = Instant::now();
+    let span = tracing::info_span!(
+        "api_request",
+        proofset_id,
+        latency_ms = tracing::field::Empty,
+        result = tracing::field::Empty,
+    );
+    let result = fetch_all_roots(client, api_base_url, proofset_id, query, retry, limiter)
+        .instrument(span.clone())
+        .await;
+    histogram!("api_request_duration_seconds").record(started.elapsed().as_secs_f64());
+    let outcome = if result.is_ok() { "ok" } else { "err" };
+    span.record("latency_ms", started.elapsed().as_millis() as u64);
+    span.record("result", outcome);
+    counter!("api_requests_total", "result" => outcome).increment(1);
+    result.map_err(|e| PdpError::from_api(e, proofset_id))
+}
+
+/// Fetches all pages of roots of a proofset, up to the first matching root if
+/// `query.stop_at_first_root`.
+async fn fetch_all_roots(
+    client: &Client,
+    api_base_url: &str,
+    proofset_id: &str,
+    query: &RootsQuery,
+    retry: RetryPolicy,
+    limiter: &RateLimiter,
+) -> anyhow::Result<ProofSetRoots> {
+    let first =
+        fetch_roots_page_with_retry(client, api_base_url, proofset_id, 0, query, retry, limiter)
+            .await?;
+    let (mut roots, mut read, mut stopped) = (first.roots, first.read, first.stopped);
+    // An API ignoring the CID filter returns every root, which isn't worth paging through just
+    // to find that out.
+    if let Some((_, cid)) = &query.cid_filter {
+        if roots.data.iter().any(|root| root.cid != *cid) {
+            return Ok(roots);
+        }
+    }
+    let mut pages = 1;
+    while !stopped && read < roots.metadata.total {
+        // Guards against an API reporting a `total` it never delivers.
+        if pages >= API_MAX_PAGES {
+            warn!(
+                "Stopping after {} pages of roots for proofset {}, got {} of {} reported",
+                pages, proofset_id, read, roots.metadata.total
+            );
+            break;
+        }
+        let page = fetch_roots_page_with_retry(
+            client,
+            api_base_url,
+            proofset_id,
+            read,
+            query,
+            retry,
+            limiter,
+        )
+        .await?;
+        pages += 1;
+        if page.read == 0 {
+            warn!(
+                "API returned no roots at offset {} for proofset {} despite reporting {} in total",
+                read, proofset_id, roots.metadata.total
+            );
+            break;
+        }
+        read += page.read;
+        stopped = page.stopped;
+        roots.data.extend(page.roots.data);
+    }
+    if stopped {
+        debug!(
+            "Stopped at the first matching root of proofset {} after {} roots",
+            proofset_id, read
+        );
+    }
+    debug!(
+        "Fetched {} roots in {} pages, kept {}",
+        read,
+        pages,
+        roots.data.len()
+    );
+    Ok(roots)
+}
+
+pub(crate) async fn fetch_roots_page_with_retry(
+    client: &Client,
+    api_base_url: &str,
+    proofset_id: &str,
+    offset: u64,
+    query: &RootsQuery,
+    retry: RetryPolicy,
+    limiter: &RateLimiter,
+) -> anyhow::Result<RootsPage> {
+    let mut retries = 0;
+    loop {
+        limiter.acquire().await;
+        match fetch_roots_page(client, api_base_url, proofset_id, offset, query).await {
+            Err(e) if retries < retry.max_retries && is_retryable(&e) => {
+                retries += 1;
+                let delay = retry.delay(retries);
+                warn!(
+                    "API request failed: {:#}, retrying in {:?} ({}/{})",
+                    e, delay, retries, retry.max_retries
+                );
+                tokio::time::sleep(delay).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Fetches a page of roots, reading them from the response as it arrives rather than buffering
+/// it, so only the kept roots are held in memory.
+pub(crate) async fn fetch_roots_page(
+    client: &Client,
+    api_base_url: &str,
+    proofset_id: &str,
+    offset: u64,
+    query: &RootsQuery,
+) -> anyhow::Result<RootsPage> {
+    let url = format!(
+        "{}/api/proofsets/{}/roots",
+        api_base_url.trim_end_matches('/'),
+        proofset_id
+    );
+    let mut params = vec![
+        ("orderBy", query.order_by.as_str().to_string()),
+        ("offset", offset.to_string()),
+        ("limit", query.page_size.to_string()),
+    ];
+    if let Some(order) = query.order {
+        params.push(("order", order.as_str().to_string()));
+    }
+    if let Some((param, cid)) = &query.cid_filter {
+        params.push((param.as_str(), cid.clone()));
+    }
+    let request = client.get(&url).query(&params).build()?;
+    debug!("Requesting URL: {}", request.url());
+    let response = client.execute(request).await?;
+    let status = response.status();
+    debug!("Response status: {}", status);
+    if !status.is_success() {
+        return Err(ApiStatusError::from_response(response).await.into());
+    }
+    let body = StreamReader::new(response.bytes_stream().map_err(std::io::Error::other));
+    let body = std::io::BufReader::new(SyncIoBridge::new(body));
+    let query = query.clone();
+    // Dropping the body once the reader returns stops receiving the rest of it.
+    tokio::task::spawn_blocking(move || read_roots_page(body, &query)).await?
+}
+
+/// Roots read from a page of the API response.
+pub(crate) struct RootsPage {
+    /// The kept roots, see [`RootsQuery::keep_cids`].
+    pub(crate) roots: ProofSetRoots,
+    /// Roots in the page, including the ones not kept.
+    pub(crate) read: u64,
+    /// Whether reading stopped at the first matching root, see
+    /// [`RootsQuery::stop_at_first_root`].
+    pub(crate) stopped: bool,
+}
+
+/// Reads a page of roots from `reader` one root at a time, keeping those selected by `query`. A
+/// page that stopped at a matching root before its `metadata` was read reports the roots read as
+/// `total`, which is all that's needed to stop paging.
+pub(crate) fn read_roots_page(
+    reader: impl std::io::Read,
+    query: &RootsQuery,
+) -> anyhow::Result<RootsPage> {
+    let mut page = PageReader {
+        query,
+        data: None,
+        metadata: None,
+        read: 0,
+        stopped: false,
+    };
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    match (&mut page).deserialize(&mut deserializer) {
+        Ok(()) => deserializer.end()?,
+        Err(_) if page.stopped => {}
+        Err(e) => return Err(e.into()),
+    }
+    let data = page
+        .data
+        .ok_or_else(|| anyhow::anyhow!("missing field `data`"))?;
+    let metadata = match page.metadata {
+        Some(metadata) => metadata,
+        None if page.stopped => Metadata {
+            total: page.read,
+            offset: 0,
+            limit: query.page_size,
+        },
+        None => anyhow::bail!("missing field `metadata`"),
+    };
+    Ok(RootsPage {
+        roots: ProofSetRoots { data, metadata },
+        read: page.read,
+        stopped: page.stopped,
+    })
+}
+
+/// State of [`read_roots_page`], filled in as the response is deserialized.
+struct PageReader<'a> {
+    query: &'a RootsQuery,
+    data: Option<Vec<ProofSetRoot>>,
+    metadata: Option<Metadata>,
+    read: u64,
+    stopped: bool,
+}
+
+impl<'de> DeserializeSeed<'de> for &mut PageReader<'_> {
+    type Value = ();
+
+    fn deserialize<D: serde::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for &mut PageReader<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("a page of roots")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "data" => map.next_value_seed(RootsReader(&mut *self))?,
+                "metadata" => self.metadata = Some(map.next_value()?),
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads the `data` array of a page into its [`PageReader`].
+struct RootsReader<'p, 'a>(&'p mut PageReader<'a>);
+
+impl<'de> DeserializeSeed<'de> for RootsReader<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D: serde::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for RootsReader<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("a list of roots")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let page = self.0;
+        let keep_cids = &page.query.keep_cids;
+        let data = page.data.insert(Vec::new());
+        while let Some(root) = seq.next_element::<ProofSetRoot>()? {
+            page.read += 1;
+            if !keep_cids.is_empty() && !keep_cids.contains(&root.cid) {
+                continue;
+            }
+            let stop = page.query.stop_at_first_root && keep_cids.first() == Some(&root.cid);
+            data.push(root);
+            if stop {
+                page.stopped = true;
+                // Abandons the rest of the response, see `read_roots_page`.
+                return Err(A::Error::custom("stopped at the first matching root"));
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) async fn fetch_proofset(
+    client: &Client,
+    api_base_url: &str,
+    proofset_id: &str,
+) -> anyhow::Result<ProofSet> {
+    let url = format!(
+        "{}/api/proofsets/{}",
+        api_base_url.trim_end_matches('/'),
+        proofset_id
+    );
+    debug!("Requesting URL: {}", url);
+    let response = client.get(&url).send().await?;
+    let status = response.status();
+    debug!("Response status: {}", status);
+    if !status.is_success() {
+        return Err(ApiStatusError::from_response(response).await.into());
+    }
+    let proofset = response.json().await?;
+    Ok(proofset)
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::cli::CHAIN_GENESIS_UNIX;
+    use crate::test_util::{
+        cli, http_response, keep_alive_response, proven_root, root, MockHttpServer,
+    };
+
+    const CID: &str = "bafkroot";
+
+    fn classify(roots: &[ProofSetRoot], zero_epoch: ZeroEpoch) -> Option<DisplayStatus> {
+        let options = ClassifyOptions {
+            by: ClassifyBy::Epoch,
+            zero_epoch,
+        };
+        classify_roots(roots, CID, 0.5, RootSelection::Aggregate, options)
+    }
+
+    fn faulted_root(root_id: u64, cid: &str, proven: u64, faulted: u64) -> ProofSetRoot {
+        ProofSetRoot {
+            last_faulted_epoch: Some(faulted),
+            total_periods_faulted: 1,
+            ..proven_root(root_id, cid, proven)
+        }
+    }
+
+    /// A root proven at epoch 30 with `faulted` of its `proofs + faulted` periods faulted.
+    fn root_with_history(proofs: u64, faulted: u64) -> ProofSetRoot {
+        ProofSetRoot {
+            total_proofs_submitted: proofs,
+            total_periods_faulted: faulted,
+            ..proven_root(1, CID, 30)
+        }
+    }
+
+    /// An older faulted root and a newer proven root with the file's CID, listed newest first.
+    fn readded_roots() -> [ProofSetRoot; 2] {
+        [
+            ProofSetRoot {
+                created_at: "2025-03-01T00:00:00Z".to_string(),
+                ..proven_root(2, CID, 30)
+            },
+            ProofSetRoot {
+                created_at: "2025-01-01T00:00:00Z".to_string(),
+                ..faulted_root(1, CID, 10, 20)
+            },
+        ]
+    }
+
+    fn classify_with(roots: &[ProofSetRoot], selection: RootSelection) -> Option<DisplayStatus> {
+        let options = ClassifyOptions {
+            by: ClassifyBy::Epoch,
+            zero_epoch: ZeroEpoch::Unset,
+        };
+        classify_roots(roots, CID, 0.5, selection, options)
+    }
+
+    #[test]
+    fn aggregate_selection_counts_stale_roots() {
+        assert_eq!(
+            classify_with(&readded_roots(), RootSelection::Aggregate),
+            Some(DisplayStatus::StoredFaulty)
+        );
+    }
+
+    #[test]
+    fn latest_selection_classifies_the_newest_root() {
+        let mut roots = readded_roots();
+        assert_eq!(
+            classify_with(&roots, RootSelection::Latest),
+            Some(DisplayStatus::StoredProven)
+        );
+        // The listing order doesn't matter.
+        roots.reverse();
+        assert_eq!(
+            classify_with(&roots, RootSelection::Latest),
+            Some(DisplayStatus::StoredProven)
+        );
+    }
+
+    #[test]
+    fn fault_history_degrades_proven_files() {
+        let classify = |root: ProofSetRoot| {
+            let options = ClassifyOptions {
+                by: ClassifyBy::Epoch,
+                zero_epoch: ZeroEpoch::Unset,
+            };
+            classify_roots(&[root], CID, 0.05, RootSelection::Aggregate, options)
+        };
+        assert_eq!(
+            classify(root_with_history(100, 0)),
+            Some(DisplayStatus::StoredProven)
+        );
+        assert_eq!(
+            classify(root_with_history(98, 2)),
+            Some(DisplayStatus::StoredProven)
+        );
+        assert_eq!(
+            classify(root_with_history(90, 10)),
+            Some(DisplayStatus::StoredDegraded)
+        );
+        // Faulted since the last proof, so it's faulty whatever its history.
+        let faulted = ProofSetRoot {
+            last_faulted_epoch: Some(40),
+            ..root_with_history(90, 10)
+        };
+        assert_eq!(classify(faulted), Some(DisplayStatus::StoredFaulty));
+    }
+
+    #[test]
+    fn classify_roots_precedence() {
+        let cases: [(&str, Vec<ProofSetRoot>, Option<DisplayStatus>); 6] = [
+            ("no roots", vec![], None),
+            (
+                "no matching root",
+                vec![proven_root(1, "bafkother", 10)],
+                None,
+            ),
+            (
+                "match without epochs",
+                vec![root(1, CID)],
+                Some(DisplayStatus::Stored),
+            ),
+            (
+                "proven only",
+                vec![proven_root(1, CID, 10)],
+                Some(DisplayStatus::StoredProven),
+            ),
+            (
+                "faulted after proven",
+                vec![faulted_root(1, CID, 10, 20)],
+                Some(DisplayStatus::StoredFaulty),
+            ),
+            (
+                "one of several roots faulty",
+                vec![
+                    proven_root(1, CID, 30),
+                    faulted_root(2, CID, 10, 20),
+                    root(3, CID),
+                    faulted_root(4, "bafkother", 10, 20),
+                ],
+                Some(DisplayStatus::StoredFaulty),
+            ),
+        ];
+        for (name, roots, expected) in cases {
+            assert_eq!(classify(&roots, ZeroEpoch::Unset), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn never_proven_faulted_root_is_faulty() {
+        let never_proven = |last_proven_epoch| ProofSetRoot {
+            last_proven_epoch,
+            last_faulted_epoch: Some(20),
+            total_periods_faulted: 1,
+            ..root(1, CID)
+        };
+        for last_proven_epoch in [None, Some(0)] {
+            let roots = [never_proven(last_proven_epoch)];
+            assert_eq!(
+                classify(&roots, ZeroEpoch::Unset),
+                Some(DisplayStatus::StoredFaulty),
+                "{:?}",
+                last_proven_epoch
+            );
+            let options = ClassifyOptions {
+                by: ClassifyBy::Epoch,
+                zero_epoch: ZeroEpoch::Unset,
+            };
+            let [status] = &root_statuses(&roots, CID, options)[..] else {
+                panic!("Expected the status of one root");
+            };
+            assert!(status.faulted && !status.proven, "{:?}", status);
+            assert_eq!(status.epoch, Some(20));
+        }
+        // It makes the file faulty even with another copy proven.
+        let roots = [proven_root(2, CID, 30), never_proven(None)];
+        assert_eq!(
+            classify(&roots, ZeroEpoch::Unset),
+            Some(DisplayStatus::StoredFaulty)
+        );
+        // Judged by timestamps, a fault without a proof time is faulty too.
+        let root = ProofSetRoot {
+            last_faulted_at: Some("2025-01-02T00:00:00Z".to_string()),
+            ..never_proven(None)
+        };
+        let options = ClassifyOptions {
+            by: ClassifyBy::Timestamp,
+            zero_epoch: ZeroEpoch::Unset,
+        };
+        assert!(root.is_faulty(options));
+    }
+
+    #[test]
+    fn recovered_roots_are_told_apart() {
+        // A root that faulted once, last proven at `proven` and last faulted at `faulted`.
+        let history = |proven, faulted| ProofSetRoot {
+            last_proven_epoch: Some(proven),
+            ..faulted_root(1, CID, 10, faulted)
+        };
+        assert_eq!(
+            classify(&[proven_root(1, CID, 30)], ZeroEpoch::Unset),
+            Some(DisplayStatus::StoredProven),
+            "never faulted"
+        );
+        assert_eq!(
+            classify(&[history(10, 20)], ZeroEpoch::Unset),
+            Some(DisplayStatus::StoredFaulty),
+            "currently faulted"
+        );
+        assert_eq!(
+            classify(&[history(30, 20)], ZeroEpoch::Unset),
+            Some(DisplayStatus::StoredRecovered),
+            "recovered"
+        );
+    }
+
+    #[test]
+    fn recovery_is_judged_by_timestamps_when_configured() {
+        let root = ProofSetRoot {
+            last_proven_at: Some("2025-01-03T00:00:00Z".to_string()),
+            last_faulted_at: Some("2025-01-02T00:00:00Z".to_string()),
+            // The epochs disagree, but aren't looked at.
+            ..faulted_root(1, CID, 10, 20)
+        };
+        let options = ClassifyOptions {
+            by: ClassifyBy::Timestamp,
+            zero_epoch: ZeroEpoch::Unset,
+        };
+        assert_eq!(
+            classify_roots(&[root], CID, 0.5, RootSelection::Aggregate, options),
+            Some(DisplayStatus::StoredRecovered)
+        );
+    }
+
+    #[test]
+    fn strategies_disagree_where_epochs_and_timestamps_do() {
+        let by = |by: ClassifyBy, root: &ProofSetRoot| {
+            let options = ClassifyOptions {
+                by,
+                zero_epoch: ZeroEpoch::Unset,
+            };
+            classify_roots(
+                std::slice::from_ref(root),
+                CID,
+                0.5,
+                RootSelection::Aggregate,
+                options,
+            )
+        };
+        // Proven after the fault by epoch, but faulted after the proof by timestamp.
+        let reordered = ProofSetRoot {
+            last_proven_at: Some("2025-01-02T00:00:00Z".to_string()),
+            last_faulted_at: Some("2025-01-03T00:00:00Z".to_string()),
+            ..faulted_root(1, CID, 30, 20)
+        };
+        assert_eq!(
+            by(ClassifyBy::Epoch, &reordered),
+            Some(DisplayStatus::StoredRecovered)
+        );
+        assert_eq!(
+            by(ClassifyBy::Timestamp, &reordered),
+            Some(DisplayStatus::StoredFaulty)
+        );
+        // A proven epoch without a proof time only counts as a proof by epoch.
+        let untimed = proven_root(1, CID, 30);
+        assert_eq!(
+            by(ClassifyBy::Epoch, &untimed),
+            Some(DisplayStatus::StoredProven)
+        );
+        assert_eq!(
+            by(ClassifyBy::Timestamp, &untimed),
+            Some(DisplayStatus::Stored)
+        );
+        // Both agree once the timestamps follow the epochs.
+        let timed = ProofSetRoot {
+            last_proven_at: Some("2025-01-02T00:00:00Z".to_string()),
+            ..untimed
+        };
+        for strategy in [ClassifyBy::Epoch, ClassifyBy::Timestamp] {
+            assert_eq!(by(strategy, &timed), Some(DisplayStatus::StoredProven));
+        }
+    }
+
+    #[test]
+    fn removed_root_makes_file_removed() {
+        let roots = [ProofSetRoot {
+            removed: true,
+            ..proven_root(1, CID, 10)
+        }];
+        assert_eq!(
+            classify(&roots, ZeroEpoch::Unset),
+            Some(DisplayStatus::Removed)
+        );
+    }
+
+    #[test]
+    fn removed_roots_are_ignored_next_to_live_ones() {
+        let roots = [
+            ProofSetRoot {
+                removed: true,
+                ..faulted_root(1, CID, 10, 20)
+            },
+            proven_root(2, CID, 30),
+        ];
+        assert_eq!(
+            classify(&roots, ZeroEpoch::Unset),
+            Some(DisplayStatus::StoredProven)
+        );
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_bounds_the_request_rate() {
+        let limiter = RateLimiter::new(Some(50.0));
+        let started = Instant::now();
+        // The first second's worth go through right away, the rest at 50 per second.
+        for _ in 0..75 {
+            limiter.acquire().await;
+        }
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(500), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_doesnt_block_while_waiting() {
+        let limiter = Arc::new(RateLimiter::new(Some(0.5)));
+        assert!(limiter.try_acquire());
+        // The reserved token lets the next request through right away.
+        tokio::time::timeout(Duration::from_millis(100), limiter.acquire())
+            .await
+            .unwrap();
+        let waiting = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire().await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+        // A request waiting for its turn doesn't keep others from finding the limit reached.
+        let started = Instant::now();
+        assert!(!limiter.try_acquire());
+        assert!(started.elapsed() < Duration::from_millis(50));
+        waiting.abort();
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_without_limit_never_waits() {
+        let limiter = RateLimiter::new(None);
+        let started = Instant::now();
+        for _ in 0..1_000 {
+            limiter.acquire().await;
+        }
+        assert!(started.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn epoch_to_datetime_pins_known_epochs() {
+        let at = |epoch, genesis_unix| epoch_to_datetime(epoch, genesis_unix, 30).to_rfc3339();
+        assert_eq!(at(0, CHAIN_GENESIS_UNIX), "2022-11-01T18:13:00+00:00");
+        assert_eq!(
+            at(2_000_000, CHAIN_GENESIS_UNIX),
+            "2024-09-26T04:53:00+00:00"
+        );
+        // Mainnet's genesis.
+        assert_eq!(at(1_000_000, 1_598_306_400), "2021-08-07T03:20:00+00:00");
+        assert_eq!(
+            epoch_to_datetime(u64::MAX, CHAIN_GENESIS_UNIX, 30),
+            DateTime::<Utc>::MAX_UTC
+        );
+    }
+
+    #[test]
+    fn proven_epoch_dates_proofs_without_timestamp() {
+        let clock = ChainClock::from_cli(&cli(&[]));
+        let root = proven_root(1, CID, 2_000_000);
+        assert_eq!(
+            root.proven_at(clock, ZeroEpoch::Unset),
+            Some("2024-09-26T04:53:00Z".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn unparseable_proof_time_is_omitted() {
+        let clock = ChainClock::from_cli(&cli(&[]));
+        let root = ProofSetRoot {
+            last_proven_at: Some("yesterday".to_string()),
+            ..root(1, CID)
+        };
+        assert_eq!(root.proven_at(clock, ZeroEpoch::Unset), None);
+        let root = ProofSetRoot {
+            last_proven_at: Some("2025-01-01T12:00:00Z".to_string()),
+            ..root
+        };
+        assert_eq!(
+            root.proven_at(clock, ZeroEpoch::Unset),
+            Some("2025-01-01T12:00:00Z".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn zero_epoch_is_unset_by_default() {
+        let roots = [proven_root(1, CID, 0)];
+        assert_eq!(
+            classify(&roots, ZeroEpoch::Unset),
+            Some(DisplayStatus::Stored)
+        );
+        assert_eq!(roots[0].proven_epoch(ZeroEpoch::Unset), None);
+    }
+
+    #[test]
+    fn zero_epoch_is_genesis() {
+        let roots = [proven_root(1, CID, 0)];
+        assert_eq!(
+            classify(&roots, ZeroEpoch::Genesis),
+            Some(DisplayStatus::StoredProven)
+        );
+        assert_eq!(roots[0].proven_epoch(ZeroEpoch::Genesis), Some(0));
+    }
+
+    #[test]
+    fn null_epoch_is_unset_in_both_modes() {
+        let roots = [root(1, CID)];
+        for zero_epoch in [ZeroEpoch::Unset, ZeroEpoch::Genesis] {
+            assert_eq!(classify(&roots, zero_epoch), Some(DisplayStatus::Stored));
+            assert_eq!(roots[0].proven_epoch(zero_epoch), None);
+        }
+    }
+
+    #[test]
+    fn fault_at_genesis_only_counts_as_genesis() {
+        let roots = [ProofSetRoot {
+            last_faulted_epoch: Some(0),
+            total_periods_faulted: 1,
+            ..root(1, CID)
+        }];
+        assert_eq!(
+            classify(&roots, ZeroEpoch::Unset),
+            Some(DisplayStatus::Stored)
+        );
+        assert_eq!(
+            classify(&roots, ZeroEpoch::Genesis),
+            Some(DisplayStatus::StoredFaulty)
+        );
+    }
+
+    fn arbitrary_root() -> impl Strategy<Value = ProofSetRoot> {
+        let epoch = proptest::option::of(0..5u64);
+        let timestamp = proptest::option::of(prop_oneof![
+            (0..5u32).prop_map(|minute| format!("2025-01-01T00:0{}:00Z", minute)),
+            Just("not a timestamp".to_string()),
+        ]);
+        (
+            prop_oneof![Just(CID.to_string()), "\\PC{0,8}"],
+            any::<bool>(),
+            (0..3u64, 0..3u64),
+            (epoch.clone(), timestamp.clone(), epoch, timestamp),
+            prop_oneof![
+                Just("2025-01-01T00:00:00Z".to_string()),
+                Just("2025-02-01T00:00:00Z".to_string()),
+                "\\PC{0,8}",
+            ],
+        )
+            .prop_map(|(cid, removed, (faulted, submitted), epochs, created_at)| {
+                ProofSetRoot {
+                    cid,
+                    removed,
+                    total_periods_faulted: faulted,
+                    total_proofs_submitted: submitted,
+                    last_proven_epoch: epochs.0,
+                    last_proven_at: epochs.1,
+                    last_faulted_epoch: epochs.2,
+                    last_faulted_at: epochs.3,
+                    created_at,
+                    ..root(0, "")
+                }
+            })
+    }
+
+    /// Roots with unique ids, as the API assigns them, along with a shuffled copy.
+    fn shuffled_roots() -> impl Strategy<Value = (Vec<ProofSetRoot>, Vec<ProofSetRoot>)> {
+        proptest::collection::vec(arbitrary_root(), 0..8).prop_flat_map(|mut roots| {
+            for (root_id, root) in roots.iter_mut().enumerate() {
+                root.root_id = root_id as u64;
+            }
+            (Just(roots.clone()), Just(roots).prop_shuffle())
+        })
+    }
+
+    fn all_options() -> impl Iterator<Item = (RootSelection, ClassifyOptions)> {
+        [RootSelection::Aggregate, RootSelection::Latest]
+            .into_iter()
+            .flat_map(|selection| {
+                [ClassifyBy::Epoch, ClassifyBy::Timestamp]
+                    .into_iter()
+                    .flat_map(move |by| {
+                        [ZeroEpoch::Unset, ZeroEpoch::Genesis]
+                            .into_iter()
+                            .map(move |zero_epoch| (selection, ClassifyOptions { by, zero_epoch }))
+                    })
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn classify_roots_never_panics(
+            roots in proptest::collection::vec(arbitrary_root(), 0..8),
+            cid in "\\PC*",
+            ratio in 0.0..1.0f64,
+        ) {
+            for (selection, options) in all_options() {
+                classify_roots(&roots, &cid, ratio, selection, options);
+                classify_roots(&roots, CID, ratio, selection, options);
+            }
+        }
+
+        #[test]
+        fn classify_roots_ignores_root_order((roots, shuffled) in shuffled_roots()) {
+            for (selection, options) in all_options() {
+                prop_assert_eq!(
+                    classify_roots(&roots, CID, 0.5, selection, options),
+                    classify_roots(&shuffled, CID, 0.5, selection, options)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn api_base_url_must_be_http() {
+        for url in ["http://localhost:8080", "https://api.example.com/pdp/"] {
+            assert!(validate_api_base_url(url).is_ok(), "{}", url);
+        }
+        for url in [
+            "ftp://api.example.com",
+            "file:///etc/passwd",
+            "api.example.com",
+            "http//typo",
+            "",
+        ] {
+            assert!(validate_api_base_url(url).is_err(), "{}", url);
+        }
+    }
+
+    /// Connections opened by a client built from `args` fetching the same roots three times in a
+    /// row, as over three poll cycles.
+    async fn connections_for_fetches(args: &[&str]) -> usize {
+        let body = r#"{"data":[],"metadata":{"total":0,"offset":0,"limit":100}}"#;
+        let server = MockHttpServer::start(move |_| keep_alive_response("200 OK", body)).await;
+        let url = server.url("");
+        let cli = cli(&[&["--api-url", &url], args].concat());
+        let provider = HttpProofStatusProvider::new(crate::build_http_client(&cli).unwrap(), &cli);
+        for _ in 0..3 {
+            provider.fetch_roots("1").await.unwrap();
+        }
+        assert_eq!(server.requests.lock().len(), 3);
+        server.connections.load(Ordering::Relaxed)
+    }
+
+    #[tokio::test]
+    async fn http_client_reuses_connections() {
+        assert_eq!(connections_for_fetches(&[]).await, 1);
+    }
+
+    #[tokio::test]
+    async fn http_client_without_idle_pool_reconnects() {
+        let args = ["--api-pool-max-idle-per-host", "0"];
+        assert_eq!(connections_for_fetches(&args).await, 3);
+    }
+
+    #[tokio::test]
+    async fn paged_roots_keep_only_the_matching_ones() {
+        // Roots 1 to 5, of which 2 and 5 have the CID looked for, two to a page.
+        let root_json = |root_id: u64| {
+            let cid = if root_id % 3 == 2 { CID } else { "bafkother" };
+            serde_json::json!({
+                "rootId": root_id, "cid": cid, "size": 1024, "removed": false,
+                "totalPeriodsFaulted": 0, "totalProofsSubmitted": 1,
+                "lastProvenEpoch": 10 + root_id, "lastProvenAt": null,
+                "lastFaultedEpoch": null, "lastFaultedAt": null,
+                "createdAt": "2025-01-01T00:00:00Z",
+            })
+        };
+        let server = MockHttpServer::start(move |request| {
+            let offset: u64 = request
+                .split_once("offset=")
+                .and_then(|(_, rest)| rest.split(['&', ' ']).next())
+                .and_then(|offset| offset.parse().ok())
+                .expect("No offset requested");
+            let data: Vec<_> = (offset + 1..=(offset + 2).min(5)).map(root_json).collect();
+            let page = serde_json::json!({
+                "data": data,
+                "metadata": {"total": 5, "offset": offset, "limit": 2},
+            });
+            keep_alive_response("200 OK", &page.to_string())
+        })
+        .await;
+        let url = server.url("");
+        let cli = cli(&["--api-url", &url, "--api-page-size", "2"]);
+        let provider = HttpProofStatusProvider::new(crate::build_http_client(&cli).unwrap(), &cli);
+        let roots = provider
+            .fetch_roots_matching("1", &[CID.to_string()])
+            .await
+            .unwrap();
+        assert_eq!(server.requests.lock().len(), 3);
+        assert_eq!(provider.requests_made(), 3);
+        let kept: Vec<_> = roots
+            .data
+            .iter()
+            .map(|root| (root.root_id, root.cid.as_str()))
+            .collect();
+        assert_eq!(kept, [(2, CID), (5, CID)]);
+    }
+
+    #[tokio::test]
+    async fn stopping_at_the_first_root_skips_the_remaining_pages() {
+        // Pages of 5,000 roots out of 20,000, the second root of the first page having the CID
+        // looked for, as does a later one.
+        let root_json = |root_id: u64| {
+            let cid = if [2, 12_000].contains(&root_id) {
+                CID
+            } else {
+                "bafkother"
+            };
+            serde_json::json!({
+                "rootId": root_id, "cid": cid, "size": 1024, "removed": false,
+                "totalPeriodsFaulted": 0, "totalProofsSubmitted": 1,
+                "lastProvenEpoch": 10 + root_id, "lastProvenAt": null,
+                "lastFaultedEpoch": null, "lastFaultedAt": null,
+                "createdAt": "2025-01-01T00:00:00Z",
+            })
+        };
+        let server = MockHttpServer::start(move |request| {
+            let offset: u64 = request
+                .split_once("offset=")
+                .and_then(|(_, rest)| rest.split(['&', ' ']).next())
+                .and_then(|offset| offset.parse().ok())
+                .expect("No offset requested");
+            let data: Vec<_> = (offset + 1..=offset + 5_000).map(root_json).collect();
+            // The metadata trails the roots, so it's never read either.
+            let page = serde_json::json!({
+                "data": data,
+                "metadata": {"total": 20_000, "offset": offset, "limit": 5_000},
+            });
+            keep_alive_response("200 OK", &page.to_string())
+        })
+        .await;
+        let url = server.url("");
+        let fetch = |stop: bool| {
+            let mut args = vec!["--api-url", &url, "--api-page-size", "5000"];
+            if stop {
+                args.push("--api-stop-at-first-root");
+            }
+            let cli = cli(&args);
+            async move {
+                let client = crate::build_http_client(&cli).unwrap();
+                let provider = HttpProofStatusProvider::new(client, &cli);
+                let roots = provider
+                    .fetch_roots_matching("1", &[CID.to_string()])
+                    .await
+                    .unwrap();
+                roots
+                    .data
+                    .iter()
+                    .map(|root| root.root_id)
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(fetch(false).await, [2, 12_000]);
+        assert_eq!(server.requests.lock().len(), 4);
+        server.requests.lock().clear();
+        assert_eq!(fetch(true).await, [2]);
+        let requests = server.requests.lock();
+        assert_eq!(requests.len(), 1, "requested further pages");
+        assert!(requests[0].contains("offset=0"), "{}", requests[0]);
+    }
+
+    #[tokio::test]
+    async fn credentials_are_sent_with_every_request() {
+        let body = r#"{"data":[],"metadata":{"total":0,"offset":0,"limit":100}}"#;
+        let server = MockHttpServer::start(move |_| http_response("200 OK", body)).await;
+        let url = server.url("");
+        let cli = cli(&[
+            "--api-url",
+            &url,
+            "--api-token",
+            "s3cret",
+            "--api-header",
+            "X-Tenant: pdp",
+        ]);
+        let provider = HttpProofStatusProvider::new(crate::build_http_client(&cli).unwrap(), &cli);
+        provider.fetch_roots("1").await.unwrap();
+        let request = server.requests.lock()[0].to_lowercase();
+        assert!(
+            request.contains("\r\nauthorization: bearer s3cret\r\n"),
+            "{}",
+            request
+        );
+        assert!(request.contains("\r\nx-tenant: pdp\r\n"), "{}", request);
+        // The token is kept out of the logged configuration.
+        assert!(!serde_json::to_string(&cli).unwrap().contains("s3cret"));
+    }
+
+    #[tokio::test]
+    async fn rejected_credentials_fail_distinctly() {
+        let server = MockHttpServer::start(|_| http_response("401 Unauthorized", "")).await;
+        let url = server.url("");
+        let cli = cli(&["--api-url", &url, "--api-token", "expired"]);
+        let provider = HttpProofStatusProvider::new(crate::build_http_client(&cli).unwrap(), &cli);
+        let err = provider.fetch_roots("1").await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                PdpError::ApiUnauthorized(reqwest::StatusCode::UNAUTHORIZED)
+            ),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn json_error_bodies_are_parsed() {
+        let error =
+            ApiError::parse(r#"{"error": "no such proofset", "code": "proofset_not_found"}"#);
+        assert_eq!(error.message, "no such proofset");
+        assert_eq!(error.code.as_deref(), Some(PROOFSET_NOT_FOUND_CODE));
+        let error = ApiError::parse(r#"{"message": "rate limited", "code": 429}"#);
+        assert_eq!(error.message, "rate limited");
+        assert_eq!(error.code.as_deref(), Some("429"));
+    }
+
+    #[test]
+    fn other_error_bodies_are_kept_as_text() {
+        let error = ApiError::parse("<html>\n  <body>502 Bad Gateway</body>\n</html>");
+        assert_eq!(error.message, "<html> <body>502 Bad Gateway</body> </html>");
+        assert_eq!(error.code, None);
+        // JSON without an error or code isn't a structured error either.
+        assert_eq!(
+            ApiError::parse(r#"{"status": "down"}"#).message,
+            r#"{"status": "down"}"#
+        );
+        assert_eq!(
+            ApiError::parse("Internal Server Error").message,
+            "Internal Server Error"
+        );
+        let long = "x".repeat(API_ERROR_TEXT_MAX_LEN * 2);
+        assert_eq!(ApiError::parse(&long).message.len(), API_ERROR_TEXT_MAX_LEN);
+    }
+
+    #[tokio::test]
+    async fn missing_proofset_is_not_found() {
+        let server = MockHttpServer::start(|_| http_response("404 Not Found", "")).await;
+        let url = server.url("");
+        let cli = cli(&["--api-url", &url]);
+        let provider = HttpProofStatusProvider::new(crate::build_http_client(&cli).unwrap(), &cli);
+        let err = provider.fetch_roots("7").await.unwrap_err();
+        assert!(
+            matches!(&err, PdpError::ProofsetNotFound(id) if id == "7"),
+            "{:?}",
+            err
+        );
+        // Not retried, it's polled again on the next cycle.
+        assert_eq!(server.requests.lock().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn proofset_not_found_code_is_not_retried() {
+        let body = r#"{"error": "no such proofset", "code": "proofset_not_found"}"#;
+        let server =
+            MockHttpServer::start(move |_| http_response("500 Internal Server Error", body)).await;
+        let url = server.url("");
+        let cli = cli(&["--api-url", &url, "--api-retry-base-delay-ms", "1"]);
+        let provider = HttpProofStatusProvider::new(crate::build_http_client(&cli).unwrap(), &cli);
+        let err = provider.fetch_roots("1").await.unwrap_err();
+        assert!(
+            matches!(&err, PdpError::ProofsetNotFound(id) if id == "1"),
+            "{:?}",
+            err
+        );
+        assert_eq!(server.requests.lock().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn other_server_errors_are_retried() {
+        let server = MockHttpServer::start(|_| {
+            http_response("500 Internal Server Error", "<html>oops</html>")
+        })
+        .await;
+        let url = server.url("");
+        let cli = cli(&[
+            "--api-url",
+            &url,
+            "--api-max-retries",
+            "2",
+            "--api-retry-base-delay-ms",
+            "1",
+        ]);
+        let provider = HttpProofStatusProvider::new(crate::build_http_client(&cli).unwrap(), &cli);
+        let err = provider.fetch_roots("1").await.unwrap_err();
+        assert!(matches!(err, PdpError::ApiUnreachable(_)), "{:?}", err);
+        assert!(err.to_string().contains("<html>oops</html>"), "{}", err);
+        assert_eq!(server.requests.lock().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn preflight_reports_reachability() {
+        let server = MockHttpServer::start(|request| {
+            if request.starts_with("HEAD /down ") {
+                http_response("503 Service Unavailable", "")
+            } else {
+                http_response("404 Not Found", "")
+            }
+        })
+        .await;
+        let client = Client::new();
+        let url = |path| validate_api_base_url(&server.url(path)).unwrap();
+        // Any answer but a server error means the API is up, even without a route for HEAD.
+        assert!(preflight_api(&client, &url("/")).await);
+        assert!(!preflight_api(&client, &url("/down")).await);
+        assert!(server
+            .requests
+            .lock()
+            .iter()
+            .all(|r| r.starts_with("HEAD ")));
+
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let unreachable = format!("http://{}", closed.local_addr().unwrap());
+        drop(closed);
+        assert!(!preflight_api(&client, &validate_api_base_url(&unreachable).unwrap()).await);
+    }
+
+    #[test]
+    fn zero_epoch_flag_parses() {
+        let cli = crate::test_util::cli(&["--zero-epoch", "genesis"]);
+        assert_eq!(
+            ClassifyOptions::from_cli(&cli).zero_epoch,
+            ZeroEpoch::Genesis
+        );
+        let cli = crate::test_util::cli(&[]);
+        assert_eq!(ClassifyOptions::from_cli(&cli).zero_epoch, ZeroEpoch::Unset);
+    }
+}
@@ -0,0 +1,1086 @@
+//! Command line configuration.
+
+use anyhow::Context;
+use clap::{parser::ValueSource, CommandFactory, FromArgMatches, Parser};
+use log::info;
+use serde::Serialize;
+
+use crate::message::Stage;
+use crate::poller::API_CHECK_INTERVAL_SECS;
+use crate::serial::{
+    StatusStrings, ARDUINO_RESET_DELAY_SECS, DEFAULT_INIT_LINE, SERIAL_TIMEOUT_MS,
+};
+use crate::status::DisplayStatus;
+use crate::CHANNEL_BUFFER_SIZE;
+
+pub(crate) const SERIAL_PORT: &str = "/dev/ttyACM1";
+pub(crate) const SERIAL_BAUD_RATE: u32 = 9_600;
+pub(crate) const ZMQ_BIND_ADDRESS: &str = "tcp://127.0.0.1:5555";
+pub(crate) const API_BASE_URL: &str = "https://calibration.pdp-explorer.eng.filoz.org";
+pub(crate) const API_ROOTS_LIMIT: u64 = 100;
+// Genesis of the Filecoin calibration network, the one the default API serves. Mainnet's is
+// 1598306400.
+pub(crate) const CHAIN_GENESIS_UNIX: i64 = 1_667_326_380;
+pub(crate) const CHAIN_EPOCH_SECS: i64 = 30;
+
+// Settings that may come from an environment variable, with its name, whose source is logged at
+// startup.
+const SOURCED_SETTINGS: [(&str, &str); 4] = [
+    ("serial_port", "PDP_SERIAL_PORT"),
+    ("baud", "PDP_BAUD"),
+    ("zmq_bind", "PDP_ZMQ_BIND"),
+    ("api_url", "PDP_API_URL"),
+];
+
+/// Where the value of a setting came from, from the highest precedence to the lowest.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum SettingSource {
+    /// A flag on the command line.
+    Flag,
+    /// A flag of the `--config` file.
+    Config,
+    /// An environment variable.
+    Env,
+    /// The compiled default.
+    Default,
+}
+
+#[derive(Parser, Serialize, Debug, Clone)]
+#[command(version, about = "Displays PDP proof status updates on an Arduino LCD")]
+pub struct Cli {
+    #[command(subcommand)]
+    #[serde(skip)]
+    pub command: Option<Command>,
+
+    /// Where the settings of `SOURCED_SETTINGS` came from, logged at startup.
+    #[arg(skip)]
+    #[serde(skip)]
+    pub(crate) setting_sources: Vec<(&'static str, SettingSource)>,
+
+    /// Arguments given on the command line, program name first, parsed again along with the
+    /// `--config` file on reload.
+    #[arg(skip)]
+    #[serde(skip)]
+    pub(crate) command_line: Vec<String>,
+
+    /// JSON file of further flags, e.g. `{"display-width": 20, "serial-verbose": true}`. Flags on
+    /// the command line take precedence. SIGHUP, the `reload` control command or `POST /reload`
+    /// re-reads it and applies the display and polling settings.
+    #[arg(long)]
+    pub config: Option<String>,
+
+    /// Token the `reload` control command, or the `X-Reload-Token` header of `POST /reload`, must
+    /// carry. Without it, any ZMQ or HTTP client may reload the `--config` file.
+    #[arg(long, env = "PDP_RELOAD_TOKEN", hide_env_values = true)]
+    #[serde(serialize_with = "serialize_redacted")]
+    pub reload_token: Option<String>,
+
+    /// Cycle a synthetic file through the statuses on the serial port instead of receiving ZMQ
+    /// messages and polling the API, e.g. to check the display renders each of them.
+    #[arg(long)]
+    pub simulate: bool,
+
+    /// Seconds between the statuses of `--simulate`.
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u64).range(1..))]
+    pub simulate_interval_secs: u64,
+
+    /// Format of the log lines written to stderr.
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Serial port the Arduino is connected to.
+    #[arg(long, env = "PDP_SERIAL_PORT", default_value = SERIAL_PORT)]
+    pub serial_port: String,
+
+    /// Which statuses `--serial-port` shows, e.g. only the uploads while an `--extra-serial-port`
+    /// shows the proofs.
+    #[arg(long, value_enum, default_value_t = SerialRoute::All)]
+    pub serial_route: SerialRoute,
+
+    /// Further Arduino showing the statuses of a route, as `<path>=<route>`, e.g.
+    /// `/dev/ttyACM2=proofs`. May be repeated. It's driven with the serial settings of
+    /// `--serial-port`.
+    #[arg(long, value_parser = parse_extra_serial_port)]
+    pub extra_serial_port: Vec<ExtraSerialPort>,
+
+    /// Serial baud rate, one of the standard rates.
+    #[arg(long, env = "PDP_BAUD", default_value_t = SERIAL_BAUD_RATE, value_parser = parse_baud_rate)]
+    pub baud: u32,
+
+    /// ZMQ endpoint to receive stage updates on, `tcp://<host>:<port>` or `ipc://<path>`. Bound in
+    /// `rep` and `router` mode, connected to in `sub` mode.
+    #[arg(long, global = true, env = "PDP_ZMQ_BIND", default_value = ZMQ_BIND_ADDRESS)]
+    pub zmq_bind: String,
+
+    /// Whether to answer stage updates on a REP or ROUTER socket or subscribe to them on a SUB
+    /// socket.
+    #[arg(long, value_enum, default_value_t = ZmqMode::Rep)]
+    pub zmq_mode: ZmqMode,
+
+    /// Topic prefix subscribed to in `sub` mode; empty subscribes to everything.
+    #[arg(long, default_value = "")]
+    pub zmq_topic: String,
+
+    /// Namespace of the topics subscribed to in `sub` mode and published on `--status-pub-bind`,
+    /// e.g. `venue-a.`, so services sharing a broker only see their own messages. Prepended to
+    /// `--zmq-topic`. Empty by default, subscribing and publishing without a namespace.
+    #[arg(long, default_value = "")]
+    pub topic_prefix: String,
+
+    /// Base URL of the PDP Explorer API. Several may be given, comma separated or repeated, to fail
+    /// over to the next one on connection failures and server errors.
+    #[arg(
+        long,
+        global = true,
+        env = "PDP_API_URL",
+        value_delimiter = ',',
+        default_value = API_BASE_URL
+    )]
+    #[serde(serialize_with = "serialize_redacted_urls")]
+    pub api_url: Vec<String>,
+
+    /// Number of roots requested per page from the API.
+    #[arg(long, global = true, default_value_t = API_ROOTS_LIMIT, value_parser = clap::value_parser!(u64).range(1..))]
+    pub api_page_size: u64,
+
+    /// Column the roots are ordered by when paging through them.
+    #[arg(long, global = true, value_enum, default_value_t = RootsOrderBy::RootId)]
+    pub api_order_by: RootsOrderBy,
+
+    /// Direction of `--api-order-by`, left to the API's default if unset.
+    #[arg(long, global = true, value_enum)]
+    pub api_order: Option<SortOrder>,
+
+    /// Maximum API requests per second across all tracked files, e.g. `0.5` for one every two
+    /// seconds. A file whose request would have to wait is checked in the next poll cycle instead,
+    /// further pages of its roots wait for their turn. Unset doesn't limit the rate.
+    #[arg(long, global = true, value_parser = parse_rate)]
+    pub api_rate_limit: Option<f64>,
+
+    /// Query parameter the file's root CID is passed in when requesting roots, e.g. `cid`, so the
+    /// API only returns the matching ones. If the API ignores it, all roots are fetched and filtered
+    /// locally instead. Unset fetches all roots.
+    #[arg(long, global = true)]
+    pub api_cid_filter_param: Option<String>,
+
+    /// Requests the roots of each file separately, keeping only the ones matching its CID while
+    /// the response is read instead of every root of its proofsets. Caps memory on proofsets with
+    /// many roots, at the cost of a request per file rather than per proofset.
+    #[arg(long, global = true)]
+    pub api_roots_per_file: bool,
+
+    /// Stops reading a file's roots at the first one matching its CID, skipping the rest of the
+    /// response and further pages. Unlike reading the roots as they arrive, which classifies them
+    /// as reading them all would, this trades classification for fewer bytes read: the other roots
+    /// of a file added to a proofset again are missed, so it's off by default and meant for
+    /// proofsets each CID is added to once. Applies where the roots of a file are requested, see
+    /// `--api-roots-per-file`.
+    #[arg(long, global = true)]
+    pub api_stop_at_first_root: bool,
+
+    /// Bearer token sent in the `Authorization` header of every API request. Best passed in the
+    /// `PDP_API_TOKEN` environment variable, which keeps it out of process listings.
+    #[arg(long, global = true, env = "PDP_API_TOKEN", hide_env_values = true)]
+    #[serde(serialize_with = "serialize_redacted")]
+    pub api_token: Option<String>,
+
+    /// Extra header sent with every API request, as `Name: value`. May be repeated.
+    #[arg(long, global = true, value_parser = parse_header)]
+    #[serde(serialize_with = "serialize_redacted_headers")]
+    pub api_header: Vec<String>,
+
+    /// Retries of an API request failing with a connection error or 5xx response.
+    #[arg(long, global = true, default_value_t = 3)]
+    pub api_max_retries: u32,
+
+    /// Delay before the first API retry in milliseconds, doubling with each further retry.
+    #[arg(long, global = true, default_value_t = 500)]
+    pub api_retry_base_delay_ms: u64,
+
+    /// Timeout of a whole API request in seconds, after which it fails and may be retried.
+    #[arg(long, global = true, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    pub api_timeout_secs: u64,
+
+    /// Timeout of connecting to the API in seconds.
+    #[arg(long, global = true, default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+    pub api_connect_timeout_secs: u64,
+
+    /// Idle connections to each API host kept open for reuse by later requests.
+    #[arg(long, global = true, default_value_t = 8)]
+    pub api_pool_max_idle_per_host: usize,
+
+    /// Seconds an idle API connection is kept open for reuse. Should exceed the poll interval so
+    /// connections survive between poll cycles.
+    #[arg(long, global = true, default_value_t = 90)]
+    pub api_pool_idle_timeout_secs: u64,
+
+    /// Interval of TCP keep-alive probes on API connections in seconds, 0 to disable them.
+    #[arg(long, global = true, default_value_t = 60)]
+    pub api_tcp_keepalive_secs: u64,
+
+    /// Seconds between API poll cycles, each polling the files that are due.
+    #[arg(long, default_value_t = API_CHECK_INTERVAL_SECS, value_parser = clap::value_parser!(u64).range(1..))]
+    pub api_poll_interval_secs: u64,
+
+    /// Longest interval in seconds between polls of a file that stays proven. Files are polled
+    /// every `--api-poll-interval-secs` otherwise, backing off by doubling the interval while they
+    /// stay proven.
+    #[arg(long, default_value_t = 300, value_parser = clap::value_parser!(u64).range(1..))]
+    pub api_max_poll_interval_secs: u64,
+
+    /// Randomize each wait between API polls by up to this percentage either way, so several
+    /// services started together spread their requests out.
+    #[arg(long, default_value_t = 20, value_parser = clap::value_parser!(u64).range(0..=100))]
+    pub api_poll_jitter_percent: u64,
+
+    /// How long fetched roots are reused for other files in the same proofset, in seconds.
+    /// Defaults to `--api-poll-interval-secs`.
+    #[arg(long)]
+    pub api_cache_ttl_secs: Option<u64>,
+
+    /// Deadline of a whole API poll cycle in seconds, after which its in-flight requests are
+    /// dropped until the next cycle. Should exceed `--api-timeout-secs` including retries.
+    #[arg(long, default_value_t = 120, value_parser = clap::value_parser!(u64).range(1..))]
+    pub api_poll_timeout_secs: u64,
+
+    /// Seconds a file with roots added may go without its root showing up in the API before it's
+    /// shown as pending and an error is logged.
+    #[arg(long, default_value_t = 300)]
+    pub api_root_grace_secs: u64,
+
+    /// Share of a proven root's periods, between 0 and 1, that may have faulted before the file is
+    /// shown as `stored & degraded` rather than `stored & proven`.
+    #[arg(long, default_value_t = 0.05, value_parser = parse_fault_ratio)]
+    pub degraded_fault_ratio: f64,
+
+    /// Which of several roots with the file's CID in a proofset decide its status, e.g. after
+    /// the file was re-added.
+    #[arg(long, global = true, value_enum, default_value_t = RootSelection::Aggregate)]
+    pub root_selection: RootSelection,
+
+    /// Which fields of the API's roots order their proofs and faults, as API versions disagree on
+    /// which of them are reliable.
+    #[arg(long, global = true, value_enum, default_value_t = ClassifyBy::Epoch)]
+    pub classify_by: ClassifyBy,
+
+    /// What an epoch of `0` in the API's roots means, as API versions disagree on it.
+    #[arg(long, global = true, value_enum, default_value_t = ZeroEpoch::Unset)]
+    pub zero_epoch: ZeroEpoch,
+
+    /// Which segment of the `file_id`, `<first>:<second>`, is the root CID looked up in the
+    /// proofsets, as producers disagree on it.
+    #[arg(long, global = true, value_enum, default_value_t = RootCidSegment::Either)]
+    pub root_cid_segment: RootCidSegment,
+
+    /// How tracked files are told apart. Keying by `file-id` keeps different files sharing a name
+    /// apart, showing each with a short `file_id` suffix.
+    #[arg(long, global = true, value_enum, default_value_t = StateKey::File)]
+    pub state_key: StateKey,
+
+    /// Unix time of the chain's genesis, used to date epochs the API reports without a
+    /// timestamp. Defaults to the calibration network's.
+    #[arg(long, default_value_t = CHAIN_GENESIS_UNIX)]
+    pub chain_genesis_unix: i64,
+
+    /// Seconds between chain epochs.
+    #[arg(long, default_value_t = CHAIN_EPOCH_SECS, value_parser = clap::value_parser!(i64).range(1..))]
+    pub chain_epoch_secs: i64,
+
+    /// Skip the startup request checking that the PDP Explorer API is reachable.
+    #[arg(long)]
+    pub skip_api_preflight: bool,
+
+    /// What to show once API polling has failed `--api-failure-threshold` times in a row.
+    #[arg(long, value_enum, default_value_t = ApiFailureMode::Hold)]
+    pub on_api_failure: ApiFailureMode,
+
+    /// Consecutive API polling failures before `--on-api-failure` kicks in.
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    pub api_failure_threshold: u32,
+
+    /// Seconds a file may go without its status being confirmed by a successful API poll before
+    /// it shows `stale`, until a poll succeeds again. Unset keeps the status shown by
+    /// `--on-api-failure`.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub api_stale_secs: Option<u64>,
+
+    /// Path prefix stripped from filenames before they are displayed.
+    #[arg(long)]
+    pub display_strip_prefix: Option<String>,
+
+    /// Maximum displayed filename length; longer names are truncated with an ellipsis.
+    #[arg(long)]
+    pub display_max_len: Option<usize>,
+
+    /// Width of the display in characters. Filenames are truncated with an ellipsis to fit.
+    #[arg(long, default_value_t = 16, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub display_width: usize,
+
+    /// Drop the extension from displayed filenames.
+    #[arg(long)]
+    pub display_drop_extension: bool,
+
+    /// Process each ZMQ message before ACKing it, instead of ACKing immediately and processing it
+    /// asynchronously.
+    #[arg(long)]
+    pub ack_after_processing: bool,
+
+    /// Warn about files staying longer than this many seconds in a status still in progress
+    /// (pending, uploaded, creating or stored), counting them in `slo_breach_total`.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub slo_secs: Option<u64>,
+
+    /// Seconds a processed message is remembered, so a redelivered duplicate of a tracked file is
+    /// skipped without processing it again. 0 disables the check.
+    #[arg(long, default_value_t = 300)]
+    pub dedup_ttl_secs: u64,
+
+    /// Write `{file},{status},{proofset_id},{cid_short}` lines to the serial port instead of
+    /// `{file},{status}`.
+    #[arg(long)]
+    pub serial_verbose: bool,
+
+    /// Number of trailing CID characters included in `--serial-verbose` lines.
+    #[arg(long, default_value_t = 8)]
+    pub serial_cid_len: usize,
+
+    /// Append how long ago the file was last proven to proven statuses, e.g. `stored & proven 5m ago`.
+    /// The age is as of when the line was sent, which happens again on every new proof.
+    #[arg(long)]
+    pub serial_proof_age: bool,
+
+    /// Append a checksum of each status line to it, as `filename,status*XX` with `XX` the
+    /// checksum in hex, so the firmware can discard lines corrupted on the way. Off by default, as
+    /// firmware not expecting it would show the checksum.
+    #[arg(long, value_enum)]
+    pub serial_checksum: Option<SerialChecksum>,
+
+    /// Append the number of proofs submitted for the file's root to each status line, as
+    /// `filename,status,count`, after the fields of `--serial-verbose`.
+    #[arg(long)]
+    pub serial_proof_count: bool,
+
+    /// Which root's count `--serial-proof-count` shows when several roots match the file.
+    #[arg(long, value_enum, default_value_t = ProofCountRoot::Max)]
+    pub serial_proof_count_root: ProofCountRoot,
+
+    /// One-shot line sent once a file becomes `stored & proven`, e.g. to blink an LED, after its
+    /// status line. Not sent again while the file stays proven. `{file}` is replaced with the
+    /// displayed filename.
+    #[arg(long)]
+    pub on_proven_line: Option<String>,
+
+    /// One-shot line sent once a file becomes `stored & faulty`, like `--on-proven-line`.
+    #[arg(long)]
+    pub on_faulty_line: Option<String>,
+
+    /// JSON file mapping statuses to the strings sent to the Arduino instead, e.g.
+    /// `{"stored & proven": "OK", "stored & faulty": "FAIL"}`.
+    #[arg(long, value_parser = parse_status_strings)]
+    pub status_strings: Option<StatusStrings>,
+
+    /// Line written once the serial port is ready, so the display starts from a known clean state
+    /// rather than whatever it showed last. Skipped when statuses are restored from
+    /// `--state-file`. Empty to send nothing.
+    #[arg(long, default_value = DEFAULT_INIT_LINE)]
+    pub init_line: String,
+
+    /// Show a "pending" status as soon as a file is first tracked, before it is classified.
+    #[arg(long)]
+    pub initial_pending: bool,
+
+    /// Stop tracking a file once all its roots were removed, clearing its display line after
+    /// showing `removed`.
+    #[arg(long)]
+    pub untrack_removed: bool,
+
+    /// Maximum number of files tracked at once. A new file beyond it evicts the least recently
+    /// updated file that is proven or removed, clearing its display line. Unset doesn't limit it.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_tracked_files: Option<u64>,
+
+    /// What to do with a new file when `--max-tracked-files` are tracked and none of them is proven
+    /// or removed.
+    #[arg(long, value_enum, default_value_t = TrackedFilesFullMode::Refuse)]
+    pub on_tracked_files_full: TrackedFilesFullMode,
+
+    /// Expect the Arduino to echo every line it displays within this many milliseconds, re-sending
+    /// the line once if it doesn't. Disabled by default as it needs firmware support.
+    #[arg(long)]
+    pub ack_timeout_ms: Option<u64>,
+
+    /// Write `PING <nonce>` to the Arduino on startup and expect it echoed back, catching a
+    /// miswired port or wrong baud rate before the first status. Needs firmware echoing lines.
+    #[arg(long)]
+    pub serial_self_test: bool,
+
+    /// What to do when `--serial-self-test` gets no matching echo.
+    #[arg(long, value_enum, default_value_t = SelfTestFailureMode::Exit)]
+    pub on_self_test_failure: SelfTestFailureMode,
+
+    /// Write a `HEARTBEAT` line to the Arduino every this many seconds. With `--ack-timeout-ms`,
+    /// heartbeats that aren't echoed before the next one count as missed. Disabled by default.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub heartbeat_interval_secs: Option<u64>,
+
+    /// Consecutive missed heartbeats after which the display is reported as hung.
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    pub heartbeat_max_misses: u32,
+
+    /// Reconnect the serial port once the display is reported as hung.
+    #[arg(long)]
+    pub heartbeat_reconnect: bool,
+
+    /// Reset the Arduino by toggling DTR once it misses `--watchdog-max-misses` echoes of lines
+    /// or heartbeats in a row. Requires `--ack-timeout-ms`.
+    #[arg(long)]
+    pub watchdog: bool,
+
+    /// Consecutive missed echoes after which the watchdog resets the Arduino.
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    pub watchdog_max_misses: u32,
+
+    /// Collect status updates arriving within this many milliseconds of each other into one
+    /// `BATCH` frame, keeping the latest update of each file. Unset writes every update on its own.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub serial_batch_ms: Option<u64>,
+
+    /// Minimum milliseconds between status writes, for displays that are slow to refresh. Updates
+    /// arriving faster wait their turn, only the latest of each file being kept, so every file still
+    /// ends up showing its last status. Unset writes updates as soon as they arrive.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub serial_min_interval_ms: Option<u64>,
+
+    /// File of `proofset_id,cid,display_name` lines, each tracked like a file announced over ZMQ
+    /// with its roots added, e.g. to show roots uploaded by someone else. SIGHUP re-reads it.
+    #[arg(long)]
+    pub watch_file: Option<String>,
+
+    /// OTLP/gRPC collector to export tracing spans of received messages, API requests and poll
+    /// cycles to, e.g. `http://localhost:4317`. Unset doesn't trace.
+    #[arg(long)]
+    pub otlp_endpoint: Option<String>,
+
+    /// Persist the tracked files and their statuses to this file, restoring them on startup.
+    #[arg(long)]
+    pub state_file: Option<String>,
+
+    /// Dump the runtime state to this file if the service panics.
+    #[arg(long)]
+    pub panic_dump_path: Option<String>,
+
+    /// Directory the `dump-state <name>` command writes its dumps to. Without it, the command is
+    /// refused.
+    #[arg(long)]
+    pub dump_dir: Option<String>,
+
+    /// Number of recent events, such as received messages, status changes and API errors, kept
+    /// for the `/events` endpoint of `--metrics-listen` and state dumps.
+    #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u64).range(1..))]
+    pub recent_events_len: u64,
+
+    /// Line the Arduino firmware prints once it booted. Startup waits for it after opening the
+    /// port, which resets the Arduino; empty to wait `--arduino-reset-delay-secs` instead.
+    #[arg(long, default_value = "READY")]
+    pub ready_banner: String,
+
+    /// How long to wait for `--ready-banner` in milliseconds before falling back to
+    /// `--arduino-reset-delay-secs`.
+    #[arg(long, default_value_t = 5_000)]
+    pub ready_timeout_ms: u64,
+
+    /// Seconds the Arduino takes to boot after opening the port resets it, waited out when no
+    /// `--ready-banner` arrives. At most 60.
+    #[arg(long, default_value_t = ARDUINO_RESET_DELAY_SECS, value_parser = clap::value_parser!(u64).range(0..=60))]
+    pub arduino_reset_delay_secs: u64,
+
+    /// Timeout of serial port reads and writes in milliseconds, between 1 and 10000.
+    #[arg(long, default_value_t = SERIAL_TIMEOUT_MS, value_parser = clap::value_parser!(u64).range(1..=10_000))]
+    pub serial_timeout_ms: u64,
+
+    /// Status updates queued for the serial writer before updates are coalesced, keeping only the
+    /// latest pending update of a file.
+    #[arg(long, default_value_t = CHANNEL_BUFFER_SIZE, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub status_queue_size: usize,
+
+    /// Publish every status sent to the Arduino as JSON on a ZMQ PUB socket bound to this endpoint.
+    #[arg(long)]
+    pub status_pub_bind: Option<String>,
+
+    /// Stream status events as JSON lines to readers of a Unix domain socket at this path.
+    #[arg(long)]
+    pub status_socket: Option<String>,
+
+    /// Append every status sent to the Arduino as a JSON line to this file, e.g. to reconcile the
+    /// display against the explorer later.
+    #[arg(long)]
+    pub status_log: Option<String>,
+
+    /// Size in bytes beyond which `--status-log` is rotated.
+    #[arg(long, default_value_t = 10_000_000, value_parser = clap::value_parser!(u64).range(1..))]
+    pub status_log_max_bytes: u64,
+
+    /// Rotated `--status-log` files to keep, as `<path>.1` (the newest) to `<path>.<n>`.
+    #[arg(long, default_value_t = 5)]
+    pub status_log_keep: usize,
+
+    /// How long to keep retrying to open the serial port and ZMQ socket at startup in seconds, e.g.
+    /// while the Arduino is still enumerating after a reboot. 0 fails on the first error.
+    #[arg(long, default_value_t = 30)]
+    pub startup_timeout_secs: u64,
+
+    /// Accept ZMQ messages right away and keep opening the serial port in the background until it
+    /// comes up, instead of failing after `--startup-timeout-secs`. Status updates wait in the
+    /// status queue meanwhile.
+    #[arg(long)]
+    pub non_blocking_startup: bool,
+
+    /// Record serial lines in memory instead of opening the serial port, to run without an Arduino.
+    #[arg(long)]
+    pub mock_serial: bool,
+
+    /// Where the statuses are shown. `stdout` draws them as a table in the terminal instead of
+    /// opening the serial port, e.g. for demos without an Arduino.
+    #[arg(long, value_enum, default_value_t = DisplayOutput::Serial)]
+    pub display: DisplayOutput,
+
+    /// Log the serial lines with a `[dry-run]` prefix instead of opening the serial port.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Serve Prometheus metrics on this address, e.g. `0.0.0.0:9090`, along with the recent events
+    /// and `POST /reload`.
+    #[arg(long)]
+    pub metrics_listen: Option<std::net::SocketAddr>,
+}
+
+impl Cli {
+    /// Parses the command line along with the `--config` file, exiting on invalid flags.
+    pub fn load() -> Self {
+        let cli = Cli::parse_with_sources(std::env::args(), 0).unwrap_or_else(|e| e.exit());
+        match cli.with_config_file() {
+            Ok(cli) => cli,
+            Err(e) => {
+                eprintln!("{:#}", e);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    /// Parses the command line again with the flags of the `--config` file inserted before it.
+    pub(crate) fn with_config_file(&self) -> anyhow::Result<Cli> {
+        let Some(path) = &self.config else {
+            return Ok(self.clone());
+        };
+        let file_args = config_file_args(path)?;
+        let mut args = self.command_line.iter().cloned();
+        let program = args.next().unwrap_or_default();
+        let config_args = file_args.len();
+        let args = std::iter::once(program).chain(file_args).chain(args);
+        Cli::parse_with_sources(args, config_args).map_err(|e| {
+            // Keeps the reason, dropping the usage hints clap appends.
+            let message = e.to_string();
+            let reason = message.lines().next().unwrap_or_default();
+            anyhow::anyhow!("Invalid flags in {}: {}", path, reason)
+        })
+    }
+
+    /// Parses `args`, whose first `config_args` arguments after the program name came from the
+    /// `--config` file, recording where the settings of `SOURCED_SETTINGS` came from.
+    pub(crate) fn parse_with_sources(
+        args: impl IntoIterator<Item = String>,
+        config_args: usize,
+    ) -> Result<Cli, clap::Error> {
+        let args: Vec<String> = args.into_iter().collect();
+        let matches = Cli::command().try_get_matches_from(&args)?;
+        let mut cli = Cli::from_arg_matches(&matches)?;
+        cli.command_line = args
+            .iter()
+            .take(1)
+            .chain(args.iter().skip(1 + config_args))
+            .cloned()
+            .collect();
+        cli.setting_sources = SOURCED_SETTINGS
+            .iter()
+            .map(|(id, _)| {
+                let source = match matches.value_source(id) {
+                    Some(ValueSource::CommandLine)
+                        if matches
+                            .index_of(id)
+                            .is_some_and(|index| index <= config_args) =>
+                    {
+                        SettingSource::Config
+                    }
+                    Some(ValueSource::CommandLine) => SettingSource::Flag,
+                    Some(ValueSource::EnvVariable) => SettingSource::Env,
+                    _ => SettingSource::Default,
+                };
+                (*id, source)
+            })
+            .collect();
+        Ok(cli)
+    }
+
+    /// Logs the value of each setting of `SOURCED_SETTINGS` along with where it came from.
+    pub(crate) fn log_setting_sources(&self) {
+        for ((id, source), (_, env)) in self.setting_sources.iter().zip(SOURCED_SETTINGS) {
+            let value = match *id {
+                "serial_port" => self.serial_port.clone(),
+                "baud" => self.baud.to_string(),
+                "zmq_bind" => self.zmq_bind.clone(),
+                _ => self
+                    .api_url
+                    .iter()
+                    .map(|url| redact_url(url))
+                    .collect::<Vec<_>>()
+                    .join(","),
+            };
+            let source = match source {
+                SettingSource::Flag => "flag",
+                SettingSource::Config => "--config",
+                SettingSource::Env => env,
+                SettingSource::Default => "default",
+            };
+            info!("--{} = {} (from {})", id.replace('_', "-"), value, source);
+        }
+    }
+
+    /// How long fetched roots are reused, see `--api-cache-ttl-secs`.
+    pub(crate) fn api_cache_ttl(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(
+            self.api_cache_ttl_secs
+                .unwrap_or(self.api_poll_interval_secs),
+        )
+    }
+
+    /// Real values of the settings serialized redacted, named as they're serialized.
+    pub(crate) fn redacted_settings(&self) -> [(&'static str, serde_json::Value); 4] {
+        [
+            ("reload_token", serde_json::json!(self.reload_token)),
+            ("api_url", serde_json::json!(self.api_url)),
+            ("api_token", serde_json::json!(self.api_token)),
+            ("api_header", serde_json::json!(self.api_header)),
+        ]
+    }
+
+    /// Applies the settings of `new` that the running tasks pick up without a restart.
+    pub(crate) fn apply_reloadable(&mut self, new: &Cli) {
+        self.display_strip_prefix = new.display_strip_prefix.clone();
+        self.display_max_len = new.display_max_len;
+        self.display_width = new.display_width;
+        self.display_drop_extension = new.display_drop_extension;
+        self.serial_verbose = new.serial_verbose;
+        self.serial_cid_len = new.serial_cid_len;
+        self.serial_proof_age = new.serial_proof_age;
+        self.serial_proof_count = new.serial_proof_count;
+        self.serial_proof_count_root = new.serial_proof_count_root;
+        self.status_strings = new.status_strings.clone();
+        self.on_proven_line = new.on_proven_line.clone();
+        self.on_faulty_line = new.on_faulty_line.clone();
+        self.api_poll_interval_secs = new.api_poll_interval_secs;
+        self.api_max_poll_interval_secs = new.api_max_poll_interval_secs;
+        self.api_poll_jitter_percent = new.api_poll_jitter_percent;
+        self.api_poll_timeout_secs = new.api_poll_timeout_secs;
+        self.api_root_grace_secs = new.api_root_grace_secs;
+        self.degraded_fault_ratio = new.degraded_fault_ratio;
+        self.root_selection = new.root_selection;
+        self.classify_by = new.classify_by;
+        self.zero_epoch = new.zero_epoch;
+        self.root_cid_segment = new.root_cid_segment;
+        self.max_tracked_files = new.max_tracked_files;
+        self.on_tracked_files_full = new.on_tracked_files_full;
+        self.api_cache_ttl_secs = new.api_cache_ttl_secs;
+        self.api_failure_threshold = new.api_failure_threshold;
+        self.on_api_failure = new.on_api_failure;
+        self.api_stale_secs = new.api_stale_secs;
+    }
+}
+
+/// Turns a JSON object of flag names and values into command line arguments.
+fn config_file_args(path: &str) -> anyhow::Result<Vec<String>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config from {}", path))?;
+    let flags: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&contents)
+        .with_context(|| format!("Failed to parse config from {}", path))?;
+    let mut args = Vec::new();
+    for (name, value) in flags {
+        let flag = format!("--{}", name);
+        let values = match value {
+            serde_json::Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                serde_json::Value::Bool(true) => args.push(flag.clone()),
+                serde_json::Value::Bool(false) | serde_json::Value::Null => {}
+                serde_json::Value::String(value) => args.extend([flag.clone(), value]),
+                serde_json::Value::Number(value) => args.extend([flag.clone(), value.to_string()]),
+                _ => anyhow::bail!("Unsupported value of {} in {}", name, path),
+            }
+        }
+    }
+    Ok(args)
+}
+
+pub(crate) const STANDARD_BAUD_RATES: &[u32] = &[
+    300, 600, 1_200, 2_400, 4_800, 9_600, 14_400, 19_200, 28_800, 38_400, 57_600, 115_200, 230_400,
+    460_800, 921_600,
+];
+
+pub(crate) fn parse_status_strings(path: &str) -> Result<StatusStrings, String> {
+    StatusStrings::load(path).map_err(|e| format!("{:#}", e))
+}
+
+pub(crate) fn parse_extra_serial_port(value: &str) -> Result<ExtraSerialPort, String> {
+    let (path, route) = value
+        .rsplit_once('=')
+        .ok_or_else(|| format!("`{}` is not of the form `<path>=<route>`", value))?;
+    if path.is_empty() {
+        return Err(format!("`{}` has no serial port path", value));
+    }
+    let route = <SerialRoute as clap::ValueEnum>::from_str(route, false).map_err(|_| {
+        format!(
+            "`{}` is not a route, expected `all`, `uploads` or `proofs`",
+            route
+        )
+    })?;
+    Ok(ExtraSerialPort {
+        path: path.to_string(),
+        route,
+    })
+}
+
+pub(crate) fn parse_header(value: &str) -> Result<String, String> {
+    let (name, header_value) = value
+        .split_once(':')
+        .ok_or_else(|| format!("`{}` is not of the form `Name: value`", value))?;
+    reqwest::header::HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|_| format!("`{}` is not a valid header name", name.trim()))?;
+    reqwest::header::HeaderValue::from_str(header_value.trim())
+        .map_err(|_| format!("The value of header `{}` isn't valid", name.trim()))?;
+    Ok(value.to_string())
+}
+
+/// Serializes a secret as `<redacted>` when set, e.g. in state dumps.
+fn serialize_redacted<S: serde::Serializer>(
+    value: &Option<String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    value.as_ref().map(|_| "<redacted>").serialize(serializer)
+}
+
+/// Serializes `Name: value` headers with their values redacted.
+fn serialize_redacted_headers<S: serde::Serializer>(
+    headers: &[String],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    headers
+        .iter()
+        .map(|header| {
+            let name = header
+                .split_once(':')
+                .map_or(header.as_str(), |(name, _)| name);
+            format!("{}: <redacted>", name.trim())
+        })
+        .collect::<Vec<_>>()
+        .serialize(serializer)
+}
+
+pub(crate) fn parse_rate(value: &str) -> Result<f64, String> {
+    let rate: f64 = value
+        .parse()
+        .map_err(|_| format!("`{}` is not a number", value))?;
+    if !(rate.is_finite() && rate > 0.0) {
+        return Err(format!("{} is not a positive rate", rate));
+    }
+    Ok(rate)
+}
+
+pub(crate) fn parse_fault_ratio(value: &str) -> Result<f64, String> {
+    let ratio: f64 = value
+        .parse()
+        .map_err(|_| format!("`{}` is not a number", value))?;
+    if !(0.0..=1.0).contains(&ratio) {
+        return Err(format!("{} is not between 0 and 1", ratio));
+    }
+    Ok(ratio)
+}
+
+pub(crate) fn parse_baud_rate(value: &str) -> Result<u32, String> {
+    let baud: u32 = value
+        .parse()
+        .map_err(|_| format!("`{}` is not a number", value))?;
+    if !STANDARD_BAUD_RATES.contains(&baud) {
+        return Err(format!(
+            "{} is not a standard baud rate, expected one of {:?}",
+            baud, STANDARD_BAUD_RATES
+        ));
+    }
+    Ok(baud)
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Send a stage update to a running instance on `--zmq-bind` and print its reply.
+    SendTest {
+        /// Name of the file.
+        #[arg(long)]
+        file: String,
+        /// File id, `<cid>:<root cid>`.
+        #[arg(long)]
+        file_id: String,
+        #[arg(long, value_enum)]
+        stage: Stage,
+        /// Proofset the roots were added to, repeated for several.
+        #[arg(long = "proofset-id")]
+        proofset_ids: Vec<String>,
+    },
+    /// Check the status of a single root once, print it and exit.
+    #[command(
+        after_help = "Exit codes: 0 if stored or proven, 2 if faulty, 3 if not found or removed, 1 on \
+                      errors."
+    )]
+    Once {
+        /// Proofset to look the root up in.
+        #[arg(long)]
+        proofset_id: String,
+        /// Root CID to check.
+        #[arg(long)]
+        cid: String,
+    },
+    /// Check the serial ports, API and ZMQ endpoints, print a pass/fail report and exit, non-zero
+    /// if a critical check failed. Opening the serial port resets the Arduino.
+    Doctor,
+    /// Replay recorded ZMQ payloads offline and print the serial lines they produce.
+    Replay {
+        /// File of newline-delimited JSON payloads, `-` for stdin.
+        #[arg(default_value = "-")]
+        input: String,
+        /// JSON object of proofset ids to recorded roots responses of the API, to classify files
+        /// with roots added as the poller would.
+        #[arg(long)]
+        roots: Option<String>,
+    },
+}
+
+#[derive(clap::ValueEnum, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    /// Human readable lines.
+    Text,
+    /// One JSON object per line with `level`, `timestamp`, `target` and `message` fields.
+    Json,
+}
+
+/// Columns of the roots endpoint the API accepts as `orderBy`.
+#[derive(clap::ValueEnum, Serialize, Debug, Clone, Copy, PartialEq)]
+#[value(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum RootsOrderBy {
+    RootId,
+    Size,
+    TotalPeriodsFaulted,
+    TotalProofsSubmitted,
+    LastProvenEpoch,
+    LastFaultedEpoch,
+    CreatedAt,
+}
+
+impl RootsOrderBy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RootsOrderBy::RootId => "root_id",
+            RootsOrderBy::Size => "size",
+            RootsOrderBy::TotalPeriodsFaulted => "total_periods_faulted",
+            RootsOrderBy::TotalProofsSubmitted => "total_proofs_submitted",
+            RootsOrderBy::LastProvenEpoch => "last_proven_epoch",
+            RootsOrderBy::LastFaultedEpoch => "last_faulted_epoch",
+            RootsOrderBy::CreatedAt => "created_at",
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SortOrder::Asc => "asc",
+            SortOrder::Desc => "desc",
+        }
+    }
+}
+
+/// Statuses shown on a serial port. Statuses not tied to a stage, `offline`, `stale`, blank and
+/// `clear`, are shown on every port.
+#[derive(clap::ValueEnum, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SerialRoute {
+    /// Every status.
+    All,
+    /// Statuses until the file's proofs are known: `pending`, `uploaded` and `stored`.
+    Uploads,
+    /// Statuses derived from the file's proofs, from `stored & proven` to `removed`.
+    Proofs,
+}
+
+/// Checksum algorithm of `--serial-checksum`.
+#[derive(clap::ValueEnum, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SerialChecksum {
+    /// Sum of the bytes modulo 256.
+    Sum8,
+    /// CRC-8 with polynomial 0x07.
+    Crc8,
+}
+
+/// Root whose proof count `--serial-proof-count` shows, see `--serial-proof-count-root`.
+#[derive(clap::ValueEnum, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProofCountRoot {
+    /// The root with the most proofs submitted.
+    Max,
+    /// The root proven or faulted most recently.
+    Latest,
+}
+
+/// Serial port given with `--extra-serial-port`.
+#[derive(Serialize, Debug, Clone)]
+pub struct ExtraSerialPort {
+    pub path: String,
+    pub route: SerialRoute,
+}
+
+#[derive(clap::ValueEnum, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RootCidSegment {
+    /// The CID before the colon.
+    First,
+    /// The CID after the colon.
+    Second,
+    /// The CID after the colon, or else the one before it if only that one is found.
+    Either,
+}
+
+#[derive(clap::ValueEnum, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RootSelection {
+    /// Take all live matching roots into account.
+    Aggregate,
+    /// Only take the most recently created matching root into account.
+    Latest,
+}
+
+#[derive(clap::ValueEnum, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClassifyBy {
+    /// A root is proven once `lastProvenEpoch` is set, and faulty if `lastFaultedEpoch` is later.
+    Epoch,
+    /// A root is proven once `lastProvenAt` is set, and faulty if `lastFaultedAt` is later.
+    Timestamp,
+}
+
+#[derive(clap::ValueEnum, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum StateKey {
+    /// The file name, so a file re-announced under a new `file_id` replaces the old one.
+    File,
+    /// The `file_id`, keeping same-named files apart.
+    FileId,
+    /// Both the file name and `file_id`.
+    Composite,
+}
+
+#[derive(clap::ValueEnum, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ZeroEpoch {
+    /// An epoch of `0` hasn't been evaluated yet, like `null`.
+    Unset,
+    /// An epoch of `0` is the genesis epoch, and only `null` is unset.
+    Genesis,
+}
+
+#[derive(clap::ValueEnum, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TrackedFilesFullMode {
+    /// Don't track the new file.
+    Refuse,
+    /// Evict the least recently updated file whatever its status.
+    EvictOldest,
+}
+
+#[derive(clap::ValueEnum, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SelfTestFailureMode {
+    /// Log a warning and carry on.
+    Warn,
+    /// Log an error and exit, refusing to run with a display that may not show anything.
+    Exit,
+}
+
+#[derive(clap::ValueEnum, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DisplayOutput {
+    /// Write the status lines to the Arduino on the serial port.
+    Serial,
+    /// Redraw a table of the files and their statuses on stdout, color coded, or print the status
+    /// lines when stdout isn't a terminal.
+    Stdout,
+}
+
+#[derive(clap::ValueEnum, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ZmqMode {
+    /// Bind a REP socket and answer every message.
+    Rep,
+    /// Bind a ROUTER socket and answer every message to its sender, so several clients can send
+    /// at once.
+    Router,
+    /// Connect a SUB socket to a publisher.
+    Sub,
+}
+
+#[derive(clap::ValueEnum, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ApiFailureMode {
+    /// Keep showing the last known status.
+    Hold,
+    /// Clear the status line.
+    Blank,
+    /// Show an "offline" status.
+    OfflineIndicator,
+}
+
+impl ApiFailureMode {
+    /// Status to display once the failure threshold is reached, or `None` to leave it as is.
+    pub fn status(self) -> Option<DisplayStatus> {
+        match self {
+            ApiFailureMode::Hold => None,
+            ApiFailureMode::Blank => Some(DisplayStatus::Blank),
+            ApiFailureMode::OfflineIndicator => Some(DisplayStatus::Offline),
+        }
+    }
+}
+
+/// Replaces any password in `url` so it can be included in a dump.
+pub(crate) fn redact_url(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(mut parsed) if parsed.password().is_some() => {
+            let _ = parsed.set_password(Some("redacted"));
+            parsed.to_string()
+        }
+        _ => url.to_string(),
+    }
+}
+
+pub(crate) fn serialize_redacted_urls<S: serde::Serializer>(
+    urls: &[String],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(urls.iter().map(|url| redact_url(url)))
+}
@@ -0,0 +1,174 @@
+//! Control commands accepted on the ZMQ socket alongside stage updates.
+
+use std::{collections::HashMap, sync::Arc};
+
+use log::info;
+use metrics::gauge;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::message::{Stage, ZmqPayload};
+use crate::reload::{reload_with_token, ReloadSummary, SharedConfig};
+use crate::status::{DisplayStatus, StageTimes, StatusEvent, StatusSender};
+
+/// Command sent as e.g. `{"command": "reset", "file": "foo.jpg"}`, answered with a JSON
+/// [`ControlReply`].
+#[derive(Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub(crate) enum ControlCommand {
+    /// Stops tracking a file and clears its line on the display.
+    Reset { file: String },
+    /// Lists the tracked files and their statuses.
+    List,
+    /// Reloads the `--config` file like SIGHUP. `token` must match `--reload-token` if set.
+    Reload { token: Option<String> },
+}
+
+impl std::fmt::Debug for ControlCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ControlCommand::Reset { file } => f.debug_struct("Reset").field("file", file).finish(),
+            ControlCommand::List => f.write_str("List"),
+            // Keeps the token out of the logs.
+            ControlCommand::Reload { .. } => f.write_str("Reload"),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct ControlReply {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    files: Option<Vec<TrackedFile>>,
+    #[serde(flatten)]
+    reload: Option<ReloadSummary>,
+}
+
+/// Tracked file as listed by [`ControlCommand::List`].
+#[derive(Serialize, Debug)]
+struct TrackedFile {
+    file: String,
+    stage: Stage,
+    /// Status last sent to the display, `None` if none was yet.
+    status: Option<DisplayStatus>,
+    proofset_ids: Vec<String>,
+    /// `None` if no status was sent yet.
+    stage_times: Option<StageTimes>,
+}
+
+impl ControlReply {
+    fn ok(files: Option<Vec<TrackedFile>>) -> Self {
+        Self {
+            ok: true,
+            error: None,
+            files,
+            reload: None,
+        }
+    }
+
+    fn error(error: String) -> Self {
+        Self {
+            ok: false,
+            error: Some(error),
+            files: None,
+            reload: None,
+        }
+    }
+}
+
+impl ControlCommand {
+    pub(crate) async fn execute(
+        self,
+        current_state: &Mutex<HashMap<String, ZmqPayload>>,
+        statuses: &Arc<Mutex<HashMap<String, StatusEvent>>>,
+        status_sender: &StatusSender,
+        config: &SharedConfig,
+    ) -> ControlReply {
+        match self {
+            ControlCommand::Reset { file } => {
+                let (removed, tracked) = {
+                    let mut state = current_state.lock();
+                    let key = state
+                        .iter()
+                        .find(|(key, payload)| **key == file || payload.data.file == file)
+                        .map(|(key, _)| key.clone());
+                    (key.and_then(|key| state.remove(&key)), state.len())
+                };
+                let Some(payload) = removed else {
+                    return ControlReply::error(format!("Unknown file: {}", file));
+                };
+                info!("Reset {}", payload.data.file);
+                gauge!("tracked_files").set(tracked as f64);
+                let key = config.read().state_key;
+                status_sender.clear(&payload, key).await;
+                ControlReply::ok(None)
+            }
+            ControlCommand::List => {
+                let statuses = statuses.lock();
+                let current_state = current_state.lock();
+                let last_sent = status_sender.last_sent.lock();
+                let key = config.read().state_key;
+                let files = current_state
+                    .values()
+                    .map(|payload| {
+                        let name = payload.data.display_name(key);
+                        TrackedFile {
+                            file: payload.data.file.clone(),
+                            stage: payload.stage.clone(),
+                            status: statuses.get(&name).map(|event| event.status),
+                            proofset_ids: payload.data.proofset_ids.clone(),
+                            stage_times: last_sent.get(&name).map(|sent| sent.stage_times()),
+                        }
+                    })
+                    .collect();
+                ControlReply::ok(Some(files))
+            }
+            ControlCommand::Reload { token } => match reload_with_token(config, token.as_deref()) {
+                Ok(summary) => ControlReply {
+                    reload: Some(summary),
+                    ..ControlReply::ok(None)
+                },
+                Err(e) => ControlReply::error(e.to_string()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{pipeline, serial_lines, stage_message};
+
+    #[tokio::test]
+    async fn reset_clears_the_file() {
+        let (handler, sink) = pipeline(&["--ack-after-processing"]);
+        let message = stage_message("a.jpg", "baga6ea4piece:bafkroot", "Uploaded");
+        assert_eq!(handler.handle(&message).await, "ACK");
+        let reply = handler
+            .handle(r#"{"command": "reset", "file": "a.jpg"}"#)
+            .await;
+        assert_eq!(reply, r#"{"ok":true}"#);
+        assert!(handler.state.current_state.lock().is_empty());
+        // Tracked again, the file's status is sent even though it's the one shown before.
+        assert_eq!(handler.handle(&message).await, "ACK");
+        assert_eq!(
+            serial_lines(&sink, 4).await,
+            [
+                "init\n",
+                "a.jpg,uploaded\n",
+                "a.jpg,clear\n",
+                "a.jpg,uploaded\n"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reset_of_unknown_file_fails() {
+        let (handler, _) = pipeline(&[]);
+        let reply = handler
+            .handle(r#"{"command": "reset", "file": "a.jpg"}"#)
+            .await;
+        assert_eq!(reply, r#"{"ok":false,"error":"Unknown file: a.jpg"}"#);
+    }
+}
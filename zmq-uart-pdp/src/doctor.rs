@@ -0,0 +1,292 @@
+//! `doctor` subcommand checking the serial ports, API and ZMQ endpoints before a first run.
+
+use std::{
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::api::validate_api_base_url;
+use crate::build_http_client;
+use crate::cli::{redact_url, Cli, DisplayOutput, ZmqMode};
+use crate::inproc::{is_inproc, reject_inproc};
+use crate::serial::open_serial_port;
+#[cfg(unix)]
+use crate::zmq_socket::ipc_socket_path;
+use crate::zmq_socket::validate_zmq_endpoint;
+
+// How long `doctor` waits for the API and for a ZMQ publisher to answer.
+const DOCTOR_TIMEOUT_SECS: u64 = 5;
+
+/// Result of a single check.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Outcome {
+    Pass,
+    /// Worth a look, but the service can still run.
+    Warn,
+    /// The service won't work until it's fixed.
+    Fail,
+}
+
+/// Pass/fail report printed to stdout as checks complete.
+#[derive(Default)]
+struct Report {
+    failures: usize,
+    warnings: usize,
+}
+
+impl Report {
+    fn record(&mut self, outcome: Outcome, message: impl std::fmt::Display) {
+        let label = match outcome {
+            Outcome::Pass => "PASS",
+            Outcome::Warn => {
+                self.warnings += 1;
+                "WARN"
+            }
+            Outcome::Fail => {
+                self.failures += 1;
+                "FAIL"
+            }
+        };
+        println!("[{}] {}", label, message);
+    }
+}
+
+/// Checks the configured serial ports, API URLs and ZMQ endpoints without starting the service,
+/// printing a report. Returns whether every critical check passed.
+pub async fn doctor(cli: &Cli) -> bool {
+    let mut report = Report::default();
+    check_serial_ports(cli, &mut report);
+    check_api(cli, &mut report).await;
+    check_zmq(cli, &mut report);
+    if report.failures == 0 {
+        println!("All critical checks passed ({} warnings)", report.warnings);
+    } else {
+        println!(
+            "{} critical checks failed ({} warnings)",
+            report.failures, report.warnings
+        );
+    }
+    report.failures == 0
+}
+
+fn check_serial_ports(cli: &Cli, report: &mut Report) {
+    if cli.dry_run || cli.mock_serial || cli.display == DisplayOutput::Stdout {
+        report.record(
+            Outcome::Pass,
+            "Serial ports not needed with --dry-run, --mock-serial or --display stdout",
+        );
+        return;
+    }
+    let available = match serialport::available_ports() {
+        Ok(ports) => {
+            let names: Vec<_> = ports.into_iter().map(|port| port.port_name).collect();
+            if names.is_empty() {
+                report.record(Outcome::Warn, "No serial ports found");
+            } else {
+                report.record(
+                    Outcome::Pass,
+                    format!("Available serial ports: {}", names.join(", ")),
+                );
+            }
+            names
+        }
+        Err(e) => {
+            report.record(Outcome::Warn, format!("Failed to list serial ports: {}", e));
+            Vec::new()
+        }
+    };
+    let paths = std::iter::once(&cli.serial_port)
+        .chain(cli.extra_serial_port.iter().map(|port| &port.path));
+    for path in paths {
+        // Symlinks such as `/dev/serial/by-id/...` aren't listed, but exist.
+        if !available.contains(path) && !Path::new(path).exists() {
+            report.record(Outcome::Fail, format!("Serial port {} doesn't exist", path));
+            continue;
+        }
+        report.record(Outcome::Pass, format!("Serial port {} exists", path));
+        // Opening the port resets the Arduino, which is back after its boot.
+        match open_serial_port(path, cli.baud, Duration::from_millis(cli.serial_timeout_ms)) {
+            Ok(_) => report.record(
+                Outcome::Pass,
+                format!("Serial port {} opens at {} baud", path, cli.baud),
+            ),
+            Err(e) => report.record(
+                Outcome::Fail,
+                format!("Failed to open serial port {}: {}", path, e),
+            ),
+        }
+    }
+}
+
+async fn check_api(cli: &Cli, report: &mut Report) {
+    let client = match build_http_client(cli) {
+        Ok(client) => client,
+        Err(e) => {
+            report.record(
+                Outcome::Fail,
+                format!("Invalid API client settings: {:#}", e),
+            );
+            return;
+        }
+    };
+    for url in &cli.api_url {
+        let shown = redact_url(url);
+        let parsed = match validate_api_base_url(url) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                report.record(Outcome::Fail, format!("{:#}", e));
+                continue;
+            }
+        };
+        let started = Instant::now();
+        let response = client
+            .get(parsed)
+            .timeout(Duration::from_secs(DOCTOR_TIMEOUT_SECS))
+            .send()
+            .await;
+        let latency = started.elapsed();
+        match response {
+            Ok(response) => {
+                let status = response.status();
+                let outcome = if status.is_server_error()
+                    || matches!(
+                        status,
+                        reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN
+                    ) {
+                    Outcome::Fail
+                } else {
+                    Outcome::Pass
+                };
+                report.record(
+                    outcome,
+                    format!("API {} answered {} in {:?}", shown, status, latency),
+                );
+            }
+            Err(e) => report.record(
+                Outcome::Fail,
+                format!("API {} not reachable after {:?}: {}", shown, latency, e),
+            ),
+        }
+    }
+}
+
+fn check_zmq(cli: &Cli, report: &mut Report) {
+    match cli.zmq_mode {
+        ZmqMode::Rep | ZmqMode::Router => check_bindable(&cli.zmq_bind, "ZMQ endpoint", report),
+        ZmqMode::Sub => check_publisher(&cli.zmq_bind, report),
+    }
+    if let Some(endpoint) = &cli.status_pub_bind {
+        match reject_inproc(endpoint, "Status PUB endpoint") {
+            Ok(()) => check_bindable(endpoint, "Status PUB endpoint", report),
+            Err(e) => report.record(Outcome::Fail, format!("{:#}", e)),
+        }
+    }
+}
+
+/// Checks that nothing else is bound to `endpoint` yet.
+fn check_bindable(endpoint: &str, what: &str, report: &mut Report) {
+    if let Err(e) = validate_zmq_endpoint(endpoint) {
+        report.record(Outcome::Fail, format!("{:#}", e));
+        return;
+    }
+    if is_inproc(endpoint) {
+        report.record(
+            Outcome::Pass,
+            format!("{} {} is in-process, not checked", what, endpoint),
+        );
+        return;
+    }
+    if let Some(address) = endpoint.strip_prefix("tcp://") {
+        let address = address.replacen('*', "0.0.0.0", 1);
+        match TcpListener::bind(&address) {
+            Ok(_) => report.record(Outcome::Pass, format!("{} {} is free", what, endpoint)),
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => report.record(
+                Outcome::Fail,
+                format!(
+                    "{} {} is already in use, is the service running?",
+                    what, endpoint
+                ),
+            ),
+            Err(e) => report.record(
+                Outcome::Fail,
+                format!("{} {} can't be bound: {}", what, endpoint, e),
+            ),
+        }
+    } else {
+        // Validation leaves `ipc://` endpoints, which only Unix has.
+        #[cfg(unix)]
+        check_ipc_bindable(endpoint, what, report);
+    }
+}
+
+/// Checks that nothing else listens on the socket file of an `ipc://` endpoint yet.
+#[cfg(unix)]
+fn check_ipc_bindable(endpoint: &str, what: &str, report: &mut Report) {
+    let Some(path) = ipc_socket_path(endpoint) else {
+        report.record(
+            Outcome::Pass,
+            format!("{} {} is an abstract socket, not checked", what, endpoint),
+        );
+        return;
+    };
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => report.record(
+            Outcome::Fail,
+            format!(
+                "{} {} is already in use, is the service running?",
+                what, endpoint
+            ),
+        ),
+        Err(_) if path.exists() => report.record(
+            Outcome::Warn,
+            format!(
+                "{} {} has a stale socket file, removed on startup",
+                what, endpoint
+            ),
+        ),
+        Err(_) => report.record(Outcome::Pass, format!("{} {} is free", what, endpoint)),
+    }
+}
+
+/// Checks that a publisher is listening on `endpoint` in `sub` mode. It may come up later, so a
+/// missing one is only a warning.
+fn check_publisher(endpoint: &str, report: &mut Report) {
+    if let Err(e) =
+        validate_zmq_endpoint(endpoint).and_then(|()| reject_inproc(endpoint, "ZMQ publisher"))
+    {
+        report.record(Outcome::Fail, format!("{:#}", e));
+        return;
+    }
+    let reachable = match endpoint.strip_prefix("tcp://") {
+        Some(address) => address
+            .to_socket_addrs()
+            .map_err(|e| e.to_string())
+            .and_then(|mut addrs| addrs.next().ok_or_else(|| "no address".to_string()))
+            .and_then(|addr| {
+                TcpStream::connect_timeout(&addr, Duration::from_secs(DOCTOR_TIMEOUT_SECS))
+                    .map_err(|e| e.to_string())
+            })
+            .map(|_| ()),
+        #[cfg(unix)]
+        None => match ipc_socket_path(endpoint) {
+            Some(path) => std::os::unix::net::UnixStream::connect(path)
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+            None => Ok(()),
+        },
+        // `ipc://` endpoints failed validation, which leaves nothing to connect to.
+        #[cfg(not(unix))]
+        None => Ok(()),
+    };
+    match reachable {
+        Ok(()) => report.record(
+            Outcome::Pass,
+            format!("ZMQ publisher {} is reachable", endpoint),
+        ),
+        Err(e) => report.record(
+            Outcome::Warn,
+            format!("ZMQ publisher {} isn't reachable yet: {}", endpoint, e),
+        ),
+    }
+}
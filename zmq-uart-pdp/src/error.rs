@@ -0,0 +1,33 @@
+//! Errors of the library functions, for callers that need to tell failure modes apart.
+
+/// Why a message, API request or serial operation failed.
+#[derive(Debug, thiserror::Error)]
+pub enum PdpError {
+    /// A ZMQ message that isn't a valid stage update.
+    #[error("{0:#}")]
+    ParseError(anyhow::Error),
+    /// The API couldn't be reached or failed with a server error, which may be transient.
+    #[error("API unreachable: {0:#}")]
+    ApiUnreachable(anyhow::Error),
+    /// The API answered with a client error or a malformed response.
+    #[error("API request failed: {0:#}")]
+    Api(anyhow::Error),
+    /// The API rejected the request's credentials with a 401 or 403.
+    #[error("API rejected the credentials with status {0}, check --api-token and --api-header")]
+    ApiUnauthorized(reqwest::StatusCode),
+    #[error("Proofset {0} not found")]
+    ProofsetNotFound(String),
+    #[error("Root {cid} not found in proofset {proofset_id}")]
+    RootNotFound { proofset_id: String, cid: String },
+    /// A request would have had to wait for `--api-rate-limit`, so it wasn't made.
+    #[error("API rate limit reached")]
+    RateLimited,
+    #[error("Serial port error: {0}")]
+    Serial(#[from] std::io::Error),
+}
+
+impl From<serialport::Error> for PdpError {
+    fn from(error: serialport::Error) -> Self {
+        PdpError::Serial(error.into())
+    }
+}
@@ -0,0 +1,310 @@
+//! Handling of the messages received on the ZMQ socket: stage updates, control commands and state
+//! dumps.
+
+use std::{path::Path, sync::Arc, time::Duration};
+
+use log::{debug, error, info};
+use parking_lot::Mutex;
+use tokio::sync::mpsc;
+use tracing::Instrument as _;
+
+use crate::control::ControlCommand;
+use crate::message::{parse_zmq_msg, process_message, RecentMessages, ZmqPayload};
+use crate::reload::SharedConfig;
+use crate::state::{dump_path, DumpSources, RecentEvent, RecentEventKind};
+use crate::status::StatusSender;
+use crate::CHANNEL_BUFFER_SIZE;
+
+/// Replies to each message received, applying stage updates to the tracked state.
+pub(crate) struct MessageHandler {
+    /// Tracked state, statuses and diagnostics, along with the startup configuration.
+    pub(crate) state: DumpSources,
+    pub(crate) recent_messages: Arc<Mutex<RecentMessages>>,
+    pub(crate) status_sender: StatusSender,
+    pub(crate) config: SharedConfig,
+    /// Queue of the task processing stage updates after they were ACKed, `None` with
+    /// `--ack-after-processing`.
+    inbound: Option<mpsc::Sender<(ZmqPayload, tracing::Span)>>,
+}
+
+impl MessageHandler {
+    /// Unless `--ack-after-processing` is passed, spawns the task processing stage updates in the
+    /// order they were received.
+    pub(crate) fn new(
+        state: DumpSources,
+        status_sender: StatusSender,
+        config: SharedConfig,
+    ) -> Self {
+        let recent_messages = Arc::new(Mutex::new(RecentMessages::new(Duration::from_secs(
+            state.cli.dedup_ttl_secs,
+        ))));
+        let inbound = (!state.cli.ack_after_processing).then(|| {
+            // Each message travels with its span, so statuses decided from it link back to it.
+            let (inbound_tx, mut inbound_rx) =
+                mpsc::channel::<(ZmqPayload, tracing::Span)>(CHANNEL_BUFFER_SIZE);
+            let current_state = state.current_state.clone();
+            let recent_messages = recent_messages.clone();
+            let status_sender = status_sender.clone();
+            let cli = state.cli.clone();
+            tokio::spawn(async move {
+                while let Some((payload, span)) = inbound_rx.recv().await {
+                    process_message(
+                        payload,
+                        &current_state,
+                        &recent_messages,
+                        &status_sender,
+                        &cli,
+                    )
+                    .instrument(span)
+                    .await;
+                }
+            });
+            inbound_tx
+        });
+        Self {
+            state,
+            recent_messages,
+            status_sender,
+            config,
+            inbound,
+        }
+    }
+
+    /// Handles a message and returns the reply to send. Stage updates are ACKed as soon as they're
+    /// received and processed by a separate task, so the socket is ready for the next message
+    /// without waiting on state updates.
+    pub(crate) async fn handle(&self, message: &str) -> String {
+        if let Some(name) = message.strip_prefix("dump-state ") {
+            let name = name.trim();
+            let dumped = match &self.state.cli.dump_dir {
+                Some(dir) => dump_path(Path::new(dir), name)
+                    .and_then(|path| self.state.dump(&path).map(|()| path)),
+                None => Err(anyhow::anyhow!(
+                    "dump-state is disabled, pass --dump-dir to enable it"
+                )),
+            };
+            return match dumped {
+                Ok(path) => {
+                    info!("State dumped to {}", path.display());
+                    format!("OK {}", path.display())
+                }
+                Err(e) => {
+                    error!("Failed to dump state: {:#}", e);
+                    format!("ERR {:#}", e)
+                }
+            };
+        }
+
+        // A message that isn't a stage update may be a control command. Anything else is NACKed
+        // with the reason instead of being processed.
+        let payload = match parse_zmq_msg(message) {
+            Ok(payload) => payload,
+            Err(e) => {
+                if let Ok(command) = serde_json::from_str::<ControlCommand>(message) {
+                    debug!("Received control command: {:?}", command);
+                    let reply = command
+                        .execute(
+                            &self.state.current_state,
+                            &self.state.statuses,
+                            &self.status_sender,
+                            &self.config,
+                        )
+                        .await;
+                    return serde_json::to_string(&reply).expect("Failed to serialize reply");
+                }
+                error!("{:#}", e);
+                return format!("NACK {:#}", e);
+            }
+        };
+
+        self.state.diagnostics.lock().record_event(RecentEvent {
+            file: Some(payload.data.file.clone()),
+            stage: Some(payload.stage.clone()),
+            ..RecentEvent::new(RecentEventKind::Message)
+        });
+
+        let span = tracing::info_span!(
+            "zmq_message",
+            file = %payload.data.file,
+            stage = ?payload.stage,
+        );
+        match &self.inbound {
+            Some(inbound) => {
+                if let Err(e) = inbound.send((payload, span)).await {
+                    error!("Failed to enqueue message for processing: {}", e);
+                }
+            }
+            None => {
+                process_message(
+                    payload,
+                    &self.state.current_state,
+                    &self.recent_messages,
+                    &self.status_sender,
+                    &self.state.cli,
+                )
+                .instrument(span)
+                .await;
+            }
+        }
+        "ACK".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::DisplayStatus;
+    use crate::test_util::{drain, handler, stage_message};
+
+    const FILE_ID: &str = "baga6ea4piece:bafkroot";
+
+    /// Holds the tracked state on another thread, stalling processing until the returned sender
+    /// is dropped.
+    fn stall_processing(handler: &MessageHandler) -> std::sync::mpsc::Sender<()> {
+        let current_state = handler.state.current_state.clone();
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        std::thread::spawn(move || {
+            let _state = current_state.lock();
+            locked_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        });
+        locked_rx.recv().unwrap();
+        release_tx
+    }
+
+    fn messages() -> Vec<String> {
+        ["a.jpg", "b.jpg", "c.jpg"]
+            .into_iter()
+            .flat_map(|file| {
+                let file_id = format!("baga6ea4{}:bafk{}", file, file);
+                [
+                    stage_message(file, &file_id, "Uploaded"),
+                    stage_message(file, &file_id, "RootsAdded"),
+                ]
+            })
+            .collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn acks_before_processing() {
+        let (handler, queue) = handler(&[]);
+        // Stalled processing doesn't hold up the ACKs.
+        let release = stall_processing(&handler);
+        for message in messages() {
+            let reply = tokio::time::timeout(Duration::from_secs(1), handler.handle(&message));
+            assert_eq!(reply.await.expect("ACK waited on processing"), "ACK");
+        }
+        drop(release);
+
+        let mut statuses = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while statuses.len() < 6 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                statuses.extend(drain(&queue));
+            }
+        })
+        .await
+        .expect("Messages weren't processed");
+        // Each file's updates are still processed in the order they were received.
+        for file in ["a.jpg", "b.jpg", "c.jpg"] {
+            let of_file: Vec<_> = statuses
+                .iter()
+                .filter(|(name, _)| name == file)
+                .map(|(_, status)| *status)
+                .collect();
+            assert_eq!(of_file, [DisplayStatus::Uploaded, DisplayStatus::Stored]);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn acks_after_processing_when_configured() {
+        let (handler, queue) = handler(&["--ack-after-processing"]);
+        let handler = Arc::new(handler);
+        let release = stall_processing(&handler);
+        let message = stage_message("a.jpg", FILE_ID, "Uploaded");
+        let reply = tokio::spawn({
+            let handler = handler.clone();
+            async move { handler.handle(&message).await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!reply.is_finished(), "ACKed before processing");
+        drop(release);
+        assert_eq!(reply.await.unwrap(), "ACK");
+        assert_eq!(
+            drain(&queue),
+            [("a.jpg".to_string(), DisplayStatus::Uploaded)]
+        );
+    }
+
+    #[tokio::test]
+    async fn nacks_invalid_messages() {
+        let (handler, queue) = handler(&[]);
+        assert!(handler.handle("not json").await.starts_with("NACK "));
+        let bad_id = stage_message("a.jpg", "no-colon", "Uploaded");
+        assert!(handler.handle(&bad_id).await.starts_with("NACK "));
+        assert_eq!(drain(&queue), []);
+    }
+
+    #[tokio::test]
+    async fn keeps_acking_with_a_full_status_queue() {
+        // Nothing reads the queue, as with a stalled serial writer.
+        let (handler, queue) = handler(&["--ack-after-processing", "--status-queue-size", "2"]);
+        for stage in ["Uploaded", "RootsAdded", "Uploaded", "RootsAdded"] {
+            for file in ["a.jpg", "b.jpg", "c.jpg"] {
+                let file_id = format!("baga6ea4{}:bafk{}{}", file, file, stage);
+                let message = stage_message(file, &file_id, stage);
+                let reply = tokio::time::timeout(Duration::from_secs(1), handler.handle(&message));
+                assert_eq!(reply.await.expect("ACK blocked on the queue"), "ACK");
+            }
+        }
+        // Each file keeps only its latest pending update once the queue is full.
+        assert_eq!(
+            drain(&queue),
+            ["a.jpg", "b.jpg", "c.jpg"].map(|file| (file.to_string(), DisplayStatus::Stored))
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_handling_after_garbage() {
+        let (handler, queue) = handler(&["--ack-after-processing"]);
+        for garbage in ["", "\u{0}\u{1}garbage", "{\"stage\":", "[1, 2, 3]"] {
+            assert!(handler.handle(garbage).await.starts_with("NACK "));
+        }
+        let message = stage_message("a.jpg", FILE_ID, "Uploaded");
+        assert_eq!(handler.handle(&message).await, "ACK");
+        assert_eq!(
+            drain(&queue),
+            [("a.jpg".to_string(), DisplayStatus::Uploaded)]
+        );
+    }
+
+    #[tokio::test]
+    async fn dump_state_is_refused_without_dump_dir() {
+        let (handler, _) = handler(&[]);
+        let reply = handler.handle("dump-state /tmp/dump.json").await;
+        assert!(reply.starts_with("ERR dump-state is disabled"), "{}", reply);
+    }
+
+    #[tokio::test]
+    async fn dump_state_writes_only_inside_dump_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let dumps = dir.path().join("dumps");
+        std::fs::create_dir(&dumps).unwrap();
+        let (handler, _) = handler(&["--dump-dir", dumps.to_str().unwrap()]);
+
+        let reply = handler.handle("dump-state bug.json").await;
+        let path = dumps.join("bug.json");
+        assert_eq!(reply, format!("OK {}", path.display()));
+        let dump: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert!(dump["config"].is_object());
+
+        let outside = dir.path().join("outside.json");
+        for name in ["../outside.json", outside.to_str().unwrap()] {
+            let reply = handler.handle(&format!("dump-state {}", name)).await;
+            assert!(reply.starts_with("ERR "), "{}", reply);
+        }
+        assert!(!outside.exists());
+    }
+}
@@ -0,0 +1,263 @@
+//! HTTP listener of `--metrics-listen`, serving the Prometheus metrics on `/metrics` and the recent
+//! events on `/events`, and reloading the `--config` file on `POST /reload`.
+
+use std::{convert::Infallible, sync::Arc, time::Duration};
+
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::{header, Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use log::{debug, error};
+use metrics_exporter_prometheus::PrometheusHandle;
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::net::TcpListener;
+
+use crate::reload::{reload_with_token, ReloadError, SharedConfig};
+use crate::state::Diagnostics;
+
+// Delay before accepting connections again after accepting one failed, e.g. out of file descriptors.
+pub(crate) const HTTP_ACCEPT_RETRY_DELAY_MS: u64 = 100;
+// Header carrying the `--reload-token` of `POST /reload`.
+pub(crate) const RELOAD_TOKEN_HEADER: &str = "x-reload-token";
+// Content type of the Prometheus text exposition format.
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// What the HTTP listener serves.
+#[derive(Clone)]
+pub(crate) struct HttpState {
+    pub(crate) metrics: PrometheusHandle,
+    pub(crate) diagnostics: Arc<Mutex<Diagnostics>>,
+    pub(crate) config: SharedConfig,
+}
+
+/// Serves HTTP/1 requests on `listener`, each connection in its own task.
+pub(crate) async fn serve_http(listener: TcpListener, state: HttpState) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Failed to accept HTTP connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(HTTP_ACCEPT_RETRY_DELAY_MS)).await;
+                continue;
+            }
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            let service = hyper::service::service_fn(|request| {
+                let state = state.clone();
+                async move { Ok::<_, Infallible>(handle(request, &state).await) }
+            });
+            if let Err(e) = hyper::server::conn::http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                debug!("HTTP connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle(request: Request<Incoming>, state: &HttpState) -> Response<Full<Bytes>> {
+    match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => {
+            response(StatusCode::OK, METRICS_CONTENT_TYPE, state.metrics.render())
+        }
+        (&Method::GET, "/events") => {
+            let events: Vec<_> = state
+                .diagnostics
+                .lock()
+                .recent_events
+                .iter()
+                .cloned()
+                .collect();
+            json(StatusCode::OK, &events)
+        }
+        (&Method::POST, "/reload") => {
+            let token = request
+                .headers()
+                .get(RELOAD_TOKEN_HEADER)
+                .and_then(|token| token.to_str().ok());
+            match reload_with_token(&state.config, token) {
+                Ok(summary) => json(StatusCode::OK, &summary),
+                Err(e) => {
+                    let status = match e {
+                        ReloadError::InvalidToken => StatusCode::UNAUTHORIZED,
+                        ReloadError::NoConfigFile | ReloadError::InvalidConfig(_) => {
+                            StatusCode::BAD_REQUEST
+                        }
+                    };
+                    json(status, &serde_json::json!({ "error": e.to_string() }))
+                }
+            }
+        }
+        (_, "/metrics" | "/events" | "/reload") => response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "text/plain",
+            "Method not allowed\n".to_string(),
+        ),
+        _ => response(
+            StatusCode::NOT_FOUND,
+            "text/plain",
+            "Not found\n".to_string(),
+        ),
+    }
+}
+
+fn response(status: StatusCode, content_type: &str, body: String) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(Full::new(Bytes::from(body)))
+        .expect("Invalid HTTP response")
+}
+
+fn json(status: StatusCode, body: &impl Serialize) -> Response<Full<Bytes>> {
+    let body = serde_json::to_string(body).expect("Failed to serialize response");
+    response(status, "application/json", body)
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics_exporter_prometheus::PrometheusBuilder;
+
+    use parking_lot::RwLock;
+
+    use super::*;
+    use crate::cli::Cli;
+    use crate::state::{RecentEvent, RecentEventKind};
+    use crate::test_util::cli;
+
+    /// Serves `diagnostics`, `config` and metrics recorded into the returned recorder on a free
+    /// port, returning the listener's base URL.
+    async fn start_with_config(
+        diagnostics: Diagnostics,
+        config: Cli,
+    ) -> (String, metrics_exporter_prometheus::PrometheusRecorder) {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(serve_http(
+            listener,
+            HttpState {
+                metrics: recorder.handle(),
+                diagnostics: Arc::new(Mutex::new(diagnostics)),
+                config: Arc::new(RwLock::new(config)),
+            },
+        ));
+        (url, recorder)
+    }
+
+    async fn start(
+        diagnostics: Diagnostics,
+    ) -> (String, metrics_exporter_prometheus::PrometheusRecorder) {
+        start_with_config(diagnostics, cli(&[])).await
+    }
+
+    #[tokio::test]
+    async fn serves_the_recent_events() {
+        let mut diagnostics = Diagnostics::new(2);
+        for file in ["a.jpg", "b.jpg", "c.jpg"] {
+            diagnostics.record_event(RecentEvent {
+                file: Some(file.to_string()),
+                ..RecentEvent::new(RecentEventKind::Message)
+            });
+        }
+        let (url, _) = start(diagnostics).await;
+        let response = reqwest::get(format!("{}/events", url)).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let events: Vec<serde_json::Value> = response.json().await.unwrap();
+        // Only the last `--recent-events-len` are kept, oldest first.
+        let files: Vec<_> = events.iter().map(|event| &event["file"]).collect();
+        assert_eq!(files, ["b.jpg", "c.jpg"]);
+        assert!(events.iter().all(|event| event["kind"] == "message"));
+        assert!(events.iter().all(|event| event["at"].is_u64()));
+    }
+
+    #[tokio::test]
+    async fn serves_the_metrics() {
+        let (url, recorder) = start(Diagnostics::new(16)).await;
+        metrics::with_local_recorder(&recorder, || {
+            metrics::counter!("zmq_messages_received_total").increment(3)
+        });
+        let response = reqwest::get(format!("{}/metrics", url)).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let body = response.text().await.unwrap();
+        assert!(body.contains("zmq_messages_received_total 3"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn rejects_other_requests() {
+        let (url, _) = start(Diagnostics::new(16)).await;
+        let client = reqwest::Client::new();
+        let status = |request: reqwest::RequestBuilder| async move {
+            request.send().await.unwrap().status()
+        };
+        assert_eq!(
+            status(client.get(format!("{}/state", url))).await,
+            reqwest::StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status(client.post(format!("{}/events", url))).await,
+            reqwest::StatusCode::METHOD_NOT_ALLOWED
+        );
+    }
+
+    #[tokio::test]
+    async fn reloads_a_valid_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        std::fs::write(&path, r#"{"display-width": 16}"#).unwrap();
+        let config = cli(&[
+            "--config",
+            path.to_str().unwrap(),
+            "--reload-token",
+            "secret",
+        ])
+        .with_config_file()
+        .unwrap();
+        let (url, _) = start_with_config(Diagnostics::new(16), config).await;
+        let client = reqwest::Client::new();
+        let reload = |token: &'static str| {
+            client
+                .post(format!("{}/reload", url))
+                .header(RELOAD_TOKEN_HEADER, token)
+                .send()
+        };
+
+        let response = reload("wrong").await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        std::fs::write(&path, r#"{"display-width": 20, "baud": 115200}"#).unwrap();
+        let response = reload("secret").await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let summary: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(summary["changed"], serde_json::json!(["display_width"]));
+        assert_eq!(summary["requires_restart"], serde_json::json!(["baud"]));
+    }
+
+    #[tokio::test]
+    async fn keeps_the_config_when_the_new_one_is_invalid() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        std::fs::write(&path, r#"{"display-width": 16}"#).unwrap();
+        let config = cli(&["--config", path.to_str().unwrap()])
+            .with_config_file()
+            .unwrap();
+        let (url, _) = start_with_config(Diagnostics::new(16), config).await;
+        let client = reqwest::Client::new();
+
+        std::fs::write(&path, r#"{"display-width": 20, "baud": "fast"}"#).unwrap();
+        let response = client.post(format!("{}/reload", url)).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = response.json().await.unwrap();
+        let error = body["error"].as_str().unwrap();
+        assert!(error.contains("baud"), "{}", error);
+
+        // Nothing was applied, so the valid part of the file shows up in the next reload.
+        std::fs::write(&path, r#"{"display-width": 20}"#).unwrap();
+        let response = client.post(format!("{}/reload", url)).send().await.unwrap();
+        let summary: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(summary["changed"], serde_json::json!(["display_width"]));
+    }
+}
@@ -0,0 +1,118 @@
+//! In-process transport of `inproc://` endpoints, which the ZMQ implementation lacks. A bound
+//! endpoint is a channel that clients in the same process send their requests over, each answered
+//! on its own reply channel, as a REP or ROUTER socket would.
+
+use std::{collections::HashMap, sync::LazyLock};
+
+use anyhow::Context as _;
+use parking_lot::Mutex;
+use tokio::sync::{mpsc, oneshot};
+use zeromq::{ZmqError, ZmqMessage, ZmqResult};
+
+use crate::CHANNEL_BUFFER_SIZE;
+
+/// A request and where to send its reply.
+type Request = (ZmqMessage, oneshot::Sender<ZmqMessage>);
+
+/// Bound endpoints, by their `inproc://` address.
+static ENDPOINTS: LazyLock<Mutex<HashMap<String, mpsc::Sender<Request>>>> =
+    LazyLock::new(Default::default);
+
+/// Whether `endpoint` is an `inproc://` endpoint.
+pub(crate) fn is_inproc(endpoint: &str) -> bool {
+    endpoint.starts_with("inproc://")
+}
+
+/// Fails for an `inproc://` `endpoint` used as `what`, which only REP and ROUTER sockets can be
+/// bound to.
+pub(crate) fn reject_inproc(endpoint: &str, what: &str) -> anyhow::Result<()> {
+    if is_inproc(endpoint) {
+        anyhow::bail!(
+            "{} {} can't be an inproc:// endpoint, only --zmq-bind in rep or router mode can",
+            what,
+            endpoint
+        );
+    }
+    Ok(())
+}
+
+/// A bound `inproc://` endpoint, unbound when dropped.
+pub(crate) struct InprocSocket {
+    endpoint: String,
+    requests: mpsc::Receiver<Request>,
+    /// Reply channel of the last received request.
+    pending: Option<oneshot::Sender<ZmqMessage>>,
+}
+
+impl InprocSocket {
+    pub(crate) fn bind(endpoint: &str) -> ZmqResult<Self> {
+        let mut endpoints = ENDPOINTS.lock();
+        if endpoints.contains_key(endpoint) {
+            return Err(ZmqError::Socket("inproc:// endpoint already bound"));
+        }
+        let (requests_tx, requests) = mpsc::channel(CHANNEL_BUFFER_SIZE);
+        endpoints.insert(endpoint.to_string(), requests_tx);
+        Ok(Self {
+            endpoint: endpoint.to_string(),
+            requests,
+            pending: None,
+        })
+    }
+
+    pub(crate) async fn recv(&mut self) -> ZmqResult<ZmqMessage> {
+        let (message, reply) = self.requests.recv().await.ok_or(ZmqError::NoMessage)?;
+        self.pending = Some(reply);
+        Ok(message)
+    }
+
+    /// Answers the last received request, if its client is still waiting.
+    pub(crate) fn reply(&mut self, reply: ZmqMessage) -> ZmqResult<()> {
+        match self.pending.take() {
+            Some(client) => client
+                .send(reply)
+                .map_err(|_| ZmqError::Other("inproc:// client went away")),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for InprocSocket {
+    fn drop(&mut self) {
+        ENDPOINTS.lock().remove(&self.endpoint);
+    }
+}
+
+/// Client of a service running in the same process with an `inproc://` `--zmq-bind`, e.g. when
+/// embedding [`crate::run`].
+pub struct InprocClient {
+    endpoint: String,
+    requests: mpsc::Sender<Request>,
+}
+
+impl InprocClient {
+    /// Connects to the bound `inproc://` `endpoint`.
+    pub fn connect(endpoint: &str) -> anyhow::Result<Self> {
+        let requests = ENDPOINTS
+            .lock()
+            .get(endpoint)
+            .cloned()
+            .with_context(|| format!("Nothing is bound to {}", endpoint))?;
+        Ok(Self {
+            endpoint: endpoint.to_string(),
+            requests,
+        })
+    }
+
+    /// Sends `message` and returns its reply.
+    pub async fn request(&self, message: &str) -> anyhow::Result<String> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.requests
+            .send((message.to_string().into(), reply_tx))
+            .await
+            .map_err(|_| anyhow::anyhow!("{} was unbound", self.endpoint))?;
+        let reply = reply_rx
+            .await
+            .with_context(|| format!("{} didn't reply", self.endpoint))?;
+        String::try_from(reply).map_err(|e| anyhow::anyhow!("Failed to decode reply: {}", e))
+    }
+}
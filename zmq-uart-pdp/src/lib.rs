@@ -0,0 +1,695 @@
+//! Relays PDP upload and proof statuses received over ZMQ and from the PDP Explorer API to an
+//! Arduino display.
+
+pub mod api;
+pub mod cli;
+mod control;
+mod doctor;
+mod error;
+mod handler;
+mod http_server;
+mod inproc;
+mod logging;
+pub mod message;
+mod poller;
+mod reload;
+mod replay;
+pub mod serial;
+mod simulate;
+mod state;
+pub mod status;
+mod status_log;
+mod status_socket;
+mod stdout_display;
+mod telemetry;
+#[cfg(test)]
+mod test_util;
+mod watch_file;
+mod zmq_socket;
+
+pub use api::{
+    best_status, classify_roots, epoch_to_datetime, last_proven_at, root_statuses, select_roots,
+    ChainClock, ClassifyOptions, ProofSet, ProofSetRoot, ProofSetRoots, ProofStatusProvider,
+};
+pub use cli::{Cli, Command};
+use cli::{SerialRoute, ZmqMode};
+pub use error::PdpError;
+pub use inproc::InprocClient;
+pub use message::{parse_file_id, parse_zmq_msg, FileData, Stage, StageChange, ZmqPayload};
+pub use status::{DisplayStatus, RootStatus, StatusEvent};
+
+use std::{
+    future::Future,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use log::{debug, error, info, warn};
+use metrics::{counter, gauge};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder};
+use parking_lot::{Mutex, RwLock};
+use reqwest::Client;
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::sync::CancellationToken;
+use zeromq::Socket as _;
+
+use api::{preflight_api, validate_api_base_url, HttpProofStatusProvider};
+pub use doctor::doctor;
+use handler::MessageHandler;
+use http_server::{serve_http, HttpState};
+use inproc::reject_inproc;
+pub use logging::init_logging;
+use poller::poll_api;
+#[cfg(unix)]
+use reload::{reload_on_sighup, reload_watch_file_on_sighup};
+pub use replay::replay;
+use serial::{spawn_serial_reader, SerialOutput, SerialWriter};
+use simulate::simulate;
+use state::{Diagnostics, DumpSources, PersistedState};
+use status::{watch_slo, StatusQueue, StatusSender, STATUS_TOPIC};
+use status_log::StatusLog;
+#[cfg(unix)]
+use status_socket::run_status_socket;
+use status_socket::STATUS_SOCKET_READER_BUFFER;
+use telemetry::{init_tracing, shutdown_tracing};
+use watch_file::WatchFile;
+pub use zmq_socket::send_test;
+
+use zmq_socket::{is_broken, remove_ipc_socket, validate_zmq_endpoint, ZmqSocket};
+
+pub(crate) const CHANNEL_BUFFER_SIZE: usize = 32;
+pub(crate) const STARTUP_RETRY_INTERVAL_SECS: u64 = 2;
+// Histogram buckets of the `api_request_duration_seconds` metric.
+const API_LATENCY_BUCKETS_SECS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+// How often histograms recorded for `--metrics-listen` are drained into the rendered metrics.
+const METRICS_UPKEEP_INTERVAL_SECS: u64 = 5;
+
+/// Calls `open` until it succeeds, retrying every `STARTUP_RETRY_INTERVAL_SECS` for up to
+/// `timeout`, e.g. while the Arduino's USB device is still enumerating after a reboot.
+async fn retry_startup<T, E: std::fmt::Display, F: Future<Output = Result<T, E>>>(
+    what: &str,
+    timeout: Duration,
+    mut open: impl FnMut() -> F,
+) -> Result<T, E> {
+    let deadline = Instant::now() + timeout;
+    let mut attempt = 1;
+    loop {
+        match open().await {
+            Ok(value) => return Ok(value),
+            Err(e) if Instant::now() < deadline => {
+                warn!(
+                    "Failed to open {} (attempt {}), retrying in {}s: {}",
+                    what, attempt, STARTUP_RETRY_INTERVAL_SECS, e
+                );
+                tokio::time::sleep(Duration::from_secs(STARTUP_RETRY_INTERVAL_SECS)).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Resolves once the process is asked to stop with Ctrl-C or SIGTERM.
+#[cfg(unix)]
+async fn shutdown_signal() {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("Failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+/// Resolves once the process is asked to stop with Ctrl-C.
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Answers the messages received on `socket` with `handler` until `shutdown` completes. Receive
+/// errors are logged, reopening the socket if it broke, and messages that aren't UTF-8 are NACKed.
+async fn serve_messages(
+    mut socket: ZmqSocket,
+    handler: &MessageHandler,
+    cli: &Cli,
+    shutdown: impl std::future::Future<Output = ()>,
+) {
+    tokio::pin!(shutdown);
+    loop {
+        let message = tokio::select! {
+            message = socket.recv() => message,
+            _ = &mut shutdown => break,
+        };
+        let message = match message {
+            Ok(message) => message,
+            Err(e) => {
+                counter!("zmq_receive_errors_total").increment(1);
+                if is_broken(&e) {
+                    error!("ZMQ socket broken, reopening it: {}", e);
+                    drop(socket);
+                    socket = tokio::select! {
+                        socket = ZmqSocket::reopen(cli) => socket,
+                        _ = &mut shutdown => break,
+                    };
+                } else {
+                    error!("Failed to receive ZMQ message: {}", e);
+                }
+                continue;
+            }
+        };
+        handler.state.diagnostics.lock().messages_received += 1;
+        counter!("zmq_messages_received_total").increment(1);
+        let repl: String = match message.try_into() {
+            Ok(repl) => repl,
+            Err(e) => {
+                error!("Failed to decode message: {}", e);
+                socket.reply(format!("NACK {}", e)).await;
+                continue;
+            }
+        };
+
+        let reply = handler.handle(&repl).await;
+        socket.reply(reply).await;
+    }
+}
+
+fn build_http_client(cli: &Cli) -> anyhow::Result<Client> {
+    let mut headers = reqwest::header::HeaderMap::new();
+    for header in &cli.api_header {
+        let (name, value) = header
+            .split_once(':')
+            .with_context(|| format!("Invalid API header {:?}", header))?;
+        let mut value = reqwest::header::HeaderValue::from_str(value.trim())?;
+        value.set_sensitive(true);
+        headers.insert(
+            reqwest::header::HeaderName::from_bytes(name.trim().as_bytes())?,
+            value,
+        );
+    }
+    if let Some(token) = &cli.api_token {
+        let mut value = reqwest::header::HeaderValue::from_str(&format!("Bearer {}", token))
+            .context("Invalid API token")?;
+        value.set_sensitive(true);
+        headers.insert(reqwest::header::AUTHORIZATION, value);
+    }
+    let pool_idle_timeout = Duration::from_secs(cli.api_pool_idle_timeout_secs);
+    let tcp_keepalive = Some(Duration::from_secs(cli.api_tcp_keepalive_secs))
+        .filter(|keepalive| !keepalive.is_zero());
+    debug!(
+        "HTTP client keeps up to {} idle connections per host for {:?}, TCP keep-alive {:?}",
+        cli.api_pool_max_idle_per_host, pool_idle_timeout, tcp_keepalive
+    );
+    let client = Client::builder()
+        .default_headers(headers)
+        .timeout(Duration::from_secs(cli.api_timeout_secs))
+        .connect_timeout(Duration::from_secs(cli.api_connect_timeout_secs))
+        .pool_max_idle_per_host(cli.api_pool_max_idle_per_host)
+        .pool_idle_timeout(pool_idle_timeout)
+        .tcp_keepalive(tcp_keepalive)
+        .build()?;
+    Ok(client)
+}
+
+/// Fetches the roots of a proofset once and classifies the root `cid`. Fails with a [`PdpError`]
+/// if the proofset or root doesn't exist or the API can't be queried.
+pub async fn check_once(cli: &Cli, proofset_id: &str, cid: &str) -> anyhow::Result<DisplayStatus> {
+    for url in &cli.api_url {
+        validate_api_base_url(url)?;
+    }
+    let provider = HttpProofStatusProvider::new(build_http_client(cli)?, cli);
+    let roots = provider
+        .fetch_roots_matching(proofset_id, &[cid.to_string()])
+        .await?;
+    let status = classify_roots(
+        &roots.data,
+        cid,
+        cli.degraded_fault_ratio,
+        cli.root_selection,
+        ClassifyOptions::from_cli(cli),
+    )
+    .ok_or_else(|| PdpError::RootNotFound {
+        proofset_id: proofset_id.to_string(),
+        cid: cid.to_string(),
+    })?;
+    Ok(status)
+}
+
+/// Runs the service until the process is stopped with Ctrl-C or SIGTERM: relays ZMQ messages and
+/// API statuses to the Arduino.
+pub async fn run(mut cli: Cli) {
+    cli.log_setting_sources();
+    let tracer_provider = cli.otlp_endpoint.as_deref().map(|endpoint| {
+        let provider = init_tracing(endpoint).expect("Failed to set up tracing");
+        info!("Exporting spans to {}", endpoint);
+        provider
+    });
+    if cli.simulate && cli.state_file.take().is_some() {
+        warn!("Ignoring --state-file, simulated statuses aren't persisted");
+    }
+    let api_base_urls: Vec<_> = cli
+        .api_url
+        .iter()
+        .map(|url| validate_api_base_url(url).expect("Invalid API base URL"))
+        .collect();
+    validate_zmq_endpoint(&cli.zmq_bind).expect("Invalid ZMQ endpoint");
+    if cli.zmq_mode == ZmqMode::Sub {
+        reject_inproc(&cli.zmq_bind, "ZMQ publisher").expect("Invalid ZMQ endpoint");
+    }
+    if let Some(endpoint) = &cli.status_pub_bind {
+        validate_zmq_endpoint(endpoint).expect("Invalid status PUB endpoint");
+        reject_inproc(endpoint, "Status PUB endpoint").expect("Invalid status PUB endpoint");
+    }
+    #[cfg(not(unix))]
+    if let Some(path) = &cli.status_socket {
+        panic!(
+            "--status-socket {} needs Unix domain sockets, which this platform doesn't have",
+            path
+        );
+    }
+    let http_client = build_http_client(&cli).expect("Failed to build HTTP client");
+    let status_log = cli.status_log.as_deref().map(|path| {
+        StatusLog::open(
+            Path::new(path),
+            cli.status_log_max_bytes,
+            cli.status_log_keep,
+        )
+        .unwrap_or_else(|e| panic!("Failed to open status log {}: {}", path, e))
+    });
+    // Installed first thing so metrics are recorded from the start, served once the listener is up.
+    let metrics = cli.metrics_listen.map(|_| {
+        let metrics = PrometheusBuilder::new()
+            .set_buckets_for_metric(
+                Matcher::Full("api_request_duration_seconds".to_string()),
+                API_LATENCY_BUCKETS_SECS,
+            )
+            .expect("Invalid API latency buckets")
+            .install_recorder()
+            .expect("Failed to install metrics recorder");
+        let upkept = metrics.clone();
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(METRICS_UPKEEP_INTERVAL_SECS));
+            loop {
+                interval.tick().await;
+                upkept.run_upkeep();
+            }
+        });
+        metrics
+    });
+
+    if cli.skip_api_preflight || cli.simulate {
+        debug!("Skipping API preflight");
+    } else {
+        for url in &api_base_urls {
+            preflight_api(&http_client, url).await;
+        }
+    }
+
+    let startup_timeout = Duration::from_secs(cli.startup_timeout_secs);
+    // Every serial port with its route, `--serial-port` first.
+    let serial_ports: Vec<(String, SerialRoute)> =
+        std::iter::once((cli.serial_port.clone(), cli.serial_route))
+            .chain(
+                cli.extra_serial_port
+                    .iter()
+                    .map(|port| (port.path.clone(), port.route)),
+            )
+            .collect();
+    let serials = if cli.non_blocking_startup {
+        None
+    } else {
+        let mut serials = Vec::with_capacity(serial_ports.len());
+        for (path, _) in &serial_ports {
+            let serial = retry_startup("serial port", startup_timeout, || async {
+                SerialOutput::open(&cli, path)
+            })
+            .await
+            .unwrap_or_else(|e| {
+                panic!(
+                    "Failed to open serial port {} within {}s: {}",
+                    path, cli.startup_timeout_secs, e
+                )
+            });
+            serials.push(serial);
+        }
+        Some(serials)
+    };
+
+    let restored = cli
+        .state_file
+        .as_deref()
+        .map(PersistedState::load_or_default)
+        .unwrap_or_default();
+    let restored_statuses: Vec<_> = restored.statuses.values().cloned().collect();
+    gauge!("tracked_files").set(restored.tracked.len() as f64);
+    let current_state = Arc::new(Mutex::new(restored.tracked));
+    let status_queues: Vec<_> = serial_ports
+        .iter()
+        .map(|_| Arc::new(StatusQueue::new(cli.status_queue_size)))
+        .collect();
+    let publisher = match &cli.status_pub_bind {
+        Some(endpoint) => {
+            zmq_socket::remove_stale_ipc_socket(endpoint);
+            let mut socket = zeromq::PubSocket::new();
+            socket
+                .bind(endpoint)
+                .await
+                .expect("Failed to bind status PUB socket");
+            info!("Publishing statuses on {}", endpoint);
+            Some(socket)
+        }
+        None => None,
+    };
+    let mut status_sender = StatusSender::new(status_queues.clone(), publisher, status_log);
+    if !cli.topic_prefix.is_empty() {
+        status_sender.publish_topic = Some(format!("{}{}", cli.topic_prefix, STATUS_TOPIC));
+    }
+    let statuses = Arc::new(Mutex::new(restored.statuses));
+    let (events_tx, _) = broadcast::channel(STATUS_SOCKET_READER_BUFFER);
+    let diagnostics = Arc::new(Mutex::new(Diagnostics::new(cli.recent_events_len as usize)));
+    let config = Arc::new(RwLock::new(cli.clone()));
+    if let (Some(addr), Some(metrics)) = (cli.metrics_listen, metrics) {
+        let listener = tokio::net::TcpListener::bind(addr)
+            .await
+            .unwrap_or_else(|e| panic!("Failed to listen on {}: {}", addr, e));
+        info!(
+            "Serving metrics on http://{}/metrics and recent events on http://{}/events",
+            addr, addr
+        );
+        tokio::spawn(serve_http(
+            listener,
+            HttpState {
+                metrics,
+                diagnostics: diagnostics.clone(),
+                config: config.clone(),
+            },
+        ));
+    }
+    let dump_sources = DumpSources {
+        cli: cli.clone(),
+        current_state: current_state.clone(),
+        statuses: statuses.clone(),
+        last_sent: status_sender.last_sent.clone(),
+        diagnostics: diagnostics.clone(),
+    };
+    if let Some(slo_secs) = cli.slo_secs {
+        tokio::spawn(watch_slo(
+            status_sender.last_sent.clone(),
+            Duration::from_secs(slo_secs),
+        ));
+    }
+
+    if let Some(path) = cli.panic_dump_path.clone() {
+        let dump_sources = dump_sources.clone();
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            default_hook(info);
+            match dump_sources.dump(Path::new(&path)) {
+                Ok(()) => eprintln!("State dumped to {}", path),
+                Err(e) => eprintln!("Failed to dump state: {:#}", e),
+            }
+        }));
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = cli.status_socket.clone() {
+        let statuses = statuses.clone();
+        let events_tx = events_tx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = run_status_socket(&path, statuses, events_tx).await {
+                error!("Status socket failed: {:#}", e);
+            }
+        });
+    }
+
+    #[cfg(unix)]
+    if cli.config.is_some() {
+        tokio::spawn(reload_on_sighup(config.clone()));
+    }
+    // The restored statuses put the display in a known state instead of the init line.
+    let restores_statuses = !restored_statuses.is_empty();
+    // Spawns the writer task of the serial port at `index` of `serial_ports`
+    let start_serial_writer = {
+        let cli = cli.clone();
+        let config = config.clone();
+        let current_state = current_state.clone();
+        let diagnostics = diagnostics.clone();
+        let serial_ports = serial_ports.clone();
+        let status_queues = status_queues.clone();
+        move |index: usize, serial: SerialOutput| {
+            let mut ack_timeout = cli.ack_timeout_ms.map(Duration::from_millis);
+            if ack_timeout.is_some() && serial.port().is_none() {
+                warn!("Ignoring --ack-timeout-ms as there is no serial port to echo lines");
+                ack_timeout = None;
+            }
+            if cli.watchdog && ack_timeout.is_none() {
+                warn!(
+                    "Ignoring --watchdog as missed echoes can't be detected without --ack-timeout-ms"
+                );
+            }
+            let self_test = cli.serial_self_test && serial.port().is_some();
+            if cli.serial_self_test && !self_test {
+                warn!("Ignoring --serial-self-test as there is no serial port to echo lines");
+            }
+            let (ack_tx, ack_rx) = mpsc::channel(CHANNEL_BUFFER_SIZE);
+            if let Some(port) = serial.port().filter(|_| ack_timeout.is_some() || self_test) {
+                spawn_serial_reader(port.reader(), ack_tx);
+            }
+            let mut serial_writer = SerialWriter::new(
+                serial,
+                config.clone(),
+                statuses.clone(),
+                current_state.clone(),
+                diagnostics.clone(),
+                events_tx.clone(),
+                ack_timeout,
+            );
+            if restores_statuses {
+                serial_writer.init_line = None;
+            }
+            serial_writer.self_test = self_test.then_some(cli.on_self_test_failure);
+            serial_writer.route = serial_ports[index].1;
+            // Every writer gets every event, so one of them is enough to keep the shared state.
+            serial_writer.mirrors_events = index == 0;
+            serial_writer.spawn(status_queues[index].clone(), ack_rx);
+        }
+    };
+    match serials {
+        Some(serials) => {
+            for (index, serial) in serials.into_iter().enumerate() {
+                start_serial_writer(index, serial);
+            }
+        }
+        None => {
+            for (index, (path, _)) in serial_ports.iter().enumerate() {
+                let cli = cli.clone();
+                let path = path.clone();
+                let status_queue = status_queues[index].clone();
+                let start_serial_writer = start_serial_writer.clone();
+                tokio::task::spawn_blocking(move || {
+                    let mut attempt = 1;
+                    let serial = loop {
+                        match SerialOutput::open(&cli, &path) {
+                            Ok(serial) => break serial,
+                            Err(e) => {
+                                warn!(
+                                    "Failed to open serial port {} (attempt {}), retrying in {}s: {}",
+                                    path, attempt, STARTUP_RETRY_INTERVAL_SECS, e
+                                );
+                                std::thread::sleep(Duration::from_secs(
+                                    STARTUP_RETRY_INTERVAL_SECS,
+                                ));
+                                attempt += 1;
+                            }
+                        }
+                    };
+                    info!(
+                        "Serial port {} ready, sending the latest queued statuses",
+                        path
+                    );
+                    status_queue.coalesce();
+                    start_serial_writer(index, serial);
+                });
+            }
+        }
+    }
+
+    if !restored_statuses.is_empty() {
+        info!("Restoring {} statuses", restored_statuses.len());
+    }
+    for event in restored_statuses {
+        status_sender.send(event).await;
+    }
+
+    if cli.simulate {
+        tokio::select! {
+            _ = simulate(&status_sender, Duration::from_secs(cli.simulate_interval_secs), cli.state_key) => {}
+            _ = shutdown_signal() => {}
+        }
+        info!("Shutting down");
+        if let Some(provider) = &tracer_provider {
+            shutdown_tracing(provider);
+        }
+        return;
+    }
+
+    let socket = retry_startup("ZMQ socket", startup_timeout, || ZmqSocket::open(&cli))
+        .await
+        .unwrap_or_else(|e| {
+            panic!(
+                "Failed to open ZMQ socket on {} within {}s: {}",
+                cli.zmq_bind, cli.startup_timeout_secs, e
+            )
+        });
+
+    if let Some(path) = cli.watch_file.clone() {
+        let mut watch_file = WatchFile::new(path, cli.state_key);
+        watch_file.sync(&current_state, &status_sender).await;
+        #[cfg(unix)]
+        tokio::spawn(reload_watch_file_on_sighup(
+            watch_file,
+            current_state.clone(),
+            status_sender.clone(),
+        ));
+    }
+
+    // Spawn API checking task, stopped along with the message loop.
+    let poll_shutdown = CancellationToken::new();
+    let polling = tokio::spawn(poll_api(
+        HttpProofStatusProvider::new(http_client, &cli),
+        current_state.clone(),
+        status_sender.clone(),
+        config.clone(),
+        diagnostics.clone(),
+        poll_shutdown.clone(),
+    ));
+
+    let handler = MessageHandler::new(dump_sources, status_sender, config);
+    serve_messages(socket, &handler, &cli, shutdown_signal()).await;
+
+    info!("Shutting down");
+    poll_shutdown.cancel();
+    if let Err(e) = polling.await {
+        error!("API checking task failed: {}", e);
+    }
+    if cli.zmq_mode != ZmqMode::Sub {
+        remove_ipc_socket(&cli.zmq_bind);
+    }
+    if let Some(endpoint) = &cli.status_pub_bind {
+        remove_ipc_socket(endpoint);
+    }
+    if let Some(provider) = &tracer_provider {
+        shutdown_tracing(provider);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use zeromq::{Socket as _, SocketRecv as _, SocketSend as _, ZmqMessage};
+
+    use super::*;
+    use crate::status::DisplayStatus;
+    use crate::test_util::{cli, drain, handler, stage_message};
+
+    async fn request(client: &mut zeromq::ReqSocket, message: ZmqMessage) -> String {
+        client.send(message).await.unwrap();
+        String::try_from(client.recv().await.unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn serving_survives_non_utf8_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        let endpoint = format!("ipc://{}", dir.path().join("pdp.sock").display());
+        let args = ["--zmq-bind", &endpoint, "--ack-after-processing"];
+        let (cli, (handler, queue)) = (cli(&args), handler(&args));
+        let socket = ZmqSocket::open(&cli).await.unwrap();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let shutdown = async {
+            let _ = shutdown_rx.await;
+        };
+
+        let client = async {
+            let mut client = zeromq::ReqSocket::new();
+            client.connect(&endpoint).await.unwrap();
+            let binary = vec![0xff, 0xfe, 0x00, 0x80];
+            let reply = request(&mut client, binary.into()).await;
+            assert!(reply.starts_with("NACK "), "{}", reply);
+            let message = stage_message("a.jpg", "baga6ea4piece:bafkroot", "Uploaded");
+            assert_eq!(request(&mut client, message.into()).await, "ACK");
+            shutdown_tx.send(()).unwrap();
+        };
+        let served = serve_messages(socket, &handler, &cli, shutdown);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::join!(served, client)
+        })
+        .await
+        .expect("Serving stopped answering");
+
+        assert_eq!(handler.state.diagnostics.lock().messages_received, 2);
+        assert_eq!(
+            drain(&queue),
+            [("a.jpg".to_string(), DisplayStatus::Uploaded)]
+        );
+    }
+
+    #[tokio::test]
+    async fn router_answers_interleaved_clients() {
+        let dir = tempfile::tempdir().unwrap();
+        let endpoint = format!("ipc://{}", dir.path().join("pdp.sock").display());
+        let args = [
+            "--zmq-mode",
+            "router",
+            "--zmq-bind",
+            &endpoint,
+            "--ack-after-processing",
+        ];
+        let (cli, (handler, queue)) = (cli(&args), handler(&args));
+        let socket = ZmqSocket::open(&cli).await.unwrap();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let shutdown = async {
+            let _ = shutdown_rx.await;
+        };
+
+        let clients = async {
+            let mut first = zeromq::ReqSocket::new();
+            first.connect(&endpoint).await.unwrap();
+            let mut second = zeromq::ReqSocket::new();
+            second.connect(&endpoint).await.unwrap();
+            // Both clients have a request in flight before either is answered.
+            for stage in ["Uploaded", "RootsAdded"] {
+                first
+                    .send(stage_message("a.jpg", "baga6ea4a:bafka", stage).into())
+                    .await
+                    .unwrap();
+                second
+                    .send(stage_message("b.jpg", "baga6ea4b:bafkb", stage).into())
+                    .await
+                    .unwrap();
+                for client in [&mut first, &mut second] {
+                    let reply = String::try_from(client.recv().await.unwrap()).unwrap();
+                    assert_eq!(reply, "ACK");
+                }
+            }
+            shutdown_tx.send(()).unwrap();
+        };
+        let served = serve_messages(socket, &handler, &cli, shutdown);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::join!(served, clients)
+        })
+        .await
+        .expect("Serving stopped answering");
+
+        assert_eq!(handler.state.diagnostics.lock().messages_received, 4);
+        let statuses = drain(&queue);
+        for file in ["a.jpg", "b.jpg"] {
+            let of_file: Vec<_> = statuses
+                .iter()
+                .filter(|(name, _)| name == file)
+                .map(|(_, status)| *status)
+                .collect();
+            assert_eq!(of_file, [DisplayStatus::Uploaded, DisplayStatus::Stored]);
+        }
+    }
+}
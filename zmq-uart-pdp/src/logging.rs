@@ -0,0 +1,23 @@
+//! Logger setup.
+
+use std::io::Write as _;
+
+use crate::cli::LogFormat;
+
+/// Initializes the global logger. `RUST_LOG` selects what is logged, defaulting to `info`.
+pub fn init_logging(format: LogFormat) {
+    let mut builder =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let line = serde_json::json!({
+                "level": record.level().as_str(),
+                "timestamp": buf.timestamp().to_string(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init();
+}
@@ -0,0 +1,796 @@
+//! ZMQ messages announcing upload stages, and the tracked state they update.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use log::{debug, info, warn};
+use metrics::{counter, gauge};
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::cli::{Cli, StateKey, TrackedFilesFullMode};
+use crate::error::PdpError;
+use crate::status::{DisplayStatus, StatusEvent, StatusSender};
+
+// Length of the `file_id` suffix appended to the displayed name when not keying by `file`.
+pub(crate) const DISPLAY_ID_SUFFIX_LEN: usize = 6;
+// Prefix of piece CIDs as sent in `file_id`s.
+const PIECE_CID_PREFIX: &str = "baga6ea4";
+// Most message keys remembered for `--dedup-ttl-secs`, beyond which the oldest is forgotten.
+pub(crate) const DEDUP_MAX_KEYS: usize = 1_024;
+
+/// Keys of recently processed messages, so redelivered duplicates are skipped early, see
+/// `--dedup-ttl-secs`.
+pub(crate) struct RecentMessages {
+    ttl: Duration,
+    /// When each key was last processed, along with the proofset ids it came with.
+    seen: HashMap<String, (Instant, Vec<String>)>,
+}
+
+impl RecentMessages {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Whether `key` was processed within the TTL with all of `proofset_ids`, so a duplicate
+    /// bringing a new proofset id is still processed.
+    fn is_duplicate(&mut self, key: &str, proofset_ids: &[String]) -> bool {
+        let ttl = self.ttl;
+        self.seen.retain(|_, (seen_at, _)| seen_at.elapsed() < ttl);
+        self.seen
+            .get(key)
+            .is_some_and(|(_, seen)| proofset_ids.iter().all(|id| seen.contains(id)))
+    }
+
+    fn record(&mut self, key: String, proofset_ids: &[String]) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        let (seen_at, seen) = self
+            .seen
+            .entry(key)
+            .or_insert_with(|| (Instant::now(), Vec::new()));
+        *seen_at = Instant::now();
+        for id in proofset_ids {
+            if !seen.contains(id) {
+                seen.push(id.clone());
+            }
+        }
+        if self.seen.len() > DEDUP_MAX_KEYS {
+            let oldest = self
+                .seen
+                .iter()
+                .min_by_key(|(_, (seen_at, _))| *seen_at)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.seen.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Applies a received message to the tracked state, sending a status update if it changed.
+/// A duplicate of a message recently processed for a file still tracked is skipped.
+pub(crate) async fn process_message(
+    mut payload: ZmqPayload,
+    current_state: &Mutex<HashMap<String, ZmqPayload>>,
+    recent: &Mutex<RecentMessages>,
+    status_sender: &StatusSender,
+    cli: &Cli,
+) {
+    let key = payload.data.state_key(cli.state_key);
+    let message_key = payload.idempotency_key();
+    let proofset_ids = payload.data.proofset_ids.clone();
+    let tracked = current_state.lock().contains_key(&key);
+    if tracked && recent.lock().is_duplicate(&message_key, &proofset_ids) {
+        debug!(
+            "Skipping duplicate message {} of {}",
+            message_key, payload.data.file
+        );
+        counter!("zmq_duplicate_messages_total").increment(1);
+        return;
+    }
+
+    // Update state and send a status update if the stage advanced
+    let (is_new_file, should_update) = {
+        let mut current_state = current_state.lock();
+        match current_state.get_mut(&key) {
+            None => (true, true),
+            Some(current) => {
+                // Proofset ids once learned are kept even if later messages omit them.
+                let mut proofset_ids = current.data.proofset_ids.clone();
+                for id in payload.data.proofset_ids.drain(..) {
+                    if !proofset_ids.contains(&id) {
+                        proofset_ids.push(id);
+                    }
+                }
+                payload.data.proofset_ids = proofset_ids;
+                match payload.stage.change_from(&current.stage) {
+                    StageChange::Advance => (false, true),
+                    // Nothing to display, but new proofsets are polled from the next cycle on.
+                    StageChange::Duplicate => {
+                        if payload.data.proofset_ids == current.data.proofset_ids {
+                            debug!("Stage of {} unchanged", payload.data.file);
+                        } else {
+                            info!(
+                                "Proofsets of {} are now {:?}",
+                                payload.data.file, payload.data.proofset_ids
+                            );
+                            current.data.proofset_ids = payload.data.proofset_ids.clone();
+                        }
+                        (false, false)
+                    }
+                    StageChange::Regression => {
+                        warn!(
+                            "Ignoring stage {:?} of {}, which is already at {:?}",
+                            payload.stage, payload.data.file, current.stage
+                        );
+                        (false, false)
+                    }
+                }
+            }
+        }
+    };
+
+    if is_new_file && !make_room(current_state, status_sender, cli).await {
+        warn!(
+            "Not tracking {}, {} files are tracked and none of them is proven or removed",
+            payload.data.file,
+            current_state.lock().len()
+        );
+        return;
+    }
+
+    if is_new_file && cli.initial_pending {
+        debug!("Tracking new file: {}", payload.data.file);
+        status_sender
+            .send_if_changed(StatusEvent::new(
+                &payload,
+                DisplayStatus::Pending,
+                cli.state_key,
+            ))
+            .await;
+    }
+
+    if should_update {
+        let status = payload.stage.status();
+        debug!(
+            "Stage of {} changed to {:?}",
+            payload.data.file, payload.stage
+        );
+        let event = StatusEvent::new(&payload, status, cli.state_key);
+        // Update the state first so it's persisted along with the status.
+        let tracked = {
+            let mut current_state = current_state.lock();
+            current_state.insert(key, payload);
+            current_state.len()
+        };
+        gauge!("tracked_files").set(tracked as f64);
+        status_sender.send_if_changed(event).await;
+    }
+    recent.lock().record(message_key, &proofset_ids);
+}
+
+/// Makes room for a new file if `--max-tracked-files` are tracked, evicting the least recently
+/// updated file that's proven or removed, or any file with `--on-tracked-files-full evict-oldest`.
+/// Returns `false` if there's no room.
+async fn make_room(
+    current_state: &Mutex<HashMap<String, ZmqPayload>>,
+    status_sender: &StatusSender,
+    cli: &Cli,
+) -> bool {
+    let Some(max_tracked_files) = cli.max_tracked_files else {
+        return true;
+    };
+    let (evicted, tracked) = {
+        let mut state = current_state.lock();
+        if (state.len() as u64) < max_tracked_files {
+            return true;
+        }
+        let last_sent = status_sender.last_sent.lock();
+        // Files without a status sent yet count as the least recently updated.
+        let updated: Vec<_> = state
+            .iter()
+            .map(|(key, payload)| {
+                let sent = last_sent.get(&payload.data.display_name(cli.state_key));
+                (
+                    key,
+                    sent.map(|sent| sent.status),
+                    sent.map(|sent| sent.changed_at),
+                )
+            })
+            .collect();
+        let evictable = |status: Option<DisplayStatus>| {
+            status.is_some_and(|status| status.is_proven() || status == DisplayStatus::Removed)
+        };
+        let oldest = updated
+            .iter()
+            .filter(|(_, status, _)| evictable(*status))
+            .min_by_key(|(_, _, changed_at)| *changed_at)
+            .or_else(|| match cli.on_tracked_files_full {
+                TrackedFilesFullMode::Refuse => None,
+                TrackedFilesFullMode::EvictOldest => {
+                    updated.iter().min_by_key(|(_, _, changed_at)| *changed_at)
+                }
+            });
+        let Some((key, _, _)) = oldest else {
+            return false;
+        };
+        let key = (*key).clone();
+        drop(last_sent);
+        (state.remove(&key), state.len())
+    };
+    gauge!("tracked_files").set(tracked as f64);
+    if let Some(evicted) = evicted {
+        info!(
+            "Evicting {} to stay within {} tracked files",
+            evicted.data.file, max_tracked_files
+        );
+        status_sender.clear(&evicted, cli.state_key).await;
+    }
+    true
+}
+
+#[derive(serde::Deserialize, Serialize, Debug, Default, PartialEq, Clone)]
+pub struct FileData {
+    pub file: String,
+    pub file_id: String,
+    /// Proofsets the file's roots were added to. `proofset_id` may be a single id or a list.
+    #[serde(rename = "proofset_id", default, deserialize_with = "one_or_many")]
+    pub proofset_ids: Vec<String>,
+    /// Root CID of a `--watch-file` entry, looked up as is. Such entries have no piece CID, so
+    /// their `file_id` is just the root CID rather than `<cid>:<root cid>`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub watched_root: Option<String>,
+}
+
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(
+        match <Option<OneOrMany> as serde::Deserialize>::deserialize(deserializer)? {
+            None => Vec::new(),
+            Some(OneOrMany::One(id)) => vec![id],
+            Some(OneOrMany::Many(ids)) => ids,
+        },
+    )
+}
+
+impl FileData {
+    /// Root CID: the watched root, or else the second part of the `file_id` (after the colon).
+    /// `None` if the `file_id` is malformed.
+    pub fn root_cid(&self) -> Option<String> {
+        if let Some(root) = &self.watched_root {
+            return Some(root.clone());
+        }
+        parse_file_id(&self.file_id)
+            .ok()
+            .map(|(_, root_cid)| root_cid)
+    }
+
+    /// Key identifying this file in the tracked state, see `--state-key`.
+    pub fn state_key(&self, key: StateKey) -> String {
+        match key {
+            StateKey::File => self.file.clone(),
+            StateKey::FileId => self.file_id.clone(),
+            StateKey::Composite => format!("{}|{}", self.file, self.file_id),
+        }
+    }
+
+    /// Name shown on the display and in the status snapshot. Unless keying by `file`, it carries
+    /// a short `file_id` suffix so same-named files can be told apart.
+    pub fn display_name(&self, key: StateKey) -> String {
+        if key == StateKey::File {
+            return self.file.clone();
+        }
+        let id: Vec<char> = self.file_id.chars().collect();
+        let suffix: String = id[id.len().saturating_sub(DISPLAY_ID_SUFFIX_LEN)..]
+            .iter()
+            .collect();
+        format!("{}#{}", self.file, suffix)
+    }
+}
+
+#[derive(serde::Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct ZmqPayload {
+    pub stage: Stage,
+    pub data: FileData,
+    /// Key the sender gives every delivery of the same message, to tell redeliveries apart from
+    /// new messages. Derived from the `file_id` and stage if absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+}
+
+impl ZmqPayload {
+    /// Key identifying this message among redeliveries, see [`ZmqPayload::idempotency_key`].
+    pub fn idempotency_key(&self) -> String {
+        match &self.idempotency_key {
+            Some(key) => key.clone(),
+            None => format!("{}|{:?}", self.data.file_id, self.stage),
+        }
+    }
+}
+
+#[derive(Serialize, clap::ValueEnum, Debug, PartialEq, Clone)]
+pub enum Stage {
+    Uploaded,
+    RootsAdded,
+}
+
+/// Accepts the stage in any case and with or without `_`/`-` separators, e.g. `ROOTS_ADDED`,
+/// `roots_added`, `Roots-Added` or `RootsAdded`, as producers disagree on the spelling.
+impl<'de> serde::Deserialize<'de> for Stage {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        let normalized: String = value
+            .chars()
+            .filter(|c| !matches!(c, '_' | '-' | ' '))
+            .flat_map(char::to_lowercase)
+            .collect();
+        match normalized.as_str() {
+            "uploaded" => Ok(Stage::Uploaded),
+            "rootsadded" => Ok(Stage::RootsAdded),
+            _ => Err(serde::de::Error::unknown_variant(
+                &value,
+                &["UPLOADED", "ROOTS_ADDED"],
+            )),
+        }
+    }
+}
+
+impl Stage {
+    /// Status shown for a file that reached this stage, until the API says otherwise.
+    pub fn status(&self) -> DisplayStatus {
+        match self {
+            Stage::Uploaded => DisplayStatus::Uploaded,
+            Stage::RootsAdded => DisplayStatus::Stored,
+        }
+    }
+
+    /// How a file at stage `current` moves when a message announces this stage. Files only move
+    /// from `Uploaded` to `RootsAdded` and stay there once reached.
+    pub fn change_from(&self, current: &Stage) -> StageChange {
+        match (current, self) {
+            (Stage::Uploaded, Stage::RootsAdded) => StageChange::Advance,
+            (Stage::RootsAdded, Stage::Uploaded) => StageChange::Regression,
+            _ => StageChange::Duplicate,
+        }
+    }
+}
+
+/// Outcome of a stage update of a tracked file, see [`Stage::change_from`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StageChange {
+    /// The file moved to a later stage.
+    Advance,
+    /// The file is already at the announced stage.
+    Duplicate,
+    /// The announced stage is earlier than the file's, e.g. a late message; it's ignored.
+    Regression,
+}
+
+// Sample payloads:
+//Received: "{\"stage\": \"UPLOADED\", \"data\": {\"file\": \"cathulhu-rise-of.jpg\", \"file_id\": \"baga6ea4seaqa66jndptvpxfbo3qimxysbivikdpqint4t2kvnnkrfxrfoi2nufy:baga6ea4seaqa66jndptvpxfbo3qimxysbivikdpqint4t2kvnnkrfxrfoi2nufy\"}}"
+//Received: "{\"stage\": \"ROOTS_ADDED\", \"data\": {\"file\": \"cathulhu-rise-of.jpg\", \"file_id\": \"baga6ea4seaqa66jndptvpxfbo3qimxysbivikdpqint4t2kvnnkrfxrfoi2nufy:baga6ea4seaqa66jndptvpxfbo3qimxysbivikdpqint4t2kvnnkrfxrfoi2nufy\", \"proofset_id\": \"51\"}}"
+//
+pub fn parse_zmq_msg(msg: &str) -> Result<ZmqPayload, PdpError> {
+    let payload: ZmqPayload = serde_json::from_str(msg)
+        .context(format!("Failed to parse message: {}", msg))
+        .map_err(PdpError::ParseError)?;
+    parse_file_id(&payload.data.file_id)
+        .with_context(|| format!("Invalid file_id in message: {}", msg))
+        .map_err(PdpError::ParseError)?;
+
+    info!(
+        "Received message - File: {} with id: {} and proofset_ids: {:?}, Stage: {:?}",
+        payload.data.file, payload.data.file_id, payload.data.proofset_ids, payload.stage
+    );
+    Ok(payload)
+}
+
+/// Splits a `file_id` into its two CIDs, `<cid>:<root cid>`, both of which must be non-empty.
+/// CIDs without the usual piece CID prefix are accepted, but logged.
+pub fn parse_file_id(file_id: &str) -> anyhow::Result<(String, String)> {
+    let segments: Vec<_> = file_id.split(':').collect();
+    let [cid, root_cid] = segments[..] else {
+        anyhow::bail!(
+            "Expected file_id of the form <cid>:<root cid>, got {} segments: {:?}",
+            segments.len(),
+            file_id
+        );
+    };
+    if cid.is_empty() || root_cid.is_empty() {
+        anyhow::bail!("Empty CID in file_id: {:?}", file_id);
+    }
+    for segment in [cid, root_cid] {
+        if !segment.starts_with(PIECE_CID_PREFIX) {
+            debug!(
+                "CID {} in file_id doesn't start with {}",
+                segment, PIECE_CID_PREFIX
+            );
+        }
+    }
+    Ok((cid.to_string(), root_cid.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use metrics_util::{CompositeKey, MetricKind};
+
+    use super::*;
+    use crate::status::DisplayStatus;
+    use crate::test_util::{
+        cli, drain, event, payload, pipeline, sender, serial_lines, stage_message,
+    };
+
+    #[test]
+    fn parse_file_id_splits_well_formed_ids() {
+        assert_eq!(
+            parse_file_id("baga6ea4piece:bafkroot").unwrap(),
+            ("baga6ea4piece".to_string(), "bafkroot".to_string())
+        );
+        // An unusual prefix is only logged.
+        assert!(parse_file_id("piece:root").is_ok());
+    }
+
+    #[test]
+    fn parse_file_id_rejects_single_segment() {
+        let err = parse_file_id("baga6ea4piece").unwrap_err();
+        assert!(err.to_string().contains("got 1 segments"), "{}", err);
+        assert!(parse_file_id("a:b:c").is_err());
+    }
+
+    #[test]
+    fn parse_file_id_rejects_empty_segments() {
+        for file_id in ["", ":bafkroot", "baga6ea4piece:", ":"] {
+            assert!(parse_file_id(file_id).is_err(), "{:?}", file_id);
+        }
+    }
+
+    #[test]
+    fn stage_parsing_ignores_case_and_separators() {
+        let parse = |stage: &str| serde_json::from_value::<Stage>(stage.into());
+        for stage in ["UPLOADED", "uploaded", "Uploaded"] {
+            assert_eq!(parse(stage).unwrap(), Stage::Uploaded);
+        }
+        for stage in ["ROOTS_ADDED", "roots_added", "Roots-Added", "RootsAdded"] {
+            assert_eq!(parse(stage).unwrap(), Stage::RootsAdded);
+        }
+    }
+
+    #[test]
+    fn unknown_stage_is_rejected() {
+        let err = serde_json::from_value::<Stage>("FOO".into()).unwrap_err();
+        assert!(err.to_string().contains("unknown variant `FOO`"), "{}", err);
+    }
+
+    #[test]
+    fn proofset_id_is_one_or_many() {
+        let parse = |proofset_id: serde_json::Value| {
+            let message = serde_json::json!({
+                "stage": "RootsAdded",
+                "data": {"file": "a.jpg", "file_id": "baga6ea4piece:bafkroot", "proofset_id": proofset_id},
+            });
+            parse_zmq_msg(&message.to_string())
+                .unwrap()
+                .data
+                .proofset_ids
+        };
+        assert_eq!(parse("51".into()), ["51"]);
+        assert_eq!(parse(serde_json::json!(["51", "52"])), ["51", "52"]);
+    }
+
+    #[test]
+    fn parse_zmq_msg_rejects_malformed_file_id() {
+        assert!(parse_zmq_msg(&stage_message(
+            "a.jpg",
+            "baga6ea4piece:bafkroot",
+            "Uploaded"
+        ))
+        .is_ok());
+        let err = parse_zmq_msg(&stage_message("a.jpg", "baga6ea4piece:", "Uploaded")).unwrap_err();
+        assert!(format!("{:#}", err).contains("Empty CID"), "{:#}", err);
+    }
+
+    /// Processes updates of a single file, returning the statuses sent and the tracked payload.
+    async fn process_stages(
+        updates: &[(Stage, &[&str])],
+    ) -> (Vec<(String, DisplayStatus)>, ZmqPayload) {
+        let cli = cli(&[]);
+        let current_state = Mutex::new(HashMap::new());
+        let recent = Mutex::new(RecentMessages::new(Duration::from_secs(60)));
+        let (status_sender, queue) = sender();
+        for (stage, proofset_ids) in updates {
+            let payload = payload(
+                "a.jpg",
+                "baga6ea4piece:bafkroot",
+                stage.clone(),
+                proofset_ids,
+            );
+            process_message(payload, &current_state, &recent, &status_sender, &cli).await;
+        }
+        let tracked = current_state.lock()["a.jpg"].clone();
+        (drain(&queue), tracked)
+    }
+
+    #[tokio::test]
+    async fn stages_progress_in_order() {
+        let (statuses, tracked) =
+            process_stages(&[(Stage::Uploaded, &["1"]), (Stage::RootsAdded, &["1"])]).await;
+        assert_eq!(
+            statuses,
+            [
+                ("a.jpg".to_string(), DisplayStatus::Uploaded),
+                ("a.jpg".to_string(), DisplayStatus::Stored)
+            ]
+        );
+        assert_eq!(tracked.stage, Stage::RootsAdded);
+    }
+
+    #[tokio::test]
+    async fn stage_regression_is_ignored() {
+        let (statuses, tracked) =
+            process_stages(&[(Stage::RootsAdded, &["1"]), (Stage::Uploaded, &["1"])]).await;
+        assert_eq!(statuses, [("a.jpg".to_string(), DisplayStatus::Stored)]);
+        assert_eq!(tracked.stage, Stage::RootsAdded);
+    }
+
+    #[tokio::test]
+    async fn duplicate_stage_sends_nothing_but_keeps_proofset_ids() {
+        let (statuses, tracked) = process_stages(&[
+            (Stage::RootsAdded, &["1"]),
+            (Stage::RootsAdded, &["1"]),
+            (Stage::RootsAdded, &["2"]),
+            (Stage::RootsAdded, &[]),
+        ])
+        .await;
+        assert_eq!(statuses, [("a.jpg".to_string(), DisplayStatus::Stored)]);
+        assert_eq!(tracked.data.proofset_ids, ["1", "2"]);
+    }
+
+    #[tokio::test]
+    async fn repeated_stages_and_new_files_write_the_expected_lines() {
+        let (handler, sink) = pipeline(&["--ack-after-processing"]);
+        let with_proofset = |file: &str, file_id: &str, proofset_id: &str| {
+            serde_json::json!({
+                "stage": "RootsAdded",
+                "data": {"file": file, "file_id": file_id, "proofset_id": proofset_id},
+            })
+            .to_string()
+        };
+        let messages = [
+            // Same file, same stage.
+            with_proofset("a.jpg", "baga6ea4a:bafka", "1"),
+            with_proofset("a.jpg", "baga6ea4a:bafka", "1"),
+            // Same file, new proofset.
+            with_proofset("a.jpg", "baga6ea4a:bafka", "2"),
+            // Different file.
+            with_proofset("b.jpg", "baga6ea4b:bafkb", "1"),
+        ];
+        for message in &messages {
+            assert_eq!(handler.handle(message).await, "ACK");
+        }
+        assert_eq!(
+            serial_lines(&sink, 3).await,
+            ["init\n", "a.jpg,stored\n", "b.jpg,stored\n"]
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(sink.lines().len(), 3);
+        // b.jpg is tracked alongside a.jpg, which keeps both proofsets.
+        let current_state = handler.state.current_state.lock();
+        let mut tracked: Vec<_> = current_state.keys().cloned().collect();
+        tracked.sort();
+        assert_eq!(tracked, ["a.jpg", "b.jpg"]);
+        assert_eq!(current_state["a.jpg"].data.proofset_ids, ["1", "2"]);
+    }
+
+    /// Tracks a.jpg and b.jpg under `--max-tracked-files 2`, b.jpg being proven if `proven`, then
+    /// processes c.jpg. Returns the tracked files and the statuses sent after c.jpg arrived.
+    async fn track_beyond_cap(
+        args: &[&str],
+        proven: bool,
+    ) -> (Vec<String>, Vec<(String, DisplayStatus)>) {
+        let cli = cli(&[&["--max-tracked-files", "2"], args].concat());
+        let current_state = Mutex::new(HashMap::new());
+        let recent = Mutex::new(RecentMessages::new(Duration::from_secs(60)));
+        let (status_sender, queue) = sender();
+        for file in ["a.jpg", "b.jpg"] {
+            let file_id = format!("baga6ea4{}:bafk{}", file, file);
+            let payload = payload(file, &file_id, Stage::RootsAdded, &["1"]);
+            process_message(payload, &current_state, &recent, &status_sender, &cli).await;
+            // Tells the files' update times apart.
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        if proven {
+            let event = event("b.jpg", "bafkb.jpg", DisplayStatus::StoredProven);
+            status_sender.send_if_changed(event).await;
+        }
+        drain(&queue);
+        let payload = payload("c.jpg", "baga6ea4c:bafkc", Stage::Uploaded, &["1"]);
+        process_message(payload, &current_state, &recent, &status_sender, &cli).await;
+        let mut tracked: Vec<_> = current_state.lock().keys().cloned().collect();
+        tracked.sort();
+        (tracked, drain(&queue))
+    }
+
+    #[tokio::test]
+    async fn cap_evicts_a_proven_file() {
+        let (tracked, statuses) = track_beyond_cap(&[], true).await;
+        assert_eq!(tracked, ["a.jpg", "c.jpg"]);
+        assert_eq!(
+            statuses,
+            [
+                ("b.jpg".to_string(), DisplayStatus::Cleared),
+                ("c.jpg".to_string(), DisplayStatus::Uploaded)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn cap_refuses_new_files_while_all_are_active() {
+        let (tracked, statuses) = track_beyond_cap(&[], false).await;
+        assert_eq!(tracked, ["a.jpg", "b.jpg"]);
+        assert_eq!(statuses, []);
+    }
+
+    #[tokio::test]
+    async fn cap_evicts_the_oldest_active_file_when_configured() {
+        let (tracked, statuses) =
+            track_beyond_cap(&["--on-tracked-files-full", "evict-oldest"], false).await;
+        assert_eq!(tracked, ["b.jpg", "c.jpg"]);
+        assert_eq!(
+            statuses,
+            [
+                ("a.jpg".to_string(), DisplayStatus::Cleared),
+                ("c.jpg".to_string(), DisplayStatus::Uploaded)
+            ]
+        );
+    }
+
+    async fn process_same_named_files(state_key: &str) -> (Vec<String>, Vec<String>) {
+        let cli = cli(&["--state-key", state_key]);
+        let current_state = Mutex::new(HashMap::new());
+        let recent = Mutex::new(RecentMessages::new(Duration::from_secs(60)));
+        let (status_sender, queue) = sender();
+        for file_id in ["baga6ea4first:bafkfirst1", "baga6ea4second:bafksecond2"] {
+            let payload = payload("photo.jpg", file_id, Stage::Uploaded, &["1"]);
+            process_message(payload, &current_state, &recent, &status_sender, &cli).await;
+        }
+        let mut keys: Vec<_> = current_state.lock().keys().cloned().collect();
+        keys.sort();
+        let files = drain(&queue)
+            .into_iter()
+            .map(|(file, status)| {
+                assert_eq!(status, DisplayStatus::Uploaded);
+                file
+            })
+            .collect();
+        (keys, files)
+    }
+
+    #[tokio::test]
+    async fn same_named_files_share_a_key_by_file() {
+        let (keys, files) = process_same_named_files("file").await;
+        assert_eq!(keys, ["photo.jpg"]);
+        // The second file replaced the first, so its status is unchanged.
+        assert_eq!(files, ["photo.jpg"]);
+    }
+
+    #[tokio::test]
+    async fn same_named_files_are_kept_apart_by_file_id() {
+        let (keys, files) = process_same_named_files("file-id").await;
+        assert_eq!(
+            keys,
+            ["baga6ea4first:bafkfirst1", "baga6ea4second:bafksecond2"]
+        );
+        assert_eq!(files, ["photo.jpg#first1", "photo.jpg#econd2"]);
+    }
+
+    #[tokio::test]
+    async fn same_named_files_are_kept_apart_by_composite_key() {
+        let (keys, files) = process_same_named_files("composite").await;
+        assert_eq!(
+            keys,
+            [
+                "photo.jpg|baga6ea4first:bafkfirst1",
+                "photo.jpg|baga6ea4second:bafksecond2"
+            ]
+        );
+        assert_eq!(files, ["photo.jpg#first1", "photo.jpg#econd2"]);
+    }
+
+    async fn statuses_of_new_file(args: &[&str]) -> Vec<(String, DisplayStatus)> {
+        let cli = cli(args);
+        let current_state = Mutex::new(HashMap::new());
+        let recent = Mutex::new(RecentMessages::new(Duration::from_secs(60)));
+        let (status_sender, queue) = sender();
+        let payload = payload("a.jpg", "baga6ea4piece:bafkroot", Stage::Uploaded, &["1"]);
+        process_message(payload, &current_state, &recent, &status_sender, &cli).await;
+        drain(&queue)
+    }
+
+    #[tokio::test]
+    async fn new_file_starts_pending_when_configured() {
+        assert_eq!(
+            statuses_of_new_file(&["--initial-pending"]).await,
+            [
+                ("a.jpg".to_string(), DisplayStatus::Pending),
+                ("a.jpg".to_string(), DisplayStatus::Uploaded),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn new_file_starts_at_its_stage_by_default() {
+        assert_eq!(
+            statuses_of_new_file(&[]).await,
+            [("a.jpg".to_string(), DisplayStatus::Uploaded)]
+        );
+    }
+
+    #[tokio::test]
+    async fn redelivered_message_is_written_once() {
+        let recorder = DebuggingRecorder::new();
+        let _recorder = metrics::set_default_local_recorder(&recorder);
+        let (handler, sink) = pipeline(&["--ack-after-processing"]);
+        let message = stage_message("a.jpg", "baga6ea4piece:bafkroot", "Uploaded");
+        for _ in 0..2 {
+            assert_eq!(handler.handle(&message).await, "ACK");
+        }
+        assert_eq!(serial_lines(&sink, 2).await, ["init\n", "a.jpg,uploaded\n"]);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(sink.lines().len(), 2);
+        let key = CompositeKey::new(
+            MetricKind::Counter,
+            metrics::Key::from_static_name("zmq_duplicate_messages_total"),
+        );
+        let (_, _, duplicates) = recorder
+            .snapshotter()
+            .snapshot()
+            .into_hashmap()
+            .remove(&key)
+            .expect("No duplicate was counted");
+        assert_eq!(duplicates, DebugValue::Counter(1));
+    }
+
+    #[tokio::test]
+    async fn redelivery_with_a_new_proofset_is_processed() {
+        let cli = cli(&[]);
+        let current_state = Mutex::new(HashMap::new());
+        let recent = Mutex::new(RecentMessages::new(Duration::from_secs(60)));
+        let (status_sender, queue) = sender();
+        for proofset_ids in [&["1"][..], &["1"], &["1", "2"]] {
+            let payload = payload(
+                "a.jpg",
+                "baga6ea4piece:bafkroot",
+                Stage::RootsAdded,
+                proofset_ids,
+            );
+            process_message(payload, &current_state, &recent, &status_sender, &cli).await;
+        }
+        assert_eq!(current_state.lock()["a.jpg"].data.proofset_ids, ["1", "2"]);
+        assert_eq!(
+            drain(&queue),
+            [("a.jpg".to_string(), DisplayStatus::Stored)]
+        );
+    }
+
+    #[test]
+    fn idempotency_key_is_derived_unless_given() {
+        let mut payload = payload("a.jpg", "baga6ea4piece:bafkroot", Stage::Uploaded, &["1"]);
+        assert_eq!(payload.idempotency_key(), "baga6ea4piece:bafkroot|Uploaded");
+        payload.idempotency_key = Some("delivery-1".to_string());
+        assert_eq!(payload.idempotency_key(), "delivery-1");
+    }
+}
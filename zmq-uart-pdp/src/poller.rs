@@ -0,0 +1,1512 @@
+//! Periodic polling of the API for the statuses of tracked files.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+
+use log::{debug, error, info, log, warn, Level};
+use metrics::{counter, gauge, histogram};
+use parking_lot::Mutex;
+use rand::Rng as _;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument as _;
+
+use crate::api::{
+    best_status, classify_roots, last_proven_at, root_statuses, ChainClock, ClassifyOptions,
+    ProofSet, ProofSetRoots, ProofStatusProvider,
+};
+use crate::cli::{Cli, RootCidSegment};
+use crate::error::PdpError;
+use crate::message::{parse_file_id, FileData, Stage, ZmqPayload};
+use crate::reload::SharedConfig;
+use crate::state::{Diagnostics, RecentEvent, RecentEventKind, TimestampedError};
+use crate::status::{DisplayStatus, StatusEvent, StatusSender};
+
+// Default `--api-poll-interval-secs`.
+pub(crate) const API_CHECK_INTERVAL_SECS: u64 = 5;
+// Window within which repeats of the same warning are counted rather than logged.
+pub(crate) const LOG_COALESCE_WINDOW_SECS: u64 = 60;
+
+/// When each tracked file is polled next. A file that stays proven is polled less and less often,
+/// doubling its interval up to a maximum, while any other outcome polls it at the base interval.
+pub(crate) struct PollSchedule {
+    base: Duration,
+    max: Duration,
+    /// Interval, next poll and last classified status of each polled file.
+    files: HashMap<String, (Duration, Instant, Option<DisplayStatus>)>,
+}
+
+impl PollSchedule {
+    pub(crate) fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            files: HashMap::new(),
+        }
+    }
+
+    /// Whether the file is due for a poll. Files not polled yet always are.
+    pub(crate) fn is_due(&self, key: &str) -> bool {
+        self.files
+            .get(key)
+            .is_none_or(|(_, next_poll, _)| Instant::now() >= *next_poll)
+    }
+
+    /// Schedules the next poll of a file after it was classified as `status`, `None` if it
+    /// couldn't be.
+    pub(crate) fn record(&mut self, key: String, status: Option<DisplayStatus>) {
+        let proven = status.is_some_and(DisplayStatus::is_proven);
+        let interval = match self.files.get(&key) {
+            Some((interval, _, previous)) if proven && *previous == status => {
+                (*interval * 2).min(self.max)
+            }
+            _ => self.base,
+        };
+        if interval > self.base {
+            debug!("Backing off polling of {} to {:?}", key, interval);
+        }
+        self.files
+            .insert(key, (interval, Instant::now() + interval, status));
+    }
+
+    /// Status the file was last classified as, `None` if it wasn't polled or couldn't be classified.
+    pub(crate) fn last_status(&self, key: &str) -> Option<DisplayStatus> {
+        self.files.get(key).and_then(|(_, _, status)| *status)
+    }
+
+    /// Forgets the files for which `tracked` returns `false`.
+    pub(crate) fn retain(&mut self, tracked: impl Fn(&str) -> bool) {
+        self.files.retain(|key, _| tracked(key));
+    }
+}
+
+/// Per-proofset cache of API responses, keyed by the proofset id, or by `roots_cache_key` for roots
+/// filtered by CID. Expired entries are evicted lazily on access and
+/// periodically via [`RootsCache::evict_expired`].
+pub(crate) struct RootsCache {
+    pub(crate) ttl: Duration,
+    pub(crate) entries: HashMap<String, (Instant, Arc<ProofSetRoots>)>,
+    pub(crate) hits: u64,
+    pub(crate) misses: u64,
+}
+
+impl RootsCache {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: HashMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub(crate) fn get(&mut self, proofset_id: &str) -> Option<Arc<ProofSetRoots>> {
+        let fresh = match self.entries.get(proofset_id) {
+            Some((inserted_at, _)) => inserted_at.elapsed() < self.ttl,
+            None => false,
+        };
+        if !fresh {
+            self.entries.remove(proofset_id);
+            self.misses += 1;
+            counter!("pdp_api_cache_misses").increment(1);
+            gauge!("pdp_api_cache_size").set(self.entries.len() as f64);
+            return None;
+        }
+        self.hits += 1;
+        counter!("pdp_api_cache_hits").increment(1);
+        self.entries
+            .get(proofset_id)
+            .map(|(_, roots)| roots.clone())
+    }
+
+    pub(crate) fn insert(&mut self, proofset_id: String, roots: Arc<ProofSetRoots>) {
+        self.entries.insert(proofset_id, (Instant::now(), roots));
+        gauge!("pdp_api_cache_size").set(self.entries.len() as f64);
+    }
+
+    /// Drops the roots of `proofset_id`, including those filtered by CID.
+    pub(crate) fn invalidate(&mut self, proofset_id: &str) {
+        let filtered_prefix = roots_cache_key(proofset_id, "");
+        self.entries
+            .retain(|key, _| key != proofset_id && !key.starts_with(&filtered_prefix));
+        gauge!("pdp_api_cache_size").set(self.entries.len() as f64);
+    }
+
+    pub(crate) fn evict_expired(&mut self) {
+        let ttl = self.ttl;
+        self.entries
+            .retain(|_, (inserted_at, _)| inserted_at.elapsed() < ttl);
+        gauge!("pdp_api_cache_size").set(self.entries.len() as f64);
+    }
+
+    pub(crate) fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub(crate) fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+/// Coalesces repetitive log messages, keyed by a tag: the first message per window is logged and
+/// repeats are only counted, then reported alongside the next message logged after the window.
+pub(crate) struct LogLimiter {
+    pub(crate) window: Duration,
+    pub(crate) tags: HashMap<&'static str, (Option<Instant>, u64)>,
+}
+
+impl LogLimiter {
+    pub(crate) fn new(window: Duration) -> Self {
+        Self {
+            window,
+            tags: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn log(&mut self, level: Level, tag: &'static str, message: std::fmt::Arguments) {
+        let Some(suppressed) = self.admit(tag) else {
+            return;
+        };
+        if suppressed > 0 {
+            log!(
+                level,
+                "{} ({} more occurrences in the last {}s)",
+                message,
+                suppressed,
+                self.window.as_secs()
+            );
+        } else {
+            log!(level, "{}", message);
+        }
+    }
+
+    /// Whether a message tagged `tag` is logged now, with the number of repeats suppressed since
+    /// the last one logged, or `None` if it's a repeat to suppress.
+    pub(crate) fn admit(&mut self, tag: &'static str) -> Option<u64> {
+        let (logged_at, suppressed) = self.tags.entry(tag).or_insert((None, 0));
+        if logged_at.is_some_and(|logged_at| logged_at.elapsed() < self.window) {
+            *suppressed += 1;
+            return None;
+        }
+        *logged_at = Some(Instant::now());
+        Some(std::mem::take(suppressed))
+    }
+}
+
+/// Randomizes `interval` by up to `jitter_percent` either way, so services started together don't
+/// poll the API in lockstep. The interval stays the same on average.
+pub(crate) fn jittered(interval: Duration, jitter_percent: u64) -> Duration {
+    let jitter = jitter_percent as f64 / 100.0;
+    interval.mul_f64(1.0 + rand::thread_rng().gen_range(-jitter..=jitter))
+}
+
+/// Key of the roots of `proofset_id` filtered by `cid` in [`RootsCache`].
+pub(crate) fn roots_cache_key(proofset_id: &str, cid: &str) -> String {
+    format!("{}/{}", proofset_id, cid)
+}
+
+/// Fetches the roots of a proofset, or takes them from `cache` if they're fresh. With `cids`, only
+/// the roots matching them are requested, if the API supports it.
+async fn fetch_roots_cached<P: ProofStatusProvider>(
+    provider: &P,
+    cache: &mut RootsCache,
+    proofset_id: &str,
+    cids: Option<&[String]>,
+) -> Result<Arc<ProofSetRoots>, PdpError> {
+    let key = match cids {
+        Some([cid, ..]) => roots_cache_key(proofset_id, cid),
+        _ => proofset_id.to_string(),
+    };
+    let roots = match cache.get(&key) {
+        Some(roots) => {
+            debug!("Using cached roots for proofset_id: {}", proofset_id);
+            Ok(roots)
+        }
+        None if !provider.try_reserve(proofset_id) => Err(PdpError::RateLimited),
+        None => {
+            info!("Making API request for proofset_id: {}", proofset_id);
+            let roots = match cids {
+                Some(cids) => provider.fetch_roots_matching(proofset_id, cids).await,
+                None => provider.fetch_roots(proofset_id).await,
+            };
+            roots.map(|roots| {
+                let roots = Arc::new(roots);
+                cache.insert(key, roots.clone());
+                roots
+            })
+        }
+    };
+    if let Ok(roots) = &roots {
+        debug!("Found {} total roots", roots.data.len());
+    }
+    roots
+}
+
+/// CIDs that may be the root of a file, in the order they're tried: the watched root, or else the
+/// segments of its `file_id` under `segment`.
+pub(crate) fn root_cid_candidates(
+    data: &FileData,
+    segment: RootCidSegment,
+) -> anyhow::Result<Vec<(&'static str, String)>> {
+    if let Some(root) = &data.watched_root {
+        return Ok(vec![("watched", root.clone())]);
+    }
+    let (first, second) = parse_file_id(&data.file_id)?;
+    Ok(match segment {
+        RootCidSegment::First => vec![("first", first)],
+        RootCidSegment::Second => vec![("second", second)],
+        RootCidSegment::Either if first == second => vec![("second", second)],
+        RootCidSegment::Either => vec![("second", second), ("first", first)],
+    })
+}
+
+/// The first of `candidates` that any of the fetched proofsets has a root with, or `None`.
+pub(crate) fn match_root_cid<'a>(
+    candidates: &'a [(&'static str, String)],
+    fetched: &[(&String, Arc<ProofSetRoots>)],
+) -> Option<&'a (&'static str, String)> {
+    candidates.iter().find(|(_, cid)| {
+        fetched
+            .iter()
+            .any(|(_, roots)| roots.data.iter().any(|root| root.cid == *cid))
+    })
+}
+
+/// Status event of a file from the roots fetched from each of its proofsets, taking the best status
+/// among them, or `None` if none of them has the file's root. Classified as configured in `cli`.
+pub(crate) fn classify_file(
+    payload: &ZmqPayload,
+    root_cid: &str,
+    fetched: &[(&String, Arc<ProofSetRoots>)],
+    cli: &Cli,
+) -> Option<StatusEvent> {
+    let (clock, selection) = (ChainClock::from_cli(cli), cli.root_selection);
+    let options = ClassifyOptions::from_cli(cli);
+    let classified: Vec<_> = fetched
+        .iter()
+        .filter_map(|(proofset_id, roots)| {
+            classify_roots(
+                &roots.data,
+                root_cid,
+                cli.degraded_fault_ratio,
+                selection,
+                options,
+            )
+            .map(|status| (*proofset_id, status))
+        })
+        .collect();
+    let status = best_status(classified.iter().map(|(_, status)| *status))?;
+    Some(StatusEvent {
+        proofset_id: classified
+            .iter()
+            .find(|(_, classified)| *classified == status)
+            .map(|(proofset_id, _)| proofset_id.to_string()),
+        roots: fetched
+            .iter()
+            .flat_map(|(_, roots)| root_statuses(&roots.data, root_cid, options))
+            .collect(),
+        last_proven_at: if status.is_proven() {
+            fetched
+                .iter()
+                .filter_map(|(_, roots)| {
+                    last_proven_at(&roots.data, root_cid, clock, selection, options.zero_epoch)
+                })
+                .max()
+        } else {
+            None
+        },
+        cid: Some(root_cid.to_string()),
+        ..StatusEvent::new(payload, status, cli.state_key)
+    })
+}
+
+/// Logs why a proofset has no root with `root_cid`: it has no roots yet, it doesn't exist, or the
+/// root is simply missing.
+async fn explain_missing_root<P: ProofStatusProvider>(
+    provider: &P,
+    log_limiter: &mut LogLimiter,
+    proofset_id: &str,
+    root_cid: &str,
+    file: &str,
+) {
+    match provider.fetch_proofset(proofset_id).await {
+        Ok(ProofSet {
+            total_roots: Some(0),
+            ..
+        }) => log_limiter.log(
+            Level::Info,
+            "proofset_awaiting_roots",
+            format_args!(
+                "Proofset {} has no roots yet, waiting for root {}",
+                proofset_id, root_cid
+            ),
+        ),
+        Err(PdpError::ProofsetNotFound(_)) => log_limiter.log(
+            Level::Warn,
+            "proofset_not_found",
+            format_args!("Unknown proofset {} of {}", proofset_id, file),
+        ),
+        result => {
+            if let Err(e) = result {
+                debug!("Failed to get proofset {}: {:#}", proofset_id, e);
+            }
+            log_limiter.log(
+                Level::Warn,
+                "root_not_found",
+                format_args!(
+                    "Could not find root with CID {} in proofset {}",
+                    root_cid, proofset_id
+                ),
+            )
+        }
+    }
+}
+
+/// Periodically fetches the roots of every file with roots added and sends their statuses, until
+/// `shutdown` is cancelled, which also abandons a cycle in progress.
+pub(crate) async fn poll_api<P: ProofStatusProvider>(
+    provider: P,
+    current_state: Arc<Mutex<HashMap<String, ZmqPayload>>>,
+    status_sender: StatusSender,
+    config: SharedConfig,
+    diagnostics: Arc<Mutex<Diagnostics>>,
+    shutdown: CancellationToken,
+) {
+    info!("API checking task started");
+    let cli = config.read().clone();
+    let mut cache = RootsCache::new(cli.api_cache_ttl());
+    let mut consecutive_failures = HashMap::<String, u32>::new();
+    // When each file's status was last confirmed by a successful poll, or first failed to be.
+    let mut confirmed_at = HashMap::<String, Instant>::new();
+    // Since when each file's root has been missing from the API, reset whenever it's found.
+    let mut missing_since = HashMap::<String, Instant>::new();
+    // Proofsets the API returned roots for. Until then, one that isn't found is still being
+    // created rather than missing.
+    let mut found_proofsets = HashSet::<String>::new();
+    let mut log_limiter = LogLimiter::new(Duration::from_secs(LOG_COALESCE_WINDOW_SECS));
+    let mut schedule = PollSchedule::new(
+        Duration::from_secs(cli.api_poll_interval_secs),
+        Duration::from_secs(cli.api_max_poll_interval_secs),
+    );
+    loop {
+        // Picks up settings reloaded since the last cycle.
+        let cli = config.read().clone();
+        let cycle_timeout = Duration::from_secs(cli.api_poll_timeout_secs);
+        let interval = Duration::from_secs(cli.api_poll_interval_secs);
+        cache.ttl = cli.api_cache_ttl();
+        schedule.base = interval;
+        schedule.max = Duration::from_secs(cli.api_max_poll_interval_secs);
+        let wait = tokio::time::sleep(jittered(interval, cli.api_poll_jitter_percent));
+        tokio::select! {
+            _ = wait => {}
+            _ = shutdown.cancelled() => break,
+        }
+        debug!("Checking API...");
+        cache.evict_expired();
+
+        let tracked_files: Vec<_> = {
+            let state = current_state.lock();
+            debug!("Current state: {:?}", *state);
+            schedule.retain(|key| state.contains_key(key));
+            missing_since.retain(|key, _| state.contains_key(key));
+            confirmed_at.retain(|key, _| state.contains_key(key));
+            state
+                .values()
+                .filter_map(|payload| {
+                    if payload.stage != Stage::RootsAdded {
+                        debug!(
+                            "Stage of {} is not RootsAdded: {:?}",
+                            payload.data.file, payload.stage
+                        );
+                        return None;
+                    }
+                    let candidates = match root_cid_candidates(&payload.data, cli.root_cid_segment)
+                    {
+                        Ok(candidates) => candidates,
+                        Err(e) => {
+                            warn!("No root CID for {}: {:#}", payload.data.file, e);
+                            return None;
+                        }
+                    };
+                    if payload.data.proofset_ids.is_empty() {
+                        return None;
+                    }
+                    Some((payload.clone(), candidates))
+                })
+                .collect()
+        };
+        if tracked_files.is_empty() {
+            debug!("No state data available for API check");
+        }
+
+        let cycle_started = Instant::now();
+        let requests_before = provider.requests_made();
+        let mut files_checked = 0;
+        let mut status_changes = 0;
+        // Guards against a request that never resolves despite the request timeout, e.g. on a
+        // half-open connection, stopping all polling.
+        let cycle = async {
+            for (payload, candidates) in tracked_files {
+                let data = &payload.data;
+                if !schedule.is_due(&data.state_key(cli.state_key)) {
+                    debug!("Skipping {} until its next poll", data.file);
+                    continue;
+                }
+                // A file may be stored in several proofsets. Failing to fetch any of them fails
+                // the whole poll, so a missing copy can't make the status look worse than it is.
+                let mut fetched = Vec::new();
+                let mut creating = Vec::new();
+                let mut error = None;
+                let mut throttled = false;
+                // Without a filter parameter or `--api-roots-per-file` the roots of a proofset are
+                // fetched once for all its files.
+                let cids: Option<Vec<_>> = (cli.api_cid_filter_param.is_some()
+                    || cli.api_roots_per_file)
+                    .then(|| candidates.iter().map(|(_, cid)| cid.clone()).collect());
+                for proofset_id in &data.proofset_ids {
+                    match fetch_roots_cached(&provider, &mut cache, proofset_id, cids.as_deref())
+                        .await
+                    {
+                        Ok(roots) => {
+                            found_proofsets.insert(proofset_id.clone());
+                            fetched.push((proofset_id, roots));
+                        }
+                        Err(PdpError::ProofsetNotFound(_))
+                            if !found_proofsets.contains(proofset_id) =>
+                        {
+                            creating.push(proofset_id);
+                        }
+                        Err(PdpError::RateLimited) => {
+                            throttled = true;
+                            break;
+                        }
+                        Err(e) => {
+                            error = Some(e);
+                            break;
+                        }
+                    }
+                }
+                // Still due, so checked in the next cycle with the roots fetched so far cached.
+                if throttled {
+                    debug!(
+                        "Deferring {} to the next poll cycle, the API rate limit is reached",
+                        data.file
+                    );
+                    continue;
+                }
+                files_checked += 1;
+                {
+                    let mut diagnostics = diagnostics.lock();
+                    diagnostics.api_cache_hits = cache.hits();
+                    diagnostics.api_cache_misses = cache.misses();
+                    diagnostics.api_cache_size = cache.len();
+                    if let Some(e) = &error {
+                        let error = format!("{:#}", e);
+                        diagnostics.record_event(RecentEvent {
+                            file: Some(data.file.clone()),
+                            error: Some(error.clone()),
+                            ..RecentEvent::new(RecentEventKind::ApiError)
+                        });
+                        diagnostics.last_api_error = Some(TimestampedError::now(error));
+                    }
+                }
+                // A copy still being created is left out, unless it's the only thing to show.
+                if error.is_none() {
+                    confirmed_at.insert(data.state_key(cli.state_key), Instant::now());
+                }
+                if error.is_none() && fetched.is_empty() && !creating.is_empty() {
+                    consecutive_failures.remove(&data.state_key(cli.state_key));
+                    log_limiter.log(
+                        Level::Info,
+                        "proofset_creating",
+                        format_args!(
+                            "Proofsets {:?} of {} not found yet, showing it as creating",
+                            creating, data.file
+                        ),
+                    );
+                    schedule.record(data.state_key(cli.state_key), Some(DisplayStatus::Creating));
+                    let event = StatusEvent::new(&payload, DisplayStatus::Creating, cli.state_key);
+                    if status_sender.send_if_changed(event).await {
+                        status_changes += 1;
+                    }
+                    continue;
+                }
+                match error {
+                    None => {
+                        consecutive_failures.remove(&data.state_key(cli.state_key));
+                        let root_cid = match match_root_cid(&candidates, &fetched) {
+                            Some((segment, cid)) => {
+                                debug!(
+                                    "Found root CID {} of {} ({} segment)",
+                                    cid, data.file, segment
+                                );
+                                cid
+                            }
+                            // Logged as missing along with the preferred segment.
+                            None => &candidates[0].1,
+                        };
+
+                        let event = classify_file(&payload, root_cid, &fetched, &cli);
+                        let status = event.as_ref().map(|event| event.status);
+                        // Roots are fetched fresh after a change, so whatever happens next, e.g.
+                        // further faults in the proofset, isn't hidden behind the cache.
+                        let previous = schedule.last_status(&data.state_key(cli.state_key));
+                        if previous.is_some() && previous != status {
+                            for proofset_id in &data.proofset_ids {
+                                cache.invalidate(proofset_id);
+                            }
+                        }
+                        schedule.record(data.state_key(cli.state_key), status);
+                        match event {
+                            Some(event) => {
+                                missing_since.remove(&data.state_key(cli.state_key));
+                                let status = event.status;
+                                if status_sender.send_if_changed(event).await {
+                                    status_changes += 1;
+                                }
+                                if status == DisplayStatus::Removed && cli.untrack_removed {
+                                    let tracked = {
+                                        let mut state = current_state.lock();
+                                        state.remove(&data.state_key(cli.state_key));
+                                        state.len()
+                                    };
+                                    gauge!("tracked_files").set(tracked as f64);
+                                    status_sender.clear(&payload, cli.state_key).await;
+                                }
+                            }
+                            None => {
+                                for (proofset_id, _) in &fetched {
+                                    explain_missing_root(
+                                        &provider,
+                                        &mut log_limiter,
+                                        proofset_id,
+                                        root_cid,
+                                        &data.file,
+                                    )
+                                    .await;
+                                }
+                                let since = *missing_since
+                                    .entry(data.state_key(cli.state_key))
+                                    .or_insert_with(Instant::now);
+                                let grace = Duration::from_secs(cli.api_root_grace_secs);
+                                if since.elapsed() >= grace {
+                                    log_limiter.log(
+                                        Level::Error,
+                                        "root_missing_after_grace",
+                                        format_args!(
+                                            "Root {} of {} still not found after {:?}",
+                                            root_cid, data.file, grace
+                                        ),
+                                    );
+                                    if status_sender
+                                        .send_if_changed(StatusEvent::new(
+                                            &payload,
+                                            DisplayStatus::Pending,
+                                            cli.state_key,
+                                        ))
+                                        .await
+                                    {
+                                        status_changes += 1;
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    Some(e) => {
+                        schedule.record(data.state_key(cli.state_key), None);
+                        let failures = consecutive_failures
+                            .entry(data.state_key(cli.state_key))
+                            .or_insert(0);
+                        *failures += 1;
+                        log_limiter.log(
+                            Level::Error,
+                            "api_failure",
+                            format_args!(
+                                "Failed to get roots from API ({} in a row): {:#}",
+                                failures, e
+                            ),
+                        );
+                        if *failures == cli.api_failure_threshold {
+                            warn!(
+                                "API failure threshold reached, applying {:?}",
+                                cli.on_api_failure
+                            );
+                            if let Some(status) = cli.on_api_failure.status() {
+                                if status_sender
+                                    .send_if_changed(StatusEvent::new(
+                                        &payload,
+                                        status,
+                                        cli.state_key,
+                                    ))
+                                    .await
+                                {
+                                    status_changes += 1;
+                                }
+                            }
+                        }
+                        let since = *confirmed_at
+                            .entry(data.state_key(cli.state_key))
+                            .or_insert_with(Instant::now);
+                        if let Some(stale) = cli.api_stale_secs.map(Duration::from_secs) {
+                            if since.elapsed() >= stale
+                                && status_sender
+                                    .send_if_changed(StatusEvent::new(
+                                        &payload,
+                                        DisplayStatus::Stale,
+                                        cli.state_key,
+                                    ))
+                                    .await
+                            {
+                                warn!(
+                                    "Status of {} not confirmed by the API for {:?}, showing it as stale",
+                                    data.file, stale
+                                );
+                                status_changes += 1;
+                            }
+                        }
+                    }
+                }
+            }
+        };
+        let cycle = cycle.instrument(tracing::info_span!("poll_cycle"));
+        tokio::select! {
+            finished = tokio::time::timeout(cycle_timeout, cycle) => {
+                if finished.is_err() {
+                    error!(
+                        "API poll cycle didn't finish within {:?}, abandoning it",
+                        cycle_timeout
+                    );
+                    counter!("api_poll_timeouts_total").increment(1);
+                }
+            }
+            _ = shutdown.cancelled() => {
+                info!("Abandoning the API poll cycle in progress to shut down");
+                break;
+            }
+        }
+        let elapsed = cycle_started.elapsed();
+        let requests = provider.requests_made() - requests_before;
+        histogram!("api_poll_cycle_duration_seconds").record(elapsed.as_secs_f64());
+        histogram!("api_poll_cycle_files_checked").record(files_checked as f64);
+        histogram!("api_poll_cycle_requests").record(requests as f64);
+        histogram!("api_poll_cycle_status_changes").record(status_changes as f64);
+        debug!(
+            "Poll cycle took {:?}: {} files checked, {} API requests, {} status changes",
+            elapsed, files_checked, requests, status_changes
+        );
+        if elapsed > interval {
+            warn!(
+                "Poll cycle took {:?}, longer than the {:?} poll interval, so polling is falling \
+                 behind. Consider raising --api-cache-ttl-secs or --api-max-poll-interval-secs",
+                elapsed, interval
+            );
+        }
+    }
+    info!("API checking task stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use metrics_util::{CompositeKey, MetricKind};
+
+    use super::*;
+    use crate::api::FakeProvider;
+    use crate::api::ProofSetRoot;
+    use crate::cli::ApiFailureMode;
+    use crate::handler::MessageHandler;
+    use crate::message::Stage;
+    use crate::serial::MockSerialSink;
+    use crate::status::StatusQueue;
+    use crate::test_util::{
+        cli, drain, payload, pipeline, proven_root, root, roots, sender, serial_lines,
+        stage_message,
+    };
+
+    const TTL: Duration = Duration::from_secs(5);
+
+    fn provider() -> FakeProvider {
+        let provider = FakeProvider::default();
+        provider.set_roots("1", roots(vec![root(1, "bafkroot")]));
+        provider
+    }
+
+    fn metric(recorder: &DebuggingRecorder, kind: MetricKind, name: &'static str) -> DebugValue {
+        let key = CompositeKey::new(kind, metrics::Key::from_static_name(name));
+        let (_, _, value) = recorder
+            .snapshotter()
+            .snapshot()
+            .into_hashmap()
+            .remove(&key)
+            .unwrap_or_else(|| panic!("{} wasn't recorded", name));
+        value
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cache_hits_fresh_roots() {
+        let provider = provider();
+        let mut cache = RootsCache::new(TTL);
+        fetch_roots_cached(&provider, &mut cache, "1", None)
+            .await
+            .unwrap();
+        tokio::time::advance(TTL - Duration::from_secs(1)).await;
+        let roots = fetch_roots_cached(&provider, &mut cache, "1", None)
+            .await
+            .unwrap();
+        assert_eq!(roots.data[0].cid, "bafkroot");
+        assert_eq!((cache.hits(), cache.misses(), cache.len()), (1, 1, 1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cache_expires_after_ttl() {
+        let mut cache = RootsCache::new(TTL);
+        cache.insert("1".to_string(), Arc::new(roots(vec![])));
+        tokio::time::advance(TTL).await;
+        assert!(cache.get("1").is_none());
+        assert_eq!((cache.hits(), cache.misses(), cache.len()), (0, 1, 0));
+
+        cache.insert("1".to_string(), Arc::new(roots(vec![])));
+        cache.insert("2".to_string(), Arc::new(roots(vec![])));
+        tokio::time::advance(TTL).await;
+        cache.evict_expired();
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cache_records_metrics() {
+        let recorder = DebuggingRecorder::new();
+        metrics::with_local_recorder(&recorder, || {
+            let mut cache = RootsCache::new(TTL);
+            assert!(cache.get("1").is_none());
+            cache.insert("1".to_string(), Arc::new(roots(vec![])));
+            cache.insert("2".to_string(), Arc::new(roots(vec![])));
+            assert!(cache.get("1").is_some());
+            assert!(cache.get("2").is_some());
+        });
+        assert_eq!(
+            metric(&recorder, MetricKind::Counter, "pdp_api_cache_hits"),
+            DebugValue::Counter(2)
+        );
+        assert_eq!(
+            metric(&recorder, MetricKind::Counter, "pdp_api_cache_misses"),
+            DebugValue::Counter(1)
+        );
+        assert_eq!(
+            metric(&recorder, MetricKind::Gauge, "pdp_api_cache_size"),
+            DebugValue::Gauge(2.0.into())
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn poll_cycles_record_their_work() {
+        let recorder = DebuggingRecorder::new();
+        let _recorder = metrics::set_default_local_recorder(&recorder);
+        let args = ["--api-cache-ttl-secs", "60"];
+        let _polling = start_polling(&args, provider(), vec![tracked_file()]);
+        poll_cycles(2).await;
+        // Taking a snapshot drains the histograms, so all of them come from one.
+        let mut snapshot = recorder.snapshotter().snapshot().into_hashmap();
+        let mut histogram = |name: &'static str| {
+            let key =
+                CompositeKey::new(MetricKind::Histogram, metrics::Key::from_static_name(name));
+            match snapshot.remove(&key) {
+                Some((_, _, DebugValue::Histogram(values))) => {
+                    values.into_iter().map(|value| value.0).collect::<Vec<_>>()
+                }
+                value => panic!("{} isn't a recorded histogram: {:?}", name, value),
+            }
+        };
+        assert_eq!(histogram("api_poll_cycle_files_checked"), [1.0, 1.0]);
+        // The second cycle takes the roots from the cache.
+        assert_eq!(histogram("api_poll_cycle_requests"), [1.0, 0.0]);
+        assert_eq!(histogram("api_poll_cycle_status_changes"), [1.0, 0.0]);
+        assert_eq!(histogram("api_poll_cycle_duration_seconds").len(), 2);
+    }
+
+    /// Tracked state and status queue of a running `poll_api`.
+    struct Polling {
+        current_state: Arc<Mutex<HashMap<String, ZmqPayload>>>,
+        queue: Arc<StatusQueue>,
+    }
+
+    /// Polls `provider` for `files` every `API_CHECK_INTERVAL_SECS`, without jitter.
+    fn start_polling(args: &[&str], provider: FakeProvider, files: Vec<ZmqPayload>) -> Polling {
+        let args: Vec<_> = ["--api-poll-jitter-percent", "0"]
+            .iter()
+            .chain(args)
+            .copied()
+            .collect();
+        let config = Arc::new(parking_lot::RwLock::new(cli(&args)));
+        let current_state = Arc::new(Mutex::new(
+            files
+                .into_iter()
+                .map(|payload| (payload.data.file.clone(), payload))
+                .collect(),
+        ));
+        let (status_sender, queue) = sender();
+        let diagnostics = Arc::new(Mutex::new(Diagnostics::new(16)));
+        tokio::spawn(poll_api(
+            provider,
+            current_state.clone(),
+            status_sender,
+            config,
+            diagnostics,
+            CancellationToken::new(),
+        ));
+        Polling {
+            current_state,
+            queue,
+        }
+    }
+
+    /// Waits for `cycles` more poll cycles to finish.
+    async fn poll_cycles(cycles: u64) {
+        tokio::time::sleep(Duration::from_secs(API_CHECK_INTERVAL_SECS) * cycles as u32).await;
+        // Lets the last cycle, due at the end of the sleep, run to completion.
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+
+    fn tracked_file() -> ZmqPayload {
+        payload("a.jpg", "baga6ea4piece:bafkroot", Stage::RootsAdded, &["1"])
+    }
+
+    fn unreachable_provider() -> FakeProvider {
+        let provider = FakeProvider::default();
+        provider.set_unreachable(true);
+        provider
+    }
+
+    #[test]
+    fn root_cid_candidates_follow_the_segment() {
+        let candidates = |file_id, segment| {
+            let data = payload("a.jpg", file_id, Stage::RootsAdded, &["1"]).data;
+            root_cid_candidates(&data, segment).unwrap()
+        };
+        let file_id = "baga6ea4piece:bafkroot";
+        assert_eq!(
+            candidates(file_id, RootCidSegment::First),
+            [("first", "baga6ea4piece".to_string())]
+        );
+        assert_eq!(
+            candidates(file_id, RootCidSegment::Second),
+            [("second", "bafkroot".to_string())]
+        );
+        assert_eq!(
+            candidates(file_id, RootCidSegment::Either),
+            [
+                ("second", "bafkroot".to_string()),
+                ("first", "baga6ea4piece".to_string())
+            ]
+        );
+        // Identical segments are looked up once.
+        assert_eq!(
+            candidates("bafkroot:bafkroot", RootCidSegment::Either),
+            [("second", "bafkroot".to_string())]
+        );
+        // A watched root has no segments to pick from.
+        let mut data = payload("a.jpg", "bafkwatched", Stage::RootsAdded, &["1"]).data;
+        data.watched_root = Some("bafkwatched".to_string());
+        assert_eq!(
+            root_cid_candidates(&data, RootCidSegment::First).unwrap(),
+            [("watched", "bafkwatched".to_string())]
+        );
+    }
+
+    /// Statuses sent after polling once for a file with `file_id`, whose proofset has a proven
+    /// `bafkroot` root.
+    async fn statuses_of_file_id(file_id: &str, segment: &str) -> Vec<DisplayStatus> {
+        let provider = FakeProvider::default();
+        provider.set_roots("1", roots(vec![proven_root(1, "bafkroot", 10)]));
+        let file = payload("a.jpg", file_id, Stage::RootsAdded, &["1"]);
+        let polling = start_polling(&["--root-cid-segment", segment], provider, vec![file]);
+        poll_cycles(1).await;
+        drain(&polling.queue)
+            .into_iter()
+            .map(|(_, status)| status)
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn root_matches_the_first_segment() {
+        let file_id = "bafkroot:bafkother";
+        for segment in ["first", "either"] {
+            assert_eq!(
+                statuses_of_file_id(file_id, segment).await,
+                [DisplayStatus::StoredProven],
+                "{}",
+                segment
+            );
+        }
+        assert_eq!(statuses_of_file_id(file_id, "second").await, []);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn root_matches_the_second_segment() {
+        let file_id = "baga6ea4piece:bafkroot";
+        for segment in ["second", "either"] {
+            assert_eq!(
+                statuses_of_file_id(file_id, segment).await,
+                [DisplayStatus::StoredProven],
+                "{}",
+                segment
+            );
+        }
+        assert_eq!(statuses_of_file_id(file_id, "first").await, []);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn root_matches_identical_segments() {
+        for segment in ["first", "second", "either"] {
+            assert_eq!(
+                statuses_of_file_id("bafkroot:bafkroot", segment).await,
+                [DisplayStatus::StoredProven],
+                "{}",
+                segment
+            );
+        }
+    }
+
+    /// Message handler and poller of `provider` writing the statuses to the returned mock sink.
+    fn start_pipeline(args: &[&str], provider: FakeProvider) -> (MessageHandler, MockSerialSink) {
+        let (handler, sink) = pipeline(&[&["--api-poll-jitter-percent", "0"], args].concat());
+        tokio::spawn(poll_api(
+            provider,
+            handler.state.current_state.clone(),
+            handler.status_sender.clone(),
+            handler.config.clone(),
+            handler.state.diagnostics.clone(),
+            CancellationToken::new(),
+        ));
+        (handler, sink)
+    }
+
+    async fn send(handler: &MessageHandler, file_id: &str, stage: &str) {
+        let message = stage_message("a.jpg", file_id, stage);
+        assert_eq!(handler.handle(&message).await, "ACK");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limited_files_dont_hold_up_the_others() {
+        let provider = provider();
+        provider.set_roots("2", roots(vec![root(1, "bafkother")]));
+        provider.set_throttled("2", true);
+        let throttled = payload(
+            "b.jpg",
+            "baga6ea4piece:bafkother",
+            Stage::RootsAdded,
+            &["2"],
+        );
+        let files = vec![throttled, tracked_file()];
+        let polling = start_polling(&[], provider.clone(), files);
+        poll_cycles(1).await;
+        assert_eq!(
+            drain(&polling.queue),
+            [("a.jpg".to_string(), DisplayStatus::Stored)]
+        );
+        assert_eq!(
+            provider.requests(),
+            1,
+            "requested the roots of a throttled file"
+        );
+        provider.set_throttled("2", false);
+        poll_cycles(1).await;
+        assert_eq!(
+            drain(&polling.queue),
+            [("b.jpg".to_string(), DisplayStatus::Stored)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn faulty_roots_reach_the_serial_sink() {
+        let provider = FakeProvider::default();
+        provider.set_roots("1", roots(vec![faulted_root(1, "bafkroot")]));
+        let (handler, sink) = start_pipeline(&[], provider);
+        send(&handler, "baga6ea4piece:bafkroot", "RootsAdded").await;
+        poll_cycles(1).await;
+        assert_eq!(
+            serial_lines(&sink, 3).await,
+            ["init\n", "a.jpg,stored\n", "a.jpg,stored & faulty\n"]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unconfirmed_status_goes_stale_until_confirmed_again() {
+        let provider = FakeProvider::default();
+        provider.set_roots("1", roots(vec![proven_root(1, "bafkroot", 10)]));
+        let (handler, sink) = start_pipeline(&["--api-stale-secs", "30"], provider.clone());
+        send(&handler, "baga6ea4piece:bafkroot", "RootsAdded").await;
+        poll_cycles(1).await;
+        let confirmed = ["init\n", "a.jpg,stored\n", "a.jpg,stored & proven\n"];
+        assert_eq!(serial_lines(&sink, 3).await, confirmed);
+
+        // Failing polls keep the last status until the window has passed.
+        provider.set_unreachable(true);
+        poll_cycles(3).await;
+        assert_eq!(sink.lines(), confirmed);
+        poll_cycles(4).await;
+        assert_eq!(
+            serial_lines(&sink, 4).await,
+            [&confirmed[..], &["a.jpg,stale\n"]].concat()
+        );
+        poll_cycles(2).await;
+        assert_eq!(sink.lines().len(), 4, "stale was sent again");
+
+        // The next successful poll restores the real status.
+        provider.set_unreachable(false);
+        poll_cycles(1).await;
+        assert_eq!(
+            serial_lines(&sink, 5).await,
+            [
+                &confirmed[..],
+                &["a.jpg,stale\n", "a.jpg,stored & proven\n"]
+            ]
+            .concat()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn transition_lines_are_sent_once_per_transition() {
+        let provider = FakeProvider::default();
+        provider.set_roots("1", roots(vec![proven_root(1, "bafkroot", 10)]));
+        let args = [
+            "--on-proven-line",
+            "BLINK {file}",
+            "--on-faulty-line",
+            "ALARM {file}",
+        ];
+        let (handler, sink) = start_pipeline(&args, provider.clone());
+        send(&handler, "baga6ea4piece:bafkroot", "RootsAdded").await;
+        // Polls confirming the proof, or finding a newer one, don't repeat the line.
+        poll_cycles(3).await;
+        provider.set_roots("1", roots(vec![proven_root(1, "bafkroot", 20)]));
+        poll_cycles(3).await;
+        provider.set_roots("1", roots(vec![faulted_root(1, "bafkroot")]));
+        poll_cycles(3).await;
+        let expected = [
+            "init\n",
+            "a.jpg,stored\n",
+            "a.jpg,stored & proven\n",
+            "BLINK a.jpg\n",
+            // The newer proof rewrites the status line alone.
+            "a.jpg,stored & proven\n",
+            "a.jpg,stored & faulty\n",
+            "ALARM a.jpg\n",
+        ];
+        assert_eq!(serial_lines(&sink, expected.len()).await, expected);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(sink.lines(), expected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn proofset_being_created_shows_as_creating_until_found() {
+        let provider = FakeProvider::default();
+        let (handler, sink) = start_pipeline(&[], provider.clone());
+        send(&handler, "baga6ea4piece:bafkroot", "RootsAdded").await;
+        // The proofset isn't found on the first cycles, and is retried on each of them.
+        poll_cycles(3).await;
+        assert!(provider.requests() >= 3, "{}", provider.requests());
+        assert_eq!(
+            serial_lines(&sink, 3).await,
+            ["init\n", "a.jpg,stored\n", "a.jpg,creating\n"]
+        );
+
+        provider.set_roots("1", roots(vec![proven_root(1, "bafkroot", 10)]));
+        poll_cycles(1).await;
+        assert_eq!(
+            serial_lines(&sink, 4).await,
+            [
+                "init\n",
+                "a.jpg,stored\n",
+                "a.jpg,creating\n",
+                "a.jpg,stored & proven\n"
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pipeline_writes_each_status_change_once() {
+        let provider = FakeProvider::default();
+        provider.set_roots("1", roots(vec![root(1, "bafkroot")]));
+        let (handler, sink) = start_pipeline(&[], provider.clone());
+        send(&handler, "baga6ea4piece:bafkroot", "Uploaded").await;
+        send(&handler, "baga6ea4piece:bafkroot", "RootsAdded").await;
+        // Polls confirming the stored root change nothing.
+        poll_cycles(2).await;
+        assert_eq!(
+            serial_lines(&sink, 3).await,
+            ["init\n", "a.jpg,uploaded\n", "a.jpg,stored\n"]
+        );
+
+        provider.set_roots("1", roots(vec![proven_root(1, "bafkroot", 10)]));
+        poll_cycles(1).await;
+        // Uploading the file again doesn't take it back from proven, nor does a new upload of it.
+        send(&handler, "baga6ea4piece:bafkroot", "Uploaded").await;
+        send(&handler, "baga6ea4other:bafkother", "Uploaded").await;
+        poll_cycles(2).await;
+        assert_eq!(
+            serial_lines(&sink, 4).await,
+            [
+                "init\n",
+                "a.jpg,uploaded\n",
+                "a.jpg,stored\n",
+                "a.jpg,stored & proven\n"
+            ]
+        );
+    }
+
+    /// Polls for `tracked_file` with a 10 second grace period, while its proofset has no roots.
+    fn start_polling_missing_root() -> (FakeProvider, Polling) {
+        let provider = FakeProvider::default();
+        provider.set_roots("1", roots(vec![]));
+        let polling = start_polling(
+            &["--api-root-grace-secs", "10"],
+            provider.clone(),
+            vec![tracked_file()],
+        );
+        (provider, polling)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn root_found_within_grace() {
+        let (provider, polling) = start_polling_missing_root();
+        poll_cycles(2).await;
+        provider.set_roots("1", roots(vec![root(1, "bafkroot")]));
+        poll_cycles(2).await;
+        assert_eq!(
+            drain(&polling.queue),
+            [("a.jpg".to_string(), DisplayStatus::Stored)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn root_found_after_grace() {
+        let (provider, polling) = start_polling_missing_root();
+        poll_cycles(3).await;
+        provider.set_roots("1", roots(vec![root(1, "bafkroot")]));
+        poll_cycles(1).await;
+        // Finding the root restarts the grace period.
+        provider.set_roots("1", roots(vec![]));
+        poll_cycles(2).await;
+        assert_eq!(
+            drain(&polling.queue),
+            [
+                ("a.jpg".to_string(), DisplayStatus::Pending),
+                ("a.jpg".to_string(), DisplayStatus::Stored)
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn root_never_found() {
+        let (_provider, polling) = start_polling_missing_root();
+        poll_cycles(2).await;
+        assert_eq!(drain(&polling.queue), [], "within the grace period");
+        poll_cycles(5).await;
+        assert_eq!(
+            drain(&polling.queue),
+            [("a.jpg".to_string(), DisplayStatus::Pending)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn removed_roots_clear_untracked_files() {
+        let provider = FakeProvider::default();
+        let removed = ProofSetRoot {
+            removed: true,
+            ..root(1, "bafkroot")
+        };
+        provider.set_roots("1", roots(vec![removed]));
+        let (handler, sink) = start_pipeline(&["--untrack-removed"], provider);
+        send(&handler, "baga6ea4piece:bafkroot", "RootsAdded").await;
+        poll_cycles(1).await;
+        assert_eq!(
+            serial_lines(&sink, 4).await,
+            [
+                "init\n",
+                "a.jpg,stored\n",
+                "a.jpg,removed\n",
+                "a.jpg,clear\n"
+            ]
+        );
+        assert!(handler.state.current_state.lock().is_empty());
+    }
+
+    fn faulted_root(root_id: u64, cid: &str) -> ProofSetRoot {
+        ProofSetRoot {
+            last_faulted_epoch: Some(20),
+            total_periods_faulted: 1,
+            ..proven_root(root_id, cid, 10)
+        }
+    }
+
+    /// Status of `tracked_file` and the proofset it was taken from, with the file's root in
+    /// proofset "1" as `first` and in proofset "2" as `second`.
+    fn classify_copies(first: ProofSetRoot, second: ProofSetRoot) -> (DisplayStatus, String) {
+        let ids = ["1".to_string(), "2".to_string()];
+        let fetched = [
+            (&ids[0], Arc::new(roots(vec![first]))),
+            (&ids[1], Arc::new(roots(vec![second]))),
+        ];
+        let event = classify_file(&tracked_file(), "bafkroot", &fetched, &cli(&[])).unwrap();
+        (event.status, event.proofset_id.unwrap())
+    }
+
+    #[test]
+    fn best_status_across_proofsets() {
+        let cases = [
+            (
+                root(1, "bafkroot"),
+                proven_root(2, "bafkroot", 10),
+                DisplayStatus::StoredProven,
+                "2",
+            ),
+            (
+                faulted_root(1, "bafkroot"),
+                proven_root(2, "bafkroot", 10),
+                DisplayStatus::StoredProven,
+                "2",
+            ),
+            // The only proven copy faulted.
+            (
+                faulted_root(1, "bafkroot"),
+                root(2, "bafkroot"),
+                DisplayStatus::StoredFaulty,
+                "1",
+            ),
+            (
+                faulted_root(1, "bafkroot"),
+                faulted_root(2, "bafkroot"),
+                DisplayStatus::StoredFaulty,
+                "1",
+            ),
+        ];
+        for (first, second, status, proofset_id) in cases {
+            assert_eq!(
+                classify_copies(first, second),
+                (status, proofset_id.to_string())
+            );
+        }
+    }
+
+    /// Never answers, as an API behind a half-open connection.
+    #[derive(Default, Clone)]
+    struct HangingProvider(Arc<std::sync::atomic::AtomicUsize>);
+
+    impl ProofStatusProvider for HangingProvider {
+        async fn fetch_roots(&self, _proofset_id: &str) -> Result<ProofSetRoots, PdpError> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            std::future::pending().await
+        }
+
+        async fn fetch_proofset(&self, _proofset_id: &str) -> Result<ProofSet, PdpError> {
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hung_poll_cycles_are_abandoned() {
+        let provider = HangingProvider::default();
+        let config = Arc::new(parking_lot::RwLock::new(cli(&[
+            "--api-poll-jitter-percent",
+            "0",
+            "--api-poll-timeout-secs",
+            "1",
+        ])));
+        let current_state = Arc::new(Mutex::new(HashMap::from([(
+            "a.jpg".to_string(),
+            tracked_file(),
+        )])));
+        let (status_sender, _queue) = sender();
+        let diagnostics = Arc::new(Mutex::new(Diagnostics::new(16)));
+        tokio::spawn(poll_api(
+            provider.clone(),
+            current_state,
+            status_sender,
+            config,
+            diagnostics,
+            CancellationToken::new(),
+        ));
+        // Each cycle hangs until its deadline, a second after it started.
+        tokio::time::sleep(Duration::from_secs(3 * (API_CHECK_INTERVAL_SECS + 1))).await;
+        // Every cycle asks again, so the hung requests didn't stop the loop.
+        assert_eq!(provider.0.load(std::sync::atomic::Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn polls_every_configured_interval() {
+        let provider = provider();
+        let args = [
+            "--api-poll-interval-secs",
+            "20",
+            "--api-cache-ttl-secs",
+            "0",
+        ];
+        let _polling = start_polling(&args, provider.clone(), vec![tracked_file()]);
+        tokio::time::sleep(Duration::from_secs(19)).await;
+        assert_eq!(provider.requests(), 0);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(provider.requests(), 1);
+        tokio::time::sleep(Duration::from_secs(20)).await;
+        assert_eq!(provider.requests(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelling_stops_a_hung_poll_cycle() {
+        let provider = HangingProvider::default();
+        let config = Arc::new(parking_lot::RwLock::new(cli(&[
+            "--api-poll-jitter-percent",
+            "0",
+        ])));
+        let current_state = Arc::new(Mutex::new(HashMap::from([(
+            "a.jpg".to_string(),
+            tracked_file(),
+        )])));
+        let (status_sender, _queue) = sender();
+        let shutdown = CancellationToken::new();
+        let polling = tokio::spawn(poll_api(
+            provider.clone(),
+            current_state,
+            status_sender,
+            config,
+            Arc::new(Mutex::new(Diagnostics::new(16))),
+            shutdown.clone(),
+        ));
+        poll_cycles(1).await;
+        assert_eq!(provider.0.load(std::sync::atomic::Ordering::Relaxed), 1);
+        // The cycle would only be abandoned after `--api-poll-timeout-secs`.
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(1), polling)
+            .await
+            .expect("Polling kept going after being cancelled")
+            .unwrap();
+    }
+
+    async fn statuses_after_failures(mode: &str) -> Vec<(String, DisplayStatus)> {
+        let polling = start_polling(
+            &["--on-api-failure", mode],
+            unreachable_provider(),
+            vec![tracked_file()],
+        );
+        poll_cycles(2).await;
+        assert_eq!(drain(&polling.queue), [], "before the threshold");
+        poll_cycles(3).await;
+        drain(&polling.queue)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn api_failure_holds_the_status() {
+        assert_eq!(statuses_after_failures("hold").await, []);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn api_failure_blanks_the_display() {
+        assert_eq!(
+            statuses_after_failures("blank").await,
+            [("a.jpg".to_string(), DisplayStatus::Blank)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn api_failure_shows_offline_indicator() {
+        assert_eq!(
+            statuses_after_failures("offline-indicator").await,
+            [("a.jpg".to_string(), DisplayStatus::Offline)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn api_failure_threshold_counts_consecutive_failures() {
+        let provider = unreachable_provider();
+        let polling = start_polling(
+            &["--on-api-failure", "blank", "--api-failure-threshold", "2"],
+            provider.clone(),
+            vec![tracked_file()],
+        );
+        poll_cycles(1).await;
+        assert_eq!(drain(&polling.queue), []);
+        // A success in between starts the count over.
+        provider.set_unreachable(false);
+        provider.set_roots("1", roots(vec![root(1, "bafkroot")]));
+        poll_cycles(1).await;
+        assert_eq!(
+            drain(&polling.queue),
+            [("a.jpg".to_string(), DisplayStatus::Stored)]
+        );
+        provider.set_unreachable(true);
+        poll_cycles(1).await;
+        assert_eq!(drain(&polling.queue), []);
+        poll_cycles(1).await;
+        assert_eq!(
+            drain(&polling.queue),
+            [("a.jpg".to_string(), DisplayStatus::Blank)]
+        );
+        assert_eq!(polling.current_state.lock().len(), 1);
+    }
+
+    #[test]
+    fn api_failure_threshold_is_at_least_one() {
+        let parsed =
+            <Cli as clap::Parser>::try_parse_from(["arduino-pdp", "--api-failure-threshold", "0"]);
+        assert!(parsed.is_err());
+        assert_eq!(cli(&[]).on_api_failure, ApiFailureMode::Hold);
+    }
+
+    #[test]
+    fn event_breaks_down_roots_of_the_file() {
+        let fetched = roots(vec![
+            ProofSetRoot {
+                last_proven_epoch: Some(120),
+                total_proofs_submitted: 4,
+                ..root(3, "bafkroot")
+            },
+            ProofSetRoot {
+                last_proven_epoch: Some(100),
+                last_faulted_epoch: Some(110),
+                total_proofs_submitted: 2,
+                total_periods_faulted: 1,
+                ..root(1, "bafkroot")
+            },
+            root(2, "bafkroot"),
+            proven_root(4, "bafkother", 130),
+        ]);
+        let proofset_id = "1".to_string();
+        let fetched = [(&proofset_id, Arc::new(fetched))];
+        let event = classify_file(&tracked_file(), "bafkroot", &fetched, &cli(&[])).unwrap();
+
+        assert_eq!(event.status, DisplayStatus::StoredFaulty);
+        let snapshot = serde_json::to_value(&event).unwrap();
+        assert_eq!(
+            snapshot["roots"],
+            serde_json::json!([
+                {"root_id": 1, "proven": false, "faulted": true, "epoch": 110, "proofs_submitted": 2},
+                {"root_id": 2, "proven": false, "faulted": false, "epoch": null, "proofs_submitted": 0},
+                {"root_id": 3, "proven": true, "faulted": false, "epoch": 120, "proofs_submitted": 4},
+            ])
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn repeated_messages_are_coalesced() {
+        let window = Duration::from_secs(LOG_COALESCE_WINDOW_SECS);
+        let mut limiter = LogLimiter::new(window);
+        assert_eq!(limiter.admit("api_failure"), Some(0));
+        for _ in 0..3 {
+            tokio::time::advance(Duration::from_secs(5)).await;
+            assert_eq!(limiter.admit("api_failure"), None);
+        }
+        // Other tags are limited separately.
+        assert_eq!(limiter.admit("root_missing"), Some(0));
+        tokio::time::advance(window).await;
+        assert_eq!(limiter.admit("api_failure"), Some(3));
+        assert_eq!(limiter.admit("api_failure"), None);
+        tokio::time::advance(window).await;
+        assert_eq!(limiter.admit("api_failure"), Some(1));
+    }
+}
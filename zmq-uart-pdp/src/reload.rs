@@ -0,0 +1,187 @@
+//! Reloading of the `--config` file and the `--watch-file` on SIGHUP, or of the `--config` file
+//! with the `reload` control command and `POST /reload`.
+
+#[cfg(unix)]
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use log::{error, info, warn};
+#[cfg(unix)]
+use parking_lot::Mutex;
+use parking_lot::RwLock;
+use serde::Serialize;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::cli::Cli;
+#[cfg(unix)]
+use crate::message::ZmqPayload;
+#[cfg(unix)]
+use crate::status::StatusSender;
+#[cfg(unix)]
+use crate::watch_file::WatchFile;
+
+/// Configuration shared with the tasks that pick up reloaded settings.
+pub(crate) type SharedConfig = Arc<RwLock<Cli>>;
+
+/// Settings changed by a reload of the `--config` file.
+#[derive(Serialize, Debug, Default)]
+pub(crate) struct ReloadSummary {
+    /// Settings whose new value was applied.
+    pub(crate) changed: Vec<String>,
+    /// Settings changed in the file that keep their current value until a restart.
+    pub(crate) requires_restart: Vec<String>,
+}
+
+/// Why a reload requested with a token was refused.
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum ReloadError {
+    #[error("Invalid token")]
+    InvalidToken,
+    #[error("No --config file to reload")]
+    NoConfigFile,
+    /// The file couldn't be read or holds invalid flags, nothing was applied.
+    #[error("{0:#}")]
+    InvalidConfig(anyhow::Error),
+}
+
+/// Reloads the `--config` file like SIGHUP on behalf of a client, which must present the
+/// `--reload-token` if one is set.
+pub(crate) fn reload_with_token(
+    config: &SharedConfig,
+    token: Option<&str>,
+) -> Result<ReloadSummary, ReloadError> {
+    let (has_config, expected) = {
+        let config = config.read();
+        (config.config.is_some(), config.reload_token.clone())
+    };
+    if expected.is_some() && token != expected.as_deref() {
+        warn!("Rejected reload with a wrong or missing token");
+        return Err(ReloadError::InvalidToken);
+    }
+    if !has_config {
+        return Err(ReloadError::NoConfigFile);
+    }
+    info!("Reloading configuration");
+    reload_config(config).map_err(|e| {
+        error!("Keeping the current configuration: {:#}", e);
+        ReloadError::InvalidConfig(e)
+    })
+}
+
+/// Re-reads the `--config` file and applies its reloadable settings, reporting other changed
+/// settings as requiring a restart. An invalid file is rejected as a whole, leaving the
+/// configuration unchanged.
+pub(crate) fn reload_config(config: &SharedConfig) -> anyhow::Result<ReloadSummary> {
+    let mut config = config.write();
+    let new = config.with_config_file()?;
+    let mut reloaded = config.clone();
+    reloaded.apply_reloadable(&new);
+    let mut summary = ReloadSummary::default();
+    // Redacted settings are compared by their real values, which never leave this function.
+    let fields = |cli: &Cli| match serde_json::to_value(cli) {
+        Ok(serde_json::Value::Object(mut fields)) => {
+            for (name, value) in cli.redacted_settings() {
+                fields.insert(name.to_string(), value);
+            }
+            Some(fields)
+        }
+        _ => None,
+    };
+    if let (Some(current_fields), Some(reloaded_fields), Some(new_fields)) =
+        (fields(&config), fields(&reloaded), fields(&new))
+    {
+        for (name, value) in new_fields {
+            if reloaded_fields.get(&name) != Some(&value) {
+                warn!(
+                    "Changing {} requires a restart, keeping the current value",
+                    name
+                );
+                summary.requires_restart.push(name);
+            } else if current_fields.get(&name) != Some(&value) {
+                summary.changed.push(name);
+            }
+        }
+    }
+    *config = reloaded;
+    info!("Configuration reloaded, changed: {:?}", summary.changed);
+    Ok(summary)
+}
+
+/// Re-reads the `--config` file on every SIGHUP, see [`reload_config`].
+#[cfg(unix)]
+pub(crate) async fn reload_on_sighup(config: SharedConfig) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            error!("Failed to install SIGHUP handler: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        info!("Reloading configuration");
+        if let Err(e) = reload_config(&config) {
+            error!("Keeping the current configuration: {:#}", e);
+        }
+    }
+}
+
+/// Re-reads the `--watch-file` on every SIGHUP.
+#[cfg(unix)]
+pub(crate) async fn reload_watch_file_on_sighup(
+    mut watch_file: WatchFile,
+    current_state: Arc<Mutex<HashMap<String, ZmqPayload>>>,
+    status_sender: StatusSender,
+) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            error!("Failed to install SIGHUP handler: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        info!("Reloading the watch file");
+        watch_file.sync(&current_state, &status_sender).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::cli;
+
+    /// Configuration loaded from a `--config` file holding `contents`, which can be rewritten.
+    fn config_file(contents: &str) -> (tempfile::TempDir, std::path::PathBuf, SharedConfig) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        std::fs::write(&path, contents).unwrap();
+        let config = cli(&["--config", path.to_str().unwrap()])
+            .with_config_file()
+            .unwrap();
+        (dir, path, Arc::new(RwLock::new(config)))
+    }
+
+    #[test]
+    fn poll_interval_is_reloaded() {
+        let (_dir, path, config) = config_file(r#"{"api-poll-interval-secs": 5}"#);
+        std::fs::write(&path, r#"{"api-poll-interval-secs": 30}"#).unwrap();
+        let summary = reload_config(&config).unwrap();
+        assert_eq!(summary.changed, ["api_poll_interval_secs"]);
+        assert_eq!(config.read().api_poll_interval_secs, 30);
+        // The cache follows the poll interval unless it's set.
+        assert_eq!(
+            config.read().api_cache_ttl(),
+            std::time::Duration::from_secs(30)
+        );
+    }
+
+    #[test]
+    fn redacted_settings_changes_are_reported() {
+        let (_dir, path, config) = config_file(r#"{"reload-token": "old"}"#);
+        std::fs::write(&path, r#"{"reload-token": "new"}"#).unwrap();
+        let summary = reload_config(&config).unwrap();
+        assert_eq!(summary.requires_restart, ["reload_token"]);
+        assert_eq!(config.read().reload_token.as_deref(), Some("old"));
+    }
+}
@@ -0,0 +1,94 @@
+//! Offline replay of recorded ZMQ messages through the message handling and classification.
+
+use std::{collections::HashMap, io::Read as _, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use chrono::{SecondsFormat, Utc};
+use parking_lot::Mutex;
+
+use crate::api::ProofSetRoots;
+use crate::cli::Cli;
+use crate::error::PdpError;
+use crate::message::{parse_zmq_msg, process_message, RecentMessages, Stage};
+use crate::poller::{classify_file, match_root_cid, root_cid_candidates};
+use crate::serial::SerialFormat;
+use crate::status::{StatusQueue, StatusSender};
+
+/// Replays the newline-delimited JSON payloads of `input` (`-` for stdin) and prints the serial
+/// lines the display would have been sent, each with the time it was produced. With a `roots`
+/// fixture, a JSON object of proofset ids to recorded roots responses of the API, files with roots
+/// added are classified after each message as the poller would.
+pub async fn replay(cli: &Cli, input: &str, roots: Option<&str>) -> anyhow::Result<()> {
+    let messages = if input == "-" {
+        let mut messages = String::new();
+        std::io::stdin()
+            .read_to_string(&mut messages)
+            .context("Failed to read messages from stdin")?;
+        messages
+    } else {
+        std::fs::read_to_string(input)
+            .with_context(|| format!("Failed to read messages from {}", input))?
+    };
+    let roots: Option<HashMap<String, ProofSetRoots>> = match roots {
+        Some(path) => {
+            let fixture = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read roots from {}", path))?;
+            let roots = serde_json::from_str(&fixture)
+                .with_context(|| format!("Failed to parse roots from {}", path))?;
+            Some(roots)
+        }
+        None => None,
+    };
+
+    let current_state = Mutex::new(HashMap::new());
+    let recent = Mutex::new(RecentMessages::new(Duration::from_secs(cli.dedup_ttl_secs)));
+    let queue = Arc::new(StatusQueue::new(cli.status_queue_size));
+    let status_sender = StatusSender::new(vec![queue.clone()], None, None);
+    let format = SerialFormat::from_cli(cli);
+    for (number, message) in messages.lines().enumerate() {
+        if message.trim().is_empty() {
+            continue;
+        }
+        let payload = match parse_zmq_msg(message) {
+            Ok(payload) => payload,
+            Err(e) => {
+                eprintln!("Line {}: NACK {:#}", number + 1, e);
+                continue;
+            }
+        };
+        let key = payload.data.state_key(cli.state_key);
+        process_message(payload, &current_state, &recent, &status_sender, cli).await;
+
+        let tracked = current_state.lock().get(&key).cloned();
+        if let (Some(recorded), Some(payload)) = (&roots, tracked) {
+            let candidates = root_cid_candidates(&payload.data, cli.root_cid_segment);
+            if let (Stage::RootsAdded, Ok(candidates)) = (&payload.stage, candidates) {
+                let mut fetched = Vec::new();
+                for proofset_id in &payload.data.proofset_ids {
+                    match recorded.get(proofset_id) {
+                        Some(roots) => fetched.push((proofset_id, Arc::new(roots.clone()))),
+                        None => eprintln!(
+                            "Line {}: {}",
+                            number + 1,
+                            PdpError::ProofsetNotFound(proofset_id.clone())
+                        ),
+                    }
+                }
+                let root_cid = match match_root_cid(&candidates, &fetched) {
+                    Some((_, cid)) => cid,
+                    None => &candidates[0].1,
+                };
+                let event = classify_file(&payload, root_cid, &fetched, cli);
+                if let Some(event) = event {
+                    status_sender.send_if_changed(event).await;
+                }
+            }
+        }
+
+        while let Some(event) = queue.try_recv() {
+            let time = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+            println!("{} {}", time, format.line(&event).trim_end());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,1765 @@
+//! Serial link to the Arduino and the line format written to it.
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use log::{debug, error, info, warn};
+use metrics::counter;
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::cli::{
+    Cli, DisplayOutput, ProofCountRoot, SelfTestFailureMode, SerialChecksum, SerialRoute,
+};
+use crate::error::PdpError;
+use crate::message::ZmqPayload;
+use crate::reload::SharedConfig;
+use crate::state::{Diagnostics, PersistedState, RecentEvent, RecentEventKind, TimestampedError};
+use crate::status::{DisplayStatus, RootStatus, StatusEvent, StatusQueue};
+use crate::stdout_display::StdoutSink;
+
+pub(crate) const SERIAL_TIMEOUT_MS: u64 = 10;
+pub(crate) const ARDUINO_RESET_DELAY_SECS: u64 = 2;
+pub(crate) const SERIAL_RECONNECT_INITIAL_BACKOFF_SECS: u64 = 1;
+pub(crate) const SERIAL_RECONNECT_MAX_BACKOFF_SECS: u64 = 30;
+pub(crate) const SERIAL_READ_RETRY_DELAY_MS: u64 = 100;
+// How long DTR is held low to reset the Arduino, see `--watchdog`.
+pub(crate) const ARDUINO_DTR_PULSE_MS: u64 = 100;
+// How long a line that the port only takes in part, or not at all for now, is retried.
+pub(crate) const SERIAL_WRITE_RETRY_TIMEOUT_MS: u64 = 1_000;
+pub(crate) const SERIAL_WRITE_RETRY_DELAY_MS: u64 = 5;
+// Written every `--heartbeat-interval-secs`. The firmware ignores lines without a comma.
+pub(crate) const HEARTBEAT_LINE: &str = "HEARTBEAT\n";
+// Command of the `--serial-self-test` line, followed by a random nonce the Arduino echoes back.
+pub(crate) const SELF_TEST_COMMAND: &str = "PING";
+// How long `--serial-self-test` waits for the echo.
+pub(crate) const SELF_TEST_TIMEOUT_MS: u64 = 2_000;
+// Default `--init-line`, written once the port is ready.
+pub(crate) const DEFAULT_INIT_LINE: &str = "init";
+// Polynomial of `--serial-checksum crc8`, x^8 + x^2 + x + 1.
+pub(crate) const CRC8_POLYNOMIAL: u8 = 0x07;
+// Header of a frame of several status lines, followed by their number, see `--serial-batch-ms`.
+pub(crate) const BATCH_HEADER: &str = "BATCH";
+
+/// Format of the lines written to the serial port.
+#[derive(Debug, Clone)]
+pub struct SerialFormat {
+    pub(crate) filename: FilenameFormat,
+    /// Append the proofset id and a short CID: `{file},{status},{proofset_id},{cid_short}`.
+    pub(crate) verbose: bool,
+    pub(crate) cid_len: usize,
+    pub(crate) status_strings: StatusStrings,
+    /// Append how long ago the file was last proven to proven statuses, e.g. `stored & proven 5m ago`.
+    pub(crate) proof_age: bool,
+    /// Checksum appended to each line as `*XX`, see `--serial-checksum`.
+    pub(crate) checksum: Option<SerialChecksum>,
+    /// Root whose proof count is appended to each line, `None` not to append it.
+    pub(crate) proof_count: Option<ProofCountRoot>,
+    /// One-shot lines sent when a file becomes proven or faulty, see `--on-proven-line`.
+    pub(crate) on_proven_line: Option<String>,
+    pub(crate) on_faulty_line: Option<String>,
+}
+
+impl SerialFormat {
+    pub(crate) fn from_cli(cli: &Cli) -> Self {
+        Self {
+            filename: FilenameFormat::from_cli(cli),
+            verbose: cli.serial_verbose,
+            cid_len: cli.serial_cid_len,
+            status_strings: cli.status_strings.clone().unwrap_or_default(),
+            proof_age: cli.serial_proof_age,
+            checksum: cli.serial_checksum,
+            proof_count: cli
+                .serial_proof_count
+                .then_some(cli.serial_proof_count_root),
+            on_proven_line: cli.on_proven_line.clone(),
+            on_faulty_line: cli.on_faulty_line.clone(),
+        }
+    }
+
+    /// One-shot line for `event` if it moves its file from `previous` into `stored & proven`, from
+    /// a status that isn't proven, or into `stored & faulty`. `{file}` in the line is replaced with
+    /// the displayed filename.
+    pub(crate) fn transition_line(
+        &self,
+        previous: Option<DisplayStatus>,
+        event: &StatusEvent,
+    ) -> Option<String> {
+        let line = match event.status {
+            DisplayStatus::StoredProven if !previous.is_some_and(DisplayStatus::is_proven) => {
+                self.on_proven_line.as_ref()
+            }
+            DisplayStatus::StoredFaulty if previous != Some(DisplayStatus::StoredFaulty) => {
+                self.on_faulty_line.as_ref()
+            }
+            _ => None,
+        }?;
+        Some(format!(
+            "{}\n",
+            line.replace("{file}", &self.filename.apply(&event.file))
+        ))
+    }
+
+    pub(crate) fn line(&self, event: &StatusEvent) -> String {
+        let file = self.filename.apply(&event.file);
+        let mut status = self.status_strings.get(event.status);
+        if let (true, true, Some(proven_at)) = (
+            self.proof_age,
+            event.status.is_proven(),
+            event.last_proven_at,
+        ) {
+            status = format!("{} {}", status, proof_age(proven_at, Utc::now()));
+        }
+        let mut payload = if self.verbose {
+            // The CID's leading characters are shared by most CIDs, so keep the trailing ones.
+            let cid: Vec<char> = event.cid.as_deref().unwrap_or_default().chars().collect();
+            let cid_short: String = cid[cid.len().saturating_sub(self.cid_len)..]
+                .iter()
+                .collect();
+            format!(
+                "{},{},{},{}",
+                file,
+                status,
+                event.proofset_id.as_deref().unwrap_or_default(),
+                cid_short
+            )
+        } else {
+            format!("{},{}", file, status)
+        };
+        if let Some(root) = self.proof_count {
+            payload = format!("{},{}", payload, proof_count(&event.roots, root));
+        }
+        match self.checksum {
+            Some(algorithm) => format!(
+                "{}*{:02X}\n",
+                payload,
+                algorithm.compute(payload.as_bytes())
+            ),
+            None => format!("{}\n", payload),
+        }
+    }
+}
+
+impl SerialChecksum {
+    /// Checksum of a line's `payload`: the sum of its bytes modulo 256 for `sum8`, or CRC-8 with
+    /// polynomial 0x07, initial value 0, no reflection and no final XOR for `crc8`.
+    pub(crate) fn compute(self, payload: &[u8]) -> u8 {
+        match self {
+            SerialChecksum::Sum8 => payload.iter().fold(0, |sum, &byte| sum.wrapping_add(byte)),
+            SerialChecksum::Crc8 => payload.iter().fold(0, |crc, &byte| {
+                (0..8).fold(crc ^ byte, |crc, _| {
+                    if crc & 0x80 != 0 {
+                        (crc << 1) ^ CRC8_POLYNOMIAL
+                    } else {
+                        crc << 1
+                    }
+                })
+            }),
+        }
+    }
+}
+
+/// Proofs submitted for the root of `roots` picked by `root`, 0 if there are none.
+pub(crate) fn proof_count(roots: &[RootStatus], root: ProofCountRoot) -> u64 {
+    let root = match root {
+        ProofCountRoot::Max => roots.iter().max_by_key(|root| root.proofs_submitted),
+        ProofCountRoot::Latest => roots.iter().max_by_key(|root| (root.epoch, root.root_id)),
+    };
+    root.map_or(0, |root| root.proofs_submitted)
+}
+
+/// Formats the time between `proven_at` and `now` in its largest whole unit, e.g. `5m ago`.
+pub(crate) fn proof_age(proven_at: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let secs = (now - proven_at).num_seconds().max(0);
+    match secs {
+        0..60 => format!("{}s ago", secs),
+        60..3_600 => format!("{}m ago", secs / 60),
+        3_600..86_400 => format!("{}h ago", secs / 3_600),
+        _ => format!("{}d ago", secs / 86_400),
+    }
+}
+
+/// Strings sent to the Arduino for each status, overriding the default status strings, e.g. for
+/// firmware expecting `OK`/`FAIL`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StatusStrings(HashMap<DisplayStatus, String>);
+
+impl StatusStrings {
+    /// Loads a JSON object mapping default status strings to the strings to send instead.
+    /// Statuses left out keep their default string.
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read status strings from {}", path))?;
+        let mapping: HashMap<String, String> = serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse status strings from {}", path))?;
+        let mut strings = HashMap::new();
+        for (status, string) in mapping {
+            let status: DisplayStatus = status.parse().with_context(|| {
+                format!(
+                    "Unknown status in {}, expected one of {:?}",
+                    path,
+                    DisplayStatus::ALL.map(|status| status.to_string())
+                )
+            })?;
+            strings.insert(status, string);
+        }
+        Ok(Self(strings))
+    }
+
+    pub fn get(&self, status: DisplayStatus) -> String {
+        match self.0.get(&status) {
+            Some(string) => string.clone(),
+            None => status.to_string(),
+        }
+    }
+}
+
+/// How filenames are normalized before being sent to the display. The tracked state and status
+/// snapshot keep the original name.
+#[derive(Debug, Clone)]
+pub(crate) struct FilenameFormat {
+    pub(crate) strip_prefix: Option<String>,
+    pub(crate) max_len: usize,
+    pub(crate) drop_extension: bool,
+}
+
+impl FilenameFormat {
+    /// The firmware shows the filename on the first row and the status on the second, so names
+    /// are limited to `--display-width` and to `--display-max-len` if that is shorter.
+    pub(crate) fn from_cli(cli: &Cli) -> Self {
+        Self {
+            strip_prefix: cli.display_strip_prefix.clone(),
+            max_len: cli
+                .display_max_len
+                .map_or(cli.display_width, |max_len| max_len.min(cli.display_width)),
+            drop_extension: cli.display_drop_extension,
+        }
+    }
+
+    pub(crate) fn apply(&self, filename: &str) -> String {
+        let mut name = filename;
+        if let Some(prefix) = &self.strip_prefix {
+            if let Some(stripped) = name.strip_prefix(prefix.as_str()) {
+                name = stripped.trim_start_matches('/');
+            }
+        }
+        if self.drop_extension {
+            // A leading dot marks a hidden file rather than an extension.
+            if let Some(idx) = name.rfind('.').filter(|&idx| idx > 0) {
+                if !name[idx..].contains('/') {
+                    name = &name[..idx];
+                }
+            }
+        }
+        truncate_with_ellipsis(name, self.max_len)
+    }
+}
+
+pub(crate) const ELLIPSIS: &str = "...";
+
+/// Truncates `text` to at most `max_len` characters, ending it with an ellipsis if it was cut.
+pub(crate) fn truncate_with_ellipsis(text: &str, max_len: usize) -> String {
+    if text.chars().count() <= max_len {
+        return text.to_string();
+    }
+    if max_len <= ELLIPSIS.len() {
+        return text.chars().take(max_len).collect();
+    }
+    let mut truncated: String = text.chars().take(max_len - ELLIPSIS.len()).collect();
+    truncated.push_str(ELLIPSIS);
+    truncated
+}
+
+/// Destination of the lines written to the Arduino.
+pub trait SerialSink {
+    fn write_line(&mut self, line: &str) -> std::io::Result<()>;
+
+    /// Takes the format of the lines about to be written, which a reload may have changed.
+    fn set_format(&mut self, _format: &SerialFormat) {}
+
+    /// Restores the connection after a failed write.
+    fn reconnect(&mut self) -> impl std::future::Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// Resets the device behind the sink, e.g. after it stopped responding.
+    fn reset(&mut self) -> impl std::future::Future<Output = std::io::Result<()>> + Send {
+        async { Ok(()) }
+    }
+}
+
+pub(crate) type SerialReader = Arc<Mutex<Option<Box<dyn serialport::SerialPort>>>>;
+
+/// Serial connection to the Arduino, reopened when it breaks, e.g. because the cable was unplugged.
+/// Writes go through `port`, reads through a clone of it shared with the reader thread so neither
+/// blocks the other.
+pub(crate) struct SerialLink {
+    pub(crate) path: String,
+    pub(crate) baud: u32,
+    pub(crate) port: Box<dyn serialport::SerialPort>,
+    pub(crate) reader: SerialReader,
+    pub(crate) reconnect_attempts: u64,
+    /// Line the Arduino prints once it booted, empty to only wait the reset delay.
+    pub(crate) ready_banner: String,
+    pub(crate) ready_timeout: Duration,
+    pub(crate) reset_delay: Duration,
+    pub(crate) timeout: Duration,
+}
+
+impl SerialLink {
+    /// Opens the port at `path` and waits for the Arduino to boot, as opening the port resets it.
+    pub(crate) fn open(cli: &Cli, path: &str) -> Result<Self, PdpError> {
+        let timeout = Duration::from_millis(cli.serial_timeout_ms);
+        let reset_delay = Duration::from_secs(cli.arduino_reset_delay_secs);
+        info!(
+            "Opening serial port {} at {} baud, timeout {:?}, reset delay {:?}",
+            path, cli.baud, timeout, reset_delay
+        );
+        let mut port = open_serial_port(path, cli.baud, timeout)?;
+        let ready_timeout = Duration::from_millis(cli.ready_timeout_ms);
+        wait_until_ready(port.as_mut(), &cli.ready_banner, ready_timeout, reset_delay);
+        let reader = Arc::new(Mutex::new(Some(port.try_clone()?)));
+        Ok(Self {
+            path: path.to_string(),
+            baud: cli.baud,
+            port,
+            reader,
+            reconnect_attempts: 0,
+            ready_banner: cli.ready_banner.clone(),
+            ready_timeout,
+            reset_delay,
+            timeout,
+        })
+    }
+
+    /// Read half of the port, swapped for the reopened port on reconnect.
+    pub(crate) fn reader(&self) -> SerialReader {
+        self.reader.clone()
+    }
+}
+
+impl SerialSink for SerialLink {
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        write_fully(&mut self.port, line.as_bytes())
+    }
+
+    /// Reopens the port, retrying with exponential backoff until it succeeds.
+    async fn reconnect(&mut self) {
+        let mut backoff = Duration::from_secs(SERIAL_RECONNECT_INITIAL_BACKOFF_SECS);
+        loop {
+            self.reconnect_attempts += 1;
+            warn!(
+                "Reopening serial port {} (reconnect attempt {})",
+                self.path, self.reconnect_attempts
+            );
+            match open_serial_port(&self.path, self.baud, self.timeout) {
+                Ok(mut port) => {
+                    info!("Serial port {} reopened", self.path);
+                    // Blocks the serial writer thread, which can't write until the Arduino is
+                    // back anyway. Waiting before the reader thread gets the port keeps it from
+                    // taking the banner for an echo.
+                    wait_until_ready(
+                        port.as_mut(),
+                        &self.ready_banner,
+                        self.ready_timeout,
+                        self.reset_delay,
+                    );
+                    *self.reader.lock() = port
+                        .try_clone()
+                        .inspect_err(|e| warn!("Failed to clone serial port for reading: {}", e))
+                        .ok();
+                    self.port = port;
+                    return;
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to reopen serial port {}: {}, retrying in {:?}",
+                        self.path, e, backoff
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff =
+                        (backoff * 2).min(Duration::from_secs(SERIAL_RECONNECT_MAX_BACKOFF_SECS));
+                }
+            }
+        }
+    }
+
+    /// Resets the Arduino by pulsing DTR low, then waits for it to boot.
+    async fn reset(&mut self) -> std::io::Result<()> {
+        // Keeps the reader thread from taking the banner for an echo.
+        let reader = self.reader.lock().take();
+        let result = self.port.write_data_terminal_ready(false).and_then(|()| {
+            std::thread::sleep(Duration::from_millis(ARDUINO_DTR_PULSE_MS));
+            self.port.write_data_terminal_ready(true)
+        });
+        if result.is_ok() {
+            wait_until_ready(
+                self.port.as_mut(),
+                &self.ready_banner,
+                self.ready_timeout,
+                self.reset_delay,
+            );
+        }
+        *self.reader.lock() = reader;
+        Ok(result?)
+    }
+}
+
+/// In-memory serial sink recording the written lines, used instead of the Arduino with
+/// `--mock-serial`.
+#[derive(Debug, Default, Clone)]
+pub struct MockSerialSink {
+    pub lines: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockSerialSink {
+    /// Lines written so far, shared with the clones of this sink.
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.lock().clone()
+    }
+}
+
+impl SerialSink for MockSerialSink {
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        info!("Mock serial: {}", line.trim_end());
+        self.lines.lock().push(line.to_string());
+        Ok(())
+    }
+}
+
+/// Waits for the Arduino to print `banner` after the reset caused by opening the port. Without a
+/// banner, or if it doesn't arrive within `timeout`, waits out `reset_delay` instead.
+fn wait_until_ready(
+    port: &mut dyn serialport::SerialPort,
+    banner: &str,
+    timeout: Duration,
+    reset_delay: Duration,
+) {
+    let started = Instant::now();
+    if !banner.is_empty() {
+        let mut line = Vec::new();
+        let mut buf = [0u8; 64];
+        while started.elapsed() < timeout {
+            match port.read(&mut buf) {
+                Ok(read) => {
+                    for &byte in &buf[..read] {
+                        if byte != b'\n' {
+                            line.push(byte);
+                            continue;
+                        }
+                        if String::from_utf8_lossy(&line).trim() == banner {
+                            info!("Arduino ready after {:?}", started.elapsed());
+                            return;
+                        }
+                        line.clear();
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {}
+                Err(e) => {
+                    warn!("Failed to read the Arduino boot banner: {}", e);
+                    break;
+                }
+            }
+        }
+        warn!(
+            "No {:?} banner from the Arduino within {:?}, falling back to the reset delay",
+            banner, timeout
+        );
+    }
+    // sleep because arduino will restart after opening the port and adding a sleep is less hassle
+    // than adding a capacitor to the reset pin.
+    // https://forum.arduino.cc/t/autoreset-disabling/350095/4
+    if let Some(remaining) = reset_delay.checked_sub(started.elapsed()) {
+        std::thread::sleep(remaining);
+    }
+}
+
+/// Serial sink logging the lines instead of writing them, used with `--dry-run`. The lines of an
+/// `--extra-serial-port` are labelled with its path.
+#[derive(Debug, Default, Clone)]
+pub struct DryRunSink(pub Option<String>);
+
+impl SerialSink for DryRunSink {
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        match &self.0 {
+            Some(path) => info!("[dry-run {}] {}", path, line.trim_end()),
+            None => info!("[dry-run] {}", line.trim_end()),
+        }
+        Ok(())
+    }
+}
+
+/// Serial sink selected on the command line.
+pub(crate) enum SerialOutput {
+    Port(SerialLink),
+    Mock(MockSerialSink),
+    DryRun(DryRunSink),
+    Stdout(StdoutSink),
+}
+
+impl SerialOutput {
+    /// Opens the serial port at `path`, unless `--dry-run`, `--mock-serial` or `--display stdout`
+    /// replace it. Only `--serial-port` is drawn on stdout, the lines of an `--extra-serial-port`
+    /// are logged as with `--dry-run`.
+    pub(crate) fn open(cli: &Cli, path: &str) -> Result<Self, PdpError> {
+        if cli.display == DisplayOutput::Stdout && path == cli.serial_port {
+            info!("Drawing the statuses on stdout instead of the serial port");
+            Ok(SerialOutput::Stdout(StdoutSink::new(cli)))
+        } else if cli.dry_run || cli.display == DisplayOutput::Stdout {
+            info!("Dry run, logging serial lines instead of writing them");
+            let label = (path != cli.serial_port).then(|| path.to_string());
+            Ok(SerialOutput::DryRun(DryRunSink(label)))
+        } else if cli.mock_serial {
+            info!("Using mock serial port");
+            Ok(SerialOutput::Mock(MockSerialSink::default()))
+        } else {
+            SerialLink::open(cli, path).map(SerialOutput::Port)
+        }
+    }
+
+    /// The real serial port, if one is used.
+    pub(crate) fn port(&self) -> Option<&SerialLink> {
+        match self {
+            SerialOutput::Port(port) => Some(port),
+            SerialOutput::Mock(_) | SerialOutput::DryRun(_) | SerialOutput::Stdout(_) => None,
+        }
+    }
+}
+
+impl SerialSink for SerialOutput {
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        match self {
+            SerialOutput::Port(port) => port.write_line(line),
+            SerialOutput::Mock(mock) => mock.write_line(line),
+            SerialOutput::DryRun(dry_run) => dry_run.write_line(line),
+            SerialOutput::Stdout(stdout) => stdout.write_line(line),
+        }
+    }
+
+    fn set_format(&mut self, format: &SerialFormat) {
+        match self {
+            SerialOutput::Port(port) => port.set_format(format),
+            SerialOutput::Mock(mock) => mock.set_format(format),
+            SerialOutput::DryRun(dry_run) => dry_run.set_format(format),
+            SerialOutput::Stdout(stdout) => stdout.set_format(format),
+        }
+    }
+
+    async fn reconnect(&mut self) {
+        match self {
+            SerialOutput::Port(port) => port.reconnect().await,
+            SerialOutput::Mock(mock) => mock.reconnect().await,
+            SerialOutput::DryRun(dry_run) => dry_run.reconnect().await,
+            SerialOutput::Stdout(stdout) => stdout.reconnect().await,
+        }
+    }
+
+    async fn reset(&mut self) -> std::io::Result<()> {
+        match self {
+            SerialOutput::Port(port) => port.reset().await,
+            SerialOutput::Mock(mock) => mock.reset().await,
+            SerialOutput::DryRun(dry_run) => dry_run.reset().await,
+            SerialOutput::Stdout(stdout) => stdout.reset().await,
+        }
+    }
+}
+
+/// Reads newline-delimited lines sent by the Arduino on a dedicated thread, since serial reads
+/// block.
+pub(crate) fn spawn_serial_reader(reader: SerialReader, lines: mpsc::Sender<String>) {
+    std::thread::spawn(move || {
+        let mut line = Vec::new();
+        let mut buf = [0u8; 64];
+        loop {
+            let read = match reader.lock().as_mut() {
+                Some(port) => port.read(&mut buf),
+                None => Err(std::io::ErrorKind::NotConnected.into()),
+            };
+            match read {
+                Ok(read) => {
+                    for &byte in &buf[..read] {
+                        if byte != b'\n' {
+                            line.push(byte);
+                            continue;
+                        }
+                        let received = String::from_utf8_lossy(&line).trim_end().to_string();
+                        line.clear();
+                        if lines.blocking_send(received).is_err() {
+                            return;
+                        }
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {}
+                Err(e) => {
+                    // The writer notices a broken port and reconnects it.
+                    debug!("Failed to read from serial port: {}", e);
+                    std::thread::sleep(Duration::from_millis(SERIAL_READ_RETRY_DELAY_MS));
+                }
+            }
+        }
+    });
+}
+
+/// A line written to the Arduino that it hasn't echoed back yet.
+pub(crate) struct PendingAck {
+    pub(crate) line: String,
+    pub(crate) sent_at: Instant,
+    pub(crate) resent: bool,
+}
+
+/// Writes status events to the Arduino, mirroring them into the shared statuses, the state file
+/// and the status socket. With several serial ports, each has a writer showing the statuses of its
+/// route, and the writer of `--serial-port` alone mirrors every event.
+pub(crate) struct SerialWriter<S> {
+    pub(crate) serial: S,
+    pub(crate) format: SerialFormat,
+    /// Source of the reloadable display settings of `format`.
+    pub(crate) config: SharedConfig,
+    pub(crate) statuses: Arc<Mutex<HashMap<String, StatusEvent>>>,
+    pub(crate) current_state: Arc<Mutex<HashMap<String, ZmqPayload>>>,
+    pub(crate) diagnostics: Arc<Mutex<Diagnostics>>,
+    pub(crate) state_file: Option<String>,
+    pub(crate) events: broadcast::Sender<StatusEvent>,
+    /// How long to wait for the Arduino to echo a line, `None` if it doesn't echo.
+    pub(crate) ack_timeout: Option<Duration>,
+    /// Lines awaiting an echo, keyed by the filename they were written for.
+    pub(crate) pending_acks: HashMap<String, PendingAck>,
+    pub(crate) heartbeat: Option<Heartbeat>,
+    pub(crate) watchdog: Option<Watchdog>,
+    /// Window within which updates are written as one frame, `None` to write them one by one.
+    pub(crate) batch_window: Option<Duration>,
+    /// Minimum time between status writes, `None` to write them as they arrive.
+    pub(crate) min_write_interval: Option<Duration>,
+    /// Whether to check the Arduino echoes a line before anything else is written, and what to do
+    /// if it doesn't, see `--serial-self-test`.
+    pub(crate) self_test: Option<SelfTestFailureMode>,
+    /// Line written before the first status, see `--init-line`. `None` once written or if the
+    /// display gets restored statuses instead.
+    pub(crate) init_line: Option<String>,
+    /// Statuses written to this port, see `--serial-route`.
+    pub(crate) route: SerialRoute,
+    /// Status this port shows for each file, so the file is cleared once its status leaves the
+    /// route and one-shot lines are only sent on transitions. Starts with the restored statuses.
+    pub(crate) shown: HashMap<String, DisplayStatus>,
+    /// Whether events are mirrored into the shared statuses, the state file and the status socket.
+    pub(crate) mirrors_events: bool,
+}
+
+/// Periodic heartbeat line, see `--heartbeat-interval-secs`.
+pub(crate) struct Heartbeat {
+    pub(crate) interval: Duration,
+    /// Consecutive unechoed heartbeats after which the display is considered hung.
+    pub(crate) max_misses: u32,
+    /// Reconnect the serial port once the display is considered hung.
+    pub(crate) reconnect: bool,
+    /// Whether the last heartbeat hasn't been echoed yet.
+    pub(crate) pending: bool,
+    pub(crate) missed: u32,
+}
+
+/// Resets an unresponsive Arduino, see `--watchdog`.
+pub(crate) struct Watchdog {
+    pub(crate) max_misses: u32,
+    /// Consecutive lines and heartbeats the Arduino didn't echo.
+    pub(crate) missed: u32,
+}
+
+impl<S: SerialSink> SerialWriter<S> {
+    pub(crate) fn new(
+        serial: S,
+        config: SharedConfig,
+        statuses: Arc<Mutex<HashMap<String, StatusEvent>>>,
+        current_state: Arc<Mutex<HashMap<String, ZmqPayload>>>,
+        diagnostics: Arc<Mutex<Diagnostics>>,
+        events: broadcast::Sender<StatusEvent>,
+        ack_timeout: Option<Duration>,
+    ) -> Self {
+        let cli = config.read().clone();
+        let shown = statuses
+            .lock()
+            .iter()
+            .map(|(file, event)| (file.clone(), event.status))
+            .collect();
+        Self {
+            serial,
+            format: SerialFormat::from_cli(&cli),
+            config,
+            statuses,
+            current_state,
+            diagnostics,
+            state_file: cli.state_file.clone(),
+            events,
+            ack_timeout,
+            pending_acks: HashMap::new(),
+            heartbeat: cli.heartbeat_interval_secs.map(|secs| Heartbeat {
+                interval: Duration::from_secs(secs),
+                max_misses: cli.heartbeat_max_misses,
+                reconnect: cli.heartbeat_reconnect,
+                pending: false,
+                missed: 0,
+            }),
+            watchdog: cli.watchdog.then_some(Watchdog {
+                max_misses: cli.watchdog_max_misses,
+                missed: 0,
+            }),
+            batch_window: cli.serial_batch_ms.map(Duration::from_millis),
+            min_write_interval: cli.serial_min_interval_ms.map(Duration::from_millis),
+            self_test: None,
+            init_line: (!cli.init_line.is_empty()).then(|| format!("{}\n", cli.init_line)),
+            route: cli.serial_route,
+            shown,
+            mirrors_events: true,
+        }
+    }
+
+    /// Runs the writer on a dedicated thread with its own single-threaded runtime. Serial writes
+    /// block until the Arduino takes the bytes, which at 9600 baud or with flow control holding
+    /// them back can take long enough to stall the main runtime's ZMQ loop and API poller. Events
+    /// are still written in the order they're received.
+    pub(crate) fn spawn(self, queue: Arc<StatusQueue>, acks: mpsc::Receiver<String>)
+    where
+        S: Send + 'static,
+    {
+        std::thread::Builder::new()
+            .name("serial-writer".to_string())
+            .spawn(move || {
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .expect("Failed to build serial writer runtime")
+                    .block_on(self.run(queue, acks))
+            })
+            .expect("Failed to spawn serial writer thread");
+    }
+
+    pub(crate) async fn run(mut self, queue: Arc<StatusQueue>, mut acks: mpsc::Receiver<String>) {
+        let check_period = self
+            .ack_timeout
+            .map_or(Duration::from_secs(1), |timeout| timeout / 2);
+        let mut ack_check = tokio::time::interval(check_period);
+        let heartbeat_period = self
+            .heartbeat
+            .as_ref()
+            .map_or(Duration::from_secs(1), |heartbeat| heartbeat.interval);
+        let mut heartbeat_tick = tokio::time::interval_at(
+            tokio::time::Instant::now() + heartbeat_period,
+            heartbeat_period,
+        );
+        // With a minimum write interval, updates waiting for their turn and when it comes.
+        let mut paced = Vec::new();
+        let mut next_write = tokio::time::Instant::now();
+        self.run_self_test(&mut acks).await;
+        self.send_init_line().await;
+        // Heartbeats are written from this loop too, so they never interleave with status lines.
+        loop {
+            tokio::select! {
+                event = queue.recv() => {
+                    let events = match self.batch_window {
+                        Some(window) => collect_batch(&queue, event, window).await,
+                        None => vec![event],
+                    };
+                    match self.min_write_interval {
+                        Some(_) => {
+                            for event in events {
+                                push_latest(&mut paced, event);
+                            }
+                        }
+                        None => self.write_events(events).await,
+                    }
+                }
+                _ = tokio::time::sleep_until(next_write), if !paced.is_empty() => {
+                    // A batch frame takes all waiting updates, otherwise they're written in turn.
+                    let events = match self.batch_window {
+                        Some(_) => std::mem::take(&mut paced),
+                        None => vec![paced.remove(0)],
+                    };
+                    self.write_events(events).await;
+                    next_write = tokio::time::Instant::now()
+                        + self.min_write_interval.unwrap_or_default();
+                }
+                Some(line) = acks.recv() => self.handle_ack(&line),
+                _ = ack_check.tick(), if self.ack_timeout.is_some() => self.check_acks().await,
+                _ = heartbeat_tick.tick(), if self.heartbeat.is_some() => {
+                    self.send_heartbeat().await
+                }
+            }
+        }
+    }
+
+    /// Writes `PING <nonce>` and waits for the Arduino to echo it, which a miswired port or a wrong
+    /// baud rate keeps from happening. Exits on failure unless told to only warn.
+    pub(crate) async fn run_self_test(&mut self, acks: &mut mpsc::Receiver<String>) {
+        let Some(on_failure) = self.self_test else {
+            return;
+        };
+        let line = format!("{} {:08x}\n", SELF_TEST_COMMAND, rand::random::<u32>());
+        let expected = line.trim_end();
+        let timeout = Duration::from_millis(SELF_TEST_TIMEOUT_MS);
+        info!("Running serial self-test with {:?}", expected);
+        let started = Instant::now();
+        // Lines received instead of the echo, garbled ones hinting at a wrong baud rate.
+        let mut unexpected = None;
+        let result = match self.serial.write_line(&line) {
+            Ok(()) => {
+                let echo = tokio::time::timeout(timeout, async {
+                    while let Some(received) = acks.recv().await {
+                        if received == expected {
+                            return true;
+                        }
+                        debug!(
+                            "Ignoring {:?} while waiting for the self-test echo",
+                            received
+                        );
+                        unexpected = Some(received);
+                    }
+                    false
+                })
+                .await;
+                match (echo, &unexpected) {
+                    (Ok(true), _) => Ok(()),
+                    (Ok(false), _) => Err("the serial port stopped being read".to_string()),
+                    (Err(_), Some(received)) => Err(format!(
+                        "got {:?} instead of the echo within {:?}, check --baud",
+                        received, timeout
+                    )),
+                    (Err(_), None) => Err(format!(
+                        "no echo within {:?}, check the wiring and that the firmware echoes lines",
+                        timeout
+                    )),
+                }
+            }
+            Err(e) => Err(format!("failed to write to the serial port: {}", e)),
+        };
+        match result {
+            Ok(()) => info!(
+                "Serial self-test passed, round trip {:?}",
+                started.elapsed()
+            ),
+            Err(e) if on_failure == SelfTestFailureMode::Exit => {
+                error!("Serial self-test failed: {}", e);
+                std::process::exit(1);
+            }
+            Err(e) => warn!("Serial self-test failed: {}, carrying on", e),
+        }
+    }
+
+    /// Writes the init line once, putting the display in a known state before the first status.
+    pub(crate) async fn send_init_line(&mut self) {
+        let Some(line) = self.init_line.take() else {
+            return;
+        };
+        debug!("Sending init line {:?}", line.trim_end());
+        if let Err(e) = self.serial.write_line(&line) {
+            error!("Failed to write init line to serial port: {}", e);
+            self.reconnect_and_resend(None).await;
+        }
+    }
+
+    /// Writes a heartbeat line. If the Arduino echoes lines, counts the heartbeats it didn't echo
+    /// and reports the display as hung after `max_misses` in a row.
+    pub(crate) async fn send_heartbeat(&mut self) {
+        let echoes = self.ack_timeout.is_some();
+        let Some(heartbeat) = &mut self.heartbeat else {
+            return;
+        };
+        let mut hung = false;
+        let missed = echoes && heartbeat.pending;
+        if missed {
+            heartbeat.missed += 1;
+            warn!(
+                "Arduino didn't echo heartbeat ({} in a row)",
+                heartbeat.missed
+            );
+            if heartbeat.missed >= heartbeat.max_misses {
+                error!(
+                    "Arduino missed {} heartbeats in a row, the display may be hung",
+                    heartbeat.missed
+                );
+                hung = heartbeat.reconnect;
+                heartbeat.missed = 0;
+            }
+        }
+        if missed && self.watchdog_missed("heartbeat") {
+            self.reset_and_resend("heartbeats").await;
+        } else if hung {
+            self.reconnect_and_resend(None).await;
+        }
+        if let Some(heartbeat) = &mut self.heartbeat {
+            heartbeat.pending = echoes;
+        }
+        debug!("Sending heartbeat");
+        if let Err(e) = self.serial.write_line(HEARTBEAT_LINE) {
+            error!("Failed to write heartbeat to serial port: {}", e);
+            self.reconnect_and_resend(None).await;
+        }
+    }
+
+    /// Writes `events` to the Arduino, a single one as its line and several as one frame of a
+    /// `BATCH <n>` header followed by their `n` lines. Events outside the port's route aren't
+    /// written, but clear the file if the port still shows it.
+    pub(crate) async fn write_events(&mut self, events: Vec<StatusEvent>) {
+        self.format = SerialFormat::from_cli(&self.config.read());
+        self.serial.set_format(&self.format);
+        let mut lines = Vec::with_capacity(events.len());
+        let mut transition_lines = Vec::new();
+        for event in &events {
+            let previous = self.shown.get(&event.file).copied();
+            let routed = self.route_event(event);
+            if routed
+                .as_ref()
+                .is_some_and(|routed| routed.status == event.status)
+            {
+                transition_lines.extend(self.format.transition_line(previous, event));
+            }
+            lines.push(routed.map(|routed| self.format.line(&routed)));
+        }
+        let written: Vec<_> = lines.iter().flatten().map(String::as_str).collect();
+        let frame = match written.as_slice() {
+            [] => None,
+            [line] => Some(line.to_string()),
+            lines => Some(format!(
+                "{} {}\n{}",
+                BATCH_HEADER,
+                lines.len(),
+                lines.concat()
+            )),
+        };
+        let mut failed = false;
+        if let Some(frame) = frame {
+            let result = self.serial.write_line(&frame);
+            counter!("serial_writes_total").increment(1);
+            if let Err(e) = &result {
+                error!("Failed to write to serial port: {}", e);
+                let mut diagnostics = self.diagnostics.lock();
+                diagnostics.record_event(RecentEvent {
+                    error: Some(e.to_string()),
+                    ..RecentEvent::new(RecentEventKind::SerialError)
+                });
+                diagnostics.last_serial_error = Some(TimestampedError::now(e));
+                failed = true;
+            }
+        }
+        // Written after the statuses, outside any batch frame, and not re-sent after a failure.
+        if !failed {
+            for line in &transition_lines {
+                debug!("Sending transition line {:?}", line.trim_end());
+                if let Err(e) = self.serial.write_line(line) {
+                    error!("Failed to write transition line to serial port: {}", e);
+                    failed = true;
+                    break;
+                }
+            }
+        }
+        let last = events
+            .iter()
+            .zip(&lines)
+            .rev()
+            .find_map(|(event, line)| Some(event.file.clone()).zip(line.clone()));
+        for (event, line) in events.into_iter().zip(lines) {
+            if let (false, Some(line)) = (failed, &line) {
+                tracing::info!(parent: &event.span, line = line.trim_end(), "serial write");
+            }
+            self.record_event(event, line);
+        }
+        if let (true, Some((file, line))) = (failed, last) {
+            self.reconnect_and_resend(Some((&file, &line))).await;
+        }
+    }
+
+    /// What to write for `event` on this port: the event if the port's route shows its status,
+    /// otherwise a `clear` of its file if the port still shows an earlier status of it.
+    fn route_event(&mut self, event: &StatusEvent) -> Option<StatusEvent> {
+        if self.route.matches(event.status) {
+            if event.status == DisplayStatus::Cleared {
+                self.shown.remove(&event.file);
+            } else {
+                self.shown.insert(event.file.clone(), event.status);
+            }
+            return Some(event.clone());
+        }
+        self.shown.remove(&event.file).map(|_| StatusEvent {
+            status: DisplayStatus::Cleared,
+            ..event.clone()
+        })
+    }
+
+    /// Mirrors an event into the shared statuses, the state file and the status socket if this
+    /// writer mirrors events, and awaits the echo of `message`, the line written for it if any.
+    pub(crate) fn record_event(&mut self, event: StatusEvent, message: Option<String>) {
+        if let Some(message) = message {
+            self.diagnostics.lock().serial_writes += 1;
+            if self.ack_timeout.is_some() {
+                self.pending_acks.insert(
+                    line_filename(&message).to_string(),
+                    PendingAck {
+                        line: message,
+                        sent_at: Instant::now(),
+                        resent: false,
+                    },
+                );
+            }
+        }
+        if !self.mirrors_events {
+            return;
+        }
+        counter!("status_updates_total", "status" => event.status.to_string()).increment(1);
+        let previous = self
+            .statuses
+            .lock()
+            .get(&event.file)
+            .map(|previous| previous.status);
+        // Only the transition alerts, so a file staying faulty doesn't on every poll.
+        let new_fault = event.status == DisplayStatus::StoredFaulty
+            && previous != Some(DisplayStatus::StoredFaulty);
+        if new_fault {
+            error!(
+                "Fault detected: {} went from {} to {}",
+                event.file,
+                previous.map_or("untracked".to_string(), |status| status.to_string()),
+                event.status
+            );
+            counter!("faults_detected_total").increment(1);
+        }
+        {
+            let mut diagnostics = self.diagnostics.lock();
+            diagnostics.record_transition(&event, previous);
+            if new_fault {
+                diagnostics.faults_detected += 1;
+            }
+        }
+        // A cleared file isn't re-sent after reconnecting, nor listed by the status socket.
+        if event.status == DisplayStatus::Cleared {
+            self.statuses.lock().remove(&event.file);
+        } else {
+            self.statuses
+                .lock()
+                .insert(event.file.clone(), event.clone());
+        }
+        if let Some(path) = &self.state_file {
+            let state = PersistedState {
+                tracked: self.current_state.lock().clone(),
+                statuses: self.statuses.lock().clone(),
+            };
+            if let Err(e) = state.save(path) {
+                error!("Failed to persist state: {:#}", e);
+            }
+        }
+        // No receivers just means no status socket reader is connected.
+        let _ = self.events.send(event);
+    }
+
+    /// Reconnects and re-sends the last known status of every file on the port's route so the
+    /// display is correct again, ending with `(file, message)` if given so it's what the display shows.
+    pub(crate) async fn reconnect_and_resend(&mut self, last: Option<(&str, &str)>) {
+        loop {
+            self.serial.reconnect().await;
+            let mut lines: Vec<_> = self
+                .statuses
+                .lock()
+                .values()
+                .filter(|status| self.route.matches(status.status))
+                .filter(|status| last.is_none_or(|(file, _)| status.file != file))
+                .map(|status| self.format.line(status))
+                .collect();
+            if let Some((_, message)) = last {
+                lines.push(message.to_string());
+            }
+            match lines
+                .iter()
+                .try_for_each(|line| self.serial.write_line(line))
+            {
+                Ok(()) => {
+                    info!("Re-sent {} statuses after reconnecting", lines.len());
+                    return;
+                }
+                Err(e) => error!("Failed to re-send statuses after reconnecting: {}", e),
+            }
+        }
+    }
+
+    pub(crate) fn handle_ack(&mut self, line: &str) {
+        if line == HEARTBEAT_LINE.trim_end() {
+            debug!("Arduino echoed heartbeat");
+            if let Some(heartbeat) = &mut self.heartbeat {
+                heartbeat.pending = false;
+                heartbeat.missed = 0;
+            }
+            if let Some(watchdog) = &mut self.watchdog {
+                watchdog.missed = 0;
+            }
+            return;
+        }
+        let filename = line_filename(line);
+        match self.pending_acks.get(filename) {
+            Some(pending) if pending.line.trim_end() == line => {
+                debug!("Arduino acknowledged: {}", line);
+                self.pending_acks.remove(filename);
+                if let Some(watchdog) = &mut self.watchdog {
+                    watchdog.missed = 0;
+                }
+            }
+            Some(pending) => debug!(
+                "Arduino echoed {:?} while expecting {:?}",
+                line,
+                pending.line.trim_end()
+            ),
+            None => debug!("Arduino sent unexpected line: {}", line),
+        }
+    }
+
+    /// Re-sends lines whose echo timed out once, then gives up on them.
+    pub(crate) async fn check_acks(&mut self) {
+        let Some(timeout) = self.ack_timeout else {
+            return;
+        };
+        let mut expired = Vec::new();
+        for (filename, pending) in self.pending_acks.iter_mut() {
+            if pending.sent_at.elapsed() < timeout {
+                continue;
+            }
+            if pending.resent {
+                warn!(
+                    "No ACK from Arduino for {:?} after re-sending",
+                    pending.line.trim_end()
+                );
+                expired.push(filename.clone());
+                continue;
+            }
+            warn!(
+                "No ACK from Arduino for {:?}, re-sending",
+                pending.line.trim_end()
+            );
+            if let Err(e) = self.serial.write_line(&pending.line) {
+                error!("Failed to re-send to serial port: {}", e);
+            }
+            pending.sent_at = Instant::now();
+            pending.resent = true;
+        }
+        let mut reset = false;
+        for filename in expired {
+            self.pending_acks.remove(&filename);
+            reset |= self.watchdog_missed("line");
+        }
+        if reset {
+            self.reset_and_resend("lines").await;
+        }
+    }
+
+    /// Counts a missed echo, returning whether the watchdog should reset the Arduino.
+    pub(crate) fn watchdog_missed(&mut self, what: &str) -> bool {
+        let Some(watchdog) = &mut self.watchdog else {
+            return false;
+        };
+        watchdog.missed += 1;
+        debug!(
+            "Watchdog counted a missed {} echo ({} in a row)",
+            what, watchdog.missed
+        );
+        watchdog.missed >= watchdog.max_misses
+    }
+
+    /// Resets the Arduino, which clears its display, and re-sends the last known statuses on the
+    /// port's route.
+    pub(crate) async fn reset_and_resend(&mut self, missed: &str) {
+        let Some(watchdog) = &mut self.watchdog else {
+            return;
+        };
+        warn!(
+            "Resetting the Arduino over DTR: it didn't echo {} {} in a row",
+            watchdog.missed, missed
+        );
+        watchdog.missed = 0;
+        counter!("arduino_resets_total").increment(1);
+        self.pending_acks.clear();
+        if let Some(heartbeat) = &mut self.heartbeat {
+            heartbeat.pending = false;
+            heartbeat.missed = 0;
+        }
+        let resent = match self.serial.reset().await {
+            Ok(()) => {
+                let lines: Vec<_> = self
+                    .statuses
+                    .lock()
+                    .values()
+                    .filter(|status| self.route.matches(status.status))
+                    .map(|status| self.format.line(status))
+                    .collect();
+                lines
+                    .iter()
+                    .try_for_each(|line| self.serial.write_line(line))
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = resent {
+            error!("Failed to reset the Arduino: {}", e);
+            self.reconnect_and_resend(None).await;
+        }
+    }
+}
+
+impl SerialRoute {
+    pub(crate) fn matches(self, status: DisplayStatus) -> bool {
+        let any_stage = matches!(
+            status,
+            DisplayStatus::Offline
+                | DisplayStatus::Stale
+                | DisplayStatus::Blank
+                | DisplayStatus::Cleared
+        );
+        match self {
+            SerialRoute::All => true,
+            SerialRoute::Uploads => !status.is_from_proofs(),
+            SerialRoute::Proofs => status.is_from_proofs() || any_stage,
+        }
+    }
+}
+
+/// Collects the updates arriving within `window` of `first`, keeping the latest update of each
+/// file in the order the files first appeared.
+pub(crate) async fn collect_batch(
+    queue: &StatusQueue,
+    first: StatusEvent,
+    window: Duration,
+) -> Vec<StatusEvent> {
+    let deadline = tokio::time::Instant::now() + window;
+    let mut events = vec![first];
+    while let Ok(event) = tokio::time::timeout_at(deadline, queue.recv()).await {
+        push_latest(&mut events, event);
+    }
+    events
+}
+
+/// Adds `event` to `events`, replacing the pending update of its file if there is one.
+pub(crate) fn push_latest(events: &mut Vec<StatusEvent>, event: StatusEvent) {
+    match events.iter_mut().find(|pending| pending.file == event.file) {
+        Some(pending) => *pending = event,
+        None => events.push(event),
+    }
+}
+
+/// Writes all of `bytes`, continuing with the remaining bytes after short writes. Transient errors
+/// (`WouldBlock`, `Interrupted` and write timeouts) are retried for up to
+/// `SERIAL_WRITE_RETRY_TIMEOUT_MS`, so a busy port doesn't leave the Arduino with half a line.
+pub(crate) fn write_fully(
+    writer: &mut impl std::io::Write,
+    mut bytes: &[u8],
+) -> std::io::Result<()> {
+    use std::io::ErrorKind;
+
+    let deadline = Instant::now() + Duration::from_millis(SERIAL_WRITE_RETRY_TIMEOUT_MS);
+    while !bytes.is_empty() {
+        match writer.write(bytes) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(written) => bytes = &bytes[written..],
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::WouldBlock | ErrorKind::Interrupted | ErrorKind::TimedOut
+                ) && Instant::now() < deadline =>
+            {
+                debug!(
+                    "Serial write not done ({}), retrying {} remaining bytes",
+                    e,
+                    bytes.len()
+                );
+                std::thread::sleep(Duration::from_millis(SERIAL_WRITE_RETRY_DELAY_MS));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Filename part of a serial line, everything before the first comma.
+pub(crate) fn line_filename(line: &str) -> &str {
+    line.split(',').next().unwrap_or_default()
+}
+
+pub(crate) fn open_serial_port(
+    path: &str,
+    baud: u32,
+    timeout: Duration,
+) -> serialport::Result<Box<dyn serialport::SerialPort>> {
+    serialport::new(path, baud).timeout(timeout).open()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{cli, event, pipeline, serial_lines, stage_message};
+
+    const LONG_PATH: &str = "/srv/uploads/2025/holiday-photos-from-the-alps.jpeg";
+
+    fn filename(args: &[&str]) -> String {
+        let mut all = vec!["--display-width", "64"];
+        all.extend_from_slice(args);
+        FilenameFormat::from_cli(&cli(&all)).apply(LONG_PATH)
+    }
+
+    #[test]
+    fn filename_strips_prefix() {
+        assert_eq!(
+            filename(&["--display-strip-prefix", "/srv/uploads"]),
+            "2025/holiday-photos-from-the-alps.jpeg"
+        );
+        // A name outside the prefix is kept whole.
+        assert_eq!(filename(&["--display-strip-prefix", "/tmp"]), LONG_PATH);
+    }
+
+    #[test]
+    fn transition_lines_only_on_entering_the_status() {
+        let format = SerialFormat::from_cli(&cli(&[
+            "--on-proven-line",
+            "BLINK {file}",
+            "--on-faulty-line",
+            "ALARM",
+        ]));
+        let line = |previous, status| {
+            format.transition_line(previous, &event("a.jpg", "bafkroot", status))
+        };
+        let proven = DisplayStatus::StoredProven;
+        let faulty = DisplayStatus::StoredFaulty;
+        assert_eq!(line(None, proven).as_deref(), Some("BLINK a.jpg\n"));
+        assert_eq!(
+            line(Some(DisplayStatus::Stored), proven).as_deref(),
+            Some("BLINK a.jpg\n")
+        );
+        assert_eq!(line(Some(faulty), proven).as_deref(), Some("BLINK a.jpg\n"));
+        assert_eq!(
+            line(Some(DisplayStatus::Stored), faulty).as_deref(),
+            Some("ALARM\n")
+        );
+        // Staying in or moving between proven statuses isn't a transition into proven.
+        for previous in [
+            proven,
+            DisplayStatus::StoredDegraded,
+            DisplayStatus::StoredRecovered,
+        ] {
+            assert_eq!(line(Some(previous), proven), None);
+        }
+        assert_eq!(line(Some(faulty), faulty), None);
+        assert_eq!(line(None, DisplayStatus::Stored), None);
+        // Without the flags, no line is sent.
+        let format = SerialFormat::from_cli(&cli(&[]));
+        assert_eq!(
+            format.transition_line(None, &event("a.jpg", "bafkroot", proven)),
+            None
+        );
+    }
+
+    fn root_status(root_id: u64, epoch: u64, proofs_submitted: u64) -> RootStatus {
+        RootStatus {
+            root_id,
+            proven: true,
+            faulted: false,
+            epoch: Some(epoch),
+            proofs_submitted,
+        }
+    }
+
+    #[test]
+    fn proof_count_of_a_single_root() {
+        let roots = [root_status(1, 10, 127)];
+        for root in [ProofCountRoot::Max, ProofCountRoot::Latest] {
+            assert_eq!(proof_count(&roots, root), 127);
+        }
+        assert_eq!(proof_count(&[], ProofCountRoot::Max), 0);
+    }
+
+    #[test]
+    fn proof_count_of_several_roots() {
+        // The older copy has more proofs than the re-added one.
+        let roots = [root_status(1, 10, 127), root_status(2, 30, 4)];
+        assert_eq!(proof_count(&roots, ProofCountRoot::Max), 127);
+        assert_eq!(proof_count(&roots, ProofCountRoot::Latest), 4);
+        // Roots at the same epoch are told apart by their id.
+        let roots = [root_status(3, 30, 9), root_status(2, 30, 4)];
+        assert_eq!(proof_count(&roots, ProofCountRoot::Latest), 9);
+    }
+
+    #[test]
+    fn proof_count_is_appended_when_enabled() {
+        let event = StatusEvent {
+            roots: vec![root_status(1, 10, 127), root_status(2, 30, 4)],
+            ..event("a.jpg", "bafkroot", DisplayStatus::StoredProven)
+        };
+        let line = |args: &[&str]| SerialFormat::from_cli(&cli(args)).line(&event);
+        assert_eq!(line(&[]), "a.jpg,stored & proven\n");
+        assert_eq!(
+            line(&["--serial-proof-count"]),
+            "a.jpg,stored & proven,127\n"
+        );
+        assert_eq!(
+            line(&[
+                "--serial-proof-count",
+                "--serial-proof-count-root",
+                "latest"
+            ]),
+            "a.jpg,stored & proven,4\n"
+        );
+    }
+
+    #[test]
+    fn checksums_match_known_vectors() {
+        // The standard check input, whose CRC-8 (SMBus) is 0xF4.
+        assert_eq!(SerialChecksum::Crc8.compute(b"123456789"), 0xF4);
+        assert_eq!(SerialChecksum::Sum8.compute(b"123456789"), 0xDD);
+        for algorithm in [SerialChecksum::Crc8, SerialChecksum::Sum8] {
+            assert_eq!(algorithm.compute(b""), 0);
+        }
+        assert_eq!(SerialChecksum::Crc8.compute(&[0x00]), 0x00);
+        assert_eq!(SerialChecksum::Crc8.compute(&[0x01]), 0x07);
+        assert_eq!(SerialChecksum::Crc8.compute(&[0xFF]), 0xF3);
+        // The sum wraps around 256.
+        assert_eq!(SerialChecksum::Sum8.compute(&[0xFF, 0x02]), 0x01);
+    }
+
+    #[test]
+    fn checksum_is_appended_to_the_payload() {
+        let format = |args: &[&str]| SerialFormat::from_cli(&cli(args));
+        let event = event("a.jpg", "bafkroot", DisplayStatus::Uploaded);
+        let payload = b"a.jpg,uploaded";
+        assert_eq!(
+            format(&["--serial-checksum", "crc8"]).line(&event),
+            format!(
+                "a.jpg,uploaded*{:02X}\n",
+                SerialChecksum::Crc8.compute(payload)
+            )
+        );
+        assert_eq!(
+            format(&["--serial-checksum", "sum8"]).line(&event),
+            format!(
+                "a.jpg,uploaded*{:02X}\n",
+                SerialChecksum::Sum8.compute(payload)
+            )
+        );
+        // Without the flag lines stay as existing firmware expects them.
+        assert_eq!(format(&[]).line(&event), "a.jpg,uploaded\n");
+    }
+
+    /// Accepts at most `chunk` bytes per write, failing every other write with `error`.
+    struct TrickleWriter {
+        written: Vec<u8>,
+        chunk: usize,
+        error: std::io::ErrorKind,
+        fail_next: bool,
+    }
+
+    impl std::io::Write for TrickleWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.fail_next = !self.fail_next;
+            if !self.fail_next {
+                return Err(self.error.into());
+            }
+            let len = buf.len().min(self.chunk);
+            self.written.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn trickle(error: std::io::ErrorKind) -> TrickleWriter {
+        TrickleWriter {
+            written: Vec::new(),
+            chunk: 3,
+            error,
+            fail_next: false,
+        }
+    }
+
+    #[test]
+    fn write_fully_retries_short_and_blocked_writes() {
+        for error in [
+            std::io::ErrorKind::WouldBlock,
+            std::io::ErrorKind::Interrupted,
+        ] {
+            let mut writer = trickle(error);
+            write_fully(&mut writer, b"cathulhu-rise-of.jpg,stored\n").unwrap();
+            assert_eq!(writer.written, b"cathulhu-rise-of.jpg,stored\n");
+        }
+    }
+
+    #[test]
+    fn write_fully_fails_on_hard_errors() {
+        let mut writer = trickle(std::io::ErrorKind::BrokenPipe);
+        let err = write_fully(&mut writer, b"a.jpg,stored\n").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+        assert_eq!(writer.written, b"a.j");
+    }
+
+    #[test]
+    fn proof_age_uses_the_largest_whole_unit() {
+        let proven_at: DateTime<Utc> = "2025-01-01T12:00:00Z".parse().unwrap();
+        let age = |now: &str| proof_age(proven_at, now.parse().unwrap());
+        assert_eq!(age("2025-01-01T12:00:42Z"), "42s ago");
+        assert_eq!(age("2025-01-01T12:05:59Z"), "5m ago");
+        assert_eq!(age("2025-01-01T15:00:00Z"), "3h ago");
+        assert_eq!(age("2025-01-03T13:00:00Z"), "2d ago");
+        // A proof timestamped ahead of the local clock isn't shown as negative.
+        assert_eq!(age("2025-01-01T11:59:00Z"), "0s ago");
+    }
+
+    #[test]
+    fn proof_age_is_omitted_without_a_proof_time() {
+        let format = SerialFormat::from_cli(&cli(&["--serial-proof-age"]));
+        let mut event = event("a.jpg", "bafkroot", DisplayStatus::StoredProven);
+        assert_eq!(format.line(&event), "a.jpg,stored & proven\n");
+        event.last_proven_at = Some(Utc::now() - chrono::Duration::minutes(5));
+        assert_eq!(format.line(&event), "a.jpg,stored & proven 5m ago\n");
+    }
+
+    #[test]
+    fn truncation_fits_the_display_width() {
+        let format = FilenameFormat::from_cli(&cli(&[]));
+        // Under and exactly at the 16 columns, names are kept whole.
+        assert_eq!(format.apply("cat.jpg"), "cat.jpg");
+        assert_eq!(format.apply("sixteen-char.jpg"), "sixteen-char.jpg");
+        assert_eq!(format.apply("cathulhu-rise-of.jpg"), "cathulhu-rise...");
+    }
+
+    #[test]
+    fn truncation_keeps_multi_byte_characters_whole() {
+        let format = FilenameFormat::from_cli(&cli(&[]));
+        assert_eq!(format.apply("żółć-gęślą-jaźń.png"), "żółć-gęślą-ja...");
+        assert_eq!(
+            format.apply("日本語のファイル名.jpg"),
+            "日本語のファイル名.jpg"
+        );
+        assert_eq!(
+            format.apply("日本語のファイル名の写真です.jpg"),
+            "日本語のファイル名の写真で..."
+        );
+    }
+
+    #[test]
+    fn filename_truncates_with_ellipsis() {
+        assert_eq!(filename(&["--display-max-len", "16"]), "/srv/uploads/...");
+        assert_eq!(filename(&["--display-max-len", "2"]), "/s");
+        // The display width caps the length too.
+        let name = FilenameFormat::from_cli(&cli(&[])).apply(LONG_PATH);
+        assert_eq!(name.chars().count(), 16);
+    }
+
+    #[test]
+    fn filename_drops_extension() {
+        assert_eq!(
+            filename(&["--display-drop-extension"]),
+            "/srv/uploads/2025/holiday-photos-from-the-alps"
+        );
+        let format = FilenameFormat::from_cli(&cli(&["--display-drop-extension"]));
+        assert_eq!(format.apply(".hidden"), ".hidden");
+        assert_eq!(format.apply("dir.d/file"), "dir.d/file");
+    }
+
+    #[test]
+    fn filename_transforms_combine() {
+        assert_eq!(
+            filename(&[
+                "--display-strip-prefix",
+                "/srv/uploads/2025",
+                "--display-drop-extension",
+                "--display-max-len",
+                "16",
+            ]),
+            "holiday-photo..."
+        );
+    }
+
+    #[test]
+    fn line_keeps_original_filename_in_event() {
+        let event = event(LONG_PATH, "bafkroot", DisplayStatus::Stored);
+        let format = SerialFormat::from_cli(&cli(&["--display-strip-prefix", "/srv/uploads/2025"]));
+        assert_eq!(format.line(&event), "holiday-photo...,stored\n");
+        assert_eq!(event.file, LONG_PATH);
+    }
+
+    #[test]
+    fn verbose_line_includes_proofset_and_short_cid() {
+        let format = SerialFormat::from_cli(&cli(&["--serial-verbose", "--serial-cid-len", "4"]));
+        let event = event("a.jpg", "bafkrootcid", DisplayStatus::StoredProven);
+        assert_eq!(format.line(&event), "a.jpg,stored & proven,1,tcid\n");
+
+        let event = StatusEvent {
+            proofset_id: None,
+            ..event
+        };
+        assert_eq!(format.line(&event), "a.jpg,stored & proven,,tcid\n");
+    }
+
+    #[test]
+    fn terse_line_by_default() {
+        let event = event("a.jpg", "bafkrootcid", DisplayStatus::StoredProven);
+        assert_eq!(
+            SerialFormat::from_cli(&cli(&[])).line(&event),
+            "a.jpg,stored & proven\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn mock_serial_records_the_pipeline_lines() {
+        let (handler, sink) = pipeline(&[]);
+        for stage in ["Uploaded", "RootsAdded"] {
+            let message = stage_message("a.jpg", "baga6ea4piece:bafkroot", stage);
+            assert_eq!(handler.handle(&message).await, "ACK");
+        }
+        assert_eq!(
+            serial_lines(&sink, 3).await,
+            ["init\n", "a.jpg,uploaded\n", "a.jpg,stored\n"]
+        );
+    }
+
+    /// Records each line along with when it was written.
+    #[derive(Clone, Default)]
+    struct TimedSink(Arc<Mutex<Vec<(tokio::time::Instant, String)>>>);
+
+    impl SerialSink for TimedSink {
+        fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+            self.0
+                .lock()
+                .push((tokio::time::Instant::now(), line.to_string()));
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paced_writes_keep_their_interval_and_end_at_the_latest_status() {
+        let interval = Duration::from_millis(100);
+        let cli = cli(&["--serial-min-interval-ms", "100"]);
+        let sink = TimedSink::default();
+        let writer = SerialWriter::new(
+            sink.clone(),
+            Arc::new(parking_lot::RwLock::new(cli.clone())),
+            Default::default(),
+            Default::default(),
+            Arc::new(Mutex::new(Diagnostics::new(16))),
+            broadcast::channel(16).0,
+            None,
+        );
+        let queue = Arc::new(StatusQueue::new(1_024));
+        let (acks_tx, acks_rx) = mpsc::channel(1);
+        tokio::spawn({
+            let queue = queue.clone();
+            async move {
+                let _acks_tx = acks_tx;
+                writer.run(queue, acks_rx).await
+            }
+        });
+        // Updates of three files arrive every 10ms, ten times faster than they may be written.
+        let statuses = [DisplayStatus::Uploaded, DisplayStatus::Stored];
+        let mut latest = HashMap::new();
+        for i in 0..100 {
+            let file = format!("{}.jpg", i % 3);
+            let event = event(&file, "bafkroot", statuses[i / 3 % 2]);
+            latest.insert(file, SerialFormat::from_cli(&cli).line(&event));
+            queue.push(event);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        let written = sink.0.lock().clone();
+        let (init, statuses) = written.split_first().unwrap();
+        assert_eq!(init.1, "init\n");
+        for pair in statuses.windows(2) {
+            assert!(
+                pair[1].0 - pair[0].0 >= interval,
+                "{:?} and {:?} written {:?} apart",
+                pair[0].1,
+                pair[1].1,
+                pair[1].0 - pair[0].0
+            );
+        }
+        assert!(statuses.len() < 20, "{} writes", statuses.len());
+        // Whatever was coalesced, each file ends at its last status.
+        for (file, line) in latest {
+            let last = statuses
+                .iter()
+                .rev()
+                .find(|(_, written)| written.starts_with(&file));
+            assert_eq!(last.map(|(_, written)| written), Some(&line));
+        }
+    }
+
+    /// Starts a writer per route on its own mock port, the first one mirroring events as
+    /// `--serial-port` does, and returns the sender feeding them.
+    fn routed_writers(
+        routes: &[SerialRoute],
+    ) -> (crate::status::StatusSender, Vec<MockSerialSink>) {
+        let cli = cli(&["--mock-serial"]);
+        let config: SharedConfig = Arc::new(parking_lot::RwLock::new(cli.clone()));
+        let statuses = Arc::new(Mutex::new(HashMap::new()));
+        let mut queues = Vec::new();
+        let mut sinks = Vec::new();
+        for (index, &route) in routes.iter().enumerate() {
+            let SerialOutput::Mock(sink) = SerialOutput::open(&cli, &cli.serial_port).unwrap()
+            else {
+                panic!("--mock-serial didn't select the mock sink");
+            };
+            let mut writer = SerialWriter::new(
+                sink.clone(),
+                config.clone(),
+                statuses.clone(),
+                Default::default(),
+                Arc::new(Mutex::new(Diagnostics::new(16))),
+                broadcast::channel(16).0,
+                None,
+            );
+            writer.route = route;
+            writer.mirrors_events = index == 0;
+            let queue = Arc::new(StatusQueue::new(16));
+            let (acks_tx, acks_rx) = mpsc::channel(1);
+            tokio::spawn({
+                let queue = queue.clone();
+                async move {
+                    let _acks_tx = acks_tx;
+                    writer.run(queue, acks_rx).await
+                }
+            });
+            queues.push(queue);
+            sinks.push(sink);
+        }
+        (crate::status::StatusSender::new(queues, None, None), sinks)
+    }
+
+    #[tokio::test]
+    async fn routes_uploads_and_proofs_to_their_ports() {
+        let (sender, sinks) = routed_writers(&[SerialRoute::Uploads, SerialRoute::Proofs]);
+        sender
+            .send(event("a.jpg", "bafka", DisplayStatus::Uploaded))
+            .await;
+        sender
+            .send(event("b.jpg", "bafkb", DisplayStatus::Uploaded))
+            .await;
+        sender
+            .send(event("a.jpg", "bafka", DisplayStatus::StoredProven))
+            .await;
+        let [uploads, proofs] = &sinks[..] else {
+            unreachable!()
+        };
+        // The uploads port clears a file once it's proven, the proofs port never shows uploads.
+        assert_eq!(
+            serial_lines(uploads, 4).await,
+            [
+                "init\n",
+                "a.jpg,uploaded\n",
+                "b.jpg,uploaded\n",
+                "a.jpg,clear\n"
+            ]
+        );
+        assert_eq!(
+            serial_lines(proofs, 2).await,
+            ["init\n", "a.jpg,stored & proven\n"]
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(uploads.lines().len(), 4);
+        assert_eq!(proofs.lines().len(), 2);
+    }
+}
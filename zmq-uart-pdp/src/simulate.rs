@@ -0,0 +1,52 @@
+//! Simulation mode cycling a synthetic file through the statuses, for display bring-up and demos.
+
+use std::time::Duration;
+
+use chrono::Utc;
+use log::info;
+
+use crate::cli::StateKey;
+use crate::message::{FileData, Stage, ZmqPayload};
+use crate::status::{DisplayStatus, StatusEvent, StatusSender};
+
+// Name of the synthetic file shown with `--simulate`.
+pub(crate) const SIMULATED_FILE: &str = "simulated.jpg";
+// Statuses the synthetic file goes through, starting over after the last one.
+const SIMULATED_STATUSES: [DisplayStatus; 5] = [
+    DisplayStatus::Uploaded,
+    DisplayStatus::Stored,
+    DisplayStatus::StoredProven,
+    DisplayStatus::StoredFaulty,
+    DisplayStatus::StoredRecovered,
+];
+
+/// Sends the synthetic file's next status every `interval`, through the same path as the statuses
+/// of real files.
+pub(crate) async fn simulate(status_sender: &StatusSender, interval: Duration, key: StateKey) {
+    info!(
+        "Simulating {} cycling through the statuses every {:?}",
+        SIMULATED_FILE, interval
+    );
+    for status in SIMULATED_STATUSES.iter().cycle() {
+        let payload = ZmqPayload {
+            stage: if *status == DisplayStatus::Uploaded {
+                Stage::Uploaded
+            } else {
+                Stage::RootsAdded
+            },
+            data: FileData {
+                file: SIMULATED_FILE.to_string(),
+                file_id: "baga6ea4simulated:baga6ea4simulatedroot".to_string(),
+                proofset_ids: vec!["0".to_string()],
+                watched_root: None,
+            },
+            idempotency_key: None,
+        };
+        let event = StatusEvent {
+            last_proven_at: status.is_proven().then(Utc::now),
+            ..StatusEvent::new(&payload, *status, key)
+        };
+        status_sender.send_if_changed(event).await;
+        tokio::time::sleep(interval).await;
+    }
+}
@@ -0,0 +1,326 @@
+//! State persisted across restarts and diagnostics dumped on request.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::cli::Cli;
+use crate::message::{Stage, ZmqPayload};
+use crate::status::{DisplayStatus, SentStatus, StageTimes, StatusEvent};
+
+// Number of recent status transitions kept for state dumps.
+pub(crate) const DIAGNOSTICS_TRANSITIONS_LEN: usize = 50;
+// How long a state dump waits for each piece of shared state before leaving it out.
+pub(crate) const DUMP_LOCK_TIMEOUT_MS: u64 = 100;
+
+/// Tracked files and their last known statuses, persisted across restarts.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub(crate) struct PersistedState {
+    pub(crate) tracked: HashMap<String, ZmqPayload>,
+    pub(crate) statuses: HashMap<String, StatusEvent>,
+}
+
+impl PersistedState {
+    /// Loads the state from `path`, starting empty if it's missing or can't be read.
+    pub(crate) fn load_or_default(path: &str) -> Self {
+        match std::fs::read(path) {
+            Ok(contents) => match serde_json::from_slice(&contents) {
+                Ok(state) => {
+                    info!("Restored state from {}", path);
+                    state
+                }
+                Err(e) => {
+                    warn!("Ignoring corrupt state file {}: {}", path, e);
+                    Self::default()
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("No state file at {}", path);
+                Self::default()
+            }
+            Err(e) => {
+                warn!("Failed to read state file {}: {}", path, e);
+                Self::default()
+            }
+        }
+    }
+
+    /// Saves the state to `path` atomically, so a crash mid-write can't leave it corrupt.
+    pub(crate) fn save(&self, path: &str) -> anyhow::Result<()> {
+        let tmp_path = format!("{}.tmp", path);
+        std::fs::write(&tmp_path, serde_json::to_vec(self)?)
+            .context(format!("Failed to write {}", tmp_path))?;
+        std::fs::rename(&tmp_path, path).context(format!("Failed to rename to {}", path))?;
+        Ok(())
+    }
+}
+
+/// Runtime diagnostics collected by the tasks, included in state dumps.
+#[derive(Serialize, Debug, Default, Clone)]
+pub(crate) struct Diagnostics {
+    pub(crate) messages_received: u64,
+    pub(crate) serial_writes: u64,
+    pub(crate) api_cache_hits: u64,
+    pub(crate) api_cache_misses: u64,
+    pub(crate) api_cache_size: usize,
+    pub(crate) recent_transitions: VecDeque<Transition>,
+    /// Transitions of a file into `stored & faulty` from any other status.
+    pub(crate) faults_detected: u64,
+    pub(crate) last_api_error: Option<TimestampedError>,
+    pub(crate) last_serial_error: Option<TimestampedError>,
+    pub(crate) recent_events: VecDeque<RecentEvent>,
+    /// Number of `recent_events` kept, the oldest being dropped first.
+    #[serde(skip)]
+    pub(crate) recent_events_len: usize,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub(crate) struct Transition {
+    pub(crate) at: u64,
+    pub(crate) file: String,
+    pub(crate) status: DisplayStatus,
+    /// Status of the file before the transition, `None` for its first status.
+    pub(crate) previous: Option<DisplayStatus>,
+}
+
+/// Event kept in the ring buffer of recent events, with the fields relevant to its kind.
+#[derive(Serialize, Debug, Clone)]
+pub(crate) struct RecentEvent {
+    pub(crate) at: u64,
+    pub(crate) kind: RecentEventKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) stage: Option<Stage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) status: Option<DisplayStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum RecentEventKind {
+    /// A stage update was received.
+    Message,
+    /// A file's status changed.
+    Status,
+    ApiError,
+    SerialError,
+}
+
+impl RecentEvent {
+    pub(crate) fn new(kind: RecentEventKind) -> Self {
+        Self {
+            at: unix_now(),
+            kind,
+            file: None,
+            stage: None,
+            status: None,
+            error: None,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub(crate) struct TimestampedError {
+    pub(crate) at: u64,
+    pub(crate) error: String,
+}
+
+impl TimestampedError {
+    pub(crate) fn now(error: impl std::fmt::Display) -> Self {
+        Self {
+            at: unix_now(),
+            error: error.to_string(),
+        }
+    }
+}
+
+impl Diagnostics {
+    pub(crate) fn new(recent_events_len: usize) -> Self {
+        Self {
+            recent_events_len,
+            ..Self::default()
+        }
+    }
+
+    /// Adds `event` to the recent events, dropping the oldest one if they're full.
+    pub(crate) fn record_event(&mut self, event: RecentEvent) {
+        if self.recent_events.len() >= self.recent_events_len {
+            self.recent_events.pop_front();
+        }
+        self.recent_events.push_back(event);
+    }
+
+    pub(crate) fn record_transition(
+        &mut self,
+        event: &StatusEvent,
+        previous: Option<DisplayStatus>,
+    ) {
+        if self.recent_transitions.len() == DIAGNOSTICS_TRANSITIONS_LEN {
+            self.recent_transitions.pop_front();
+        }
+        self.recent_transitions.push_back(Transition {
+            at: unix_now(),
+            file: event.file.clone(),
+            status: event.status,
+            previous,
+        });
+        self.record_event(RecentEvent {
+            file: Some(event.file.clone()),
+            status: Some(event.status),
+            ..RecentEvent::new(RecentEventKind::Status)
+        });
+    }
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/// Shared state aggregated into a state dump.
+#[derive(Clone)]
+pub(crate) struct DumpSources {
+    pub(crate) cli: Cli,
+    pub(crate) current_state: Arc<Mutex<HashMap<String, ZmqPayload>>>,
+    pub(crate) statuses: Arc<Mutex<HashMap<String, StatusEvent>>>,
+    pub(crate) last_sent: Arc<Mutex<HashMap<String, SentStatus>>>,
+    pub(crate) diagnostics: Arc<Mutex<Diagnostics>>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct StateDump {
+    pub(crate) generated_at: u64,
+    pub(crate) config: Cli,
+    pub(crate) tracked: Option<Vec<ZmqPayload>>,
+    pub(crate) statuses: Option<Vec<StatusEvent>>,
+    /// Stage times of each file a status was sent for, by file.
+    pub(crate) stage_times: Option<HashMap<String, StageTimes>>,
+    pub(crate) diagnostics: Option<Diagnostics>,
+}
+
+impl DumpSources {
+    /// Collects a dump of the runtime state. Locks are only waited on briefly so a dump taken
+    /// from the panic hook can't deadlock; whatever couldn't be locked is left out (`null`).
+    pub(crate) fn collect(&self) -> StateDump {
+        let timeout = Duration::from_millis(DUMP_LOCK_TIMEOUT_MS);
+        StateDump {
+            generated_at: unix_now(),
+            config: self.cli.clone(),
+            tracked: self
+                .current_state
+                .try_lock_for(timeout)
+                .map(|state| state.values().cloned().collect()),
+            statuses: self
+                .statuses
+                .try_lock_for(timeout)
+                .map(|statuses| statuses.values().cloned().collect()),
+            stage_times: self.last_sent.try_lock_for(timeout).map(|last_sent| {
+                last_sent
+                    .iter()
+                    .map(|(file, sent)| (file.clone(), sent.stage_times()))
+                    .collect()
+            }),
+            diagnostics: self
+                .diagnostics
+                .try_lock_for(timeout)
+                .map(|diagnostics| diagnostics.clone()),
+        }
+    }
+
+    pub(crate) fn dump(&self, path: &Path) -> anyhow::Result<()> {
+        let dump = serde_json::to_vec_pretty(&self.collect())?;
+        std::fs::write(path, dump)
+            .context(format!("Failed to write state dump: {}", path.display()))
+    }
+}
+
+/// Path of the dump named `name` in `dir`, refusing names that would leave it: absolute paths and
+/// ones with `..` components.
+pub(crate) fn dump_path(dir: &Path, name: &str) -> anyhow::Result<PathBuf> {
+    let name = Path::new(name);
+    anyhow::ensure!(name.components().next().is_some(), "No dump name given");
+    for component in name.components() {
+        anyhow::ensure!(
+            matches!(component, Component::Normal(_) | Component::CurDir),
+            "Dump name must be a relative path inside the dump directory, got: {}",
+            name.display()
+        );
+    }
+    Ok(dir.join(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{event, handler, stage_message};
+
+    #[test]
+    fn dump_path_stays_in_dump_dir() {
+        let dir = Path::new("/var/lib/pdp/dumps");
+        assert_eq!(
+            dump_path(dir, "bug-42.json").unwrap(),
+            dir.join("bug-42.json")
+        );
+        assert_eq!(
+            dump_path(dir, "incidents/bug-42.json").unwrap(),
+            dir.join("incidents/bug-42.json")
+        );
+        for name in ["", "/etc/passwd", "../bug.json", "incidents/../../bug.json"] {
+            assert!(dump_path(dir, name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn dump_contains_all_sections() {
+        let (handler, _) = handler(&["--ack-after-processing", "--api-token", "secret-token"]);
+        let message = stage_message("a.jpg", "baga6ea4piece:bafkroot", "Uploaded");
+        assert_eq!(handler.handle(&message).await, "ACK");
+        let stored = event("a.jpg", "bafkroot", DisplayStatus::Stored);
+        handler
+            .state
+            .statuses
+            .lock()
+            .insert(stored.file.clone(), stored.clone());
+        {
+            let mut diagnostics = handler.state.diagnostics.lock();
+            diagnostics.record_transition(&stored, Some(DisplayStatus::Uploaded));
+            diagnostics.faults_detected = 2;
+            diagnostics.last_api_error = Some(TimestampedError::now("API unreachable"));
+        }
+
+        let dump = serde_json::to_value(handler.state.collect()).unwrap();
+        assert_eq!(dump["config"]["api_token"], "<redacted>");
+        assert_eq!(dump["tracked"][0]["data"]["file"], "a.jpg");
+        assert_eq!(dump["statuses"][0]["status"], "stored");
+        assert!(dump["stage_times"]["a.jpg"].is_object());
+        let diagnostics = &dump["diagnostics"];
+        let transition = &diagnostics["recent_transitions"][0];
+        assert_eq!(
+            (&transition["status"], &transition["previous"]),
+            (&"stored".into(), &"uploaded".into())
+        );
+        assert_eq!(diagnostics["faults_detected"], 2);
+        assert_eq!(diagnostics["last_api_error"]["error"], "API unreachable");
+        let kinds: Vec<_> = diagnostics["recent_events"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| event["kind"].as_str().unwrap())
+            .collect();
+        assert_eq!(kinds, ["message", "status"]);
+        assert!(diagnostics["last_serial_error"].is_null());
+    }
+}
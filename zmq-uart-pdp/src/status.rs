@@ -0,0 +1,804 @@
+//! Display statuses and the events carrying them to the serial writer.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use log::{debug, error, info, warn};
+use metrics::counter;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use zeromq::SocketSend as _;
+
+use crate::cli::StateKey;
+use crate::message::{Stage, ZmqPayload};
+use crate::state::unix_now;
+use crate::status_log::StatusLog;
+
+// Source of `StatusEvent::seq`, counting up as statuses are decided.
+static NEXT_EVENT_SEQ: AtomicU64 = AtomicU64::new(1);
+// Longest time between two checks of `--slo-secs`.
+const SLO_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Status and last proof time last sent for a file.
+pub(crate) struct SentStatus {
+    pub(crate) status: DisplayStatus,
+    /// Sequence number of the event last sent.
+    seq: u64,
+    pub(crate) last_proven_at: Option<DateTime<Utc>>,
+    /// When the status or last proof time last changed.
+    pub(crate) changed_at: Instant,
+    /// When the file last entered each status it has been in.
+    entered_at: HashMap<DisplayStatus, Instant>,
+    /// Wall-clock time of each `entered_at`.
+    entered_at_utc: HashMap<DisplayStatus, DateTime<Utc>>,
+    /// Time spent in each earlier status, summed over every time the file was in it.
+    time_in: HashMap<DisplayStatus, Duration>,
+    /// Whether the file was reported for staying too long in its current status, see
+    /// `--slo-secs`.
+    slo_breached: bool,
+}
+
+impl SentStatus {
+    fn new(event: &StatusEvent) -> Self {
+        let now = Instant::now();
+        Self {
+            status: event.status,
+            seq: event.seq,
+            last_proven_at: event.last_proven_at,
+            changed_at: now,
+            entered_at: HashMap::from([(event.status, now)]),
+            entered_at_utc: HashMap::from([(event.status, Utc::now())]),
+            time_in: HashMap::new(),
+            slo_breached: false,
+        }
+    }
+
+    /// Moves on to the status and last proof time of `event`.
+    fn update(&mut self, event: &StatusEvent) {
+        let now = Instant::now();
+        if event.status != self.status {
+            let spent = self.time_in_status(now);
+            *self.time_in.entry(self.status).or_default() += spent;
+            self.entered_at.insert(event.status, now);
+            self.entered_at_utc.insert(event.status, Utc::now());
+            self.status = event.status;
+            self.slo_breached = false;
+        }
+        self.seq = event.seq;
+        self.last_proven_at = event.last_proven_at;
+        self.changed_at = now;
+    }
+
+    fn time_in_status(&self, now: Instant) -> Duration {
+        self.entered_at
+            .get(&self.status)
+            .map_or(Duration::ZERO, |entered_at| now - *entered_at)
+    }
+
+    pub(crate) fn stage_times(&self) -> StageTimes {
+        let between = |from, to| {
+            let from = self.entered_at.get(&from)?;
+            let to = self.entered_at.get(&to)?;
+            (to >= from).then(|| (*to - *from).as_secs_f64())
+        };
+        StageTimes {
+            entered_at: self.entered_at_utc.clone(),
+            uploaded_to_stored_secs: between(DisplayStatus::Uploaded, DisplayStatus::Stored),
+            stored_to_proven_secs: between(DisplayStatus::Stored, DisplayStatus::StoredProven),
+        }
+    }
+}
+
+/// When a file last entered each status it has been in since it was first sent, and the durations
+/// derived from them, as listed by the `list` control command and in state dumps.
+#[derive(Serialize, Debug, Clone)]
+pub(crate) struct StageTimes {
+    pub(crate) entered_at: HashMap<DisplayStatus, DateTime<Utc>>,
+    /// `None` until the file got stored after being uploaded.
+    pub(crate) uploaded_to_stored_secs: Option<f64>,
+    /// `None` until the file got proven after being stored.
+    pub(crate) stored_to_proven_secs: Option<f64>,
+}
+
+/// Warns once about each file staying longer than `slo` in a status still in progress, counting
+/// it in `slo_breach_total`, see `--slo-secs`.
+pub(crate) async fn watch_slo(last_sent: Arc<Mutex<HashMap<String, SentStatus>>>, slo: Duration) {
+    let mut interval = tokio::time::interval(SLO_CHECK_INTERVAL.min(slo));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+        let mut breaches = Vec::new();
+        for (file, sent) in last_sent.lock().iter_mut() {
+            if sent.slo_breached || !sent.status.is_in_progress() {
+                continue;
+            }
+            let spent = sent.time_in_status(now);
+            if spent > slo {
+                sent.slo_breached = true;
+                breaches.push((file.clone(), sent.status, spent));
+            }
+        }
+        for (file, status, spent) in breaches {
+            warn!(
+                "{} has been {} for {:.1?}, longer than the SLO of {:?}",
+                file, status, spent, slo
+            );
+            counter!("slo_breach_total", "status" => status.to_string()).increment(1);
+        }
+    }
+}
+
+/// Logs a change of the status displayed for a file as one line of `key=value` fields under the
+/// `transition` target, e.g. to measure how long files take to get stored and proven.
+fn log_transition(previous: Option<&SentStatus>, event: &StatusEvent) {
+    let now = Instant::now();
+    let mut line = format!(
+        "file={:?} from={} to={} proofset_id={} cid={}",
+        event.file,
+        transition_field(previous.map(|sent| sent.status)),
+        transition_field(Some(event.status)),
+        transition_field(event.proofset_id.as_ref()),
+        transition_field(event.cid.as_ref()),
+    );
+    if let Some(previous) = previous {
+        let mut time_in = previous.time_in.clone();
+        *time_in.entry(previous.status).or_default() += previous.time_in_status(now);
+        let mut time_in: Vec<_> = time_in
+            .into_iter()
+            .map(|(status, time)| format!("{}:{:.3}", status, time.as_secs_f64()))
+            .collect();
+        time_in.sort();
+        line += &format!(
+            " in_previous_secs={:.3} time_in={:?}",
+            previous.time_in_status(now).as_secs_f64(),
+            time_in.join(",")
+        );
+        let since = |status| {
+            previous
+                .entered_at
+                .get(&status)
+                .map(|entered_at| (now - *entered_at).as_secs_f64())
+        };
+        match event.status {
+            DisplayStatus::Stored => {
+                if let Some(secs) = since(DisplayStatus::Uploaded) {
+                    line += &format!(" uploaded_to_stored_secs={:.3}", secs);
+                }
+            }
+            DisplayStatus::StoredProven => {
+                if let Some(secs) = since(DisplayStatus::Stored) {
+                    line += &format!(" stored_to_proven_secs={:.3}", secs);
+                }
+            }
+            _ => {}
+        }
+    }
+    info!(target: "transition", "{}", line);
+}
+
+/// Quoted value of a [`log_transition`] field, `-` if unset.
+fn transition_field(value: Option<impl std::fmt::Display>) -> String {
+    value.map_or_else(
+        || "-".to_string(),
+        |value| format!("{:?}", value.to_string()),
+    )
+}
+
+/// Sends status events to the serial writer, and to the status PUB socket if one is bound. Every
+/// status update goes through [`StatusSender::send_if_changed`] so the display only gets written
+/// when a status changes.
+#[derive(Clone)]
+pub(crate) struct StatusSender {
+    /// Queue of the writer of each serial port, each getting every event and writing those on
+    /// its route.
+    pub(crate) queues: Vec<Arc<StatusQueue>>,
+    /// Status and last proof time last sent for each file.
+    pub(crate) last_sent: Arc<Mutex<HashMap<String, SentStatus>>>,
+    /// Socket republishing every status sent to the serial writer, see `--status-pub-bind`.
+    pub(crate) publisher: Option<Arc<tokio::sync::Mutex<zeromq::PubSocket>>>,
+    /// Topic frame sent before each published status, see `--topic-prefix`. `None` publishes the
+    /// statuses as single frames.
+    pub(crate) publish_topic: Option<String>,
+    /// File recording every status sent to the serial writer, see `--status-log`.
+    pub(crate) status_log: Option<Arc<Mutex<StatusLog>>>,
+}
+
+// Topic of published statuses, after `--topic-prefix`.
+pub(crate) const STATUS_TOPIC: &str = "status";
+
+/// Status published on the status PUB socket.
+#[derive(Serialize)]
+struct PublishedStatus<'a> {
+    file: &'a str,
+    status: DisplayStatus,
+    stage: Option<&'a Stage>,
+    /// Unix time in seconds.
+    timestamp: u64,
+}
+
+impl StatusSender {
+    pub(crate) fn new(
+        queues: Vec<Arc<StatusQueue>>,
+        publisher: Option<zeromq::PubSocket>,
+        status_log: Option<StatusLog>,
+    ) -> Self {
+        Self {
+            queues,
+            last_sent: Arc::new(Mutex::new(HashMap::new())),
+            publisher: publisher.map(|socket| Arc::new(tokio::sync::Mutex::new(socket))),
+            publish_topic: None,
+            status_log: status_log.map(|status_log| Arc::new(Mutex::new(status_log))),
+        }
+    }
+
+    /// Sends `event` unless its status and last proof time are the ones last sent for the file, so
+    /// a new proof of a proven file is sent too. The first status of a file is always sent. Returns
+    /// whether `event` was sent.
+    ///
+    /// A status only reflecting the upload stage is dropped if a status derived from the file's
+    /// proofs was decided after it and sent first, since it's based on older information.
+    pub(crate) async fn send_if_changed(&self, event: StatusEvent) -> bool {
+        // Queued while holding `last_sent`, so the serial writer gets the updates of a file in the
+        // order they were checked in.
+        let message = {
+            let mut last_sent = self.last_sent.lock();
+            match last_sent.get_mut(&event.file) {
+                Some(sent)
+                    if sent.status == event.status
+                        && sent.last_proven_at == event.last_proven_at =>
+                {
+                    debug!("Status of {} unchanged: {}", event.file, event.status);
+                    return false;
+                }
+                Some(sent)
+                    if event.seq < sent.seq
+                        && !event.status.is_from_proofs()
+                        && sent.status.is_from_proofs() =>
+                {
+                    debug!(
+                        "Dropping {} of {} decided before the current {}",
+                        event.status, event.file, sent.status
+                    );
+                    return false;
+                }
+                Some(sent) if sent.status == event.status => {
+                    debug!("New proof of {}", event.file);
+                    sent.update(&event);
+                }
+                Some(sent) => {
+                    info!("Status of {} changed to: {}", event.file, event.status);
+                    log_transition(Some(sent), &event);
+                    sent.update(&event);
+                }
+                None => {
+                    info!("Status of {} changed to: {}", event.file, event.status);
+                    log_transition(None, &event);
+                    last_sent.insert(event.file.clone(), SentStatus::new(&event));
+                }
+            }
+            self.enqueue(event)
+        };
+        self.publish(message).await;
+        true
+    }
+
+    /// Sends `event` regardless of the status last sent for the file.
+    pub(crate) async fn send(&self, event: StatusEvent) {
+        self.last_sent
+            .lock()
+            .insert(event.file.clone(), SentStatus::new(&event));
+        self.send_to_writer(event).await;
+    }
+
+    /// Sends that a file is no longer tracked. It's always sent, and the file's next status is
+    /// sent as if it were its first.
+    pub(crate) async fn clear(&self, payload: &ZmqPayload, key: StateKey) {
+        let event = StatusEvent::new(payload, DisplayStatus::Cleared, key);
+        let previous = self.last_sent.lock().remove(&event.file);
+        info!("Clearing {}", event.file);
+        log_transition(previous.as_ref(), &event);
+        self.send_to_writer(event).await;
+    }
+
+    pub(crate) async fn send_to_writer(&self, event: StatusEvent) {
+        let message = self.enqueue(event);
+        self.publish(message).await;
+    }
+
+    /// Queues `event` for the serial writers and appends it to the status log. Returns the message
+    /// to publish on the status PUB socket, if one is bound.
+    fn enqueue(&self, event: StatusEvent) -> Option<String> {
+        let mut published = None;
+        if self.publisher.is_some() || self.status_log.is_some() {
+            let status = PublishedStatus {
+                file: &event.file,
+                status: event.status,
+                stage: event.stage.as_ref(),
+                timestamp: unix_now(),
+            };
+            match serde_json::to_string(&status) {
+                Ok(message) => {
+                    if let Some(status_log) = &self.status_log {
+                        status_log.lock().append(&message);
+                    }
+                    published = self.publisher.is_some().then_some(message);
+                }
+                Err(e) => error!("Failed to serialize published status: {}", e),
+            }
+        }
+        if let Some((last, queues)) = self.queues.split_last() {
+            for queue in queues {
+                queue.push(event.clone());
+            }
+            last.push(event);
+        }
+        published
+    }
+
+    async fn publish(&self, message: Option<String>) {
+        if let (Some(publisher), Some(message)) = (&self.publisher, message) {
+            let mut message = zeromq::ZmqMessage::from(message);
+            if let Some(topic) = &self.publish_topic {
+                message.push_front(topic.clone().into());
+            }
+            if let Err(e) = publisher.lock().await.send(message).await {
+                error!("Failed to publish status: {}", e);
+            }
+        }
+    }
+}
+
+/// Status updates waiting for the serial writer. Pushing never waits, so a stalled writer can't
+/// hold up the ZMQ loop or the poller. Once `capacity` updates are queued, a new update replaces
+/// the oldest pending one of the same file; updates of other files are still queued.
+pub(crate) struct StatusQueue {
+    capacity: usize,
+    events: Mutex<VecDeque<StatusEvent>>,
+    notify: Notify,
+}
+
+impl StatusQueue {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            events: Mutex::new(VecDeque::with_capacity(capacity)),
+            notify: Notify::new(),
+        }
+    }
+
+    pub(crate) fn push(&self, event: StatusEvent) {
+        {
+            let mut events = self.events.lock();
+            if events.len() >= self.capacity {
+                match events.iter().position(|pending| pending.file == event.file) {
+                    Some(idx) => {
+                        let dropped = events.remove(idx);
+                        warn!(
+                            "Status queue full, replacing pending {:?} update of {} with {:?}",
+                            dropped.map(|dropped| dropped.status),
+                            event.file,
+                            event.status
+                        );
+                    }
+                    None => warn!(
+                        "Status queue full, queueing update of {} beyond {} updates",
+                        event.file, self.capacity
+                    ),
+                }
+            }
+            events.push_back(event);
+        }
+        self.notify.notify_one();
+    }
+
+    /// Drops every queued update superseded by a later one of the same file.
+    pub(crate) fn coalesce(&self) {
+        let mut events = self.events.lock();
+        let queued = events.len();
+        let mut latest = VecDeque::with_capacity(queued);
+        while let Some(event) = events.pop_back() {
+            if !latest
+                .iter()
+                .any(|later: &StatusEvent| later.file == event.file)
+            {
+                latest.push_front(event);
+            }
+        }
+        debug!("Coalesced {} queued updates into {}", queued, latest.len());
+        *events = latest;
+    }
+
+    /// Takes the next update if there is one.
+    pub(crate) fn try_recv(&self) -> Option<StatusEvent> {
+        self.events.lock().pop_front()
+    }
+
+    /// Waits for the next update.
+    pub(crate) async fn recv(&self) -> StatusEvent {
+        loop {
+            if let Some(event) = self.try_recv() {
+                return event;
+            }
+            self.notify.notified().await;
+        }
+    }
+}
+
+/// Status shown on the display for a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DisplayStatus {
+    /// Not classified yet, see `--initial-pending`.
+    Pending,
+    Uploaded,
+    /// The file's proofset isn't known to the API yet, as it's still being created on chain.
+    Creating,
+    Stored,
+    StoredProven,
+    /// Proven, but faulted in more than `--degraded-fault-ratio` of its periods.
+    StoredDegraded,
+    /// Proven again after a fault.
+    StoredRecovered,
+    StoredFaulty,
+    /// All roots of the file were removed from the proofset.
+    Removed,
+    /// The API has been unreachable for a while, see `--on-api-failure`.
+    Offline,
+    /// The file's status hasn't been confirmed by the API for a while, see `--api-stale-secs`.
+    Stale,
+    /// Nothing to show, see `--on-api-failure`.
+    Blank,
+    /// The file is no longer tracked and its display line can be freed.
+    Cleared,
+}
+
+impl DisplayStatus {
+    pub const ALL: [DisplayStatus; 13] = [
+        DisplayStatus::Pending,
+        DisplayStatus::Uploaded,
+        DisplayStatus::Creating,
+        DisplayStatus::Stored,
+        DisplayStatus::StoredProven,
+        DisplayStatus::StoredDegraded,
+        DisplayStatus::StoredRecovered,
+        DisplayStatus::StoredFaulty,
+        DisplayStatus::Removed,
+        DisplayStatus::Offline,
+        DisplayStatus::Stale,
+        DisplayStatus::Blank,
+        DisplayStatus::Cleared,
+    ];
+}
+
+impl DisplayStatus {
+    /// Whether the status is derived from the file's proofs rather than only its upload stage.
+    pub fn is_from_proofs(self) -> bool {
+        matches!(
+            self,
+            DisplayStatus::StoredProven
+                | DisplayStatus::StoredDegraded
+                | DisplayStatus::StoredRecovered
+                | DisplayStatus::StoredFaulty
+                | DisplayStatus::Removed
+        )
+    }
+
+    /// Whether the file is still on its way to being proven.
+    pub fn is_in_progress(self) -> bool {
+        matches!(
+            self,
+            DisplayStatus::Pending
+                | DisplayStatus::Uploaded
+                | DisplayStatus::Creating
+                | DisplayStatus::Stored
+        )
+    }
+
+    /// Whether the file is currently proven, degraded, recovered or not.
+    pub fn is_proven(self) -> bool {
+        matches!(
+            self,
+            DisplayStatus::StoredProven
+                | DisplayStatus::StoredDegraded
+                | DisplayStatus::StoredRecovered
+        )
+    }
+}
+
+impl std::fmt::Display for DisplayStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = match self {
+            DisplayStatus::Pending => "pending",
+            DisplayStatus::Uploaded => "uploaded",
+            DisplayStatus::Creating => "creating",
+            DisplayStatus::Stored => "stored",
+            DisplayStatus::StoredProven => "stored & proven",
+            DisplayStatus::StoredDegraded => "stored & degraded",
+            DisplayStatus::StoredRecovered => "stored & recovered",
+            DisplayStatus::StoredFaulty => "stored & faulty",
+            DisplayStatus::Removed => "removed",
+            DisplayStatus::Offline => "offline",
+            DisplayStatus::Stale => "stale",
+            DisplayStatus::Blank => "",
+            DisplayStatus::Cleared => "clear",
+        };
+        f.write_str(status)
+    }
+}
+
+impl std::str::FromStr for DisplayStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(status: &str) -> anyhow::Result<Self> {
+        Ok(match status {
+            "pending" => DisplayStatus::Pending,
+            "uploaded" => DisplayStatus::Uploaded,
+            "creating" => DisplayStatus::Creating,
+            "stored" => DisplayStatus::Stored,
+            "stored & proven" => DisplayStatus::StoredProven,
+            "stored & degraded" => DisplayStatus::StoredDegraded,
+            "stored & recovered" => DisplayStatus::StoredRecovered,
+            "stored & faulty" => DisplayStatus::StoredFaulty,
+            "removed" => DisplayStatus::Removed,
+            "offline" => DisplayStatus::Offline,
+            "stale" => DisplayStatus::Stale,
+            "" => DisplayStatus::Blank,
+            "clear" => DisplayStatus::Cleared,
+            _ => anyhow::bail!("Unknown status: {}", status),
+        })
+    }
+}
+
+/// Serializes as the wire string sent to the Arduino.
+impl Serialize for DisplayStatus {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for DisplayStatus {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let status = String::deserialize(deserializer)?;
+        status.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Status last sent to the display for a file, as streamed to status socket readers.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatusEvent {
+    pub file: String,
+    pub status: DisplayStatus,
+    pub proofset_id: Option<String>,
+    pub cid: Option<String>,
+    /// Stage of the file when the status was sent, `None` in state saved before it was recorded.
+    #[serde(default)]
+    pub stage: Option<Stage>,
+    /// Breakdown of the roots matching the file's CID the status was derived from, if any.
+    pub roots: Vec<RootStatus>,
+    /// When the file was last proven, if known. Only set on proven statuses.
+    #[serde(default)]
+    pub last_proven_at: Option<DateTime<Utc>>,
+    /// Order in which statuses were decided, see `StatusSender::send_if_changed`. Restored
+    /// events get 0.
+    #[serde(skip)]
+    pub seq: u64,
+    /// Span the status was decided in, linking its serial write back to the message or poll
+    /// cycle behind it, see `--otlp-endpoint`.
+    #[serde(skip, default = "tracing::Span::none")]
+    pub(crate) span: tracing::Span,
+}
+
+impl StatusEvent {
+    /// Event of `payload`'s file shown under its display name when keyed by `key`.
+    pub fn new(payload: &ZmqPayload, status: DisplayStatus, key: StateKey) -> Self {
+        Self {
+            file: payload.data.display_name(key),
+            status,
+            proofset_id: payload.data.proofset_ids.first().cloned(),
+            cid: payload.data.root_cid(),
+            stage: Some(payload.stage.clone()),
+            roots: Vec::new(),
+            last_proven_at: None,
+            seq: NEXT_EVENT_SEQ.fetch_add(1, Ordering::Relaxed),
+            span: tracing::Span::current(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RootStatus {
+    pub root_id: u64,
+    pub proven: bool,
+    pub faulted: bool,
+    /// Most recent proven or faulted epoch.
+    pub epoch: Option<u64>,
+    /// Proofs submitted for the root, `0` in state saved before it was recorded.
+    #[serde(default)]
+    pub proofs_submitted: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use metrics_util::MetricKind;
+    use zeromq::{Socket as _, SocketRecv as _};
+
+    use super::*;
+    use crate::test_util::{drain, event, sender};
+
+    #[tokio::test]
+    async fn stale_stage_status_doesnt_overwrite_a_proof_status() {
+        let (status_sender, queue) = sender();
+        // The ZMQ loop decides "stored" while the poller decides "stored & proven", whose update
+        // reaches the sender first.
+        let stored = event("a.jpg", "bafkroot", DisplayStatus::Stored);
+        let proven = event("a.jpg", "bafkroot", DisplayStatus::StoredProven);
+        assert!(status_sender.send_if_changed(proven).await);
+        assert!(!status_sender.send_if_changed(stored).await);
+        assert_eq!(
+            drain(&queue),
+            [("a.jpg".to_string(), DisplayStatus::StoredProven)]
+        );
+    }
+
+    #[tokio::test]
+    async fn newer_stage_status_replaces_a_proof_status() {
+        let (status_sender, queue) = sender();
+        let proven = event("a.jpg", "bafkroot", DisplayStatus::StoredProven);
+        assert!(status_sender.send_if_changed(proven).await);
+        // Decided after the proof, e.g. for the file uploaded again.
+        let uploaded = event("a.jpg", "bafkroot", DisplayStatus::Uploaded);
+        assert!(status_sender.send_if_changed(uploaded).await);
+        assert_eq!(
+            drain(&queue),
+            [
+                ("a.jpg".to_string(), DisplayStatus::StoredProven),
+                ("a.jpg".to_string(), DisplayStatus::Uploaded)
+            ]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn interleaved_senders_never_regress_the_display() {
+        let (status_sender, queue) = sender();
+        // Pairs of statuses decided stage first, then sent from two tasks racing each other.
+        for round in 0..50 {
+            let file = format!("{}.jpg", round);
+            let stored = event(&file, "bafkroot", DisplayStatus::Stored);
+            let proven = event(&file, "bafkroot", DisplayStatus::StoredProven);
+            let (zmq, poller) = (status_sender.clone(), status_sender.clone());
+            let zmq = tokio::spawn(async move { zmq.send_if_changed(stored).await });
+            let poller = tokio::spawn(async move { poller.send_if_changed(proven).await });
+            let _ = tokio::join!(zmq, poller);
+        }
+        let mut last = HashMap::new();
+        for (file, status) in drain(&queue) {
+            last.insert(file, status);
+        }
+        assert_eq!(last.len(), 50);
+        assert!(last
+            .values()
+            .all(|status| *status == DisplayStatus::StoredProven));
+    }
+
+    #[tokio::test]
+    async fn statuses_are_published_under_the_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let endpoint = format!("ipc://{}", dir.path().join("status.sock").display());
+        let mut publisher = zeromq::PubSocket::new();
+        publisher.bind(&endpoint).await.unwrap();
+        let mut status_sender = StatusSender::new(vec![], Some(publisher), None);
+        status_sender.publish_topic = Some(format!("venue-a.{}", STATUS_TOPIC));
+        let subscribe = |prefix: &'static str| {
+            let endpoint = endpoint.clone();
+            async move {
+                let mut socket = zeromq::SubSocket::new();
+                socket.connect(&endpoint).await.unwrap();
+                socket.subscribe(prefix).await.unwrap();
+                socket
+            }
+        };
+        let (mut venue_a, mut venue_b) = (subscribe("venue-a.").await, subscribe("venue-b.").await);
+        let publishing = tokio::spawn(async move {
+            loop {
+                status_sender
+                    .send(event("a.jpg", "bafkroot", DisplayStatus::Uploaded))
+                    .await;
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        });
+        let message = tokio::time::timeout(Duration::from_secs(5), venue_a.recv());
+        let message = message.await.unwrap().unwrap();
+        assert_eq!(message.get(0).unwrap().as_ref(), b"venue-a.status");
+        let status: serde_json::Value = serde_json::from_slice(message.get(1).unwrap()).unwrap();
+        assert_eq!(status["file"], "a.jpg");
+        let other = tokio::time::timeout(Duration::from_millis(200), venue_b.recv());
+        assert!(other.await.is_err(), "received a status of another prefix");
+        publishing.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stage_times_follow_the_file() {
+        let (status_sender, _queue) = sender();
+        let stage_times = || status_sender.last_sent.lock()["a.jpg"].stage_times();
+        status_sender
+            .send_if_changed(event("a.jpg", "bafkroot", DisplayStatus::Uploaded))
+            .await;
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        status_sender
+            .send_if_changed(event("a.jpg", "bafkroot", DisplayStatus::Stored))
+            .await;
+        let times = stage_times();
+        assert_eq!(times.uploaded_to_stored_secs, Some(30.0));
+        assert_eq!(times.stored_to_proven_secs, None);
+
+        tokio::time::sleep(Duration::from_secs(90)).await;
+        status_sender
+            .send_if_changed(event("a.jpg", "bafkroot", DisplayStatus::StoredProven))
+            .await;
+        let times = stage_times();
+        assert_eq!(times.uploaded_to_stored_secs, Some(30.0));
+        assert_eq!(times.stored_to_proven_secs, Some(90.0));
+        let entered = [
+            DisplayStatus::Uploaded,
+            DisplayStatus::Stored,
+            DisplayStatus::StoredProven,
+        ];
+        assert_eq!(times.entered_at.len(), entered.len());
+        assert!(entered
+            .iter()
+            .all(|status| times.entered_at.contains_key(status)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slo_breaches_are_counted_once_per_status() {
+        let recorder = DebuggingRecorder::new();
+        let _recorder = metrics::set_default_local_recorder(&recorder);
+        let (status_sender, _queue) = sender();
+        tokio::spawn(watch_slo(
+            status_sender.last_sent.clone(),
+            Duration::from_secs(60),
+        ));
+        status_sender
+            .send_if_changed(event("a.jpg", "bafkroot", DisplayStatus::Uploaded))
+            .await;
+        tokio::time::sleep(Duration::from_secs(59)).await;
+        status_sender
+            .send_if_changed(event("a.jpg", "bafkroot", DisplayStatus::Stored))
+            .await;
+        // Long past the SLO in stored, then proven, which is no longer in progress.
+        tokio::time::sleep(Duration::from_secs(180)).await;
+        status_sender
+            .send_if_changed(event("a.jpg", "bafkroot", DisplayStatus::StoredProven))
+            .await;
+        tokio::time::sleep(Duration::from_secs(180)).await;
+
+        let breaches: HashMap<_, _> = recorder
+            .snapshotter()
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .filter(|(key, ..)| {
+                key.kind() == MetricKind::Counter && key.key().name() == "slo_breach_total"
+            })
+            .map(|(key, _, _, value)| {
+                let status = key.key().labels().next().unwrap().value().to_string();
+                (status, value)
+            })
+            .collect();
+        assert_eq!(
+            breaches,
+            HashMap::from([("stored".to_string(), DebugValue::Counter(1))])
+        );
+    }
+}
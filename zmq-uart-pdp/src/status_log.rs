@@ -0,0 +1,67 @@
+//! Audit file recording every status sent to the display, rotated by size.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+};
+
+use log::{error, info};
+
+/// Appends one line per status to `--status-log`. Once the file would grow beyond `max_bytes`, it's
+/// renamed to `<path>.1` and older files shift to `<path>.2` and so on, overwriting the oldest of
+/// the `keep` retained files.
+pub(crate) struct StatusLog {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+impl StatusLog {
+    pub(crate) fn open(path: &Path, max_bytes: u64, keep: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_bytes,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    /// Appends `line`, logging rather than failing if it can't be written, e.g. on a full disk.
+    pub(crate) fn append(&mut self, line: &str) {
+        let line = format!("{}\n", line);
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            if let Err(e) = self.rotate() {
+                error!("Failed to rotate status log {}: {}", self.path.display(), e);
+            }
+        }
+        match self.file.write_all(line.as_bytes()) {
+            Ok(()) => self.size += line.len() as u64,
+            Err(e) => error!("Failed to write status log {}: {}", self.path.display(), e),
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+        for n in (1..=self.keep).rev() {
+            let from = if n == 1 {
+                self.path.clone()
+            } else {
+                rotated(n - 1)
+            };
+            if from.exists() {
+                std::fs::rename(&from, rotated(n))?;
+            }
+        }
+        // The current file was renamed, or is started over if no rotated files are kept.
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        info!("Rotated status log {}", self.path.display());
+        Ok(())
+    }
+}
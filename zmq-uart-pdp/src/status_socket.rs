@@ -0,0 +1,156 @@
+//! Unix domain socket streaming status events to local readers. Only available on Unix, elsewhere
+//! `--status-socket` is rejected at startup.
+
+#[cfg(unix)]
+use std::{collections::HashMap, sync::Arc};
+
+#[cfg(unix)]
+use anyhow::Context;
+#[cfg(unix)]
+use log::{debug, info, warn};
+#[cfg(unix)]
+use parking_lot::Mutex;
+#[cfg(unix)]
+use serde::Serialize;
+#[cfg(unix)]
+use tokio::{
+    io::AsyncWriteExt as _,
+    net::{UnixListener, UnixStream},
+    sync::broadcast,
+};
+
+#[cfg(unix)]
+use crate::status::StatusEvent;
+
+// Events buffered per status socket reader before a slow reader starts missing updates.
+pub(crate) const STATUS_SOCKET_READER_BUFFER: usize = 16;
+
+#[cfg(unix)]
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum StatusStreamMessage<'a> {
+    Snapshot { files: Vec<&'a StatusEvent> },
+    Update(&'a StatusEvent),
+}
+
+/// Streams newline-delimited JSON status events to every reader connecting to `path`: a snapshot
+/// of all known statuses on connect, followed by live updates.
+#[cfg(unix)]
+pub(crate) async fn run_status_socket(
+    path: &str,
+    statuses: Arc<Mutex<HashMap<String, StatusEvent>>>,
+    events: broadcast::Sender<StatusEvent>,
+) -> anyhow::Result<()> {
+    // A socket file left over from a previous run would make the bind fail.
+    if let Err(e) = std::fs::remove_file(path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            return Err(e).context(format!("Failed to remove stale status socket: {}", path));
+        }
+    }
+    let listener =
+        UnixListener::bind(path).context(format!("Failed to bind status socket: {}", path))?;
+    info!("Streaming status events on {}", path);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        debug!("Status socket reader connected");
+        // Subscribe before taking the snapshot so no update falls in between.
+        let updates = events.subscribe();
+        let snapshot: Vec<StatusEvent> = statuses.lock().values().cloned().collect();
+        tokio::spawn(async move {
+            if let Err(e) = serve_status_reader(stream, snapshot, updates).await {
+                debug!("Status socket reader disconnected: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(unix)]
+pub(crate) async fn serve_status_reader(
+    mut stream: UnixStream,
+    snapshot: Vec<StatusEvent>,
+    mut updates: broadcast::Receiver<StatusEvent>,
+) -> anyhow::Result<()> {
+    let snapshot = StatusStreamMessage::Snapshot {
+        files: snapshot.iter().collect(),
+    };
+    write_json_line(&mut stream, &snapshot).await?;
+
+    loop {
+        match updates.recv().await {
+            Ok(event) => write_json_line(&mut stream, &StatusStreamMessage::Update(&event)).await?,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!("Status socket reader too slow, dropped {} updates", skipped);
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+#[cfg(unix)]
+pub(crate) async fn write_json_line(
+    stream: &mut UnixStream,
+    message: &impl Serialize,
+) -> anyhow::Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    stream.write_all(&line).await?;
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use tokio::io::{AsyncBufRead, AsyncBufReadExt as _, BufReader};
+
+    use super::*;
+    use crate::status::DisplayStatus;
+    use crate::test_util::event;
+
+    async fn read_message(lines: &mut (impl AsyncBufRead + Unpin)) -> serde_json::Value {
+        let mut line = String::new();
+        lines.read_line(&mut line).await.unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[tokio::test]
+    async fn reader_gets_snapshot_then_updates() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("status.sock");
+        let path = path.to_str().unwrap().to_string();
+        let stored = event("a.txt", "bafka", DisplayStatus::Stored);
+        let statuses = Arc::new(Mutex::new(HashMap::from([("a.txt".to_string(), stored)])));
+        let (events, _) = broadcast::channel(STATUS_SOCKET_READER_BUFFER);
+        let server = {
+            let (path, events) = (path.clone(), events.clone());
+            async move { run_status_socket(&path, statuses, events).await }
+        };
+        tokio::spawn(server);
+
+        // The socket only exists once the server bound it.
+        let stream = loop {
+            match UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        };
+        let mut lines = BufReader::new(stream);
+        let snapshot = read_message(&mut lines).await;
+        assert_eq!(snapshot["type"], "snapshot");
+        assert_eq!(snapshot["files"][0]["file"], "a.txt");
+        assert_eq!(snapshot["files"][0]["status"], "stored");
+
+        // The reader subscribed before the snapshot was taken, so it gets every later update.
+        events
+            .send(event("a.txt", "bafka", DisplayStatus::StoredProven))
+            .unwrap();
+        events
+            .send(event("b.txt", "bafkb", DisplayStatus::Stored))
+            .unwrap();
+        let update = read_message(&mut lines).await;
+        assert_eq!(update["type"], "update");
+        assert_eq!(update["file"], "a.txt");
+        assert_eq!(update["status"], "stored & proven");
+        let update = read_message(&mut lines).await;
+        assert_eq!(update["file"], "b.txt");
+    }
+}
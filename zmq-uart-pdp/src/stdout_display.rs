@@ -0,0 +1,198 @@
+//! Terminal display drawing the statuses as a table instead of sending them to an Arduino, see
+//! `--display stdout`.
+
+use std::{
+    collections::BTreeMap,
+    io::{IsTerminal as _, Write as _},
+};
+
+use log::debug;
+
+use crate::cli::Cli;
+use crate::serial::{SerialFormat, SerialSink, StatusStrings, BATCH_HEADER};
+use crate::status::DisplayStatus;
+
+// Moves the cursor to the top left corner and clears the terminal.
+const CLEAR_SCREEN: &str = "\x1b[H\x1b[2J";
+const GREEN: &str = "\x1b[32m";
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
+
+/// Serial sink redrawing a table of the files and their statuses on stdout on every write. When
+/// stdout isn't a terminal, the lines are printed as written instead.
+pub(crate) struct StdoutSink {
+    /// Status strings of the lines written, replaced on reload along with the rest of the format.
+    status_strings: StatusStrings,
+    /// Whether the lines end with a `*XX` checksum, see `--serial-checksum`.
+    checksum: bool,
+    /// Status and line last written for each displayed filename, without the filename.
+    rows: BTreeMap<String, (DisplayStatus, String)>,
+    is_terminal: bool,
+}
+
+impl StdoutSink {
+    pub(crate) fn new(cli: &Cli) -> Self {
+        Self {
+            status_strings: cli.status_strings.clone().unwrap_or_default(),
+            checksum: cli.serial_checksum.is_some(),
+            rows: BTreeMap::new(),
+            is_terminal: std::io::stdout().is_terminal(),
+        }
+    }
+
+    /// Status shown by `fields`, the part of a line after the filename. The status string may be
+    /// followed by the proof age, see `--serial-proof-age`.
+    fn status_of(&self, fields: &str) -> Option<DisplayStatus> {
+        let field = fields.split(',').next().unwrap_or_default();
+        DisplayStatus::ALL
+            .into_iter()
+            .map(|status| (status, self.status_strings.get(status)))
+            .filter(|(_, string)| {
+                field == string
+                    || field
+                        .strip_prefix(string.as_str())
+                        .is_some_and(|rest| rest.starts_with(' '))
+            })
+            .max_by_key(|(_, string)| string.len())
+            .map(|(status, _)| status)
+    }
+
+    /// Updates the rows with the status lines of `lines`.
+    fn apply(&mut self, lines: &str) {
+        for line in lines.lines() {
+            if line.starts_with(BATCH_HEADER) {
+                continue;
+            }
+            let line = match (self.checksum, line.rsplit_once('*')) {
+                (true, Some((payload, _))) => payload,
+                _ => line,
+            };
+            let Some((file, fields)) = line.split_once(',') else {
+                debug!("Not drawing {:?}", line);
+                continue;
+            };
+            match self.status_of(fields) {
+                Some(DisplayStatus::Cleared) => {
+                    self.rows.remove(file);
+                }
+                Some(status) => {
+                    self.rows
+                        .insert(file.to_string(), (status, fields.to_string()));
+                }
+                None => debug!("Not drawing {:?}", line),
+            }
+        }
+    }
+
+    fn draw(&self, out: &mut impl std::io::Write) -> std::io::Result<()> {
+        // Padding counts characters, so the names are measured in characters too.
+        let width = self
+            .rows
+            .keys()
+            .map(|file| file.chars().count())
+            .max()
+            .unwrap_or(0)
+            .max(4);
+        write!(out, "{}", CLEAR_SCREEN)?;
+        writeln!(out, "{:<width$}  STATUS", "FILE")?;
+        for (file, (status, fields)) in &self.rows {
+            let color = match status {
+                DisplayStatus::StoredProven | DisplayStatus::StoredRecovered => GREEN,
+                DisplayStatus::StoredFaulty => RED,
+                DisplayStatus::Stored => YELLOW,
+                _ => "",
+            };
+            let reset = if color.is_empty() { "" } else { RESET };
+            writeln!(out, "{:<width$}  {}{}{}", file, color, fields, reset)?;
+        }
+        out.flush()
+    }
+}
+
+impl SerialSink for StdoutSink {
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        let mut stdout = std::io::stdout().lock();
+        if !self.is_terminal {
+            stdout.write_all(line.as_bytes())?;
+            return stdout.flush();
+        }
+        self.apply(line);
+        self.draw(&mut stdout)
+    }
+
+    fn set_format(&mut self, format: &SerialFormat) {
+        self.status_strings = format.status_strings.clone();
+        self.checksum = format.checksum.is_some();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::cli;
+
+    fn drawn(sink: &StdoutSink) -> String {
+        let mut out = Vec::new();
+        sink.draw(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn draws_a_colored_row_per_file() {
+        let mut sink = StdoutSink::new(&cli(&[]));
+        sink.apply("BATCH 3\na.jpg,stored & proven\nb.jpg,stored & faulty\nc.jpg,stored\n");
+        sink.apply("init\nd.jpg,uploaded\n");
+        assert_eq!(
+            drawn(&sink),
+            format!(
+                "{}FILE   STATUS\n\
+                 a.jpg  {GREEN}stored & proven{RESET}\n\
+                 b.jpg  {RED}stored & faulty{RESET}\n\
+                 c.jpg  {YELLOW}stored{RESET}\n\
+                 d.jpg  uploaded\n",
+                CLEAR_SCREEN
+            )
+        );
+        sink.apply("b.jpg,clear\n");
+        assert!(!drawn(&sink).contains("b.jpg"));
+    }
+
+    #[test]
+    fn pads_names_by_characters() {
+        let mut sink = StdoutSink::new(&cli(&[]));
+        sink.apply("ab.jpg,uploaded\nżółw.jpg,uploaded\n");
+        // "żółw.jpg" is 8 characters but 11 bytes.
+        assert_eq!(
+            drawn(&sink),
+            format!(
+                "{}FILE      STATUS\nab.jpg    uploaded\nżółw.jpg  uploaded\n",
+                CLEAR_SCREEN
+            )
+        );
+    }
+
+    #[test]
+    fn draws_reloaded_status_strings() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("strings.json");
+        std::fs::write(&path, r#"{"stored & proven": "OK"}"#).unwrap();
+        let mut sink = StdoutSink::new(&cli(&[]));
+        sink.apply("a.jpg,stored & proven\n");
+        let reloaded = cli(&["--status-strings", path.to_str().unwrap()]);
+        sink.set_format(&SerialFormat::from_cli(&reloaded));
+        sink.apply("b.jpg,OK\n");
+        let drawn = drawn(&sink);
+        // Rows drawn before the reload keep their status.
+        assert!(
+            drawn.contains(&format!("a.jpg  {GREEN}stored & proven{RESET}")),
+            "{}",
+            drawn
+        );
+        assert!(
+            drawn.contains(&format!("b.jpg  {GREEN}OK{RESET}")),
+            "{}",
+            drawn
+        );
+    }
+}
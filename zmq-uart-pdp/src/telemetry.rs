@@ -0,0 +1,104 @@
+//! Export of tracing spans to an OTLP collector, see `--otlp-endpoint`.
+//!
+//! Spans are created with `tracing`, which does nothing until a subscriber is installed, so
+//! without `--otlp-endpoint` they cost next to nothing and logging goes through `log` as usual.
+
+use anyhow::Context;
+use opentelemetry::{trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::WithExportConfig as _;
+use opentelemetry_sdk::{runtime, trace::TracerProvider, Resource};
+use tracing_subscriber::{filter::Targets, layer::SubscriberExt as _, Layer as _};
+
+// Service name spans are exported under.
+const SERVICE_NAME: &str = "arduino-pdp";
+// Target of this crate's spans, the only ones exported. Dependencies such as the OTLP client
+// itself trace too.
+const TRACED_TARGET: &str = "arduino_pdp";
+
+/// Installs a subscriber exporting the spans of this crate to the OTLP/gRPC collector at
+/// `endpoint`, e.g. `http://localhost:4317`. The returned provider flushes the pending spans on
+/// [`shutdown_tracing`].
+pub(crate) fn init_tracing(endpoint: &str) -> anyhow::Result<TracerProvider> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()
+        .context("Failed to build OTLP exporter")?;
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new([KeyValue::new("service.name", SERVICE_NAME)]))
+        .build();
+    tracing::subscriber::set_global_default(subscriber(&provider))
+        .context("Failed to install tracing subscriber")?;
+    Ok(provider)
+}
+
+/// Subscriber handing the spans of this crate to `provider`.
+fn subscriber(provider: &TracerProvider) -> impl tracing::Subscriber + Send + Sync {
+    tracing_subscriber::registry().with(
+        tracing_opentelemetry::layer()
+            .with_tracer(provider.tracer(SERVICE_NAME))
+            .with_filter(Targets::new().with_target(TRACED_TARGET, tracing::Level::INFO)),
+    )
+}
+
+/// Exports the spans not sent yet.
+pub(crate) fn shutdown_tracing(provider: &TracerProvider) {
+    if let Err(e) = provider.shutdown() {
+        log::warn!("Failed to flush spans to the OTLP collector: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use opentelemetry::Value;
+    use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+    use parking_lot::Mutex;
+
+    use super::*;
+    use crate::test_util::{handler, stage_message};
+
+    /// Keeps the exported spans in memory.
+    #[derive(Debug, Clone, Default)]
+    struct CollectedSpans(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for CollectedSpans {
+        fn export(
+            &mut self,
+            batch: Vec<SpanData>,
+        ) -> futures_util::future::BoxFuture<'static, ExportResult> {
+            self.0.lock().extend(batch);
+            Box::pin(std::future::ready(Ok(())))
+        }
+    }
+
+    #[tokio::test]
+    async fn processed_message_exports_a_span() {
+        let spans = CollectedSpans::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(spans.clone())
+            .build();
+        let _subscriber = tracing::subscriber::set_default(subscriber(&provider));
+        let (handler, _) = handler(&["--ack-after-processing"]);
+        let message = stage_message("a.jpg", "baga6ea4piece:bafkroot", "Uploaded");
+        assert_eq!(handler.handle(&message).await, "ACK");
+        // The span ends once the status events decided in it are gone too.
+        drop(handler);
+
+        let spans = spans.0.lock();
+        let span = spans
+            .iter()
+            .find(|span| span.name == "zmq_message")
+            .expect("No span was exported for the message");
+        let attribute = |key: &str| {
+            span.attributes
+                .iter()
+                .find(|attribute| attribute.key.as_str() == key)
+                .map(|attribute| attribute.value.clone())
+        };
+        assert_eq!(attribute("file"), Some(Value::from("a.jpg")));
+        assert_eq!(attribute("stage"), Some(Value::from("Uploaded")));
+    }
+}
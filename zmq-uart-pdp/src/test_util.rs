@@ -0,0 +1,270 @@
+//! Fixtures shared by the unit tests.
+
+use std::sync::Arc;
+
+use crate::api::{Metadata, ProofSetRoot, ProofSetRoots};
+use crate::cli::{Cli, StateKey};
+use crate::handler::MessageHandler;
+use crate::message::{FileData, Stage, ZmqPayload};
+use crate::serial::{MockSerialSink, SerialOutput, SerialWriter};
+use crate::state::{Diagnostics, DumpSources};
+use crate::status::{DisplayStatus, StatusEvent, StatusQueue, StatusSender};
+
+/// Parses `args`, the flags after the program name.
+pub(crate) fn cli(args: &[&str]) -> Cli {
+    let args = std::iter::once("arduino-pdp").chain(args.iter().copied());
+    Cli::parse_with_sources(args.map(str::to_string), 0).expect("Invalid test flags")
+}
+
+/// A live root with `cid` that was neither proven nor faulted yet.
+pub(crate) fn root(root_id: u64, cid: &str) -> ProofSetRoot {
+    ProofSetRoot {
+        root_id,
+        cid: cid.to_string(),
+        size: 1024,
+        removed: false,
+        total_periods_faulted: 0,
+        total_proofs_submitted: 0,
+        last_proven_epoch: None,
+        last_proven_at: None,
+        last_faulted_epoch: None,
+        last_faulted_at: None,
+        created_at: "2025-01-01T00:00:00Z".to_string(),
+    }
+}
+
+/// `root` proven at `epoch`.
+pub(crate) fn proven_root(root_id: u64, cid: &str, epoch: u64) -> ProofSetRoot {
+    ProofSetRoot {
+        last_proven_epoch: Some(epoch),
+        total_proofs_submitted: 1,
+        ..root(root_id, cid)
+    }
+}
+
+/// A single page of the API's roots of a proofset with `data`.
+pub(crate) fn roots(data: Vec<ProofSetRoot>) -> ProofSetRoots {
+    ProofSetRoots {
+        metadata: Metadata {
+            total: data.len() as u64,
+            offset: 0,
+            limit: 100,
+        },
+        data,
+    }
+}
+
+/// A stage update of `file` with `file_id`, whose roots go to `proofset_ids`.
+pub(crate) fn payload(
+    file: &str,
+    file_id: &str,
+    stage: Stage,
+    proofset_ids: &[&str],
+) -> ZmqPayload {
+    ZmqPayload {
+        stage,
+        data: FileData {
+            file: file.to_string(),
+            file_id: file_id.to_string(),
+            proofset_ids: proofset_ids.iter().map(|id| id.to_string()).collect(),
+            watched_root: None,
+        },
+        idempotency_key: None,
+    }
+}
+
+/// Status event of `file` with a file id made of `cid`.
+pub(crate) fn event(file: &str, cid: &str, status: DisplayStatus) -> StatusEvent {
+    let file_id = format!("{}:{}", cid, cid);
+    let payload = payload(file, &file_id, Stage::RootsAdded, &["1"]);
+    StatusEvent::new(&payload, status, StateKey::File)
+}
+
+/// Status sender feeding a single queue, standing in for the serial writer.
+pub(crate) fn sender() -> (StatusSender, Arc<StatusQueue>) {
+    let queue = Arc::new(StatusQueue::new(1_024));
+    (StatusSender::new(vec![queue.clone()], None, None), queue)
+}
+
+/// Files and statuses of the events queued so far, oldest first.
+pub(crate) fn drain(queue: &StatusQueue) -> Vec<(String, DisplayStatus)> {
+    std::iter::from_fn(|| queue.try_recv())
+        .map(|event| (event.file, event.status))
+        .collect()
+}
+
+/// HTTP/1.1 server on a local port answering each request with `respond(request)`, where the
+/// request is its head followed by its body, e.g. `"GET /path HTTP/1.1\r\n...\r\n\r\n"`. The
+/// requests received are kept. A connection is closed after a response with `connection: close`,
+/// as from [`http_response`], and kept for the next request otherwise.
+pub(crate) struct MockHttpServer {
+    pub(crate) addr: std::net::SocketAddr,
+    pub(crate) requests: Arc<parking_lot::Mutex<Vec<String>>>,
+    /// Connections accepted so far.
+    pub(crate) connections: Arc<std::sync::atomic::AtomicUsize>,
+}
+
+impl MockHttpServer {
+    pub(crate) async fn start(respond: impl Fn(&str) -> String + Send + Sync + 'static) -> Self {
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let connections = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let respond = Arc::new(respond);
+        let (recorded, accepted) = (requests.clone(), connections.clone());
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                accepted.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                let (respond, recorded) = (respond.clone(), recorded.clone());
+                tokio::spawn(async move {
+                    // Bytes read past the end of the last request, the start of the next one.
+                    let mut request = Vec::new();
+                    let mut buf = [0; 4096];
+                    loop {
+                        let head_len = loop {
+                            if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                                break end + 4;
+                            }
+                            let read = stream.read(&mut buf).await.unwrap_or(0);
+                            if read == 0 {
+                                return;
+                            }
+                            request.extend_from_slice(&buf[..read]);
+                        };
+                        let head = String::from_utf8_lossy(&request[..head_len]).to_lowercase();
+                        let body_len = head
+                            .lines()
+                            .find_map(|line| line.strip_prefix("content-length:"))
+                            .and_then(|len| len.trim().parse::<usize>().ok())
+                            .unwrap_or(0);
+                        while request.len() < head_len + body_len {
+                            let read = stream.read(&mut buf).await.unwrap_or(0);
+                            if read == 0 {
+                                break;
+                            }
+                            request.extend_from_slice(&buf[..read]);
+                        }
+                        let rest = request.split_off((head_len + body_len).min(request.len()));
+                        let received = String::from_utf8_lossy(&request).into_owned();
+                        request = rest;
+                        let response = respond(&received);
+                        recorded.lock().push(received);
+                        if stream.write_all(response.as_bytes()).await.is_err() {
+                            return;
+                        }
+                        if response
+                            .to_lowercase()
+                            .contains("\r\nconnection: close\r\n")
+                        {
+                            let _ = stream.shutdown().await;
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        Self {
+            addr,
+            requests,
+            connections,
+        }
+    }
+
+    pub(crate) fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+}
+
+/// An HTTP response with `status`, e.g. `"200 OK"`, and a JSON `body`, closing the connection.
+pub(crate) fn http_response(status: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// [`http_response`] keeping the connection open for the next request.
+pub(crate) fn keep_alive_response(status: &str, body: &str) -> String {
+    http_response(status, body).replacen("connection: close\r\n", "", 1)
+}
+
+/// Message handler configured with `args`, with the status queue of its sender.
+pub(crate) fn handler(args: &[&str]) -> (MessageHandler, Arc<StatusQueue>) {
+    let cli = cli(args);
+    let queue = Arc::new(StatusQueue::new(cli.status_queue_size));
+    let status_sender = StatusSender::new(vec![queue.clone()], None, None);
+    let state = DumpSources {
+        cli: cli.clone(),
+        current_state: Default::default(),
+        statuses: Default::default(),
+        last_sent: status_sender.last_sent.clone(),
+        diagnostics: Arc::new(parking_lot::Mutex::new(Diagnostics::new(16))),
+    };
+    let config = Arc::new(parking_lot::RwLock::new(cli));
+    (MessageHandler::new(state, status_sender, config), queue)
+}
+
+/// Message handler configured with `args` and `--mock-serial`, whose statuses are written to the
+/// returned mock sink by a serial writer, as from ZMQ to the Arduino.
+pub(crate) fn pipeline(args: &[&str]) -> (MessageHandler, MockSerialSink) {
+    let cli = cli(&[args, &["--mock-serial"]].concat());
+    let SerialOutput::Mock(sink) = SerialOutput::open(&cli, &cli.serial_port).unwrap() else {
+        panic!("--mock-serial didn't select the mock sink");
+    };
+    let queue = Arc::new(StatusQueue::new(1_024));
+    let status_sender = StatusSender::new(vec![queue.clone()], None, None);
+    let state = DumpSources {
+        cli: cli.clone(),
+        current_state: Default::default(),
+        statuses: Default::default(),
+        last_sent: status_sender.last_sent.clone(),
+        diagnostics: Arc::new(parking_lot::Mutex::new(Diagnostics::new(16))),
+    };
+    let config = Arc::new(parking_lot::RwLock::new(cli));
+    let writer = SerialWriter::new(
+        sink.clone(),
+        config.clone(),
+        state.statuses.clone(),
+        state.current_state.clone(),
+        state.diagnostics.clone(),
+        tokio::sync::broadcast::channel(16).0,
+        None,
+    );
+    let (acks_tx, acks_rx) = tokio::sync::mpsc::channel(1);
+    tokio::spawn(async move {
+        // No echoes come, but a closed channel would end the writer's wait on them.
+        let _acks_tx = acks_tx;
+        writer.run(queue, acks_rx).await
+    });
+    (MessageHandler::new(state, status_sender, config), sink)
+}
+
+/// Waits until `sink` recorded `count` lines and returns them.
+pub(crate) async fn serial_lines(sink: &MockSerialSink, count: usize) -> Vec<String> {
+    let wait = async {
+        while sink.lines().len() < count {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+    };
+    if tokio::time::timeout(std::time::Duration::from_secs(5), wait)
+        .await
+        .is_err()
+    {
+        panic!("Expected {} serial lines, got {:?}", count, sink.lines());
+    }
+    sink.lines()
+}
+
+/// A stage update message as sent over ZMQ.
+pub(crate) fn stage_message(file: &str, file_id: &str, stage: &str) -> String {
+    serde_json::json!({
+        "stage": stage,
+        "data": {"file": file, "file_id": file_id, "proofset_id": "1"},
+    })
+    .to_string()
+}
@@ -0,0 +1,295 @@
+//! `--watch-file` of roots tracked alongside the files announced over ZMQ.
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Context;
+use log::{error, info, warn};
+use metrics::gauge;
+use parking_lot::Mutex;
+
+use crate::cli::StateKey;
+use crate::message::{FileData, Stage, ZmqPayload};
+use crate::status::{StatusEvent, StatusSender};
+
+/// Parses `proofset_id,cid,display_name` lines into payloads of files whose roots were added.
+/// Blank lines and lines starting with `#` are skipped. The display name may contain commas.
+pub(crate) fn parse_watch_file(contents: &str) -> anyhow::Result<Vec<ZmqPayload>> {
+    let mut entries = Vec::new();
+    for (idx, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<_> = line.splitn(3, ',').map(str::trim).collect();
+        let [proofset_id, cid, name] = fields[..] else {
+            anyhow::bail!(
+                "Line {}: expected proofset_id,cid,display_name, got {:?}",
+                idx + 1,
+                line
+            );
+        };
+        if proofset_id.is_empty() || cid.is_empty() || name.is_empty() {
+            anyhow::bail!("Line {}: empty field in {:?}", idx + 1, line);
+        }
+        entries.push(ZmqPayload {
+            stage: Stage::RootsAdded,
+            data: FileData {
+                file: name.to_string(),
+                file_id: cid.to_string(),
+                proofset_ids: vec![proofset_id.to_string()],
+                watched_root: Some(cid.to_string()),
+            },
+            idempotency_key: None,
+        });
+    }
+    Ok(entries)
+}
+
+/// Entries of the `--watch-file` currently in the tracked state.
+pub(crate) struct WatchFile {
+    path: String,
+    /// How the entries are keyed in the tracked state, see `--state-key`.
+    key: StateKey,
+    /// State keys of the entries added from the file.
+    watched: HashSet<String>,
+}
+
+impl WatchFile {
+    pub(crate) fn new(path: String, key: StateKey) -> Self {
+        Self {
+            path,
+            key,
+            watched: HashSet::new(),
+        }
+    }
+
+    /// Re-reads the file, tracking its new or changed entries and clearing the ones no longer in
+    /// it. A file that can't be read or parsed is rejected as a whole, keeping the current entries.
+    /// Entries of files already tracked from ZMQ are skipped.
+    pub(crate) async fn sync(
+        &mut self,
+        current_state: &Mutex<HashMap<String, ZmqPayload>>,
+        status_sender: &StatusSender,
+    ) {
+        let entries = match std::fs::read_to_string(&self.path)
+            .context(format!("Failed to read {}", self.path))
+            .and_then(|contents| parse_watch_file(&contents))
+        {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("Keeping the current watch file entries: {:#}", e);
+                return;
+            }
+        };
+        let keys: HashSet<_> = entries
+            .iter()
+            .map(|entry| entry.data.state_key(self.key))
+            .collect();
+        let removed: Vec<_> = {
+            let mut state = current_state.lock();
+            self.watched
+                .difference(&keys)
+                .filter_map(|key| state.remove(key))
+                .collect()
+        };
+        self.watched.retain(|key| keys.contains(key));
+        for payload in removed {
+            info!("{} is no longer in the watch file", payload.data.file);
+            status_sender.clear(&payload, self.key).await;
+        }
+        for payload in entries {
+            let key = payload.data.state_key(self.key);
+            let changed = {
+                let mut state = current_state.lock();
+                match state.get(&key) {
+                    // Also restored from `--state-file`, without a new status to send.
+                    Some(current) if *current == payload => false,
+                    Some(_) if !self.watched.contains(&key) => {
+                        warn!(
+                            "Not watching {}, it's already tracked from ZMQ",
+                            payload.data.file
+                        );
+                        continue;
+                    }
+                    _ => {
+                        state.insert(key.clone(), payload.clone());
+                        true
+                    }
+                }
+            };
+            self.watched.insert(key);
+            if changed {
+                status_sender
+                    .send_if_changed(StatusEvent::new(&payload, payload.stage.status(), self.key))
+                    .await;
+            }
+        }
+        gauge!("tracked_files").set(current_state.lock().len() as f64);
+        info!("Watching {} entries from {}", self.watched.len(), self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::api::FakeProvider;
+    use crate::poller::{poll_api, API_CHECK_INTERVAL_SECS};
+    use crate::state::Diagnostics;
+    use crate::status::DisplayStatus;
+    use crate::test_util::{cli, drain, payload, proven_root, roots, sender};
+
+    const WATCHED: &str = "# Partner uploads\n\n1,bafkone,One, the first\n2, bafktwo , two.jpg\n";
+
+    #[test]
+    fn entries_are_parsed() {
+        let entries = parse_watch_file(WATCHED).unwrap();
+        let fields: Vec<_> = entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry.data.file.as_str(),
+                    entry.data.watched_root.as_deref(),
+                    entry.data.proofset_ids.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                ("One, the first", Some("bafkone"), vec!["1".to_string()]),
+                ("two.jpg", Some("bafktwo"), vec!["2".to_string()]),
+            ]
+        );
+        assert!(entries.iter().all(|entry| entry.stage == Stage::RootsAdded));
+        assert_eq!(entries[0].data.root_cid().as_deref(), Some("bafkone"));
+    }
+
+    #[test]
+    fn malformed_lines_are_rejected() {
+        for (contents, error) in [
+            ("1,bafkone,one.jpg\n1,bafktwo\n", "Line 2: expected"),
+            ("1,,one.jpg\n", "Line 1: empty field"),
+            ("1,bafkone, \n", "Line 1: empty field"),
+        ] {
+            let err = parse_watch_file(contents).unwrap_err();
+            assert!(err.to_string().starts_with(error), "{}", err);
+        }
+    }
+
+    struct Watching {
+        file: tempfile::NamedTempFile,
+        watch_file: WatchFile,
+        current_state: Mutex<HashMap<String, ZmqPayload>>,
+        status_sender: StatusSender,
+        queue: Arc<crate::status::StatusQueue>,
+    }
+
+    impl Watching {
+        fn new(contents: &str) -> Self {
+            let file = tempfile::NamedTempFile::new().unwrap();
+            std::fs::write(file.path(), contents).unwrap();
+            let path = file.path().to_str().unwrap().to_string();
+            let (status_sender, queue) = sender();
+            Self {
+                file,
+                watch_file: WatchFile::new(path, StateKey::File),
+                current_state: Mutex::new(HashMap::new()),
+                status_sender,
+                queue,
+            }
+        }
+
+        async fn sync(&mut self, contents: &str) -> Vec<(String, DisplayStatus)> {
+            std::fs::write(self.file.path(), contents).unwrap();
+            self.watch_file
+                .sync(&self.current_state, &self.status_sender)
+                .await;
+            drain(&self.queue)
+        }
+
+        fn proofsets(&self, file: &str) -> Option<Vec<String>> {
+            let state = self.current_state.lock();
+            state.get(file).map(|entry| entry.data.proofset_ids.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn sync_follows_the_file() {
+        let mut watching = Watching::new("");
+        assert_eq!(
+            watching.sync(WATCHED).await,
+            [
+                ("One, the first".to_string(), DisplayStatus::Stored),
+                ("two.jpg".to_string(), DisplayStatus::Stored),
+            ]
+        );
+
+        // An entry moved to another proofset is tracked there, a removed one is cleared.
+        assert_eq!(
+            watching.sync("3,bafkone,One, the first\n").await,
+            [("two.jpg".to_string(), DisplayStatus::Cleared)]
+        );
+        assert_eq!(
+            watching.proofsets("One, the first"),
+            Some(vec!["3".to_string()])
+        );
+        assert_eq!(watching.proofsets("two.jpg"), None);
+
+        // A malformed file keeps the current entries.
+        assert_eq!(watching.sync("3,bafkone\n").await, []);
+        assert_eq!(
+            watching.proofsets("One, the first"),
+            Some(vec!["3".to_string()])
+        );
+
+        assert_eq!(watching.sync("4,bafknew,new.jpg\n").await.len(), 2);
+        assert_eq!(watching.proofsets("One, the first"), None);
+        assert_eq!(watching.proofsets("new.jpg"), Some(vec!["4".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn files_tracked_from_zmq_are_not_watched() {
+        let mut watching = Watching::new("");
+        let tracked = payload("two.jpg", "baga6ea4piece:bafkzmq", Stage::Uploaded, &[]);
+        watching
+            .current_state
+            .lock()
+            .insert("two.jpg".to_string(), tracked.clone());
+        assert_eq!(
+            watching.sync(WATCHED).await,
+            [("One, the first".to_string(), DisplayStatus::Stored)]
+        );
+        assert_eq!(watching.current_state.lock()["two.jpg"], tracked);
+        // Nor cleared once they leave the file.
+        assert_eq!(watching.sync("").await.len(), 1);
+        assert_eq!(watching.current_state.lock()["two.jpg"], tracked);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn watched_roots_are_classified() {
+        let mut watching = Watching::new("");
+        watching.sync("1,bafkone,one.jpg\n").await;
+        let provider = FakeProvider::default();
+        provider.set_roots("1", roots(vec![proven_root(1, "bafkone", 10)]));
+        let current_state = Arc::new(Mutex::new(watching.current_state.lock().clone()));
+        let config = Arc::new(parking_lot::RwLock::new(cli(&[
+            "--api-poll-jitter-percent",
+            "0",
+        ])));
+        tokio::spawn(poll_api(
+            provider,
+            current_state,
+            watching.status_sender.clone(),
+            config,
+            Arc::new(Mutex::new(Diagnostics::new(16))),
+            tokio_util::sync::CancellationToken::new(),
+        ));
+        tokio::time::sleep(std::time::Duration::from_secs(API_CHECK_INTERVAL_SECS + 1)).await;
+        assert_eq!(
+            drain(&watching.queue),
+            [("one.jpg".to_string(), DisplayStatus::StoredProven)]
+        );
+    }
+}
@@ -0,0 +1,388 @@
+//! ZMQ socket receiving stage updates, either replying to each message or subscribed to a
+//! publisher.
+
+use std::{path::Path, time::Duration};
+
+use anyhow::Context;
+use log::{debug, error, info, warn};
+use zeromq::{Socket as _, SocketRecv as _, SocketSend as _, ZmqError, ZmqMessage, ZmqResult};
+
+use crate::cli::{Cli, ZmqMode};
+use crate::inproc::{is_inproc, InprocSocket};
+use crate::message::ZmqPayload;
+use crate::STARTUP_RETRY_INTERVAL_SECS;
+
+pub(crate) enum ZmqSocket {
+    /// Bound REP socket, every message is answered.
+    Rep(zeromq::RepSocket),
+    /// Bound ROUTER socket, every message is answered to the client that sent it. Clients don't
+    /// wait on each other, unlike with a REP socket.
+    Router {
+        socket: zeromq::RouterSocket,
+        /// Identity and delimiter frames of the last received message, to address its reply.
+        envelope: Option<ZmqMessage>,
+    },
+    /// SUB socket connected to a publisher, messages can't be answered. Messages are expected to
+    /// start with the topic, `--topic-prefix` followed by `--zmq-topic`, either as a separate
+    /// frame or as a prefix of the payload.
+    Sub {
+        socket: zeromq::SubSocket,
+        topic: String,
+    },
+    /// Bound `inproc://` endpoint, answered like a REP socket, for clients in the same process.
+    Inproc(InprocSocket),
+}
+
+/// Checks that `endpoint` uses a supported transport, `tcp://`, `ipc://` or `inproc://`. `ipc://`
+/// is only supported on Unix.
+pub(crate) fn validate_zmq_endpoint(endpoint: &str) -> anyhow::Result<()> {
+    match endpoint.split_once("://") {
+        Some(("ipc", _)) if !cfg!(unix) => anyhow::bail!(
+            "ipc:// endpoints need Unix domain sockets, which this platform doesn't have, use \
+             tcp:// or inproc:// instead: {}",
+            endpoint
+        ),
+        Some(("tcp" | "ipc" | "inproc", address)) if !address.is_empty() => Ok(()),
+        Some((scheme, _)) => anyhow::bail!(
+            "Unsupported ZMQ endpoint scheme {:?} in {}, expected tcp://, ipc:// or inproc://",
+            scheme,
+            endpoint
+        ),
+        None => anyhow::bail!(
+            "ZMQ endpoint {} has no scheme, expected tcp://, ipc:// or inproc://",
+            endpoint
+        ),
+    }
+}
+
+/// Socket file of an `ipc://` endpoint, `None` for other endpoints and abstract sockets.
+pub(crate) fn ipc_socket_path(endpoint: &str) -> Option<&Path> {
+    endpoint
+        .strip_prefix("ipc://")
+        .filter(|path| !path.starts_with('@'))
+        .map(Path::new)
+}
+
+/// Removes the socket file of an `ipc://` endpoint left behind by a crashed instance. A socket still
+/// accepting connections belongs to a running instance and is left alone.
+#[cfg(unix)]
+pub(crate) fn remove_stale_ipc_socket(endpoint: &str) {
+    let Some(path) = ipc_socket_path(endpoint) else {
+        return;
+    };
+    match std::os::unix::net::UnixStream::connect(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+            match std::fs::remove_file(path) {
+                Ok(()) => info!("Removed stale IPC socket {}", path.display()),
+                Err(e) => warn!(
+                    "Failed to remove stale IPC socket {}: {}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+        _ => {}
+    }
+}
+
+/// `ipc://` endpoints are rejected off Unix, so there's no socket file to remove.
+#[cfg(not(unix))]
+pub(crate) fn remove_stale_ipc_socket(_endpoint: &str) {}
+
+/// Removes the socket file of an `ipc://` endpoint on shutdown.
+pub(crate) fn remove_ipc_socket(endpoint: &str) {
+    if let Some(path) = ipc_socket_path(endpoint) {
+        if let Err(e) = std::fs::remove_file(path) {
+            warn!("Failed to remove IPC socket {}: {}", path.display(), e);
+        }
+    }
+}
+
+impl ZmqSocket {
+    /// Binds a REP or ROUTER socket to, or connects a SUB socket to `--zmq-bind`, depending on
+    /// `--zmq-mode`. An `inproc://` endpoint is bound in-process in REP and ROUTER mode, there's
+    /// no in-process publisher to subscribe to.
+    pub(crate) async fn open(cli: &Cli) -> ZmqResult<Self> {
+        if is_inproc(&cli.zmq_bind) {
+            return match cli.zmq_mode {
+                ZmqMode::Rep | ZmqMode::Router => {
+                    Ok(ZmqSocket::Inproc(InprocSocket::bind(&cli.zmq_bind)?))
+                }
+                ZmqMode::Sub => Err(ZmqError::Socket(
+                    "inproc:// endpoints can't be subscribed to",
+                )),
+            };
+        }
+        match cli.zmq_mode {
+            ZmqMode::Rep => {
+                remove_stale_ipc_socket(&cli.zmq_bind);
+                let mut socket = zeromq::RepSocket::new();
+                socket.bind(&cli.zmq_bind).await?;
+                Ok(ZmqSocket::Rep(socket))
+            }
+            ZmqMode::Router => {
+                remove_stale_ipc_socket(&cli.zmq_bind);
+                let mut socket = zeromq::RouterSocket::new();
+                socket.bind(&cli.zmq_bind).await?;
+                Ok(ZmqSocket::Router {
+                    socket,
+                    envelope: None,
+                })
+            }
+            ZmqMode::Sub => {
+                let topic = format!("{}{}", cli.topic_prefix, cli.zmq_topic);
+                let mut socket = zeromq::SubSocket::new();
+                socket.connect(&cli.zmq_bind).await?;
+                socket.subscribe(&topic).await?;
+                Ok(ZmqSocket::Sub { socket, topic })
+            }
+        }
+    }
+
+    /// Opens the socket again after it broke, retrying every `STARTUP_RETRY_INTERVAL_SECS` until it
+    /// succeeds.
+    pub(crate) async fn reopen(cli: &Cli) -> Self {
+        let mut attempt = 1;
+        loop {
+            match Self::open(cli).await {
+                Ok(socket) => {
+                    info!("Reopened ZMQ socket on {}", cli.zmq_bind);
+                    return socket;
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to reopen ZMQ socket on {} (attempt {}), retrying in {}s: {}",
+                        cli.zmq_bind, attempt, STARTUP_RETRY_INTERVAL_SECS, e
+                    );
+                    tokio::time::sleep(Duration::from_secs(STARTUP_RETRY_INTERVAL_SECS)).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// Receives the next message, without the envelope in ROUTER mode and the topic in SUB mode.
+    /// Messages of other topics are skipped without being parsed, in case the publisher sends them
+    /// anyway.
+    pub(crate) async fn recv(&mut self) -> ZmqResult<ZmqMessage> {
+        match self {
+            ZmqSocket::Rep(socket) => socket.recv().await,
+            ZmqSocket::Router { socket, envelope } => {
+                let mut message = socket.recv().await?;
+                let payload = message.split_off(message.len() - 1);
+                *envelope = Some(message);
+                Ok(payload)
+            }
+            ZmqSocket::Sub { socket, topic } => loop {
+                let mut message = socket.recv().await?;
+                let first = message.get(0).cloned().unwrap_or_default();
+                if !first.starts_with(topic.as_bytes()) {
+                    debug!("Skipping message outside topic {:?}", topic);
+                    continue;
+                }
+                if message.len() > 1 {
+                    return Ok(message.split_off(message.len() - 1));
+                }
+                let payload = &first[topic.len()..];
+                return Ok(payload.trim_ascii_start().to_vec().into());
+            },
+            ZmqSocket::Inproc(socket) => socket.recv().await,
+        }
+    }
+
+    /// Answers the last received message, logging rather than failing if the reply can't be sent.
+    /// Does nothing in SUB mode.
+    pub(crate) async fn reply(&mut self, reply: String) {
+        let result = match self {
+            ZmqSocket::Rep(socket) => socket.send(reply.into()).await,
+            ZmqSocket::Router { socket, envelope } => match envelope.take() {
+                Some(mut message) => {
+                    message.push_back(reply.into());
+                    socket.send(message).await
+                }
+                None => return,
+            },
+            ZmqSocket::Sub { .. } => return,
+            ZmqSocket::Inproc(socket) => socket.reply(reply.into()),
+        };
+        if let Err(e) = result {
+            error!("Failed to send ZMQ reply: {}", e);
+        }
+    }
+}
+
+/// Whether a receive error means the socket stopped working and has to be reopened, rather than
+/// just the message being lost.
+pub(crate) fn is_broken(error: &ZmqError) -> bool {
+    matches!(
+        error,
+        ZmqError::Network(_) | ZmqError::NoMessage | ZmqError::Task(_)
+    )
+}
+
+/// Sends `payload` to the REP socket of a running instance at `endpoint` and returns its reply.
+pub async fn send_test(endpoint: &str, payload: &ZmqPayload) -> anyhow::Result<String> {
+    validate_zmq_endpoint(endpoint)?;
+    if is_inproc(endpoint) {
+        anyhow::bail!(
+            "inproc:// endpoints are only reachable from the process that bound them, use \
+             tcp:// or ipc:// to send test messages"
+        );
+    }
+    let mut socket = zeromq::ReqSocket::new();
+    socket
+        .connect(endpoint)
+        .await
+        .with_context(|| format!("Failed to connect to {}", endpoint))?;
+    let message = serde_json::to_string(payload).context("Failed to serialize payload")?;
+    socket
+        .send(message.into())
+        .await
+        .context("Failed to send payload")?;
+    let reply = socket.recv().await.context("Failed to receive reply")?;
+    String::try_from(reply).map_err(|e| anyhow::anyhow!("Failed to decode reply: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inproc::InprocClient;
+    use crate::message::Stage;
+    use crate::test_util::{cli, payload};
+
+    #[test]
+    fn endpoint_schemes_are_validated() {
+        for endpoint in ["tcp://127.0.0.1:5555", "tcp://*:5555", "inproc://pdp"] {
+            assert!(validate_zmq_endpoint(endpoint).is_ok(), "{}", endpoint);
+        }
+        // Only Unix has the domain sockets `ipc://` endpoints are made of.
+        assert_eq!(
+            validate_zmq_endpoint("ipc:///tmp/pdp.sock").is_ok(),
+            cfg!(unix)
+        );
+        for endpoint in [
+            "udp://127.0.0.1:5555",
+            "127.0.0.1:5555",
+            "tcp://",
+            "inproc://",
+        ] {
+            assert!(validate_zmq_endpoint(endpoint).is_err(), "{}", endpoint);
+        }
+    }
+
+    #[test]
+    fn ipc_socket_path_skips_abstract_sockets() {
+        assert_eq!(
+            ipc_socket_path("ipc:///tmp/pdp.sock"),
+            Some(Path::new("/tmp/pdp.sock"))
+        );
+        assert_eq!(ipc_socket_path("ipc://@pdp"), None);
+        assert_eq!(ipc_socket_path("tcp://127.0.0.1:5555"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_stale_ipc_sockets_are_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pdp.sock");
+        let endpoint = format!("ipc://{}", path.display());
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        remove_stale_ipc_socket(&endpoint);
+        assert!(path.exists(), "removed the socket of a running instance");
+        drop(listener);
+        remove_stale_ipc_socket(&endpoint);
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn ipc_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pdp.sock");
+        let endpoint = format!("ipc://{}", path.display());
+        // A socket file left behind by a crashed instance doesn't keep the socket from binding.
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let mut socket = ZmqSocket::open(&cli(&["--zmq-bind", &endpoint]))
+            .await
+            .unwrap();
+        let server = tokio::spawn(async move {
+            let message = socket.recv().await.unwrap();
+            let received = String::try_from(message).unwrap();
+            socket.reply("ACK".to_string()).await;
+            received
+        });
+        let sent = payload("a.jpg", "baga6ea4piece:bafkroot", Stage::Uploaded, &["1"]);
+        let reply = tokio::time::timeout(Duration::from_secs(5), send_test(&endpoint, &sent));
+        assert_eq!(reply.await.unwrap().unwrap(), "ACK");
+        let received = server.await.unwrap();
+        assert_eq!(received, serde_json::to_string(&sent).unwrap());
+        remove_ipc_socket(&endpoint);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn inproc_round_trip() {
+        let endpoint = "inproc://pdp-round-trip";
+        for mode in ["rep", "router"] {
+            let bind = cli(&["--zmq-mode", mode, "--zmq-bind", endpoint]);
+            let mut socket = ZmqSocket::open(&bind).await.unwrap();
+            let duplicate = ZmqSocket::open(&bind).await;
+            assert!(duplicate.is_err(), "bound {} twice", endpoint);
+            let client = InprocClient::connect(endpoint).unwrap();
+            let server = tokio::spawn(async move {
+                let message = socket.recv().await.unwrap();
+                let received = String::try_from(message).unwrap();
+                socket.reply("ACK".to_string()).await;
+                received
+            });
+            let sent = payload("a.jpg", "baga6ea4piece:bafkroot", Stage::Uploaded, &["1"]);
+            let sent = serde_json::to_string(&sent).unwrap();
+            let reply = tokio::time::timeout(Duration::from_secs(5), client.request(&sent));
+            assert_eq!(reply.await.unwrap().unwrap(), "ACK");
+            assert_eq!(server.await.unwrap(), sent);
+            // The endpoint is unbound with its socket, so it can be bound again.
+            assert!(InprocClient::connect(endpoint).is_err());
+        }
+        let sub = cli(&["--zmq-mode", "sub", "--zmq-bind", endpoint]);
+        assert!(ZmqSocket::open(&sub).await.is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn sub_sockets_only_receive_their_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let endpoint = format!("ipc://{}", dir.path().join("pub.sock").display());
+        let mut publisher = zeromq::PubSocket::new();
+        publisher.bind(&endpoint).await.unwrap();
+        let open = |prefix: &'static str| {
+            let cli = cli(&[
+                "--zmq-mode",
+                "sub",
+                "--zmq-bind",
+                &endpoint,
+                "--topic-prefix",
+                prefix,
+            ]);
+            async move { ZmqSocket::open(&cli).await.unwrap() }
+        };
+        let (mut venue_a, mut venue_b) = (open("venue-a.").await, open("venue-b.").await);
+        // Subscriptions take a moment to reach the publisher, so keep publishing until both
+        // sockets received a few messages.
+        let publishing = tokio::spawn(async move {
+            loop {
+                for message in ["venue-a. for a", "venue-b. for b", "venue-c. for c"] {
+                    publisher.send(message.into()).await.unwrap();
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        });
+        for (socket, expected) in [(&mut venue_a, "for a"), (&mut venue_b, "for b")] {
+            for _ in 0..3 {
+                let message = tokio::time::timeout(Duration::from_secs(5), socket.recv());
+                let received = String::try_from(message.await.unwrap().unwrap()).unwrap();
+                assert_eq!(received, expected);
+            }
+        }
+        publishing.abort();
+    }
+}